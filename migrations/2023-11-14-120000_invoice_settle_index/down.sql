@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS invoices_payment_hash_idx;
+ALTER TABLE invoices DROP COLUMN IF EXISTS settle_index;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS settle_index BIGINT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS invoices_payment_hash_idx ON invoices(payment_hash);
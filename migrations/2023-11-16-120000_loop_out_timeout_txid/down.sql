@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE loop_outs DROP COLUMN IF EXISTS timeout_txid;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE loop_outs ADD COLUMN IF NOT EXISTS timeout_txid TEXT;
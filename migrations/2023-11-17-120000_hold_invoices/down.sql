@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE loop_outs DROP COLUMN IF EXISTS amount;
+ALTER TABLE invoices DROP COLUMN IF EXISTS is_hold;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS is_hold BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE loop_outs ADD COLUMN IF NOT EXISTS amount BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'SWAP';
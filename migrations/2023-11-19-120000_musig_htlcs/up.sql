@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE scripts ADD COLUMN IF NOT EXISTS is_musig BOOLEAN NOT NULL DEFAULT FALSE;
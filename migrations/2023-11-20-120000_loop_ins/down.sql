@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE scripts DROP COLUMN IF EXISTS loop_in_id;
+DROP TABLE IF EXISTS loop_ins;
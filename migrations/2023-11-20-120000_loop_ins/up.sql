@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS loop_ins (
+    id                  BIGSERIAL   PRIMARY KEY,
+    state               TEXT        NOT NULL,
+    payment_request     TEXT        NOT NULL,
+    payment_hash        TEXT        NOT NULL,
+    payment_preimage    TEXT,
+    amount              BIGINT      NOT NULL,
+    fee                 BIGINT      NOT NULL,
+    claim_txid          TEXT,
+    created_at          TIMESTAMP   NOT NULL DEFAULT NOW(),
+    updated_at          TIMESTAMP   NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS loop_ins_payment_hash_idx ON loop_ins(payment_hash);
+
+ALTER TABLE scripts ADD COLUMN IF NOT EXISTS loop_in_id BIGINT REFERENCES loop_ins(id) ON DELETE CASCADE;
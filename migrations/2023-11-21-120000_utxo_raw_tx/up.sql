@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE utxos ADD COLUMN IF NOT EXISTS raw_tx TEXT;
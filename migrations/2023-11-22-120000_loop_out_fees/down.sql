@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE loop_outs DROP COLUMN IF EXISTS fee_rate;
+ALTER TABLE loop_outs DROP COLUMN IF EXISTS miner_fee;
+ALTER TABLE loop_outs DROP COLUMN IF EXISTS fee;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE loop_outs ADD COLUMN IF NOT EXISTS fee BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE loop_outs ADD COLUMN IF NOT EXISTS miner_fee BIGINT;
+ALTER TABLE loop_outs ADD COLUMN IF NOT EXISTS fee_rate DOUBLE PRECISION;
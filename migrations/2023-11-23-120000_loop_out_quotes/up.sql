@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS loop_out_quotes (
+    id                  BIGSERIAL           PRIMARY KEY,
+    quote_id            TEXT                NOT NULL,
+    amount              BIGINT              NOT NULL,
+    fee                 BIGINT              NOT NULL,
+    miner_fee           BIGINT              NOT NULL,
+    fee_rate            DOUBLE PRECISION    NOT NULL,
+    cltv_delta          BIGINT              NOT NULL,
+    expires_at          TIMESTAMP           NOT NULL,
+    redeemed_at         TIMESTAMP,
+    created_at          TIMESTAMP           NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS loop_out_quotes_quote_id_idx ON loop_out_quotes (quote_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE loop_out_quotes DROP COLUMN IF EXISTS batched;
+ALTER TABLE loop_outs DROP COLUMN IF EXISTS batched;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE loop_outs ADD COLUMN IF NOT EXISTS batched BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE loop_out_quotes ADD COLUMN IF NOT EXISTS batched BOOLEAN NOT NULL DEFAULT false;
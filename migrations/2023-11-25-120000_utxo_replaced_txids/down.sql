@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE utxos DROP COLUMN IF EXISTS replaced_txids;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE utxos ADD COLUMN IF NOT EXISTS replaced_txids TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP SEQUENCE IF EXISTS htlc_key_index_seq;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- HTLC keys are derived at indexes handed out by this sequence, so no two swaps share a key, even across restarts
+CREATE SEQUENCE IF NOT EXISTS htlc_key_index_seq AS INTEGER MINVALUE 0 START WITH 0;
+-- continue after the indexes used before the sequence existed
+SELECT setval('htlc_key_index_seq', COALESCE((SELECT MAX(local_pubkey_index) + 1 FROM scripts), 0), false);
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS loop_out_state_history (
+    id                  BIGSERIAL   PRIMARY KEY,
+    loop_out_id         BIGINT      NOT NULL REFERENCES loop_outs(id) ON DELETE CASCADE,
+    from_state          TEXT,
+    to_state            TEXT        NOT NULL,
+    reason              TEXT        NOT NULL,
+    created_at          TIMESTAMP   NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS loop_out_state_history_loop_out_id_idx ON loop_out_state_history (loop_out_id);
+
+-- the history of existing loop outs starts at their current state
+INSERT INTO loop_out_state_history (loop_out_id, to_state, reason, created_at)
+SELECT id, state, 'recorded by migration', updated_at FROM loop_outs;
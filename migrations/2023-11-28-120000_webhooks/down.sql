@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS webhook_deliveries;
+ALTER TABLE loop_out_state_history DROP COLUMN IF EXISTS notified_at;
+ALTER TABLE loop_outs DROP COLUMN IF EXISTS webhook_secret;
+ALTER TABLE loop_outs DROP COLUMN IF EXISTS webhook_url;
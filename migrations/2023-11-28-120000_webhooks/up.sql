@@ -0,0 +1,25 @@
+-- Your SQL goes here
+ALTER TABLE loop_outs ADD COLUMN IF NOT EXISTS webhook_url TEXT;
+ALTER TABLE loop_outs ADD COLUMN IF NOT EXISTS webhook_secret TEXT;
+
+ALTER TABLE loop_out_state_history ADD COLUMN IF NOT EXISTS notified_at TIMESTAMP;
+-- state changes from before webhooks existed aren't sent
+UPDATE loop_out_state_history SET notified_at = NOW() WHERE notified_at IS NULL;
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id                  BIGSERIAL   PRIMARY KEY,
+    state_change_id     BIGINT      NOT NULL REFERENCES loop_out_state_history(id) ON DELETE CASCADE,
+    url                 TEXT        NOT NULL,
+    payload             TEXT        NOT NULL,
+    signature           TEXT        NOT NULL,
+    state               TEXT        NOT NULL,
+    attempts            INTEGER     NOT NULL DEFAULT 0,
+    next_attempt_at     TIMESTAMP   NOT NULL DEFAULT NOW(),
+    last_status         INTEGER,
+    last_error          TEXT,
+    delivered_at        TIMESTAMP,
+    created_at          TIMESTAMP   NOT NULL DEFAULT NOW(),
+    updated_at          TIMESTAMP   NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_state_idx ON webhook_deliveries (state, next_attempt_at);
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE loop_outs ADD COLUMN IF NOT EXISTS timeout_confirmed_at TIMESTAMP;
+-- sweeps from before this were never watched after being broadcast
+UPDATE loop_outs SET timeout_confirmed_at = updated_at WHERE timeout_txid IS NOT NULL;
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS liquidity_reservations (
+    id                  BIGSERIAL   PRIMARY KEY,
+    loop_out_id         BIGINT      NOT NULL UNIQUE REFERENCES loop_outs(id) ON DELETE CASCADE,
+    amount              BIGINT      NOT NULL,
+    released_at         TIMESTAMP,
+    created_at          TIMESTAMP   NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS liquidity_reservations_active_idx ON liquidity_reservations (released_at)
+    WHERE released_at IS NULL;
+
+-- loop outs waiting to be funded keep their funds reserved
+INSERT INTO liquidity_reservations (loop_out_id, amount)
+SELECT id, amount FROM loop_outs WHERE state = 'AWAITING_PAYMENT'
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+UPDATE loop_ins SET state = 'CLAIMED' WHERE state = 'CLAIMING';
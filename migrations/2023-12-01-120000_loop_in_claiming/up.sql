@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- claims used to be CLAIMED once broadcast, so they're watched again until they're buried
+UPDATE loop_ins SET state = 'CLAIMING' WHERE state = 'CLAIMED';
@@ -5,7 +5,10 @@ use rocket::{
     Request,
 };
 
-use crate::services::{loop_out::LoopOutServiceError, NOT_FOUND};
+use crate::services::{
+    loop_in::LoopInServiceError, loop_out::LoopOutServiceError, INSUFFICIENT_LIQUIDITY,
+    INVALID_CLAIM, INVALID_INVOICE, INVALID_QUOTE, NOT_FOUND,
+};
 
 use std::io::Cursor;
 
@@ -42,6 +45,24 @@ impl<'r, 'o: 'r> Responder<'r, 'o> for LooperErrorResponse {
     }
 }
 
+// the gRPC API reports the same errors as the REST API, with the closest status code and the param in the message
+impl From<LooperErrorResponse> for tonic::Status {
+    fn from(e: LooperErrorResponse) -> Self {
+        let code = match e.code {
+            Status::BadRequest => tonic::Code::InvalidArgument,
+            Status::NotFound => tonic::Code::NotFound,
+            Status::TooManyRequests => tonic::Code::ResourceExhausted,
+            Status::ServiceUnavailable => tonic::Code::Unavailable,
+            _ => tonic::Code::Internal,
+        };
+        let message = match e.error.param.as_str() {
+            "" => e.error.message,
+            param => format!("{}: {}", e.error.message, param),
+        };
+        tonic::Status::new(code, message)
+    }
+}
+
 pub fn not_found(item: String) -> LooperErrorResponse {
     LooperErrorResponse::new(Status::NotFound, "not found".to_string(), item)
 }
@@ -58,6 +79,14 @@ pub fn bad_request(message: String, param: String) -> LooperErrorResponse {
     LooperErrorResponse::new(Status::BadRequest, message, param)
 }
 
+pub fn service_unavailable(message: String, param: String) -> LooperErrorResponse {
+    LooperErrorResponse::new(Status::ServiceUnavailable, message, param)
+}
+
+pub fn too_many_requests(message: String, param: String) -> LooperErrorResponse {
+    LooperErrorResponse::new(Status::TooManyRequests, message, param)
+}
+
 pub fn invalid_parameter(param: String) -> LooperErrorResponse {
     bad_request("invalid parameter".to_string(), param)
 }
@@ -70,6 +99,33 @@ pub fn handle_loop_out_error(e: LoopOutServiceError) -> LooperErrorResponse {
             not_found("loop_out".to_string())
         }
 
+        INVALID_CLAIM => bad_request(INVALID_CLAIM.to_string(), "claim".to_string()),
+
+        INVALID_QUOTE => bad_request(INVALID_QUOTE.to_string(), "quote_id".to_string()),
+
+        // the wallet can't fund the HTLC right now, a smaller amount or a later request might succeed
+        INSUFFICIENT_LIQUIDITY => {
+            service_unavailable(INSUFFICIENT_LIQUIDITY.to_string(), "amount".to_string())
+        }
+
+        e => {
+            log::error!("internal server error: {:?}", e);
+
+            internal_server_error()
+        }
+    }
+}
+
+pub fn handle_loop_in_error(e: LoopInServiceError) -> LooperErrorResponse {
+    match e.message.as_str() {
+        NOT_FOUND => {
+            log::info!("loop in not found: {:?}", e);
+
+            not_found("loop_in".to_string())
+        }
+
+        INVALID_INVOICE => invalid_parameter("invoice".to_string()),
+
         e => {
             log::error!("internal server error: {:?}", e);
 
@@ -77,3 +133,25 @@ pub fn handle_loop_out_error(e: LoopOutServiceError) -> LooperErrorResponse {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grpc_status() {
+        let status = tonic::Status::from(invalid_parameter("amount".to_string()));
+        assert_eq!(tonic::Code::InvalidArgument, status.code());
+        assert_eq!("invalid parameter: amount", status.message());
+
+        let status = tonic::Status::from(too_many_requests(
+            "too many requests".to_string(),
+            "ip".to_string(),
+        ));
+        assert_eq!(tonic::Code::ResourceExhausted, status.code());
+
+        let status = tonic::Status::from(internal_server_error());
+        assert_eq!(tonic::Code::Internal, status.code());
+        assert_eq!("internal server error", status.message());
+    }
+}
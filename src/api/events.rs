@@ -0,0 +1,253 @@
+use crate::models::{LoopOut, LoopOutState, LoopOutStateChange};
+use rocket::response::stream::Event;
+use rocket::serde::Serialize;
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StateEvent {
+    pub id: i64,
+    // from_state is None for the state the loop out was created in
+    pub from_state: Option<String>,
+    pub state: String,
+    pub reason: String,
+    // created_at is the unix timestamp of the state change
+    pub created_at: i64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConfirmationsEvent {
+    pub txid: String,
+    pub confirmations: u32,
+    // target_confs is how many confirmations the funding tx needs before the loop out is CONFIRMED
+    pub target_confs: u32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TxEvent {
+    pub txid: String,
+}
+
+/// LoopOutEvent is an update pushed on GET /loop/out/<payment_hash>/events. The SSE event name is the variant in
+/// lowercase and its data is the JSON encoded payload.
+#[derive(Debug, PartialEq)]
+pub enum LoopOutEvent {
+    State(StateEvent),
+    Confirmations(ConfirmationsEvent),
+    Claim(TxEvent),
+    Timeout(TxEvent),
+}
+
+impl LoopOutEvent {
+    pub fn into_event(self) -> Event {
+        match self {
+            LoopOutEvent::State(e) => Event::json(&e).event("state"),
+            LoopOutEvent::Confirmations(e) => Event::json(&e).event("confirmations"),
+            LoopOutEvent::Claim(e) => Event::json(&e).event("claim"),
+            LoopOutEvent::Timeout(e) => Event::json(&e).event("timeout"),
+        }
+    }
+}
+
+/// LoopOutEvents remembers what a client has been sent about a loop out, so that each poll only pushes what changed.
+#[derive(Default)]
+pub struct LoopOutEvents {
+    last_change_id: i64,
+    // confirmations is the funding (txid, confirmations) last sent. A replaced funding tx is sent again.
+    confirmations: Option<(String, u32)>,
+    claim_txid: Option<String>,
+    timeout_txid: Option<String>,
+}
+
+impl LoopOutEvents {
+    /// updates returns the events for everything that changed since the last call: new state changes, the funding tx's
+    /// (txid, confirmations) if given, and the claim or timeout txid.
+    pub fn updates(
+        &mut self,
+        loop_out: &LoopOut,
+        changes: Vec<LoopOutStateChange>,
+        confirmations: Option<(String, u32)>,
+        target_confs: u32,
+    ) -> Vec<LoopOutEvent> {
+        let mut events = vec![];
+
+        for change in changes {
+            if change.id <= self.last_change_id {
+                continue;
+            }
+            self.last_change_id = change.id;
+            events.push(LoopOutEvent::State(StateEvent {
+                id: change.id,
+                from_state: change.from_state.map(|s| s.to_string()),
+                state: change.to_state.to_string(),
+                reason: change.reason,
+                created_at: change.created_at.timestamp(),
+            }));
+        }
+
+        if let Some((txid, confirmations)) = confirmations {
+            if self.confirmations.as_ref() != Some(&(txid.clone(), confirmations)) {
+                self.confirmations = Some((txid.clone(), confirmations));
+                events.push(LoopOutEvent::Confirmations(ConfirmationsEvent {
+                    txid,
+                    confirmations,
+                    target_confs,
+                }));
+            }
+        }
+
+        if loop_out.claim_txid.is_some() && loop_out.claim_txid != self.claim_txid {
+            self.claim_txid = loop_out.claim_txid.clone();
+            events.push(LoopOutEvent::Claim(TxEvent {
+                txid: loop_out.claim_txid.clone().unwrap_or_default(),
+            }));
+        }
+        if loop_out.timeout_txid.is_some() && loop_out.timeout_txid != self.timeout_txid {
+            self.timeout_txid = loop_out.timeout_txid.clone();
+            events.push(LoopOutEvent::Timeout(TxEvent {
+                txid: loop_out.timeout_txid.clone().unwrap_or_default(),
+            }));
+        }
+
+        events
+    }
+
+    /// is_done reports whether the loop out won't change anymore. A swept HTLC can still be claimed until the sweep
+    /// is buried, so a loop out that timed out, or expired after being funded, is only done then.
+    pub fn is_done(loop_out: &LoopOut, funded: bool) -> bool {
+        match loop_out.state {
+            LoopOutState::Claimed => true,
+            LoopOutState::Timeout => loop_out.timeout_confirmed_at.is_some(),
+            LoopOutState::Expired => !funded || loop_out.timeout_confirmed_at.is_some(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn new_loop_out(state: LoopOutState) -> LoopOut {
+        let now = chrono::Utc::now().naive_utc();
+        LoopOut {
+            id: 1,
+            state,
+            created_at: now,
+            updated_at: now,
+            claim_txid: None,
+            timeout_txid: None,
+            amount: 100_000,
+            fee: 0,
+            miner_fee: None,
+            fee_rate: None,
+            batched: false,
+            webhook_url: None,
+            webhook_secret: None,
+            timeout_confirmed_at: None,
+        }
+    }
+
+    fn new_change(id: i64, from: Option<LoopOutState>, to: LoopOutState) -> LoopOutStateChange {
+        LoopOutStateChange {
+            id,
+            loop_out_id: 1,
+            from_state: from,
+            to_state: to,
+            reason: "test-reason".to_string(),
+            created_at: chrono::Utc::now().naive_utc(),
+            notified_at: None,
+        }
+    }
+
+    #[test]
+    fn test_loop_out_events() {
+        let mut events = LoopOutEvents::default();
+
+        let loop_out = new_loop_out(LoopOutState::Initiated);
+        let sent = events.updates(
+            &loop_out,
+            vec![new_change(1, None, LoopOutState::Initiated)],
+            Some(("test-txid".to_string(), 0)),
+            6,
+        );
+        assert_eq!(2, sent.len());
+        assert!(matches!(&sent[0], LoopOutEvent::State(e) if e.state == "INITIATED"));
+        assert!(matches!(&sent[1], LoopOutEvent::Confirmations(e) if e.confirmations == 0));
+
+        // nothing changed
+        let sent = events.updates(
+            &loop_out,
+            vec![new_change(1, None, LoopOutState::Initiated)],
+            Some(("test-txid".to_string(), 0)),
+            6,
+        );
+        assert!(sent.is_empty());
+
+        // the funding tx was replaced
+        let sent = events.updates(
+            &loop_out,
+            vec![
+                new_change(1, None, LoopOutState::Initiated),
+                new_change(2, Some(LoopOutState::Initiated), LoopOutState::Initiated),
+            ],
+            Some(("test-replacement-txid".to_string(), 0)),
+            6,
+        );
+        assert_eq!(2, sent.len());
+        assert!(matches!(&sent[0], LoopOutEvent::State(e) if e.id == 2));
+        assert!(
+            matches!(&sent[1], LoopOutEvent::Confirmations(e) if e.txid == "test-replacement-txid")
+        );
+
+        let mut loop_out = new_loop_out(LoopOutState::Claimed);
+        loop_out.claim_txid = Some("test-claim-txid".to_string());
+        let sent = events.updates(
+            &loop_out,
+            vec![
+                new_change(1, None, LoopOutState::Initiated),
+                new_change(2, Some(LoopOutState::Initiated), LoopOutState::Initiated),
+                new_change(3, Some(LoopOutState::Initiated), LoopOutState::Claimed),
+            ],
+            None,
+            6,
+        );
+        assert_eq!(2, sent.len());
+        assert!(matches!(&sent[0], LoopOutEvent::State(e) if e.id == 3));
+        assert_eq!(
+            LoopOutEvent::Claim(TxEvent {
+                txid: "test-claim-txid".to_string()
+            }),
+            sent[1]
+        );
+        assert!(LoopOutEvents::is_done(&loop_out, true));
+    }
+
+    #[test]
+    fn test_loop_out_events_is_done() {
+        assert!(!LoopOutEvents::is_done(
+            &new_loop_out(LoopOutState::Confirmed),
+            true
+        ));
+        assert!(LoopOutEvents::is_done(
+            &new_loop_out(LoopOutState::Expired),
+            false
+        ));
+
+        // the funded HTLC of an expired loop out still has to be swept, and the sweep buried
+        let mut loop_out = new_loop_out(LoopOutState::Expired);
+        assert!(!LoopOutEvents::is_done(&loop_out, true));
+        loop_out.timeout_txid = Some("test-timeout-txid".to_string());
+        assert!(!LoopOutEvents::is_done(&loop_out, true));
+        loop_out.timeout_confirmed_at = Some(chrono::Utc::now().naive_utc());
+        assert!(LoopOutEvents::is_done(&loop_out, true));
+
+        // a claim can still replace an unburied timeout sweep
+        let mut loop_out = new_loop_out(LoopOutState::Timeout);
+        loop_out.timeout_txid = Some("test-timeout-txid".to_string());
+        assert!(!LoopOutEvents::is_done(&loop_out, true));
+        loop_out.timeout_confirmed_at = Some(chrono::Utc::now().naive_utc());
+        assert!(LoopOutEvents::is_done(&loop_out, true));
+    }
+}
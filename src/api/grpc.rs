@@ -0,0 +1,259 @@
+use crate::{
+    api::{self, rate_limit::RateLimiter, server::LooperServer},
+    services::loop_out::LoopOutService,
+    settings,
+};
+use looperrpc::swap_server_server::{SwapServer, SwapServerServer};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::{
+    transport::{server::TcpIncoming, Server},
+    Request, Response, Status,
+};
+
+pub mod looperrpc {
+    tonic::include_proto!("looperrpc");
+}
+
+// default address the gRPC API listens on. Override with grpc.address.
+pub const GRPC_ADDRESS: &str = "127.0.0.1:50051";
+
+#[derive(Debug)]
+pub struct LooperGrpcServerError {
+    pub message: String,
+}
+
+impl LooperGrpcServerError {
+    pub fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+
+/// LooperGrpcServer serves the Loop Out API defined in proto/looper/looper.proto. It shares the loop out service,
+/// rate limiter, validation and errors with the REST API, on a port of its own.
+pub struct LooperGrpcServer {
+    loop_out_svc: Arc<LoopOutService>,
+    rate_limiter: Arc<RateLimiter>,
+    address: SocketAddr,
+}
+
+impl LooperGrpcServer {
+    pub fn new(
+        cfg: &settings::Config,
+        loop_out_svc: Arc<LoopOutService>,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> Result<Self, LooperGrpcServerError> {
+        let address: String = settings::get_or(cfg, "grpc.address", GRPC_ADDRESS.to_string())
+            .map_err(|e| {
+                LooperGrpcServerError::new(format!("error getting grpc.address from config: {}", e))
+            })?;
+        let address = address.parse().map_err(|e| {
+            LooperGrpcServerError::new(format!("invalid grpc.address {}: {:?}", address, e))
+        })?;
+
+        Ok(Self {
+            loop_out_svc,
+            rate_limiter,
+            address,
+        })
+    }
+
+    /// bind listens on grpc.address. It's separate from serve so that an address that's taken fails startup instead
+    /// of the spawned server.
+    pub fn bind(&self) -> Result<TcpIncoming, LooperGrpcServerError> {
+        TcpIncoming::new(self.address, false, None).map_err(|e| {
+            LooperGrpcServerError::new(format!(
+                "error binding gRPC API to {}: {:?}",
+                self.address, e
+            ))
+        })
+    }
+
+    pub async fn serve(self, incoming: TcpIncoming) {
+        log::info!("serving gRPC API on {}", self.address);
+
+        if let Err(e) = Server::builder()
+            .add_service(SwapServerServer::new(self))
+            .serve_with_incoming(incoming)
+            .await
+        {
+            log::error!("error serving gRPC API: {:?}", e);
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SwapServer for LooperGrpcServer {
+    async fn terms(
+        &self,
+        _: Request<looperrpc::TermsRequest>,
+    ) -> Result<Response<looperrpc::TermsResponse>, Status> {
+        let resp = api::map_loop_out_terms_to_response(
+            self.loop_out_svc.get_config(),
+            self.loop_out_svc.get_network(),
+        );
+        Ok(Response::new(resp.into()))
+    }
+
+    async fn quote(
+        &self,
+        request: Request<looperrpc::QuoteRequest>,
+    ) -> Result<Response<looperrpc::QuoteResponse>, Status> {
+        let client_ip = request.remote_addr().map(|a| a.ip());
+        let req = request.into_inner();
+        let resp = LooperServer::quote(
+            &self.loop_out_svc,
+            &self.rate_limiter,
+            client_ip,
+            req.amount,
+            req.batched,
+        )
+        .await?;
+        Ok(Response::new(resp.into()))
+    }
+
+    async fn loop_out(
+        &self,
+        request: Request<looperrpc::LoopOutRequest>,
+    ) -> Result<Response<looperrpc::LoopOutResponse>, Status> {
+        let client_ip = request.remote_addr().map(|a| a.ip());
+        let resp = LooperServer::loop_out(
+            &self.loop_out_svc,
+            &self.rate_limiter,
+            client_ip,
+            request.into_inner().into(),
+        )
+        .await?;
+        Ok(Response::new(resp.into()))
+    }
+
+    async fn get_loop_out(
+        &self,
+        request: Request<looperrpc::GetLoopOutRequest>,
+    ) -> Result<Response<looperrpc::LoopOutResponse>, Status> {
+        let resp =
+            LooperServer::get_loop_out(&self.loop_out_svc, request.into_inner().payment_hash)?;
+        Ok(Response::new(resp.into()))
+    }
+}
+
+// proto3 has no optional strings, so unset fields are empty
+fn non_empty(s: String) -> Option<String> {
+    match s.is_empty() {
+        true => None,
+        false => Some(s),
+    }
+}
+
+impl From<looperrpc::LoopOutRequest> for api::LoopOutRequest {
+    fn from(req: looperrpc::LoopOutRequest) -> Self {
+        Self {
+            pubkey: req.pubkey,
+            amount: req.amount,
+            payment_hash: non_empty(req.payment_hash),
+            musig: req.musig,
+            quote_id: non_empty(req.quote_id),
+            batched: req.batched,
+            webhook_url: non_empty(req.webhook_url),
+            webhook_secret: non_empty(req.webhook_secret),
+        }
+    }
+}
+
+impl From<api::LoopOutTermsResponse> for looperrpc::TermsResponse {
+    fn from(resp: api::LoopOutTermsResponse) -> Self {
+        Self {
+            min_amount: resp.min_amount,
+            max_amount: resp.max_amount,
+            cltv_delta: resp.cltv_delta,
+            base_fee: resp.base_fee,
+            fee_ppm: resp.fee_ppm,
+            pass_through_miner_fee: resp.pass_through_miner_fee,
+            prepay_amount: resp.prepay_amount,
+            target_confs: resp.target_confs,
+            quote_expiry: resp.quote_expiry,
+            invoice_lifetime: resp.invoice_lifetime,
+            batch_window: resp.batch_window,
+            network: resp.network,
+            protocol_versions: resp.protocol_versions,
+        }
+    }
+}
+
+impl From<api::LoopOutQuoteResponse> for looperrpc::QuoteResponse {
+    fn from(resp: api::LoopOutQuoteResponse) -> Self {
+        Self {
+            quote_id: resp.quote_id,
+            amount: resp.amount,
+            fee: resp.fee,
+            miner_fee: resp.miner_fee,
+            fee_rate: resp.fee_rate,
+            cltv_delta: resp.cltv_delta,
+            expires_at: resp.expires_at,
+            batched: resp.batched,
+        }
+    }
+}
+
+impl From<api::LoopOutResponse> for looperrpc::LoopOutResponse {
+    fn from(resp: api::LoopOutResponse) -> Self {
+        let tsi = resp.taproot_script_info;
+        let info = resp.loop_info;
+
+        Self {
+            invoice: resp.invoice,
+            prepay_invoice: resp.prepay_invoice.unwrap_or_default(),
+            address: resp.address,
+            looper_pubkey: resp.looper_pubkey,
+            txid: resp.txid.unwrap_or_default(),
+            vout: resp.vout.unwrap_or_default(),
+            replaced_txids: resp.replaced_txids,
+            taproot_script_info: Some(looperrpc::TaprootScriptInfo {
+                external_key: tsi.external_key,
+                internal_key: tsi.internal_key,
+                internal_key_tweak: tsi.internal_key_tweak,
+                tree: tsi.tree,
+                musig: tsi.musig,
+            }),
+            loop_info: Some(looperrpc::LoopOutInfo {
+                fee: info.fee,
+                miner_fee: info.miner_fee.unwrap_or_default(),
+                fee_rate: info.fee_rate.unwrap_or_default(),
+                batched: info.batched,
+                loop_hash: info.loop_hash,
+                cltv_expiry: info.cltv_expiry,
+                state: info.state,
+                claim_txid: info.claim_txid.unwrap_or_default(),
+                timeout_txid: info.timeout_txid.unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_out_request_from_proto() {
+        let req: api::LoopOutRequest = looperrpc::LoopOutRequest {
+            pubkey: "test-pubkey".to_string(),
+            amount: 100_000,
+            payment_hash: "".to_string(),
+            musig: true,
+            quote_id: "test-quote-id".to_string(),
+            batched: false,
+            webhook_url: "".to_string(),
+            webhook_secret: "".to_string(),
+        }
+        .into();
+
+        assert_eq!("test-pubkey", req.pubkey);
+        assert_eq!(100_000, req.amount);
+        assert_eq!(None, req.payment_hash);
+        assert!(req.musig);
+        assert_eq!(Some("test-quote-id".to_string()), req.quote_id);
+        assert_eq!(None, req.webhook_url);
+        assert_eq!(None, req.webhook_secret);
+    }
+}
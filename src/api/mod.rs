@@ -1,11 +1,14 @@
 use crate::{
-    models::{FullLoopOutData, Script},
+    models::{FullLoopInData, FullLoopOutData, LoopOutQuote, Script},
     services::loop_out,
 };
-use bdk::bitcoin::{secp256k1::SecretKey, taproot::TaprootSpendInfo};
+use bdk::bitcoin::{secp256k1::SecretKey, taproot::TaprootSpendInfo, Network};
 use rocket::serde::{Deserialize, Serialize};
 
 pub mod errors;
+pub mod events;
+pub mod grpc;
+pub mod rate_limit;
 pub mod server;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -13,24 +16,114 @@ pub mod server;
 pub struct LoopOutRequest {
     pub pubkey: String,
     pub amount: i64,
+    // payment_hash is set by clients that keep the preimage themselves. The swap is then paid through a hold invoice
+    // and the HTLC is only funded once the payment is accepted.
+    pub payment_hash: Option<String>,
+    // musig makes the HTLC's internal key the MuSig2 aggregate of pubkey and looper_pubkey, so that once the swap is
+    // paid it can be claimed cooperatively through the key path. See MusigClaimRequest.
+    #[serde(default)]
+    pub musig: bool,
+    // quote_id redeems a quote from GET /loop/out/quote, charging its fee. amount must match the quoted amount.
+    pub quote_id: Option<String>,
+    // batched trades speed for a lower fee: the HTLC is funded along with other loop outs in a single tx, at most
+    // batch_window seconds after the request. Only available if the server has batching enabled, see the terms.
+    #[serde(default)]
+    pub batched: bool,
+    // webhook_url is sent a signed JSON event on every state change of the loop out. Events are signed with
+    // webhook_secret, which is required along with it. See the README.
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LoopOutTermsResponse {
+    pub min_amount: i64,
+    pub max_amount: i64,
+    pub cltv_delta: u64,
+    // the swap fee is base_fee + amount * fee_ppm / 1_000_000, in sats. If pass_through_miner_fee is set, the cost of
+    // funding and sweeping the HTLC at the current fee rate is added. See GET /loop/out/quote for the exact fee.
+    pub base_fee: i64,
+    pub fee_ppm: i64,
+    pub pass_through_miner_fee: bool,
+    // prepay_amount is deducted from the swap invoice and must be paid before the HTLC is funded. 0 if disabled.
+    pub prepay_amount: i64,
+    // target_confs is how many confirmations the funding tx needs before the loop out is CONFIRMED
+    pub target_confs: u32,
+    // quote_expiry and invoice_lifetime are in seconds
+    pub quote_expiry: i64,
+    pub invoice_lifetime: i64,
+    // batch_window is how many seconds batched loop outs wait to share a funding tx. 0 if batching is disabled.
+    pub batch_window: i64,
+    pub network: String,
+    pub protocol_versions: Vec<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LoopOutQuoteResponse {
+    pub quote_id: String,
+    pub amount: i64,
+    // fee is the swap fee that will be included in the invoice
+    pub fee: i64,
+    // miner_fee is the estimated cost of the funding tx at fee_rate (sat/vB), paid by the server
+    pub miner_fee: i64,
+    pub fee_rate: f64,
+    pub cltv_delta: u64,
+    // expires_at is the unix timestamp after which the quote can no longer be redeemed
+    pub expires_at: i64,
+    // batched quotes can only be redeemed by batched loop out requests, and vice versa
+    pub batched: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MusigClaimRequest {
+    pub preimage: String,
+    // pubnonce is the client's hex encoded 66 byte MuSig2 public nonce
+    pub pubnonce: String,
+    // tx is the hex encoded unsigned claim tx. It must only spend the HTLC, through the key path.
+    pub tx: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MusigClaimResponse {
+    pub pubnonce: String,
+    pub partial_sig: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct LoopOutInfo {
+    // fee is the swap fee included in the invoice, on top of the HTLC amount
     pub fee: i64,
+    // miner_fee and fee_rate (sat/vB) are those of the funding tx, paid by the server. None until the HTLC is funded.
+    pub miner_fee: Option<i64>,
+    pub fee_rate: Option<f64>,
+    // batched loop outs share their funding tx with other loop outs. miner_fee is then their share of its fee.
+    pub batched: bool,
     pub loop_hash: String,
     pub cltv_expiry: u32,
+    pub state: String,
+    pub claim_txid: Option<String>,
+    pub timeout_txid: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(crate = "rocket::serde")]
 pub struct LoopOutResponse {
     pub invoice: String,
+    // prepay_invoice must be paid before the HTLC is funded
+    pub prepay_invoice: Option<String>,
     pub address: String,
     pub looper_pubkey: String,
-    pub txid: String,
-    pub vout: u32,
+    // txid and vout are None until the HTLC is funded
+    pub txid: Option<String>,
+    pub vout: Option<u32>,
+    // replaced_txids are the funding txs the server replaced to bump their fee, oldest first. The HTLC is at vout of
+    // txid, so clients watching a replaced tx should switch to it.
+    pub replaced_txids: Vec<String>,
     pub taproot_script_info: TaprootScriptInfo,
     pub loop_info: LoopOutInfo,
 }
@@ -42,6 +135,41 @@ pub struct TaprootScriptInfo {
     pub internal_key: String,
     pub internal_key_tweak: String,
     pub tree: Vec<String>,
+    // musig is set when internal_key is the MuSig2 aggregate of both pubkeys. internal_key_tweak is empty then.
+    pub musig: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LoopInRequest {
+    // pubkey is the client's refund key, spending the HTLC through the timeout leaf
+    pub pubkey: String,
+    // invoice is paid once the HTLC funding confirms
+    pub invoice: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LoopInInfo {
+    pub fee: i64,
+    pub loop_hash: String,
+    pub cltv_expiry: u32,
+    pub state: String,
+    pub claim_txid: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LoopInResponse {
+    pub address: String,
+    // amount is what the client must send to address: the invoice amount plus the fee
+    pub amount: i64,
+    pub looper_pubkey: String,
+    // txid and vout are None until the client's HTLC funding is confirmed
+    pub txid: Option<String>,
+    pub vout: Option<u32>,
+    pub taproot_script_info: TaprootScriptInfo,
+    pub loop_info: LoopInInfo,
 }
 
 #[allow(dead_code)]
@@ -51,6 +179,7 @@ fn new_taproot_script_info(tsi: &TaprootSpendInfo, tweak: SecretKey) -> TaprootS
         internal_key: tsi.internal_key().to_string(),
         internal_key_tweak: hex::encode(tweak.secret_bytes()),
         tree: loop_out::tree_to_vec(tsi),
+        musig: false,
     }
 }
 
@@ -68,6 +197,7 @@ fn script_to_taproot_script_info(script: Script) -> TaprootScriptInfo {
         internal_key: script.internal_tapkey,
         internal_key_tweak: script.internal_tapkey_tweak,
         tree,
+        musig: script.is_musig,
     }
 }
 
@@ -79,16 +209,83 @@ fn map_loop_out_data_to_response(data: FullLoopOutData) -> LoopOutResponse {
 
     LoopOutResponse {
         invoice: data.invoice.payment_request,
+        prepay_invoice: data.prepay_invoice.map(|i| i.payment_request),
         address,
         looper_pubkey,
-        txid: data.utxo.txid,
-        vout: data.utxo.vout as u32,
+        txid: data.utxo.as_ref().map(|u| u.txid.clone()),
+        vout: data.utxo.as_ref().map(|u| u.vout as u32),
+        replaced_txids: data
+            .utxo
+            .as_ref()
+            .map(|u| u.replaced_txids.iter().flatten().cloned().collect())
+            .unwrap_or_default(),
         taproot_script_info: script_to_taproot_script_info(data.script),
         loop_info: LoopOutInfo {
-            // TODO: calculate fee or store it in db?
-            fee: 0,
+            fee: data.loop_out.fee,
+            miner_fee: data.loop_out.miner_fee,
+            fee_rate: data.loop_out.fee_rate,
+            batched: data.loop_out.batched,
             loop_hash: data.invoice.payment_hash,
             cltv_expiry,
+            state: data.loop_out.state.to_string(),
+            claim_txid: data.loop_out.claim_txid,
+            timeout_txid: data.loop_out.timeout_txid,
+        },
+    }
+}
+
+fn map_loop_out_terms_to_response(
+    cfg: &loop_out::LoopOutConfig,
+    network: Network,
+) -> LoopOutTermsResponse {
+    LoopOutTermsResponse {
+        min_amount: cfg.min_amount,
+        max_amount: cfg.max_amount,
+        cltv_delta: cfg.cltv_delta,
+        base_fee: cfg.fee.base_fee,
+        fee_ppm: cfg.fee.fee_ppm,
+        pass_through_miner_fee: cfg.fee.pass_through_miner_fee,
+        prepay_amount: cfg.prepay_amount,
+        target_confs: cfg.target_confs,
+        quote_expiry: cfg.quote_expiry,
+        invoice_lifetime: cfg.invoice_lifetime,
+        batch_window: cfg.batch_window,
+        network: network.to_string(),
+        protocol_versions: loop_out::PROTOCOL_VERSIONS.to_vec(),
+    }
+}
+
+fn map_loop_out_quote_to_response(quote: LoopOutQuote) -> LoopOutQuoteResponse {
+    LoopOutQuoteResponse {
+        quote_id: quote.quote_id,
+        amount: quote.amount,
+        fee: quote.fee,
+        miner_fee: quote.miner_fee,
+        fee_rate: quote.fee_rate,
+        cltv_delta: quote.cltv_delta as u64,
+        expires_at: quote.expires_at.timestamp(),
+        batched: quote.batched,
+    }
+}
+
+fn map_loop_in_data_to_response(data: FullLoopInData) -> LoopInResponse {
+    let cltv_expiry = data.script.cltv_expiry as u32;
+    let address = data.script.address.clone();
+    let looper_pubkey = data.script.local_pubkey.clone();
+
+    LoopInResponse {
+        address,
+        amount: data.loop_in.amount + data.loop_in.fee,
+        looper_pubkey,
+        txid: data.utxo.as_ref().map(|u| u.txid.clone()),
+        vout: data.utxo.as_ref().map(|u| u.vout as u32),
+        taproot_script_info: script_to_taproot_script_info(data.script),
+        loop_info: LoopInInfo {
+            fee: data.loop_in.fee,
+            loop_hash: data.loop_in.payment_hash,
+            cltv_expiry,
+            state: data.loop_in.state,
+            claim_txid: data.loop_in.claim_txid,
         },
     }
 }
@@ -0,0 +1,491 @@
+use crate::{
+    api::errors::{self, LooperErrorResponse},
+    settings,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// default number of requests a client IP or pubkey can make per window. Override with ratelimit.requests.
+pub const MAX_REQUESTS: usize = 30;
+
+// default length of the rate limit window in seconds. Override with ratelimit.window.
+pub const WINDOW_SECS: u64 = 60;
+
+// default number of loop outs a client IP or pubkey can have in flight. Override with ratelimit.concurrent_swaps.
+pub const MAX_CONCURRENT_SWAPS: usize = 10;
+
+// default number of event streams a client IP can have open. Override with ratelimit.streams_per_ip.
+pub const MAX_STREAMS_PER_IP: usize = 5;
+
+// default number of event streams open across all clients. Override with ratelimit.streams.
+pub const MAX_STREAMS: usize = 1_000;
+
+// how often swaps tracked for clients that stopped making requests are checked and forgotten once they're done.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+pub struct RateLimitConfig {
+    // max_requests per window for each key, 0 disables rate limiting
+    pub max_requests: usize,
+    pub window: Duration,
+    // max_concurrent_swaps for each key, 0 disables the cap
+    pub max_concurrent_swaps: usize,
+    // max_streams_per_ip and max_streams cap the open event streams per client IP and overall, 0 disables either cap
+    pub max_streams_per_ip: usize,
+    pub max_streams: usize,
+    // ip_header, if set, is the header a trusted reverse proxy puts the client's IP in. Otherwise the IP of the peer
+    // is used, since clients can set any header they like.
+    pub ip_header: Option<String>,
+}
+
+// TrackedSwap is a loop out counted against a key, Reserved while it's being created.
+#[derive(Debug, PartialEq)]
+enum TrackedSwap {
+    Reserved(u64),
+    InFlight(String),
+}
+
+struct Swaps {
+    by_key: HashMap<String, Vec<TrackedSwap>>,
+    next_reservation: u64,
+    swept_at: Instant,
+}
+
+/// RateLimiter limits how many swap requests each client can make, keyed by IP and by pubkey. Clients are tracked
+/// in memory, so the limits reset when the server restarts.
+pub struct RateLimiter {
+    cfg: RateLimitConfig,
+    // requests holds the time of each request in the current window, per key
+    requests: Mutex<HashMap<String, VecDeque<Instant>>>,
+    // swaps holds the loop outs each key started that may still be in flight
+    swaps: Mutex<Swaps>,
+    // streams holds the number of open event streams per client IP, with None counting clients of unknown IP
+    streams: Mutex<HashMap<Option<IpAddr>, usize>>,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: RateLimitConfig) -> Self {
+        Self {
+            cfg,
+            requests: Mutex::new(HashMap::new()),
+            swaps: Mutex::new(Swaps {
+                by_key: HashMap::new(),
+                next_reservation: 0,
+                swept_at: Instant::now(),
+            }),
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(cfg: &settings::Config) -> Result<Self, RateLimiterError> {
+        let map_err =
+            |e| RateLimiterError::new(format!("error getting rate limits from config: {}", e));
+        let max_requests =
+            settings::get_or(cfg, "ratelimit.requests", MAX_REQUESTS).map_err(map_err)?;
+        let window = settings::get_or(cfg, "ratelimit.window", WINDOW_SECS).map_err(map_err)?;
+        let max_concurrent_swaps =
+            settings::get_or(cfg, "ratelimit.concurrent_swaps", MAX_CONCURRENT_SWAPS)
+                .map_err(map_err)?;
+        let max_streams_per_ip =
+            settings::get_or(cfg, "ratelimit.streams_per_ip", MAX_STREAMS_PER_IP)
+                .map_err(map_err)?;
+        let max_streams =
+            settings::get_or(cfg, "ratelimit.streams", MAX_STREAMS).map_err(map_err)?;
+        let ip_header = settings::get_opt(cfg, "ratelimit.ip_header").map_err(map_err)?;
+
+        Ok(Self::new(RateLimitConfig {
+            max_requests,
+            window: Duration::from_secs(window),
+            max_concurrent_swaps,
+            max_streams_per_ip,
+            max_streams,
+            ip_header,
+        }))
+    }
+
+    pub fn ip_header(&self) -> Option<&str> {
+        self.cfg.ip_header.as_deref()
+    }
+
+    /// check_rate counts a request against each (param, key) pair, e.g. ("pubkey", pubkey), and rejects it if any key
+    /// has used up its requests for the current window. Rejected requests still count.
+    pub fn check_rate(&self, keys: &[(&str, String)]) -> Result<(), LooperErrorResponse> {
+        if self.cfg.max_requests == 0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        // forget keys whose requests all fell out of the window
+        requests.retain(|_, times| {
+            times.retain(|t| now.duration_since(*t) < self.cfg.window);
+            !times.is_empty()
+        });
+
+        let mut limited = None;
+        for (param, key) in keys {
+            let times = requests.entry(Self::key(param, key)).or_default();
+            times.push_back(now);
+            if times.len() > self.cfg.max_requests && limited.is_none() {
+                limited = Some(param);
+            }
+        }
+
+        match limited {
+            Some(param) => {
+                log::info!("rate limited {}: {:?}", param, keys);
+                Err(errors::too_many_requests(
+                    "too many requests".to_string(),
+                    param.to_string(),
+                ))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// reserve_swap rejects a new loop out if any (param, key) pair already has max_concurrent_swaps in flight, and
+    /// otherwise counts it against every key until the returned reservation is dropped or confirmed. Reserving and
+    /// checking happen under one lock, so concurrent requests can't all pass the check. is_in_flight is asked about
+    /// each tracked swap before the lock is taken, since it may hit the db, and finished ones are forgotten. Swaps
+    /// that start tracking in between are counted as in flight.
+    pub fn reserve_swap<F>(
+        &self,
+        keys: &[(&str, String)],
+        is_in_flight: F,
+    ) -> Result<SwapReservation<'_>, LooperErrorResponse>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if self.cfg.max_concurrent_swaps == 0 {
+            return Ok(SwapReservation {
+                limiter: self,
+                keys: vec![],
+                id: None,
+            });
+        }
+
+        let keys: Vec<(&str, String)> = keys
+            .iter()
+            .map(|(param, key)| (*param, Self::key(param, key)))
+            .collect();
+
+        // clients that never come back would otherwise be tracked forever
+        let (sweep, payment_hashes) = {
+            let swaps = self.swaps.lock().unwrap();
+            let sweep = swaps.swept_at.elapsed() >= SWEEP_INTERVAL;
+            let payment_hashes: HashSet<String> = swaps
+                .by_key
+                .iter()
+                .filter(|(key, _)| sweep || keys.iter().any(|(_, k)| k == *key))
+                .flat_map(|(_, tracked)| tracked)
+                .filter_map(|swap| match swap {
+                    TrackedSwap::InFlight(payment_hash) => Some(payment_hash.clone()),
+                    TrackedSwap::Reserved(_) => None,
+                })
+                .collect();
+            (sweep, payment_hashes)
+        };
+        let finished: HashSet<String> = payment_hashes
+            .into_iter()
+            .filter(|payment_hash| !is_in_flight(payment_hash.as_str()))
+            .collect();
+        let is_live = |swap: &TrackedSwap| match swap {
+            TrackedSwap::Reserved(_) => true,
+            TrackedSwap::InFlight(payment_hash) => !finished.contains(payment_hash),
+        };
+
+        let mut swaps = self.swaps.lock().unwrap();
+        if sweep {
+            swaps.by_key.retain(|_, tracked| {
+                tracked.retain(&is_live);
+                !tracked.is_empty()
+            });
+            swaps.swept_at = Instant::now();
+        }
+
+        for (param, key) in &keys {
+            let in_flight = match swaps.by_key.get_mut(key) {
+                Some(tracked) => {
+                    tracked.retain(&is_live);
+                    tracked.len()
+                }
+                None => 0,
+            };
+            if in_flight == 0 {
+                swaps.by_key.remove(key);
+            }
+
+            if in_flight >= self.cfg.max_concurrent_swaps {
+                log::info!("too many concurrent swaps for {}", key);
+                return Err(errors::too_many_requests(
+                    "too many concurrent swaps".to_string(),
+                    param.to_string(),
+                ));
+            }
+        }
+
+        let id = swaps.next_reservation;
+        swaps.next_reservation += 1;
+        for (_, key) in &keys {
+            swaps
+                .by_key
+                .entry(key.clone())
+                .or_default()
+                .push(TrackedSwap::Reserved(id));
+        }
+
+        Ok(SwapReservation {
+            limiter: self,
+            keys: keys.into_iter().map(|(_, key)| key).collect(),
+            id: Some(id),
+        })
+    }
+
+    // replace_reservation swaps the reservation for the loop out it became, or drops it if there's none.
+    fn replace_reservation(&self, keys: &[String], id: u64, payment_hash: Option<&str>) {
+        let mut swaps = self.swaps.lock().unwrap();
+        for key in keys {
+            let tracked = match swaps.by_key.get_mut(key) {
+                Some(tracked) => tracked,
+                None => continue,
+            };
+            tracked.retain(|swap| *swap != TrackedSwap::Reserved(id));
+            if let Some(payment_hash) = payment_hash {
+                tracked.push(TrackedSwap::InFlight(payment_hash.to_string()));
+            }
+            if tracked.is_empty() {
+                swaps.by_key.remove(key);
+            }
+        }
+    }
+
+    /// open_stream counts an event stream opened by client_ip until the returned guard is dropped, rejecting it if the
+    /// client or the server already has as many streams open as allowed.
+    pub fn open_stream(
+        self: &Arc<Self>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<StreamGuard, LooperErrorResponse> {
+        let mut streams = self.streams.lock().unwrap();
+        let total: usize = streams.values().sum();
+        let open = streams.get(&client_ip).copied().unwrap_or(0);
+        if self.cfg.max_streams > 0 && total >= self.cfg.max_streams {
+            log::warn!("too many event streams open: {}", total);
+            return Err(errors::too_many_requests(
+                "too many event streams".to_string(),
+                "ip".to_string(),
+            ));
+        }
+        if self.cfg.max_streams_per_ip > 0 && open >= self.cfg.max_streams_per_ip {
+            log::info!("too many event streams for {:?}", client_ip);
+            return Err(errors::too_many_requests(
+                "too many event streams".to_string(),
+                "ip".to_string(),
+            ));
+        }
+        *streams.entry(client_ip).or_default() += 1;
+
+        Ok(StreamGuard {
+            limiter: self.clone(),
+            client_ip,
+        })
+    }
+
+    fn close_stream(&self, client_ip: Option<IpAddr>) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(open) = streams.get_mut(&client_ip) {
+            *open -= 1;
+            if *open == 0 {
+                streams.remove(&client_ip);
+            }
+        }
+    }
+
+    fn key(param: &str, key: &str) -> String {
+        format!("{}:{}", param, key)
+    }
+}
+
+/// SwapReservation holds a loop out's place under the concurrent swap cap while it's being created. Confirm it with
+/// the loop out's payment hash once it's created. Dropping it releases the place, e.g. when creating the loop out fails.
+pub struct SwapReservation<'a> {
+    limiter: &'a RateLimiter,
+    keys: Vec<String>,
+    id: Option<u64>,
+}
+
+impl SwapReservation<'_> {
+    pub fn confirm(mut self, payment_hash: &str) {
+        if let Some(id) = self.id.take() {
+            self.limiter
+                .replace_reservation(&self.keys, id, Some(payment_hash));
+        }
+    }
+}
+
+impl Drop for SwapReservation<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.limiter.replace_reservation(&self.keys, id, None);
+        }
+    }
+}
+
+/// StreamGuard keeps an event stream counted against its client's cap until it's dropped with the stream.
+pub struct StreamGuard {
+    limiter: Arc<RateLimiter>,
+    client_ip: Option<IpAddr>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.limiter.close_stream(self.client_ip);
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiterError {
+    pub message: String,
+}
+
+impl RateLimiterError {
+    pub fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn new_limiter(max_requests: usize, max_concurrent_swaps: usize) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            max_requests,
+            window: Duration::from_secs(60),
+            max_concurrent_swaps,
+            max_streams_per_ip: 1,
+            max_streams: 2,
+            ip_header: None,
+        })
+    }
+
+    #[test]
+    fn test_check_rate() {
+        let limiter = new_limiter(2, 0);
+        let ip = ("ip", "127.0.0.1".to_string());
+
+        assert!(limiter.check_rate(&[ip.clone()]).is_ok());
+        assert!(limiter.check_rate(&[ip.clone()]).is_ok());
+
+        // the pubkey is still under its limit, but the ip isn't
+        let err = limiter
+            .check_rate(&[ip.clone(), ("pubkey", "pk".to_string())])
+            .unwrap_err();
+        assert_eq!(rocket::http::Status::TooManyRequests, err.code);
+        assert_eq!("ip", err.error.param);
+
+        // other clients aren't affected
+        assert!(limiter
+            .check_rate(&[("ip", "127.0.0.2".to_string())])
+            .is_ok());
+    }
+
+    #[test]
+    fn test_reserve_swap() {
+        let limiter = new_limiter(0, 1);
+        let keys = [("pubkey", "pk".to_string())];
+
+        let reservation = limiter.reserve_swap(&keys, |_| true).unwrap();
+        // the reservation counts until the loop out is created
+        let err = limiter.reserve_swap(&keys, |_| true).unwrap_err();
+        assert_eq!(rocket::http::Status::TooManyRequests, err.code);
+        assert_eq!("pubkey", err.error.param);
+        reservation.confirm("hash");
+
+        let err = limiter.reserve_swap(&keys, |_| true).unwrap_err();
+        assert_eq!("pubkey", err.error.param);
+
+        // once the swap is done, another can start
+        assert!(limiter.reserve_swap(&keys, |_| false).is_ok());
+    }
+
+    #[test]
+    fn test_reserve_swap_released_on_drop() {
+        let limiter = new_limiter(0, 1);
+        let keys = [
+            ("ip", "127.0.0.1".to_string()),
+            ("pubkey", "pk".to_string()),
+        ];
+
+        // creating the loop out failed
+        drop(limiter.reserve_swap(&keys, |_| true).unwrap());
+
+        assert!(limiter.reserve_swap(&keys, |_| true).is_ok());
+        assert!(limiter.swaps.lock().unwrap().by_key.is_empty());
+    }
+
+    #[test]
+    fn test_reserve_swap_checks_in_flight_unlocked() {
+        let limiter = new_limiter(0, 2);
+        let keys = [("pubkey", "pk".to_string())];
+        limiter
+            .reserve_swap(&keys, |_| true)
+            .unwrap()
+            .confirm("hash");
+
+        // a slow db lookup mustn't hold up other requests
+        let reservation = limiter
+            .reserve_swap(&keys, |payment_hash| {
+                assert_eq!("hash", payment_hash);
+                assert!(limiter.swaps.try_lock().is_ok());
+                true
+            })
+            .unwrap();
+        drop(reservation);
+
+        // finished swaps are forgotten
+        let _reservation = limiter.reserve_swap(&keys, |_| false).unwrap();
+        assert_eq!(
+            vec![TrackedSwap::Reserved(2)],
+            limiter.swaps.lock().unwrap().by_key[&RateLimiter::key("pubkey", "pk")]
+        );
+    }
+
+    #[test]
+    fn test_open_stream() {
+        let limiter = Arc::new(new_limiter(0, 0));
+        let ip = |last| Some(IpAddr::from([127, 0, 0, last]));
+
+        let first = limiter.open_stream(ip(1)).unwrap();
+        let err = limiter.open_stream(ip(1)).unwrap_err();
+        assert_eq!(rocket::http::Status::TooManyRequests, err.code);
+
+        // the server only has room for two streams
+        let _second = limiter.open_stream(ip(2)).unwrap();
+        assert!(limiter.open_stream(ip(3)).is_err());
+
+        // closing a stream frees its place
+        drop(first);
+        assert!(limiter.open_stream(ip(1)).is_ok());
+        assert!(!limiter.streams.lock().unwrap().contains_key(&ip(1)));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let cfg = settings::Config::builder()
+            .set_override("ratelimit.requests", 5)
+            .and_then(|b| b.build())
+            .expect("failed to build config");
+        let limiter = RateLimiter::from_config(&cfg).expect("failed to load rate limits");
+        assert_eq!(5, limiter.cfg.max_requests);
+        assert_eq!(MAX_STREAMS, limiter.cfg.max_streams);
+        assert_eq!(None, limiter.ip_header());
+
+        // a mistyped limit isn't silently replaced by the default
+        let cfg = settings::Config::builder()
+            .set_override("ratelimit.concurrent_swaps", "ten")
+            .and_then(|b| b.build())
+            .expect("failed to build config");
+        assert!(RateLimiter::from_config(&cfg).is_err());
+    }
+}
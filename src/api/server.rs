@@ -2,20 +2,50 @@ use crate::{
     api::{
         self,
         errors::{self, LooperErrorResponse},
-        LoopOutRequest, LoopOutResponse,
+        events::{LoopOutEvent, LoopOutEvents},
+        rate_limit::RateLimiter,
+        LoopInRequest, LoopInResponse, LoopOutQuoteResponse, LoopOutRequest, LoopOutResponse,
+        LoopOutTermsResponse, MusigClaimRequest, MusigClaimResponse,
+    },
+    models::LoopOutState,
+    services::{
+        loop_in::LoopInService,
+        loop_out::{LoopOutService, LoopOutServiceError},
+        webhook::{self, WebhookService, WebhookTarget},
     },
-    services::loop_out::LoopOutService,
 };
+use rocket::response::stream::EventStream;
 use rocket::serde::json::Json;
+use rocket::tokio::{select, time::sleep};
+use rocket::Shutdown;
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+// how often event streams check their loop out for updates.
+const EVENT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// how long an event stream stays open. Clients reconnect to keep following the loop out.
+const EVENT_STREAM_LIFETIME: Duration = Duration::from_secs(3600);
 
 pub struct LooperServer {
-    pub loop_out_svc: LoopOutService,
+    pub loop_out_svc: Arc<LoopOutService>,
+    pub loop_in_svc: Arc<LoopInService>,
+    pub rate_limiter: Arc<RateLimiter>,
 }
 
 impl LooperServer {
-    pub fn new(loop_out_svc: LoopOutService) -> Self {
-        Self { loop_out_svc }
+    pub fn new(
+        loop_out_svc: Arc<LoopOutService>,
+        loop_in_svc: Arc<LoopInService>,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> Self {
+        Self {
+            loop_out_svc,
+            loop_in_svc,
+            rate_limiter,
+        }
     }
     pub fn start(self) {
         self.serve();
@@ -31,16 +61,112 @@ impl LooperServer {
                     .unwrap();
                 rt.block_on(async move {
                     // TODO: build custom with config & timeout
-                    let builder = rocket::build()
+                    // the client IP is only read from a header set by a trusted proxy, if any
+                    let figment = match self.rate_limiter.ip_header() {
+                        Some(ip_header) => {
+                            rocket::Config::figment().merge(("ip_header", ip_header))
+                        }
+                        None => rocket::Config::figment().merge(("ip_header", false)),
+                    };
+                    let builder = rocket::custom(figment)
                         .manage(self.loop_out_svc)
-                        .mount("/loop", routes![index, new_loop_out, get_loop_out]);
+                        .manage(self.loop_in_svc)
+                        .manage(self.rate_limiter)
+                        .mount(
+                            "/loop",
+                            routes![
+                                index,
+                                loop_out_terms,
+                                quote_loop_out,
+                                new_loop_out,
+                                get_loop_out,
+                                loop_out_events,
+                                claim_loop_out,
+                                new_loop_in,
+                                get_loop_in
+                            ],
+                        );
                     let _ = builder.launch().await;
                 });
             })
             .unwrap();
     }
 
-    fn validate_loop_out_request(
+    // quote rate limits and validates a quote request before quoting it. Shared by the REST and gRPC APIs.
+    pub(crate) async fn quote(
+        loop_out_svc: &LoopOutService,
+        rate_limiter: &RateLimiter,
+        client_ip: Option<IpAddr>,
+        amount: i64,
+        batched: bool,
+    ) -> Result<LoopOutQuoteResponse, LooperErrorResponse> {
+        rate_limiter.check_rate(&Self::rate_limit_keys(client_ip, None))?;
+        loop_out_svc.validate_amount(amount).map_err(|e| {
+            log::info!("invalid amount: {:?}", e);
+            errors::invalid_parameter("amount".to_string())
+        })?;
+        loop_out_svc.validate_batched(batched).map_err(|e| {
+            log::info!("invalid batched: {:?}", e);
+            errors::invalid_parameter("batched".to_string())
+        })?;
+
+        let quote = loop_out_svc
+            .quote_loop_out(amount, batched)
+            .await
+            .map_err(errors::handle_loop_out_error)?;
+
+        Ok(api::map_loop_out_quote_to_response(quote))
+    }
+
+    // loop_out rate limits and validates a loop out request before creating it. Shared by the REST and gRPC APIs.
+    pub(crate) async fn loop_out(
+        loop_out_svc: &LoopOutService,
+        rate_limiter: &RateLimiter,
+        client_ip: Option<IpAddr>,
+        req: LoopOutRequest,
+    ) -> Result<LoopOutResponse, LooperErrorResponse> {
+        let keys = Self::rate_limit_keys(client_ip, Some(&req.pubkey));
+        rate_limiter.check_rate(&keys)?;
+        Self::validate_loop_out_request(loop_out_svc, &req).await?;
+        let reservation = rate_limiter.reserve_swap(&keys, |payment_hash| {
+            // count the swap if we can't tell, rather than letting a client exceed the cap
+            loop_out_svc
+                .is_loop_out_in_flight(payment_hash.to_string())
+                .unwrap_or(true)
+        })?;
+
+        let resp = loop_out_svc
+            .handle_loop_out_request(
+                req.pubkey,
+                req.amount,
+                req.payment_hash,
+                req.musig,
+                req.quote_id,
+                req.batched,
+                req.webhook_url
+                    .zip(req.webhook_secret)
+                    .map(|(url, secret)| WebhookTarget { url, secret }),
+            )
+            .await
+            .map_err(errors::handle_loop_out_error)?;
+        reservation.confirm(&resp.invoice.payment_hash);
+
+        Ok(api::map_loop_out_data_to_response(resp))
+    }
+
+    // get_loop_out validates the payment hash before looking the loop out up. Shared by the REST and gRPC APIs.
+    pub(crate) fn get_loop_out(
+        loop_out_svc: &LoopOutService,
+        payment_hash: String,
+    ) -> Result<LoopOutResponse, LooperErrorResponse> {
+        Self::validate_payment_hash(&payment_hash)?;
+        let resp = loop_out_svc
+            .get_loop_out(payment_hash)
+            .map_err(errors::handle_loop_out_error)?;
+        Ok(api::map_loop_out_data_to_response(resp))
+    }
+
+    async fn validate_loop_out_request(
         loop_out_svc: &LoopOutService,
         req: &LoopOutRequest,
     ) -> Result<(), LooperErrorResponse> {
@@ -54,9 +180,60 @@ impl LooperServer {
             let msg = format!("invalid pubkey: {:?}", e);
             log::info!("{}", &msg);
             errors::invalid_parameter("pubkey".to_string())
+        })?;
+
+        loop_out_svc.validate_batched(req.batched).map_err(|e| {
+            log::info!("invalid batched: {:?}", e);
+            errors::invalid_parameter("batched".to_string())
+        })?;
+
+        match (&req.webhook_url, &req.webhook_secret) {
+            (Some(url), Some(secret)) => {
+                WebhookService::validate_target(url, secret).map_err(|e| {
+                    log::info!("invalid webhook: {:?}", e);
+                    errors::invalid_parameter("webhook_url".to_string())
+                })?;
+                webhook::resolve_public_target(url).await.map_err(|e| {
+                    log::info!("invalid webhook: {:?}", e);
+                    errors::invalid_parameter("webhook_url".to_string())
+                })?;
+            }
+            (Some(_), None) => return Err(errors::invalid_parameter("webhook_secret".to_string())),
+            (None, _) => {}
+        }
+
+        match &req.payment_hash {
+            Some(payment_hash) => Self::validate_payment_hash(payment_hash),
+            None => Ok(()),
+        }
+    }
+
+    fn validate_loop_in_request(
+        loop_in_svc: &LoopInService,
+        req: &LoopInRequest,
+    ) -> Result<(), LooperErrorResponse> {
+        loop_in_svc.validate_pubkey(&req.pubkey).map_err(|e| {
+            let msg = format!("invalid pubkey: {:?}", e);
+            log::info!("{}", &msg);
+            errors::invalid_parameter("pubkey".to_string())
         })
     }
 
+    // rate_limit_keys are the keys a request is rate limited by: the client's IP, if known, and pubkey, if any.
+    fn rate_limit_keys(
+        client_ip: Option<IpAddr>,
+        pubkey: Option<&str>,
+    ) -> Vec<(&'static str, String)> {
+        let mut keys = vec![];
+        if let Some(client_ip) = client_ip {
+            keys.push(("ip", client_ip.to_string()));
+        }
+        if let Some(pubkey) = pubkey {
+            keys.push(("pubkey", pubkey.to_string()));
+        }
+        keys
+    }
+
     fn validate_payment_hash(pay_hash: &String) -> Result<(), LooperErrorResponse> {
         // TODO: avoid instantiating this every time. make const or only instantiate if err?
         let err_invalid = errors::invalid_parameter("payment_hash".to_string());
@@ -81,30 +258,180 @@ pub fn index() -> &'static str {
     "Hello, world!"
 }
 
+#[get("/out/terms")]
+pub fn loop_out_terms(
+    loop_out_svc: &rocket::State<Arc<LoopOutService>>,
+) -> Json<LoopOutTermsResponse> {
+    Json(api::map_loop_out_terms_to_response(
+        loop_out_svc.get_config(),
+        loop_out_svc.get_network(),
+    ))
+}
+
+#[get("/out/quote?<amount>&<batched>")]
+pub async fn quote_loop_out(
+    loop_out_svc: &rocket::State<Arc<LoopOutService>>,
+    rate_limiter: &rocket::State<Arc<RateLimiter>>,
+    client_ip: Option<IpAddr>,
+    amount: i64,
+    batched: Option<bool>,
+) -> Result<Json<LoopOutQuoteResponse>, LooperErrorResponse> {
+    let resp = LooperServer::quote(
+        loop_out_svc,
+        rate_limiter,
+        client_ip,
+        amount,
+        batched.unwrap_or(false),
+    )
+    .await?;
+    Ok(Json(resp))
+}
+
 #[post("/out", format = "json", data = "<loop_out>")]
 pub async fn new_loop_out(
-    loop_out_svc: &rocket::State<LoopOutService>,
+    loop_out_svc: &rocket::State<Arc<LoopOutService>>,
+    rate_limiter: &rocket::State<Arc<RateLimiter>>,
+    client_ip: Option<IpAddr>,
     loop_out: Json<LoopOutRequest>,
 ) -> Result<Json<LoopOutResponse>, LooperErrorResponse> {
-    let req = loop_out.into_inner();
-    LooperServer::validate_loop_out_request(loop_out_svc.inner(), &req)?;
-
-    let resp = loop_out_svc
-        .handle_loop_out_request(req.pubkey, req.amount)
-        .await
-        .map_err(errors::handle_loop_out_error)?;
-
-    Ok(Json(api::map_loop_out_data_to_response(resp)))
+    let resp = LooperServer::loop_out(loop_out_svc, rate_limiter, client_ip, loop_out.into_inner())
+        .await?;
+    Ok(Json(resp))
 }
 
 #[get("/out/<payment_hash>")]
 pub fn get_loop_out(
-    loop_out_svc: &rocket::State<LoopOutService>,
+    loop_out_svc: &rocket::State<Arc<LoopOutService>>,
     payment_hash: String,
 ) -> Result<Json<LoopOutResponse>, LooperErrorResponse> {
+    Ok(Json(LooperServer::get_loop_out(
+        loop_out_svc,
+        payment_hash,
+    )?))
+}
+
+// loop_out_events streams the loop out's state changes, its funding tx's confirmations until it's CONFIRMED and its
+// claim or timeout txid as server-sent events. The stream starts with every state change so far and ends once the
+// loop out is done, or after EVENT_STREAM_LIFETIME. Open streams are capped per client IP and overall.
+#[get("/out/<payment_hash>/events")]
+pub fn loop_out_events(
+    loop_out_svc: &rocket::State<Arc<LoopOutService>>,
+    rate_limiter: &rocket::State<Arc<RateLimiter>>,
+    client_ip: Option<IpAddr>,
+    payment_hash: String,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], LooperErrorResponse> {
+    LooperServer::validate_payment_hash(&payment_hash)?;
+    // unknown loop outs fail before the stream starts
+    loop_out_svc
+        .get_loop_out(payment_hash.clone())
+        .map_err(errors::handle_loop_out_error)?;
+    let stream_guard = rate_limiter.open_stream(client_ip)?;
+    let loop_out_svc = loop_out_svc.inner().clone();
+
+    Ok(EventStream! {
+        // the stream's place under the cap is freed when the stream is dropped
+        let _stream_guard = stream_guard;
+        let closes_at = Instant::now() + EVENT_STREAM_LIFETIME;
+        let mut events = LoopOutEvents::default();
+        while Instant::now() < closes_at {
+            match poll_loop_out_events(&loop_out_svc, &payment_hash, &mut events).await {
+                Ok((updates, done)) => {
+                    for update in updates {
+                        yield update.into_event();
+                    }
+                    if done {
+                        break;
+                    }
+                }
+                Err(e) => log::error!("error polling loop out {} events: {:?}", payment_hash, e),
+            }
+
+            select! {
+                _ = sleep(EVENT_POLL_INTERVAL) => {},
+                _ = &mut shutdown => break,
+            }
+        }
+    })
+}
+
+// poll_loop_out_events returns what changed since the last poll and whether the loop out is done.
+async fn poll_loop_out_events(
+    loop_out_svc: &LoopOutService,
+    payment_hash: &str,
+    events: &mut LoopOutEvents,
+) -> Result<(Vec<LoopOutEvent>, bool), LoopOutServiceError> {
+    let data = loop_out_svc.get_loop_out(payment_hash.to_string())?;
+    let changes = loop_out_svc.list_state_changes(data.loop_out.id)?;
+    let confirmations = match data.loop_out.state {
+        LoopOutState::Initiated => loop_out_svc
+            .get_funding_confirmations(&data)
+            .await?
+            .map(|(txid, confs)| (txid.to_string(), confs)),
+        _ => None,
+    };
+
+    let updates = events.updates(
+        &data.loop_out,
+        changes,
+        confirmations,
+        loop_out_svc.get_config().target_confs,
+    );
+    let done = LoopOutEvents::is_done(&data.loop_out, data.utxo.is_some());
+
+    Ok((updates, done))
+}
+
+#[post("/out/<payment_hash>/claim", format = "json", data = "<claim>")]
+pub async fn claim_loop_out(
+    loop_out_svc: &rocket::State<Arc<LoopOutService>>,
+    rate_limiter: &rocket::State<Arc<RateLimiter>>,
+    client_ip: Option<IpAddr>,
+    payment_hash: String,
+    claim: Json<MusigClaimRequest>,
+) -> Result<Json<MusigClaimResponse>, LooperErrorResponse> {
+    rate_limiter.check_rate(&LooperServer::rate_limit_keys(client_ip, None))?;
     LooperServer::validate_payment_hash(&payment_hash)?;
-    let resp = loop_out_svc
-        .get_loop_out(payment_hash)
+    let req = claim.into_inner();
+
+    let (pubnonce, partial_sig) = loop_out_svc
+        .sign_musig_claim(payment_hash, req.preimage, req.pubnonce, req.tx)
+        .await
         .map_err(errors::handle_loop_out_error)?;
-    Ok(Json(api::map_loop_out_data_to_response(resp)))
+
+    Ok(Json(MusigClaimResponse {
+        pubnonce: hex::encode(pubnonce.serialize()),
+        partial_sig: hex::encode(partial_sig.serialize()),
+    }))
+}
+
+#[post("/in", format = "json", data = "<loop_in>")]
+pub async fn new_loop_in(
+    loop_in_svc: &rocket::State<Arc<LoopInService>>,
+    rate_limiter: &rocket::State<Arc<RateLimiter>>,
+    client_ip: Option<IpAddr>,
+    loop_in: Json<LoopInRequest>,
+) -> Result<Json<LoopInResponse>, LooperErrorResponse> {
+    let req = loop_in.into_inner();
+    rate_limiter.check_rate(&LooperServer::rate_limit_keys(client_ip, Some(&req.pubkey)))?;
+    LooperServer::validate_loop_in_request(loop_in_svc.inner(), &req)?;
+
+    let resp = loop_in_svc
+        .handle_loop_in_request(req.pubkey, req.invoice)
+        .await
+        .map_err(errors::handle_loop_in_error)?;
+
+    Ok(Json(api::map_loop_in_data_to_response(resp)))
+}
+
+#[get("/in/<payment_hash>")]
+pub fn get_loop_in(
+    loop_in_svc: &rocket::State<Arc<LoopInService>>,
+    payment_hash: String,
+) -> Result<Json<LoopInResponse>, LooperErrorResponse> {
+    LooperServer::validate_payment_hash(&payment_hash)?;
+    let resp = loop_in_svc
+        .get_loop_in(payment_hash)
+        .map_err(errors::handle_loop_in_error)?;
+    Ok(Json(api::map_loop_in_data_to_response(resp)))
 }
@@ -1,5 +1,8 @@
 use crate::models::{
-    FullLoopOutData, Invoice, LoopOut, NewInvoice, NewLoopOut, NewScript, NewUTXO, Script, Utxo,
+    self, FullLoopInData, FullLoopOutData, Invoice, InvoiceState, LiquidityReservation, LoopIn,
+    LoopOut, LoopOutQuote, LoopOutState, LoopOutStateChange, NewInvoice, NewLiquidityReservation,
+    NewLoopIn, NewLoopOut, NewLoopOutQuote, NewLoopOutStateChange, NewScript, NewUTXO,
+    NewWebhookDelivery, Script, Utxo, WebhookDelivery, WebhookDeliveryState,
 };
 use crate::settings;
 use diesel::{
@@ -12,6 +15,7 @@ use diesel::{
 // use diesel_async::AsyncConnection;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
+#[derive(Clone)]
 pub struct DBConfig {
     pub host: String,
     pub port: i64,
@@ -26,6 +30,8 @@ pub type ConnectionPool = Pool<ConnectionManager<PgConnection>>;
 pub type PooledConnection =
     r2d2::PooledConnection<diesel::r2d2::ConnectionManager<diesel::PgConnection>>;
 
+diesel::sql_function!(fn nextval(sequence: diesel::sql_types::Text) -> diesel::sql_types::BigInt);
+
 // Embeds the database migrations into the binary. This is used to run the migrations on application startup.
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
@@ -78,6 +84,7 @@ fn build_db_connection_string(cfg: &DBConfig) -> String {
     )
 }
 
+#[derive(Clone)]
 pub struct DB {
     pub cfg: DBConfig,
     pool: ConnectionPool,
@@ -123,7 +130,6 @@ pub fn insert_invoice(
     Ok(res)
 }
 
-#[allow(dead_code)]
 pub fn update_invoice(
     conn: &mut PooledConnection,
     invoice: Invoice,
@@ -150,10 +156,51 @@ pub fn get_invoice(
     Ok(invoice)
 }
 
+pub fn get_invoice_by_payment_hash(
+    conn: &mut PooledConnection,
+    pay_hash: &str,
+) -> Result<Invoice, diesel::result::Error> {
+    use crate::schema::invoices::dsl::*;
+
+    let invoice = invoices
+        .filter(payment_hash.eq(pay_hash))
+        .first::<Invoice>(conn)?;
+
+    Ok(invoice)
+}
+
+pub fn get_loop_out_invoice(
+    conn: &mut PooledConnection,
+    lo_id: i64,
+    invoice_kind: &str,
+) -> Result<Invoice, diesel::result::Error> {
+    use crate::schema::invoices::dsl::*;
+
+    let invoice = invoices
+        .filter(loop_out_id.eq(lo_id))
+        .filter(kind.eq(invoice_kind))
+        .first::<Invoice>(conn)?;
+
+    Ok(invoice)
+}
+
+// Returns the highest LND settle_index recorded for any invoice, or 0 if none have settled yet.
+pub fn get_max_invoice_settle_index(
+    conn: &mut PooledConnection,
+) -> Result<i64, diesel::result::Error> {
+    use crate::schema::invoices::dsl::*;
+
+    let max_index = invoices
+        .select(diesel::dsl::max(settle_index))
+        .first::<Option<i64>>(conn)?;
+
+    Ok(max_index.unwrap_or(0))
+}
+
 #[allow(dead_code)]
 pub fn list_invoices_in_state(
     conn: &mut PooledConnection,
-    invoice_state: String,
+    invoice_state: InvoiceState,
 ) -> Result<Vec<Invoice>, diesel::result::Error> {
     use crate::schema::invoices::dsl::*;
 
@@ -207,6 +254,21 @@ pub fn insert_utxo(
     Ok(res)
 }
 
+pub fn update_utxo(
+    conn: &mut PooledConnection,
+    mut utxo: Utxo,
+) -> Result<Utxo, diesel::result::Error> {
+    use crate::schema::utxos::dsl::*;
+
+    utxo.updated_at = chrono::Utc::now().naive_utc();
+    let res = diesel::update(utxos.find(utxo.id))
+        .set(&utxo)
+        .returning(utxos::all_columns())
+        .get_result(conn)?;
+
+    Ok(res)
+}
+
 #[allow(dead_code)]
 pub fn get_utxo(conn: &mut PooledConnection, utxo_id: i64) -> Result<Utxo, diesel::result::Error> {
     use crate::schema::utxos::dsl::*;
@@ -216,20 +278,32 @@ pub fn get_utxo(conn: &mut PooledConnection, utxo_id: i64) -> Result<Utxo, diese
     Ok(utxo)
 }
 
+// Reserves the index of a new HTLC key. Indexes come from a sequence, so they are never handed out twice, even
+// across restarts or for swaps that are never stored.
+pub fn next_htlc_key_index(conn: &mut PooledConnection) -> Result<u32, diesel::result::Error> {
+    let index: i64 = diesel::select(nextval("htlc_key_index_seq")).get_result(conn)?;
+
+    Ok(index as u32)
+}
+
 // LoopOuts
 
+// Inserts a new loop out and records the state it starts in.
 pub fn insert_loop_out(
     conn: &mut PooledConnection,
     loop_out: NewLoopOut,
 ) -> Result<LoopOut, diesel::result::Error> {
     use crate::schema::loop_outs::dsl::*;
 
-    let res = diesel::insert_into(loop_outs)
-        .values(&loop_out)
-        .returning(loop_outs::all_columns())
-        .get_result(conn)?;
+    conn.transaction(|conn| {
+        let res: LoopOut = diesel::insert_into(loop_outs)
+            .values(&loop_out)
+            .returning(loop_outs::all_columns())
+            .get_result(conn)?;
+        insert_loop_out_state_change(conn, res.id, None, res.state, "created")?;
 
-    Ok(res)
+        Ok(res)
+    })
 }
 
 #[allow(dead_code)]
@@ -256,52 +330,684 @@ pub fn get_full_loop_out(
     use crate::schema::utxos::{self, dsl::*};
 
     let (loop_out, invoice, script, utxo) = loop_outs
-        .left_join(invoices.on(invoices::loop_out_id.eq(loop_outs::id.nullable())))
+        .left_join(
+            invoices.on(invoices::loop_out_id
+                .eq(loop_outs::id.nullable())
+                .and(invoices::kind.eq(models::INVOICE_KIND_SWAP))),
+        )
         .left_join(scripts.on(scripts::loop_out_id.eq(loop_outs::id.nullable())))
         .left_join(utxos.on(utxos::script_id.nullable().eq(scripts::id.nullable())))
         .filter(invoices::payment_hash.eq(pay_hash))
         .first(conn)?;
 
-    match (invoice, script, utxo) {
-        (Some(invoice), Some(script), Some(utxo)) => {
-            Ok(new_full_loop_out_data(loop_out, invoice, script, utxo))
+    let mut data = match (invoice, script) {
+        (Some(invoice), Some(script)) => new_full_loop_out_data(loop_out, invoice, script, utxo),
+        _ => return Err(diesel::result::Error::NotFound),
+    };
+    data.prepay_invoice = get_prepay_invoices(conn, vec![data.loop_out.id])?.pop();
+
+    Ok(data)
+}
+
+// Updates the loop out. If its state changed, the transition is checked and recorded with reason.
+pub fn update_loop_out(
+    conn: &mut PooledConnection,
+    mut loop_out: LoopOut,
+    reason: &str,
+) -> Result<LoopOut, diesel::result::Error> {
+    use crate::schema::loop_outs::dsl::*;
+
+    conn.transaction(|conn| {
+        transition_loop_out(conn, loop_out.id, loop_out.state, reason)?;
+
+        loop_out.updated_at = chrono::Utc::now().naive_utc();
+        let res = diesel::update(loop_outs.find(loop_out.id))
+            .set(&loop_out)
+            .returning(loop_outs::all_columns())
+            .get_result(conn)?;
+
+        Ok(res)
+    })
+}
+
+// Moves the loop out to new_state, recording the transition with reason.
+pub fn update_loop_out_state(
+    conn: &mut PooledConnection,
+    loop_out_id: i64,
+    new_state: LoopOutState,
+    reason: &str,
+) -> Result<LoopOut, diesel::result::Error> {
+    use crate::schema::loop_outs::dsl::*;
+
+    conn.transaction(|conn| {
+        transition_loop_out(conn, loop_out_id, new_state, reason)?;
+
+        let res = diesel::update(loop_outs.find(loop_out_id))
+            .set((state.eq(new_state), updated_at.eq(diesel::dsl::now)))
+            .returning(loop_outs::all_columns())
+            .get_result(conn)?;
+
+        Ok(res)
+    })
+}
+
+// Checks that the loop out can move from its current state to new_state and records the transition. Staying in the
+// same state is a no-op. The loop out is locked until the surrounding transaction ends, so concurrent updates are
+// checked against each other. Illegal transitions fail with a SerializationError wrapping InvalidStateTransition.
+fn transition_loop_out(
+    conn: &mut PooledConnection,
+    loop_out_id: i64,
+    new_state: LoopOutState,
+    reason: &str,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::loop_outs::dsl::*;
+
+    let curr_state: LoopOutState = loop_outs
+        .find(loop_out_id)
+        .select(state)
+        .for_update()
+        .first(conn)?;
+    if curr_state == new_state {
+        return Ok(());
+    }
+
+    curr_state
+        .transition(new_state)
+        .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+    insert_loop_out_state_change(conn, loop_out_id, Some(curr_state), new_state, reason)
+}
+
+fn insert_loop_out_state_change(
+    conn: &mut PooledConnection,
+    change_loop_out_id: i64,
+    from: Option<LoopOutState>,
+    to: LoopOutState,
+    change_reason: &str,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::loop_out_state_history::dsl::*;
+
+    diesel::insert_into(loop_out_state_history)
+        .values(NewLoopOutStateChange {
+            loop_out_id: change_loop_out_id,
+            from_state: from,
+            to_state: to,
+            reason: change_reason,
+        })
+        .execute(conn)?;
+
+    Ok(())
+}
+
+// Lists every state the loop out has been in, oldest first.
+pub fn list_loop_out_state_history(
+    conn: &mut PooledConnection,
+    history_loop_out_id: i64,
+) -> Result<Vec<LoopOutStateChange>, diesel::result::Error> {
+    use crate::schema::loop_out_state_history::dsl::*;
+
+    let results = loop_out_state_history
+        .filter(loop_out_id.eq(history_loop_out_id))
+        .order(id.asc())
+        .load::<LoopOutStateChange>(conn)?;
+
+    Ok(results)
+}
+
+// Forgets the timeout sweep of a loop out because it was dropped from the mempool, moving the loop out to new_state.
+// The transition is checked and recorded with reason.
+pub fn reopen_loop_out_timeout(
+    conn: &mut PooledConnection,
+    loop_out_id: i64,
+    new_state: LoopOutState,
+    reason: &str,
+) -> Result<LoopOut, diesel::result::Error> {
+    use crate::schema::loop_outs::dsl::*;
+
+    conn.transaction(|conn| {
+        transition_loop_out(conn, loop_out_id, new_state, reason)?;
+
+        let res = diesel::update(loop_outs.find(loop_out_id))
+            .set((
+                state.eq(new_state),
+                timeout_txid.eq(None::<String>),
+                updated_at.eq(diesel::dsl::now),
+            ))
+            .returning(loop_outs::all_columns())
+            .get_result(conn)?;
+
+        Ok(res)
+    })
+}
+
+// Marks the timeout sweep of a loop out as buried, after which its HTLC is no longer watched.
+pub fn confirm_loop_out_timeout(
+    conn: &mut PooledConnection,
+    loop_out_id: i64,
+) -> Result<LoopOut, diesel::result::Error> {
+    use crate::schema::loop_outs::dsl::*;
+
+    let res = diesel::update(loop_outs.find(loop_out_id))
+        .set((
+            timeout_confirmed_at.eq(diesel::dsl::now.nullable()),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .returning(loop_outs::all_columns())
+        .get_result(conn)?;
+
+    Ok(res)
+}
+
+// Lists state changes whose webhook deliveries haven't been queued yet, oldest first, along with their loop out and
+// its swap invoice.
+pub fn list_unnotified_state_changes(
+    conn: &mut PooledConnection,
+    max_changes: i64,
+) -> Result<Vec<(LoopOutStateChange, LoopOut, Option<Invoice>)>, diesel::result::Error> {
+    use crate::schema::invoices::{self, dsl::*};
+    use crate::schema::loop_out_state_history::{self, dsl::*};
+    use crate::schema::loop_outs::{self, dsl::*};
+
+    let rows = loop_out_state_history
+        .inner_join(loop_outs)
+        .left_join(
+            invoices.on(invoices::loop_out_id
+                .eq(loop_outs::id.nullable())
+                .and(invoices::kind.eq(models::INVOICE_KIND_SWAP))),
+        )
+        .filter(loop_out_state_history::notified_at.is_null())
+        .order(loop_out_state_history::id.asc())
+        .limit(max_changes)
+        .load::<(LoopOutStateChange, LoopOut, Option<Invoice>)>(conn)?;
+
+    Ok(rows)
+}
+
+// Queues the webhook deliveries of a state change and marks it notified in a single transaction, so each change is
+// queued once. deliveries may be empty if nobody is subscribed.
+pub fn enqueue_webhook_deliveries(
+    conn: &mut PooledConnection,
+    change_id: i64,
+    deliveries: Vec<NewWebhookDelivery>,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::loop_out_state_history::dsl::*;
+    use crate::schema::webhook_deliveries;
+
+    conn.transaction(|conn| {
+        diesel::insert_into(webhook_deliveries::table)
+            .values(&deliveries)
+            .execute(conn)?;
+        diesel::update(loop_out_state_history.find(change_id))
+            .set(notified_at.eq(diesel::dsl::now.nullable()))
+            .execute(conn)?;
+
+        Ok(())
+    })
+}
+
+// Lists pending webhook deliveries whose next attempt is due, oldest first, along with their loop out.
+pub fn list_due_webhook_deliveries(
+    conn: &mut PooledConnection,
+    max_deliveries: i64,
+) -> Result<Vec<(WebhookDelivery, LoopOut)>, diesel::result::Error> {
+    use crate::schema::loop_out_state_history;
+    use crate::schema::loop_outs;
+    use crate::schema::webhook_deliveries::{self, dsl::*};
+
+    let results = webhook_deliveries
+        .inner_join(loop_out_state_history::table.inner_join(loop_outs::table))
+        .filter(webhook_deliveries::state.eq(WebhookDeliveryState::Pending))
+        .filter(next_attempt_at.le(diesel::dsl::now))
+        .order(webhook_deliveries::id.asc())
+        .limit(max_deliveries)
+        .select((webhook_deliveries::all_columns, loop_outs::all_columns))
+        .load::<(WebhookDelivery, LoopOut)>(conn)?;
+
+    Ok(results)
+}
+
+pub fn update_webhook_delivery(
+    conn: &mut PooledConnection,
+    mut delivery: WebhookDelivery,
+) -> Result<WebhookDelivery, diesel::result::Error> {
+    use crate::schema::webhook_deliveries::dsl::*;
+
+    delivery.updated_at = chrono::Utc::now().naive_utc();
+    let res = diesel::update(webhook_deliveries.find(delivery.id))
+        .set(&delivery)
+        .returning(webhook_deliveries::all_columns())
+        .get_result(conn)?;
+
+    Ok(res)
+}
+
+// Lists every loop out in one of the given states, skipping any that are missing their invoice or script.
+pub fn list_full_loop_outs_in_states(
+    conn: &mut PooledConnection,
+    loop_out_states: Vec<LoopOutState>,
+) -> Result<Vec<FullLoopOutData>, diesel::result::Error> {
+    use crate::schema::invoices::{self, dsl::*};
+    use crate::schema::loop_outs::{self, dsl::*};
+    use crate::schema::scripts::{self, dsl::*};
+    use crate::schema::utxos::{self, dsl::*};
+
+    let rows = loop_outs
+        .left_join(
+            invoices.on(invoices::loop_out_id
+                .eq(loop_outs::id.nullable())
+                .and(invoices::kind.eq(models::INVOICE_KIND_SWAP))),
+        )
+        .left_join(scripts.on(scripts::loop_out_id.eq(loop_outs::id.nullable())))
+        .left_join(utxos.on(utxos::script_id.nullable().eq(scripts::id.nullable())))
+        .filter(loop_outs::state.eq_any(loop_out_states))
+        .order(loop_outs::id.asc())
+        .load::<(LoopOut, Option<Invoice>, Option<Script>, Option<Utxo>)>(conn)?;
+
+    with_prepay_invoices(conn, rows_to_full_loop_outs(rows))
+}
+
+// Lists every loop out in the given state whose invoice is in invoice_state.
+pub fn list_full_loop_outs_with_invoice_state(
+    conn: &mut PooledConnection,
+    loop_out_state: LoopOutState,
+    invoice_state: InvoiceState,
+) -> Result<Vec<FullLoopOutData>, diesel::result::Error> {
+    use crate::schema::invoices::{self, dsl::*};
+    use crate::schema::loop_outs::{self, dsl::*};
+    use crate::schema::scripts::{self, dsl::*};
+    use crate::schema::utxos::{self, dsl::*};
+
+    let rows = loop_outs
+        .left_join(
+            invoices.on(invoices::loop_out_id
+                .eq(loop_outs::id.nullable())
+                .and(invoices::kind.eq(models::INVOICE_KIND_SWAP))),
+        )
+        .left_join(scripts.on(scripts::loop_out_id.eq(loop_outs::id.nullable())))
+        .left_join(utxos.on(utxos::script_id.nullable().eq(scripts::id.nullable())))
+        .filter(loop_outs::state.eq(loop_out_state))
+        .filter(invoices::state.eq(invoice_state))
+        .order(loop_outs::id.asc())
+        .load::<(LoopOut, Option<Invoice>, Option<Script>, Option<Utxo>)>(conn)?;
+
+    with_prepay_invoices(conn, rows_to_full_loop_outs(rows))
+}
+
+// Returns the prepay invoices of the given loop outs.
+pub fn get_prepay_invoices(
+    conn: &mut PooledConnection,
+    loop_out_ids: Vec<i64>,
+) -> Result<Vec<Invoice>, diesel::result::Error> {
+    use crate::schema::invoices::dsl::*;
+
+    let results = invoices
+        .filter(loop_out_id.assume_not_null().eq_any(loop_out_ids))
+        .filter(kind.eq(models::INVOICE_KIND_PREPAY))
+        .load::<Invoice>(conn)?;
+
+    Ok(results)
+}
+
+fn with_prepay_invoices(
+    conn: &mut PooledConnection,
+    mut full_loop_outs: Vec<FullLoopOutData>,
+) -> Result<Vec<FullLoopOutData>, diesel::result::Error> {
+    let ids = full_loop_outs.iter().map(|l| l.loop_out.id).collect();
+    for prepay_invoice in get_prepay_invoices(conn, ids)? {
+        if let Some(data) = full_loop_outs
+            .iter_mut()
+            .find(|l| Some(l.loop_out.id) == prepay_invoice.loop_out_id)
+        {
+            data.prepay_invoice = Some(prepay_invoice);
         }
-        _ => Err(diesel::result::Error::NotFound),
     }
+
+    Ok(full_loop_outs)
 }
 
-// unused for now, but a first attempt at db transactions
-// Insert Full Loop Out Data
-#[allow(dead_code)]
+fn rows_to_full_loop_outs(
+    rows: Vec<(LoopOut, Option<Invoice>, Option<Script>, Option<Utxo>)>,
+) -> Vec<FullLoopOutData> {
+    rows.into_iter()
+        .filter_map(
+            |(loop_out, invoice, script, utxo)| match (invoice, script) {
+                (Some(invoice), Some(script)) => {
+                    Some(new_full_loop_out_data(loop_out, invoice, script, utxo))
+                }
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+// Inserts a new loop out with its invoices, script, the liquidity reserved for it and, if the HTLC is funded right
+// away, its funding utxo in a single transaction, so a failure at any step leaves nothing behind. The ids linking the
+// rows are set here. If quote_id is set, the quote is redeemed in the same transaction, failing with NotFound if it
+// can't be, so a quote is only used up by a loop out that was stored.
+#[allow(clippy::too_many_arguments)]
 pub fn insert_full_loop_out_data(
     conn: &mut PooledConnection,
     loop_out: NewLoopOut,
     invoice: &mut NewInvoice,
+    prepay_invoice: Option<&mut NewInvoice>,
     script: &mut NewScript,
-    utxo: &mut NewUTXO,
+    utxo: Option<&mut NewUTXO>,
+    reserved_amount: i64,
+    quote_id: Option<&str>,
 ) -> Result<FullLoopOutData, diesel::result::Error> {
-    let loop_out = insert_loop_out(conn, loop_out)?;
-    invoice.loop_out_id = loop_out.id;
-    let invoice = insert_invoice(conn, invoice.clone())?;
-    script.loop_out_id = loop_out.id;
-    let script = insert_script(conn, script.clone())?;
-    utxo.script_id = script.id;
-    let utxo = insert_utxo(conn, utxo.clone())?;
+    use crate::schema::liquidity_reservations;
 
-    Ok(new_full_loop_out_data(loop_out, invoice, script, utxo))
+    conn.transaction(|conn| {
+        if let Some(quote_id) = quote_id {
+            redeem_loop_out_quote(conn, quote_id, loop_out.amount, loop_out.batched)?;
+        }
+        let loop_out = insert_loop_out(conn, loop_out)?;
+        diesel::insert_into(liquidity_reservations::table)
+            .values(NewLiquidityReservation {
+                loop_out_id: loop_out.id,
+                amount: reserved_amount,
+            })
+            .execute(conn)?;
+        invoice.loop_out_id = loop_out.id;
+        let invoice = insert_invoice(conn, invoice.clone())?;
+        let prepay_invoice = match prepay_invoice {
+            Some(prepay_invoice) => {
+                prepay_invoice.loop_out_id = loop_out.id;
+                Some(insert_invoice(conn, prepay_invoice.clone())?)
+            }
+            None => None,
+        };
+        script.loop_out_id = Some(loop_out.id);
+        let script = insert_script(conn, script.clone())?;
+        let utxo = match utxo {
+            Some(utxo) => {
+                utxo.script_id = script.id;
+                Some(insert_utxo(conn, utxo.clone())?)
+            }
+            None => None,
+        };
+
+        let mut data = new_full_loop_out_data(loop_out, invoice, script, utxo);
+        data.prepay_invoice = prepay_invoice;
+
+        Ok(data)
+    })
+}
+
+// Lists the liquidity reservations that haven't been released, along with their loop out and its HTLC utxo, if funded.
+pub fn list_active_liquidity_reservations(
+    conn: &mut PooledConnection,
+) -> Result<Vec<(LiquidityReservation, LoopOut, Option<Utxo>)>, diesel::result::Error> {
+    use crate::schema::liquidity_reservations::{self, dsl::*};
+    use crate::schema::loop_outs;
+    use crate::schema::scripts;
+    use crate::schema::utxos;
+
+    let rows = liquidity_reservations
+        .inner_join(loop_outs::table)
+        .left_join(scripts::table.on(scripts::loop_out_id.eq(loop_outs::id.nullable())))
+        .left_join(utxos::table.on(utxos::script_id.nullable().eq(scripts::id.nullable())))
+        .filter(released_at.is_null())
+        .order(liquidity_reservations::id.asc())
+        .select((
+            liquidity_reservations::all_columns,
+            loop_outs::all_columns,
+            utxos::all_columns.nullable(),
+        ))
+        .load::<(LiquidityReservation, LoopOut, Option<Utxo>)>(conn)?;
+
+    Ok(rows)
+}
+
+// Returns the total amount of the liquidity reservations that haven't been released.
+pub fn sum_active_liquidity_reservations(
+    conn: &mut PooledConnection,
+) -> Result<i64, diesel::result::Error> {
+    use crate::schema::liquidity_reservations::dsl::*;
+
+    let amounts = liquidity_reservations
+        .filter(released_at.is_null())
+        .select(amount)
+        .load::<i64>(conn)?;
+
+    Ok(amounts.iter().sum())
+}
+
+// Releases the given liquidity reservations.
+pub fn release_liquidity_reservations(
+    conn: &mut PooledConnection,
+    reservation_ids: Vec<i64>,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::liquidity_reservations::dsl::*;
+
+    diesel::update(liquidity_reservations.filter(id.eq_any(reservation_ids)))
+        .set(released_at.eq(diesel::dsl::now.nullable()))
+        .execute(conn)
+}
+
+// Inserts the HTLC utxo of each (loop_out_id, utxo) pair and moves the loop outs to INITIATED in a single
+// transaction. A batch shares one funding tx, so funding_miner_fee is each loop out's share of its fee.
+pub fn insert_loop_out_fundings(
+    conn: &mut PooledConnection,
+    fundings: Vec<(i64, NewUTXO)>,
+    funding_miner_fee: i64,
+    funding_fee_rate: f64,
+) -> Result<Vec<Utxo>, diesel::result::Error> {
+    use crate::schema::loop_outs::dsl::*;
+
+    conn.transaction(|conn| {
+        let mut utxos = vec![];
+        for (loop_out_id, utxo) in fundings {
+            let reason = format!("funded by {}:{}", utxo.txid, utxo.vout);
+            transition_loop_out(conn, loop_out_id, LoopOutState::Initiated, &reason)?;
+            utxos.push(insert_utxo(conn, utxo)?);
+            diesel::update(loop_outs.find(loop_out_id))
+                .set((
+                    state.eq(LoopOutState::Initiated),
+                    miner_fee.eq(funding_miner_fee),
+                    fee_rate.eq(funding_fee_rate),
+                    updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok(utxos)
+    })
+}
+
+// Updates the HTLC utxo of each (loop_out_id, utxo) pair after their shared funding tx was replaced, along with the
+// loop outs' funding fees, in a single transaction. funding_fees is None if the fees of the tx are unknown. The
+// replacement is recorded in each loop out's state history as a change that stays in its current state.
+pub fn replace_loop_out_funding(
+    conn: &mut PooledConnection,
+    replacements: Vec<(i64, Utxo)>,
+    funding_fees: Option<(i64, f64)>,
+) -> Result<Vec<Utxo>, diesel::result::Error> {
+    use crate::schema::loop_outs::dsl::*;
+
+    conn.transaction(|conn| {
+        let mut utxos = vec![];
+        for (loop_out_id, utxo) in replacements {
+            let curr_state: LoopOutState = loop_outs
+                .find(loop_out_id)
+                .select(state)
+                .for_update()
+                .first(conn)?;
+            let reason = format!("funding tx replaced by {}:{}", utxo.txid, utxo.vout);
+            insert_loop_out_state_change(conn, loop_out_id, Some(curr_state), curr_state, &reason)?;
+            utxos.push(update_utxo(conn, utxo)?);
+            if let Some((funding_miner_fee, funding_fee_rate)) = funding_fees {
+                diesel::update(loop_outs.find(loop_out_id))
+                    .set((
+                        miner_fee.eq(funding_miner_fee),
+                        fee_rate.eq(funding_fee_rate),
+                        updated_at.eq(diesel::dsl::now),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+
+        Ok(utxos)
+    })
 }
 
 pub fn new_full_loop_out_data(
     loop_out: LoopOut,
     invoice: Invoice,
     script: Script,
-    utxo: Utxo,
+    utxo: Option<Utxo>,
 ) -> FullLoopOutData {
     FullLoopOutData {
         loop_out,
         invoice,
         script,
         utxo,
+        prepay_invoice: None,
+    }
+}
+
+// LoopOutQuotes
+
+pub fn insert_loop_out_quote(
+    conn: &mut PooledConnection,
+    quote: NewLoopOutQuote,
+) -> Result<LoopOutQuote, diesel::result::Error> {
+    use crate::schema::loop_out_quotes::dsl::*;
+
+    let res = diesel::insert_into(loop_out_quotes)
+        .values(&quote)
+        .returning(loop_out_quotes::all_columns())
+        .get_result(conn)?;
+
+    Ok(res)
+}
+
+// Returns the quote if it's unexpired, unredeemed and for quote_amount and quote_batched, and NotFound otherwise.
+pub fn get_redeemable_loop_out_quote(
+    conn: &mut PooledConnection,
+    redeemable_quote_id: &str,
+    quote_amount: i64,
+    quote_batched: bool,
+) -> Result<LoopOutQuote, diesel::result::Error> {
+    use crate::schema::loop_out_quotes::dsl::*;
+
+    let res = loop_out_quotes
+        .filter(quote_id.eq(redeemable_quote_id))
+        .filter(amount.eq(quote_amount))
+        .filter(batched.eq(quote_batched))
+        .filter(redeemed_at.is_null())
+        .filter(expires_at.gt(diesel::dsl::now))
+        .first::<LoopOutQuote>(conn)?;
+
+    Ok(res)
+}
+
+// Marks the quote as redeemed and returns it. Returns NotFound if no unexpired, unredeemed quote for quote_amount
+// and quote_batched exists, so a quote can only be redeemed once.
+pub fn redeem_loop_out_quote(
+    conn: &mut PooledConnection,
+    redeemed_quote_id: &str,
+    quote_amount: i64,
+    quote_batched: bool,
+) -> Result<LoopOutQuote, diesel::result::Error> {
+    use crate::schema::loop_out_quotes::dsl::*;
+
+    let res = diesel::update(loop_out_quotes)
+        .filter(quote_id.eq(redeemed_quote_id))
+        .filter(amount.eq(quote_amount))
+        .filter(batched.eq(quote_batched))
+        .filter(redeemed_at.is_null())
+        .filter(expires_at.gt(diesel::dsl::now))
+        .set(redeemed_at.eq(diesel::dsl::now.nullable()))
+        .returning(loop_out_quotes::all_columns())
+        .get_result(conn)?;
+
+    Ok(res)
+}
+
+// LoopIns
+
+pub fn insert_loop_in(
+    conn: &mut PooledConnection,
+    loop_in: NewLoopIn,
+) -> Result<LoopIn, diesel::result::Error> {
+    use crate::schema::loop_ins::dsl::*;
+
+    let res = diesel::insert_into(loop_ins)
+        .values(&loop_in)
+        .returning(loop_ins::all_columns())
+        .get_result(conn)?;
+
+    Ok(res)
+}
+
+pub fn update_loop_in(
+    conn: &mut PooledConnection,
+    mut loop_in: LoopIn,
+) -> Result<LoopIn, diesel::result::Error> {
+    use crate::schema::loop_ins::dsl::*;
+
+    loop_in.updated_at = chrono::Utc::now().naive_utc();
+    let res = diesel::update(loop_ins.find(loop_in.id))
+        .set(&loop_in)
+        .returning(loop_ins::all_columns())
+        .get_result(conn)?;
+
+    Ok(res)
+}
+
+pub fn get_full_loop_in(
+    conn: &mut PooledConnection,
+    pay_hash: String,
+) -> Result<FullLoopInData, diesel::result::Error> {
+    use crate::schema::loop_ins::{self, dsl::*};
+    use crate::schema::scripts::{self, dsl::*};
+    use crate::schema::utxos::{self, dsl::*};
+
+    let (loop_in, script, utxo) = loop_ins
+        .left_join(scripts.on(scripts::loop_in_id.eq(loop_ins::id.nullable())))
+        .left_join(utxos.on(utxos::script_id.nullable().eq(scripts::id.nullable())))
+        .filter(loop_ins::payment_hash.eq(pay_hash))
+        .first(conn)?;
+
+    match script {
+        Some(script) => Ok(new_full_loop_in_data(loop_in, script, utxo)),
+        None => Err(diesel::result::Error::NotFound),
+    }
+}
+
+// Lists every loop in in one of the given states, skipping any that are missing their script.
+pub fn list_full_loop_ins_in_states(
+    conn: &mut PooledConnection,
+    loop_in_states: Vec<String>,
+) -> Result<Vec<FullLoopInData>, diesel::result::Error> {
+    use crate::schema::loop_ins::{self, dsl::*};
+    use crate::schema::scripts::{self, dsl::*};
+    use crate::schema::utxos::{self, dsl::*};
+
+    let rows = loop_ins
+        .left_join(scripts.on(scripts::loop_in_id.eq(loop_ins::id.nullable())))
+        .left_join(utxos.on(utxos::script_id.nullable().eq(scripts::id.nullable())))
+        .filter(loop_ins::state.eq_any(loop_in_states))
+        .order(loop_ins::id.asc())
+        .load::<(LoopIn, Option<Script>, Option<Utxo>)>(conn)?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(loop_in, script, utxo)| {
+            script.map(|script| new_full_loop_in_data(loop_in, script, utxo))
+        })
+        .collect())
+}
+
+pub fn new_full_loop_in_data(
+    loop_in: LoopIn,
+    script: Script,
+    utxo: Option<Utxo>,
+) -> FullLoopInData {
+    FullLoopInData {
+        loop_in,
+        script,
+        utxo,
     }
 }
 
@@ -310,7 +1016,8 @@ pub(crate) mod tests {
     use crate::{
         db::DB,
         models::{
-            self, Invoice, LoopOut, NewInvoice, NewLoopOut, NewScript, NewUTXO, Script, Utxo,
+            self, Invoice, LoopOut, NewInvoice, NewLoopIn, NewLoopOut, NewLoopOutQuote, NewScript,
+            NewUTXO, Script, Utxo,
         },
         settings,
     };
@@ -337,7 +1044,9 @@ pub(crate) mod tests {
     fn truncate_tables(conn: &mut super::PooledConnection) {
         use diesel::RunQueryDsl;
 
-        diesel::sql_query("TRUNCATE TABLE loop_outs, invoices, scripts, utxos CASCADE;")
+        diesel::sql_query(
+            "TRUNCATE TABLE loop_outs, loop_ins, loop_out_quotes, invoices, scripts, utxos CASCADE;",
+        )
             .execute(conn)
             .expect("failed to truncate tables");
     }
@@ -348,13 +1057,20 @@ pub(crate) mod tests {
         let conn = &mut DB.get_conn().expect("failed to get new connection");
 
         let loop_out = NewLoopOut {
-            state: models::LOOP_OUT_STATE_INITIATED.to_string(),
+            state: models::LoopOutState::Initiated,
+            amount: 100,
+            fee: 0,
+            miner_fee: None,
+            fee_rate: None,
+            batched: false,
+            webhook_url: None,
+            webhook_secret: None,
         };
 
         let inserted_loop_out =
             super::insert_loop_out(conn, loop_out).expect("failed to insert loop out");
 
-        assert_eq!(models::LOOP_OUT_STATE_INITIATED, inserted_loop_out.state);
+        assert_eq!(models::LoopOutState::Initiated, inserted_loop_out.state);
 
         // get loop_out from db
         let fetched_loop_out =
@@ -368,24 +1084,229 @@ pub(crate) mod tests {
         assert_eq!(l1.state, l2.state);
     }
 
+    #[test]
+    fn test_loop_out_state_transitions() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let loop_out = super::insert_loop_out(
+            conn,
+            NewLoopOut {
+                state: models::LoopOutState::Initiated,
+                amount: 100,
+                fee: 0,
+                miner_fee: None,
+                fee_rate: None,
+                batched: false,
+                webhook_url: None,
+                webhook_secret: None,
+            },
+        )
+        .expect("failed to insert loop out");
+
+        let mut loop_out = loop_out;
+        loop_out.state = models::LoopOutState::Claimed;
+        loop_out.claim_txid = Some("test-claim-txid".to_string());
+        let loop_out = super::update_loop_out(conn, loop_out, "test-claimed")
+            .expect("failed to update loop out");
+
+        // a claimed loop out can't go back
+        let err = super::update_loop_out_state(
+            conn,
+            loop_out.id,
+            models::LoopOutState::Initiated,
+            "test-initiated",
+        )
+        .expect_err("claimed loop out moved to initiated");
+        assert!(matches!(err, diesel::result::Error::SerializationError(_)));
+        let fetched = super::get_loop_out(conn, loop_out.id).expect("failed to get loop out");
+        assert_eq!(models::LoopOutState::Claimed, fetched.state);
+
+        let history = super::list_loop_out_state_history(conn, loop_out.id)
+            .expect("failed to list loop out history");
+        let transitions = history
+            .iter()
+            .map(|c| (c.from_state, c.to_state, c.reason.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (None, models::LoopOutState::Initiated, "created"),
+                (
+                    Some(models::LoopOutState::Initiated),
+                    models::LoopOutState::Claimed,
+                    "test-claimed"
+                ),
+            ],
+            transitions
+        );
+    }
+
+    #[test]
+    fn test_reopen_loop_out_timeout() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let mut ids = vec![];
+        for state in [models::LoopOutState::Timeout, models::LoopOutState::Expired] {
+            let mut loop_out = super::insert_loop_out(
+                conn,
+                NewLoopOut {
+                    state,
+                    amount: 100,
+                    fee: 0,
+                    miner_fee: None,
+                    fee_rate: None,
+                    batched: false,
+                    webhook_url: None,
+                    webhook_secret: None,
+                },
+            )
+            .expect("failed to insert loop out");
+            loop_out.timeout_txid = Some("test-timeout-txid".to_string());
+            let loop_out = super::update_loop_out(conn, loop_out, "test-swept")
+                .expect("failed to update loop out");
+            ids.push(loop_out.id);
+        }
+
+        // a dropped sweep hands a TIMEOUT loop out back to the timeout sweeper
+        let loop_out = super::reopen_loop_out_timeout(
+            conn,
+            ids[0],
+            models::LoopOutState::Confirmed,
+            "test-dropped",
+        )
+        .expect("failed to reopen timeout");
+        assert_eq!(models::LoopOutState::Confirmed, loop_out.state);
+        assert_eq!(None, loop_out.timeout_txid);
+        let history = super::list_loop_out_state_history(conn, ids[0])
+            .expect("failed to list loop out history");
+        let last = history.last().expect("no loop out history");
+        assert_eq!(
+            (
+                Some(models::LoopOutState::Timeout),
+                models::LoopOutState::Confirmed,
+                "test-dropped"
+            ),
+            (last.from_state, last.to_state, last.reason.as_str())
+        );
+
+        // an EXPIRED loop out only forgets its sweep
+        let loop_out = super::reopen_loop_out_timeout(
+            conn,
+            ids[1],
+            models::LoopOutState::Expired,
+            "test-dropped",
+        )
+        .expect("failed to reopen timeout");
+        assert_eq!(models::LoopOutState::Expired, loop_out.state);
+        assert_eq!(None, loop_out.timeout_txid);
+        let history = super::list_loop_out_state_history(conn, ids[1])
+            .expect("failed to list loop out history");
+        assert_eq!(1, history.len());
+
+        // an EXPIRED loop out can't be reopened for another sweep
+        let err = super::reopen_loop_out_timeout(
+            conn,
+            ids[1],
+            models::LoopOutState::Confirmed,
+            "test-dropped",
+        )
+        .expect_err("expired loop out moved to confirmed");
+        assert!(matches!(err, diesel::result::Error::SerializationError(_)));
+    }
+
+    #[test]
+    fn test_webhook_delivery_queue() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let loop_out = super::insert_loop_out(
+            conn,
+            NewLoopOut {
+                state: models::LoopOutState::AwaitingPayment,
+                amount: 100,
+                fee: 0,
+                miner_fee: None,
+                fee_rate: None,
+                batched: false,
+                webhook_url: Some("http://127.0.0.1/test-hook".to_string()),
+                webhook_secret: Some("test-secret".to_string()),
+            },
+        )
+        .expect("failed to insert loop out");
+
+        let changes =
+            super::list_unnotified_state_changes(conn, 10_000).expect("failed to list changes");
+        let (change, changed_loop_out, _) = changes
+            .into_iter()
+            .find(|(c, _, _)| c.loop_out_id == loop_out.id)
+            .expect("state change not listed");
+        assert_eq!(models::LoopOutState::AwaitingPayment, change.to_state);
+        assert_eq!(
+            Some("http://127.0.0.1/test-hook".to_string()),
+            changed_loop_out.webhook_url
+        );
+
+        super::enqueue_webhook_deliveries(
+            conn,
+            change.id,
+            vec![models::NewWebhookDelivery {
+                state_change_id: change.id,
+                url: "http://127.0.0.1/test-hook".to_string(),
+                payload: "{}".to_string(),
+                signature: "test-signature".to_string(),
+                state: models::WebhookDeliveryState::Pending,
+            }],
+        )
+        .expect("failed to enqueue deliveries");
+
+        // each change is only queued once
+        let changes =
+            super::list_unnotified_state_changes(conn, 10_000).expect("failed to list changes");
+        assert!(changes.iter().all(|(c, _, _)| c.id != change.id));
+
+        let mut delivery = super::list_due_webhook_deliveries(conn, 10_000)
+            .expect("failed to list deliveries")
+            .into_iter()
+            .map(|(d, _)| d)
+            .find(|d| d.state_change_id == change.id)
+            .expect("delivery not listed");
+        delivery.attempts = 1;
+        delivery.state = models::WebhookDeliveryState::Delivered;
+        super::update_webhook_delivery(conn, delivery).expect("failed to update delivery");
+
+        let due =
+            super::list_due_webhook_deliveries(conn, 10_000).expect("failed to list deliveries");
+        assert!(due.iter().all(|(d, _)| d.state_change_id != change.id));
+    }
+
     #[test]
     fn test_insert_and_select_full_loop_out() {
         setup_test_db();
         let conn = &mut DB.get_conn().expect("failed to get new connection");
 
         let loop_out = NewLoopOut {
-            state: models::LOOP_OUT_STATE_INITIATED.to_string(),
+            state: models::LoopOutState::Initiated,
+            amount: 100,
+            fee: 1,
+            miner_fee: Some(154),
+            fee_rate: Some(1.5),
+            batched: false,
+            webhook_url: None,
+            webhook_secret: None,
         };
         let mut invoice = NewInvoice {
-            state: models::INVOICE_STATE_OPEN.to_string(),
+            state: models::InvoiceState::Open,
             payment_hash: "test-payhash",
             payment_preimage: Some("test-preimage"),
             payment_request: "test-invoice",
             amount: 100,
             loop_out_id: 0,
+            is_hold: false,
+            kind: models::INVOICE_KIND_SWAP.to_string(),
         };
         let mut script = NewScript {
-            loop_out_id: 0,
+            loop_out_id: Some(0),
             address: "test-address",
             external_tapkey: "test-external-tapkey",
             internal_tapkey: "test-internal-tapkey",
@@ -396,15 +1317,26 @@ pub(crate) mod tests {
             remote_pubkey: "test-remote-pubkey".to_string(),
             local_pubkey: "test-local-pubkey".to_string(),
             local_pubkey_index: 100,
+            is_musig: false,
+            loop_in_id: None,
         };
         let mut utxo = NewUTXO {
             txid: "test-txid",
             vout: 100,
             amount: 100,
             script_id: 0,
+            raw_tx: Some("test-raw-tx"),
         };
-        let resp =
-            super::insert_full_loop_out_data(conn, loop_out, &mut invoice, &mut script, &mut utxo);
+        let resp = super::insert_full_loop_out_data(
+            conn,
+            loop_out,
+            &mut invoice,
+            None,
+            &mut script,
+            Some(&mut utxo),
+            100,
+            None,
+        );
 
         assert!(resp.is_ok());
 
@@ -412,8 +1344,11 @@ pub(crate) mod tests {
 
         assert_eq!(
             full_loop_out.loop_out.state,
-            models::LOOP_OUT_STATE_INITIATED
+            models::LoopOutState::Initiated
         );
+        assert_eq!(1, full_loop_out.loop_out.fee);
+        assert_eq!(Some(154), full_loop_out.loop_out.miner_fee);
+        assert_eq!(Some(1.5), full_loop_out.loop_out.fee_rate);
         // Assert all db FKs are set correctly
         assert_eq!(
             Some(full_loop_out.loop_out.id),
@@ -423,11 +1358,574 @@ pub(crate) mod tests {
             Some(full_loop_out.loop_out.id),
             full_loop_out.script.loop_out_id
         );
-        assert_eq!(full_loop_out.script.id, full_loop_out.utxo.script_id);
+        let full_utxo = full_loop_out.utxo.expect("utxo not set");
+        assert_eq!(full_loop_out.script.id, full_utxo.script_id);
+
+        // the liquidity reserved for the loop out is stored with it
+        let (reservation, _, reserved_utxo) = super::list_active_liquidity_reservations(conn)
+            .expect("failed to list reservations")
+            .into_iter()
+            .find(|(r, _, _)| r.loop_out_id == full_loop_out.loop_out.id)
+            .expect("reservation not listed");
+        assert_eq!(100, reservation.amount);
+        assert_eq!(Some(full_utxo.id), reserved_utxo.map(|u| u.id));
+        super::release_liquidity_reservations(conn, vec![reservation.id])
+            .expect("failed to release reservation");
+        assert!(super::list_active_liquidity_reservations(conn)
+            .expect("failed to list reservations")
+            .iter()
+            .all(|(r, _, _)| r.id != reservation.id));
 
         assert_new_invoice_matches_invoice(invoice, full_loop_out.invoice);
         assert_new_script_matches_script(script, full_loop_out.script);
-        assert_new_utxo_matches_utxo(utxo, full_loop_out.utxo);
+        assert_new_utxo_matches_utxo(utxo, full_utxo);
+    }
+
+    #[test]
+    fn test_next_htlc_key_index() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let first = super::next_htlc_key_index(conn).expect("failed to reserve key index");
+        let second = super::next_htlc_key_index(conn).expect("failed to reserve key index");
+        assert!(second > first);
+    }
+
+    #[test]
+    fn test_replace_loop_out_funding() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let mut invoice = NewInvoice {
+            state: models::InvoiceState::Open,
+            payment_hash: "test-rbf-payhash",
+            payment_preimage: None,
+            payment_request: "test-rbf-invoice",
+            amount: 100,
+            loop_out_id: 0,
+            is_hold: false,
+            kind: models::INVOICE_KIND_SWAP.to_string(),
+        };
+        let mut script = NewScript {
+            loop_out_id: Some(0),
+            address: "test-rbf-address",
+            external_tapkey: "test-rbf-external-tapkey",
+            internal_tapkey: "test-rbf-internal-tapkey",
+            internal_tapkey_tweak: "test-rbf-internal-tapkey-tweak",
+            payment_hash: "test-rbf-payment-hash",
+            tree: vec![],
+            cltv_expiry: 100,
+            remote_pubkey: "test-remote-pubkey".to_string(),
+            local_pubkey: "test-local-pubkey".to_string(),
+            local_pubkey_index: 101,
+            is_musig: false,
+            loop_in_id: None,
+        };
+        let mut utxo = NewUTXO {
+            txid: "test-rbf-txid",
+            vout: 0,
+            amount: 100,
+            script_id: 0,
+            raw_tx: Some("test-rbf-raw-tx"),
+        };
+        let data = super::insert_full_loop_out_data(
+            conn,
+            NewLoopOut {
+                state: models::LoopOutState::Initiated,
+                amount: 100,
+                fee: 1,
+                miner_fee: Some(154),
+                fee_rate: Some(1.0),
+                batched: false,
+                webhook_url: None,
+                webhook_secret: None,
+            },
+            &mut invoice,
+            None,
+            &mut script,
+            Some(&mut utxo),
+            100,
+            None,
+        )
+        .expect("failed to insert loop out");
+        let mut utxo = data.utxo.expect("utxo not set");
+        assert!(utxo.replaced_txids.is_empty());
+
+        utxo.replaced_txids.push(Some(utxo.txid.clone()));
+        utxo.txid = "test-rbf-replacement-txid".to_string();
+        utxo.vout = 1;
+        utxo.raw_tx = Some("test-rbf-replacement-raw-tx".to_string());
+        super::replace_loop_out_funding(conn, vec![(data.loop_out.id, utxo)], Some((308, 2.0)))
+            .expect("failed to replace funding");
+
+        let data = super::get_full_loop_out(conn, "test-rbf-payhash".to_string())
+            .expect("failed to get loop out");
+        let utxo = data.utxo.expect("utxo not set");
+        assert_eq!("test-rbf-replacement-txid", utxo.txid);
+        assert_eq!(1, utxo.vout);
+        assert_eq!(vec![Some("test-rbf-txid".to_string())], utxo.replaced_txids);
+        assert_eq!(Some(308), data.loop_out.miner_fee);
+        assert_eq!(Some(2.0), data.loop_out.fee_rate);
+        assert_eq!(models::LoopOutState::Initiated, data.loop_out.state);
+
+        let change = super::list_loop_out_state_history(conn, data.loop_out.id)
+            .expect("failed to list state history")
+            .pop()
+            .expect("replacement not recorded");
+        assert_eq!(Some(models::LoopOutState::Initiated), change.from_state);
+        assert_eq!(models::LoopOutState::Initiated, change.to_state);
+        assert_eq!(
+            "funding tx replaced by test-rbf-replacement-txid:1",
+            change.reason
+        );
+    }
+
+    #[test]
+    fn test_insert_full_loop_out_rolls_back_on_error() {
+        use crate::schema::loop_outs::dsl::*;
+        use diesel::prelude::*;
+
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let loop_out = NewLoopOut {
+            state: models::LoopOutState::Initiated,
+            amount: 4242,
+            fee: 0,
+            miner_fee: None,
+            fee_rate: None,
+            batched: false,
+            webhook_url: None,
+            webhook_secret: None,
+        };
+        let mut invoice = NewInvoice {
+            state: models::InvoiceState::Open,
+            payment_hash: "test-rollback-payhash",
+            payment_preimage: Some("test-rollback-preimage"),
+            payment_request: "test-rollback-invoice",
+            amount: 4242,
+            loop_out_id: 0,
+            is_hold: false,
+            kind: models::INVOICE_KIND_SWAP.to_string(),
+        };
+        let mut script = NewScript {
+            loop_out_id: None,
+            address: "test-rollback-address",
+            external_tapkey: "test-external-tapkey",
+            internal_tapkey: "test-internal-tapkey",
+            internal_tapkey_tweak: "test-internal-tapkey-tweak",
+            payment_hash: "test-rollback-payhash",
+            tree: vec!["test-tree".to_string()],
+            cltv_expiry: 100,
+            remote_pubkey: "test-remote-pubkey".to_string(),
+            local_pubkey: "test-local-pubkey".to_string(),
+            local_pubkey_index: 102,
+            is_musig: false,
+            loop_in_id: None,
+        };
+        // postgres rejects NUL bytes in text, so the last insert fails
+        let mut utxo = NewUTXO {
+            txid: "test-rollback\0txid",
+            vout: 0,
+            amount: 4242,
+            script_id: 0,
+            raw_tx: None,
+        };
+        let resp = super::insert_full_loop_out_data(
+            conn,
+            loop_out,
+            &mut invoice,
+            None,
+            &mut script,
+            Some(&mut utxo),
+            100,
+            None,
+        );
+        assert!(resp.is_err());
+
+        let count: i64 = loop_outs
+            .filter(id.eq(invoice.loop_out_id))
+            .count()
+            .get_result(conn)
+            .expect("failed to count loop outs");
+        assert_eq!(0, count);
+    }
+
+    #[test]
+    fn test_settle_invoice_by_payment_hash() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let loop_out = super::insert_loop_out(
+            conn,
+            NewLoopOut {
+                state: models::LoopOutState::Initiated,
+                amount: 100,
+                fee: 0,
+                miner_fee: None,
+                fee_rate: None,
+                batched: false,
+                webhook_url: None,
+                webhook_secret: None,
+            },
+        )
+        .expect("failed to insert loop out");
+        let new_invoice = NewInvoice {
+            state: models::InvoiceState::Open,
+            payment_hash: "test-settle-payhash",
+            payment_preimage: None,
+            payment_request: "test-settle-invoice",
+            amount: 100,
+            loop_out_id: loop_out.id,
+            is_hold: false,
+            kind: models::INVOICE_KIND_SWAP.to_string(),
+        };
+        super::insert_invoice(conn, new_invoice).expect("failed to insert invoice");
+
+        let mut invoice = super::get_invoice_by_payment_hash(conn, "test-settle-payhash")
+            .expect("failed to get invoice by payment hash");
+        assert_eq!(models::InvoiceState::Open, invoice.state);
+        assert_eq!(None, invoice.settle_index);
+
+        let settle_index =
+            super::get_max_invoice_settle_index(conn).expect("failed to get max settle index") + 1;
+        invoice.state = models::InvoiceState::Settled;
+        invoice.settle_index = Some(settle_index);
+        let invoice = super::update_invoice(conn, invoice).expect("failed to update invoice");
+
+        assert_eq!(models::InvoiceState::Settled, invoice.state);
+        assert_eq!(Some(settle_index), invoice.settle_index);
+        assert!(
+            super::get_max_invoice_settle_index(conn).expect("failed to get max settle index")
+                >= settle_index
+        );
+
+        let swap_invoice =
+            super::get_loop_out_invoice(conn, loop_out.id, models::INVOICE_KIND_SWAP)
+                .expect("failed to get loop out invoice");
+        assert_eq!(invoice.id, swap_invoice.id);
+        assert!(matches!(
+            super::get_loop_out_invoice(conn, loop_out.id, models::INVOICE_KIND_PREPAY),
+            Err(diesel::result::Error::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_update_and_list_loop_outs_by_state() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let loop_out = NewLoopOut {
+            state: models::LoopOutState::Initiated,
+            amount: 100,
+            fee: 0,
+            miner_fee: None,
+            fee_rate: None,
+            batched: false,
+            webhook_url: None,
+            webhook_secret: None,
+        };
+        let mut invoice = NewInvoice {
+            state: models::InvoiceState::Open,
+            payment_hash: "test-state-payhash",
+            payment_preimage: Some("test-state-preimage"),
+            payment_request: "test-state-invoice",
+            amount: 100,
+            loop_out_id: 0,
+            is_hold: false,
+            kind: models::INVOICE_KIND_SWAP.to_string(),
+        };
+        let mut script = NewScript {
+            loop_out_id: Some(0),
+            address: "test-state-address",
+            external_tapkey: "test-external-tapkey",
+            internal_tapkey: "test-internal-tapkey",
+            internal_tapkey_tweak: "test-internal-tapkey-tweak",
+            payment_hash: "test-state-payhash",
+            tree: vec!["test-tree".to_string(), "test-tree2".to_string()],
+            cltv_expiry: 100,
+            remote_pubkey: "test-remote-pubkey".to_string(),
+            local_pubkey: "test-local-pubkey".to_string(),
+            local_pubkey_index: 101,
+            is_musig: false,
+            loop_in_id: None,
+        };
+        let mut utxo = NewUTXO {
+            txid: "test-state-txid",
+            vout: 0,
+            amount: 100,
+            script_id: 0,
+            raw_tx: Some("test-raw-tx"),
+        };
+        let full_loop_out = super::insert_full_loop_out_data(
+            conn,
+            loop_out,
+            &mut invoice,
+            None,
+            &mut script,
+            Some(&mut utxo),
+            100,
+            None,
+        )
+        .expect("failed to insert full loop out");
+
+        let updated = super::update_loop_out_state(
+            conn,
+            full_loop_out.loop_out.id,
+            models::LoopOutState::Confirmed,
+            "test-confirmed",
+        )
+        .expect("failed to update loop out state");
+        assert_eq!(models::LoopOutState::Confirmed, updated.state);
+
+        let confirmed =
+            super::list_full_loop_outs_in_states(conn, vec![models::LoopOutState::Confirmed])
+                .expect("failed to list loop outs");
+        let listed = confirmed
+            .iter()
+            .find(|l| l.loop_out.id == full_loop_out.loop_out.id)
+            .expect("confirmed loop out not listed");
+        assert_eq!(
+            full_loop_out.utxo.map(|u| u.id),
+            listed.utxo.as_ref().map(|u| u.id)
+        );
+
+        let initiated =
+            super::list_full_loop_outs_in_states(conn, vec![models::LoopOutState::Initiated])
+                .expect("failed to list loop outs");
+        assert!(initiated
+            .iter()
+            .all(|l| l.loop_out.id != full_loop_out.loop_out.id));
+    }
+
+    #[test]
+    fn test_list_unfunded_loop_outs_with_prepay() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let loop_out = super::insert_loop_out(
+            conn,
+            NewLoopOut {
+                state: models::LoopOutState::AwaitingPayment,
+                amount: 100,
+                fee: 0,
+                miner_fee: None,
+                fee_rate: None,
+                batched: false,
+                webhook_url: None,
+                webhook_secret: None,
+            },
+        )
+        .expect("failed to insert loop out");
+        let invoice = super::insert_invoice(
+            conn,
+            NewInvoice {
+                state: models::InvoiceState::Open,
+                payment_hash: "test-hold-payhash",
+                payment_preimage: None,
+                payment_request: "test-hold-invoice",
+                amount: 100,
+                loop_out_id: loop_out.id,
+                is_hold: true,
+                kind: models::INVOICE_KIND_SWAP.to_string(),
+            },
+        )
+        .expect("failed to insert invoice");
+        super::insert_script(
+            conn,
+            NewScript {
+                loop_out_id: Some(loop_out.id),
+                address: "test-hold-address",
+                external_tapkey: "test-external-tapkey",
+                internal_tapkey: "test-internal-tapkey",
+                internal_tapkey_tweak: "test-internal-tapkey-tweak",
+                payment_hash: "test-hold-payhash",
+                tree: vec!["test-tree".to_string(), "test-tree2".to_string()],
+                cltv_expiry: 100,
+                remote_pubkey: "test-remote-pubkey".to_string(),
+                local_pubkey: "test-local-pubkey".to_string(),
+                local_pubkey_index: 102,
+                is_musig: false,
+                loop_in_id: None,
+            },
+        )
+        .expect("failed to insert script");
+
+        super::insert_invoice(
+            conn,
+            NewInvoice {
+                state: models::InvoiceState::Open,
+                payment_hash: "test-prepay-payhash",
+                payment_preimage: Some("test-prepay-preimage"),
+                payment_request: "test-prepay-invoice",
+                amount: 10,
+                loop_out_id: loop_out.id,
+                is_hold: false,
+                kind: models::INVOICE_KIND_PREPAY.to_string(),
+            },
+        )
+        .expect("failed to insert prepay invoice");
+
+        let full_loop_out = super::get_full_loop_out(conn, "test-hold-payhash".to_string())
+            .expect("failed to get unfunded loop out");
+        assert!(full_loop_out.invoice.is_hold);
+        assert_eq!(models::INVOICE_KIND_SWAP, full_loop_out.invoice.kind);
+        assert!(full_loop_out.utxo.is_none());
+        let prepay_invoice = full_loop_out
+            .prepay_invoice
+            .expect("prepay invoice not set");
+        assert_eq!("test-prepay-payhash", prepay_invoice.payment_hash);
+
+        let open = super::list_full_loop_outs_with_invoice_state(
+            conn,
+            models::LoopOutState::AwaitingPayment,
+            models::InvoiceState::Open,
+        )
+        .expect("failed to list loop outs");
+        let listed = open
+            .iter()
+            .find(|l| l.loop_out.id == loop_out.id)
+            .expect("unfunded loop out not listed");
+        assert_eq!(
+            Some(models::INVOICE_KIND_PREPAY),
+            listed.prepay_invoice.as_ref().map(|i| i.kind.as_str())
+        );
+
+        let mut invoice = invoice;
+        invoice.state = models::InvoiceState::Accepted;
+        super::update_invoice(conn, invoice).expect("failed to update invoice");
+
+        let open = super::list_full_loop_outs_with_invoice_state(
+            conn,
+            models::LoopOutState::AwaitingPayment,
+            models::InvoiceState::Open,
+        )
+        .expect("failed to list loop outs");
+        assert!(open.iter().all(|l| l.loop_out.id != loop_out.id));
+    }
+
+    #[test]
+    fn test_redeem_loop_out_quote() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let new_quote = |id, expires_at| NewLoopOutQuote {
+            quote_id: id,
+            amount: 100_000,
+            fee: 1_000,
+            miner_fee: 300,
+            fee_rate: 2.0,
+            cltv_delta: 210,
+            expires_at,
+            batched: false,
+        };
+        let in_an_hour = chrono::Utc::now().naive_utc() + chrono::Duration::hours(1);
+        let an_hour_ago = chrono::Utc::now().naive_utc() - chrono::Duration::hours(1);
+
+        let quote = super::insert_loop_out_quote(conn, new_quote("test-quote", in_an_hour))
+            .expect("failed to insert quote");
+        assert!(quote.redeemed_at.is_none());
+
+        // the amount must match the quoted one
+        assert!(super::redeem_loop_out_quote(conn, "test-quote", 99_999, false).is_err());
+        // and so must the funding mode
+        assert!(super::redeem_loop_out_quote(conn, "test-quote", 100_000, true).is_err());
+
+        // looking a quote up doesn't redeem it
+        let redeemable = super::get_redeemable_loop_out_quote(conn, "test-quote", 100_000, false)
+            .expect("failed to get quote");
+        assert_eq!(quote.id, redeemable.id);
+        assert!(super::get_redeemable_loop_out_quote(conn, "test-quote", 100_000, true).is_err());
+
+        let redeemed = super::redeem_loop_out_quote(conn, "test-quote", 100_000, false)
+            .expect("failed to redeem quote");
+        assert_eq!(quote.id, redeemed.id);
+        assert_eq!(1_000, redeemed.fee);
+        assert!(redeemed.redeemed_at.is_some());
+
+        // quotes can only be redeemed once
+        assert!(super::redeem_loop_out_quote(conn, "test-quote", 100_000, false).is_err());
+        assert!(super::get_redeemable_loop_out_quote(conn, "test-quote", 100_000, false).is_err());
+
+        super::insert_loop_out_quote(conn, new_quote("test-expired-quote", an_hour_ago))
+            .expect("failed to insert quote");
+        assert!(super::redeem_loop_out_quote(conn, "test-expired-quote", 100_000, false).is_err());
+    }
+
+    #[test]
+    fn test_insert_and_list_loop_ins() {
+        setup_test_db();
+        let conn = &mut DB.get_conn().expect("failed to get new connection");
+
+        let loop_in = super::insert_loop_in(
+            conn,
+            NewLoopIn {
+                state: models::LOOP_IN_STATE_INITIATED.to_string(),
+                payment_request: "test-loop-in-invoice",
+                payment_hash: "test-loop-in-payhash",
+                amount: 100,
+                fee: 1,
+            },
+        )
+        .expect("failed to insert loop in");
+        let script = super::insert_script(
+            conn,
+            NewScript {
+                loop_out_id: None,
+                address: "test-loop-in-address",
+                external_tapkey: "test-external-tapkey",
+                internal_tapkey: "test-internal-tapkey",
+                internal_tapkey_tweak: "test-internal-tapkey-tweak",
+                payment_hash: "test-loop-in-payhash",
+                tree: vec!["test-tree".to_string(), "test-tree2".to_string()],
+                cltv_expiry: 100,
+                remote_pubkey: "test-remote-pubkey".to_string(),
+                local_pubkey: "test-local-pubkey".to_string(),
+                local_pubkey_index: 103,
+                is_musig: false,
+                loop_in_id: Some(loop_in.id),
+            },
+        )
+        .expect("failed to insert script");
+
+        let full_loop_in = super::get_full_loop_in(conn, "test-loop-in-payhash".to_string())
+            .expect("failed to get loop in");
+        assert_eq!(loop_in.id, full_loop_in.loop_in.id);
+        assert_eq!(script.id, full_loop_in.script.id);
+        assert!(full_loop_in.utxo.is_none());
+
+        // loop ins aren't loop outs
+        assert!(super::get_full_loop_out(conn, "test-loop-in-payhash".to_string()).is_err());
+
+        super::insert_utxo(
+            conn,
+            NewUTXO {
+                txid: "test-loop-in-txid",
+                vout: 1,
+                amount: 101,
+                script_id: script.id,
+                raw_tx: None,
+            },
+        )
+        .expect("failed to insert utxo");
+        let mut loop_in = full_loop_in.loop_in;
+        loop_in.state = models::LOOP_IN_STATE_FUNDED.to_string();
+        super::update_loop_in(conn, loop_in).expect("failed to update loop in");
+
+        let funded = super::list_full_loop_ins_in_states(
+            conn,
+            vec![models::LOOP_IN_STATE_FUNDED.to_string()],
+        )
+        .expect("failed to list loop ins");
+        let listed = funded
+            .iter()
+            .find(|l| l.script.id == script.id)
+            .expect("funded loop in not listed");
+        assert_eq!(
+            Some(1),
+            listed.utxo.as_ref().map(|u| u.vout),
+            "utxo not joined"
+        );
     }
 
     fn assert_new_invoice_matches_invoice(ni: NewInvoice, si: Invoice) {
@@ -463,5 +1961,6 @@ pub(crate) mod tests {
         assert_eq!(nu.txid, su.txid);
         assert_eq!(nu.vout, su.vout);
         assert_eq!(nu.amount, su.amount);
+        assert_eq!(nu.raw_tx.map(String::from), su.raw_tx);
     }
 }
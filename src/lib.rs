@@ -3,6 +3,7 @@ pub mod lnd;
 // pub mod models;
 // pub mod schema;
 mod models;
+pub mod musig;
 mod schema;
 pub mod settings;
 pub mod utils;
@@ -3,6 +3,7 @@ use hex;
 use std::collections::HashMap;
 
 use tokio::sync::Mutex;
+use tonic::codec::Streaming;
 
 use fedimint_tonic_lnd::{
     invoicesrpc,
@@ -60,7 +61,8 @@ pub struct LNDGateway {
 
 #[derive(Debug)]
 pub struct AddInvoiceResp {
-    pub preimage: String,
+    // preimage is None for hold invoices, where only the payer knows it
+    pub preimage: Option<String>,
     pub payment_hash: String,
     pub invoice: String,
     pub add_index: u64,
@@ -100,6 +102,7 @@ impl LNDGateway {
     pub async fn add_invoice(
         &self,
         value: i64,
+        memo: &str,
     ) -> Result<AddInvoiceResp, fedimint_tonic_lnd::Error> {
         let mut client = self.get_client().await;
 
@@ -109,7 +112,7 @@ impl LNDGateway {
         // resolves lint vs compile error dilemma
         #[allow(deprecated)]
         let req = lnrpc::Invoice {
-            memo: "looper swap out".to_string(),
+            memo: memo.to_string(),
             r_preimage: preimage.to_vec(),
             r_hash: payment_hash.to_vec(),
             expiry: self.cfg.invoice_lifetime,
@@ -145,7 +148,7 @@ impl LNDGateway {
             Ok(resp) => {
                 let resp = resp.into_inner();
                 Ok(AddInvoiceResp {
-                    preimage: hex::encode(preimage),
+                    preimage: Some(hex::encode(preimage)),
                     payment_hash: hex::encode(payment_hash),
                     invoice: resp.payment_request,
                     add_index: resp.add_index,
@@ -155,13 +158,15 @@ impl LNDGateway {
         }
     }
 
+    // add_hold_invoice adds an invoice for a payment_hash chosen by the payer. LND holds incoming payments in
+    // ACCEPTED until settle_invoice is called with the preimage or cancel_invoice fails them back.
     pub async fn add_hold_invoice(
         &self,
         value: i64,
-        cltv_timout: u64,
+        payment_hash: &[u8; 32],
+        cltv_expiry: u64,
     ) -> Result<AddInvoiceResp, fedimint_tonic_lnd::Error> {
         let mut client = self.get_client().await;
-        let (preimage, payment_hash) = Self::new_preimage();
 
         let req = invoicesrpc::AddHoldInvoiceRequest {
             memo: "looper swap out".to_string(),
@@ -169,9 +174,9 @@ impl LNDGateway {
             value,
             value_msat: 0,
             description_hash: vec![],
-            expiry: 86000,
+            expiry: self.cfg.invoice_lifetime,
             fallback_addr: "".to_string(),
-            cltv_expiry: cltv_timout,
+            cltv_expiry,
             route_hints: vec![],
             private: true,
         };
@@ -182,7 +187,7 @@ impl LNDGateway {
             Ok(resp) => {
                 let resp = resp.into_inner();
                 Ok(AddInvoiceResp {
-                    preimage: hex::encode(preimage),
+                    preimage: None,
                     payment_hash: hex::encode(payment_hash),
                     invoice: resp.payment_request,
                     add_index: resp.add_index,
@@ -192,10 +197,125 @@ impl LNDGateway {
         }
     }
 
+    pub async fn settle_invoice(
+        &self,
+        preimage: &[u8; 32],
+    ) -> Result<(), fedimint_tonic_lnd::Error> {
+        let mut client = self.get_client().await;
+        let req = invoicesrpc::SettleInvoiceMsg {
+            preimage: preimage.to_vec(),
+        };
+
+        let resp = client.invoices().settle_invoice(req).await;
+
+        match resp {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn cancel_invoice(
+        &self,
+        payment_hash: &[u8; 32],
+    ) -> Result<(), fedimint_tonic_lnd::Error> {
+        let mut client = self.get_client().await;
+        let req = invoicesrpc::CancelInvoiceMsg {
+            payment_hash: payment_hash.to_vec(),
+        };
+
+        let resp = client.invoices().cancel_invoice(req).await;
+
+        match resp {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn lookup_invoice(
+        &self,
+        payment_hash: &[u8; 32],
+    ) -> Result<lnrpc::Invoice, fedimint_tonic_lnd::Error> {
+        let mut client = self.get_client().await;
+        // resolves lint vs compile error dilemma
+        #[allow(deprecated)]
+        let req = lnrpc::PaymentHash {
+            r_hash_str: "".to_string(),
+            r_hash: payment_hash.to_vec(),
+        };
+
+        let resp = client.lightning().lookup_invoice(req).await;
+
+        match resp {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn decode_invoice(
+        &self,
+        invoice: &str,
+    ) -> Result<lnrpc::PayReq, fedimint_tonic_lnd::Error> {
+        let mut client = self.get_client().await;
+        let req = lnrpc::PayReqString {
+            pay_req: invoice.to_string(),
+        };
+
+        let resp = client.lightning().decode_pay_req(req).await;
+
+        match resp {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // lookup_payment returns the final state of our payment to payment_hash, or None if we never tried to pay it. If
+    // the payment is still in flight, it waits for it to resolve.
+    pub async fn lookup_payment(
+        &self,
+        payment_hash: &[u8; 32],
+    ) -> Result<Option<lnrpc::Payment>, fedimint_tonic_lnd::Error> {
+        let mut client = self.get_client().await;
+        let req = routerrpc::TrackPaymentRequest {
+            payment_hash: payment_hash.to_vec(),
+            no_inflight_updates: true,
+        };
+
+        let mut stream = match client.router().track_payment_v2(req).await {
+            Ok(resp) => resp.into_inner(),
+            Err(e) if e.code() == tonic::Code::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        stream.message().await
+    }
+
+    // subscribe_invoices streams invoice updates from LND. Passing a non-zero settle_index replays every
+    // invoice settled after that index before switching to live updates.
+    pub async fn subscribe_invoices(
+        &self,
+        add_index: u64,
+        settle_index: u64,
+    ) -> Result<Streaming<lnrpc::Invoice>, fedimint_tonic_lnd::Error> {
+        let mut client = self.get_client().await;
+        let req = lnrpc::InvoiceSubscription {
+            add_index,
+            settle_index,
+        };
+
+        let resp = client.lightning().subscribe_invoices(req).await;
+
+        match resp {
+            Ok(resp) => Ok(resp.into_inner()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // cltv_limit caps the total timelock of the route, so the payment can't be held for longer than that many blocks.
     pub async fn pay_invoice_async(
         &self,
         invoice: String,
         fee_limit: i64,
+        cltv_limit: u32,
     ) -> Result<(), fedimint_tonic_lnd::Error> {
         let mut client = self.get_client().await;
         // resolves lint vs compile error dilemma
@@ -213,9 +333,7 @@ impl LNDGateway {
             outgoing_chan_id: 0,
             outgoing_chan_ids: vec![],
             last_hop_pubkey: vec![],
-            // TODO: SET ME to cltv_delta + block height so that we can ensure the invoice can't be held
-            // until the onchain timelock expires.
-            cltv_limit: 0,
+            cltv_limit: cltv_limit as i32,
             route_hints: vec![],
             dest_custom_records: HashMap::new(),
             allow_self_payment: false,
@@ -236,10 +354,12 @@ impl LNDGateway {
         }
     }
 
+    // pay_invoice_sync blocks until the payment succeeds or fails. See pay_invoice_async for cltv_limit.
     pub async fn pay_invoice_sync(
         &self,
         invoice: String,
         fee_limit: i64,
+        cltv_limit: u32,
     ) -> Result<lnrpc::SendResponse, fedimint_tonic_lnd::Error> {
         let mut client = self.get_client().await;
         // resolves lint vs compile error dilemma
@@ -259,8 +379,7 @@ impl LNDGateway {
             }),
             outgoing_chan_id: 0,
             last_hop_pubkey: vec![],
-            // TODO: we need to set this when paying Loop invoices to avoid the invoice being held too long
-            cltv_limit: 0,
+            cltv_limit,
             dest_custom_records: HashMap::new(),
             allow_self_payment: false,
             dest_features: vec![],
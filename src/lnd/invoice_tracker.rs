@@ -1 +1,364 @@
-// TODO: track invoice settle events
+use std::time::Duration;
+
+use fedimint_tonic_lnd::lnrpc::{self, invoice::InvoiceState};
+
+use crate::{
+    db::{self, DB},
+    lnd::client::LNDGateway,
+    models, utils,
+};
+
+// how long to wait before resubscribing after the invoice stream fails or closes.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// InvoiceTracker follows LND's invoice stream and moves looper invoices out of OPEN once they are accepted, settle
+/// or are cancelled.
+pub struct InvoiceTracker {
+    db: DB,
+    lnd_gateway: LNDGateway,
+}
+
+impl InvoiceTracker {
+    pub fn new(db: DB, lnd_gateway: LNDGateway) -> Self {
+        Self { db, lnd_gateway }
+    }
+
+    /// start runs until the process exits. Every (re)subscription resumes from the highest settle_index
+    /// stored in the db, so settle events that happened while we were disconnected are replayed by LND.
+    pub async fn start(&self) {
+        loop {
+            match self.track_invoices().await {
+                Ok(_) => log::warn!("invoice subscription closed"),
+                Err(e) => log::error!("error tracking invoices: {:?}", e),
+            }
+
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    async fn track_invoices(&self) -> Result<(), InvoiceTrackerError> {
+        let settle_index = {
+            let conn = &mut self.get_conn()?;
+            db::get_max_invoice_settle_index(conn).map_err(|e| {
+                InvoiceTrackerError::new(format!("error getting max settle_index: {:?}", e))
+            })?
+        };
+
+        log::info!("subscribing to invoices from settle_index {}", settle_index);
+        let mut stream = self
+            .lnd_gateway
+            .subscribe_invoices(0, settle_index as u64)
+            .await
+            .map_err(|e| {
+                InvoiceTrackerError::new(format!("error subscribing to invoices: {:?}", e))
+            })?;
+
+        while let Some(update) = stream.message().await.map_err(|e| {
+            InvoiceTrackerError::new(format!("error reading invoice stream: {:?}", e))
+        })? {
+            self.handle_invoice_update(update).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_invoice_update(
+        &self,
+        update: lnrpc::Invoice,
+    ) -> Result<(), InvoiceTrackerError> {
+        if InvoiceState::from_i32(update.state) == Some(InvoiceState::Open) {
+            return Ok(());
+        }
+
+        let payment_hash = hex::encode(&update.r_hash);
+        let conn = &mut self.get_conn()?;
+        let mut invoice = match db::get_invoice_by_payment_hash(conn, &payment_hash) {
+            Ok(invoice) => invoice,
+            // not one of ours
+            Err(diesel::result::Error::NotFound) => return Ok(()),
+            Err(e) => {
+                return Err(InvoiceTrackerError::new(format!(
+                    "error getting invoice {} from db: {:?}",
+                    payment_hash, e
+                )))
+            }
+        };
+
+        if !apply_invoice_update(&mut invoice, &update) {
+            return Ok(());
+        }
+
+        let cancelled = match (invoice.state, invoice.loop_out_id) {
+            (models::InvoiceState::Cancelled, Some(loop_out_id)) => {
+                Some((loop_out_id, invoice.kind.clone()))
+            }
+            _ => None,
+        };
+        db::update_invoice(conn, invoice).map_err(|e| {
+            InvoiceTrackerError::new(format!(
+                "error updating invoice {} in db: {:?}",
+                payment_hash, e
+            ))
+        })?;
+
+        if let Some((loop_out_id, kind)) = cancelled {
+            self.expire_loop_out(conn, loop_out_id, &kind).await?;
+        }
+
+        Ok(())
+    }
+
+    /// expire_loop_out moves a loop out to EXPIRED once one of its invoices is cancelled unpaid, since it can no longer
+    /// be paid for. When the prepay is cancelled, the swap invoice is cancelled too so the buyer can't pay for a swap
+    /// that will never be funded.
+    async fn expire_loop_out(
+        &self,
+        conn: &mut db::PooledConnection,
+        loop_out_id: i64,
+        kind: &str,
+    ) -> Result<(), InvoiceTrackerError> {
+        let loop_out = db::get_loop_out(conn, loop_out_id).map_err(|e| {
+            InvoiceTrackerError::new(format!(
+                "error getting loop out {} from db: {:?}",
+                loop_out_id, e
+            ))
+        })?;
+        let (next, reason) = match loop_out_state_after_cancel(kind, loop_out.state) {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+
+        if kind == models::INVOICE_KIND_PREPAY {
+            let swap_invoice =
+                db::get_loop_out_invoice(conn, loop_out_id, models::INVOICE_KIND_SWAP).map_err(
+                    |e| {
+                        InvoiceTrackerError::new(format!(
+                            "error getting swap invoice of loop out {}: {:?}",
+                            loop_out_id, e
+                        ))
+                    },
+                )?;
+            if swap_invoice.state == models::InvoiceState::Open {
+                let payment_hash =
+                    utils::decode_hex32(&swap_invoice.payment_hash).map_err(|e| {
+                        InvoiceTrackerError::new(format!(
+                            "error decoding {}: {:?}",
+                            swap_invoice.payment_hash, e
+                        ))
+                    })?;
+                // the cancel update for the swap invoice arrives on the stream and finds the loop out EXPIRED
+                self.lnd_gateway
+                    .cancel_invoice(&payment_hash)
+                    .await
+                    .map_err(|e| {
+                        InvoiceTrackerError::new(format!(
+                            "error cancelling swap invoice {}: {:?}",
+                            swap_invoice.payment_hash, e
+                        ))
+                    })?;
+            }
+        }
+
+        match db::update_loop_out_state(conn, loop_out_id, next, reason) {
+            Ok(_) => {
+                log::info!("loop out {} expired: {}", loop_out_id, reason);
+                Ok(())
+            }
+            // the loop out moved on, e.g. it was claimed, since we read it
+            Err(diesel::result::Error::SerializationError(e)) => {
+                log::info!("not expiring loop out {}: {:?}", loop_out_id, e);
+                Ok(())
+            }
+            Err(e) => Err(InvoiceTrackerError::new(format!(
+                "error updating loop out {} state: {:?}",
+                loop_out_id, e
+            ))),
+        }
+    }
+
+    fn get_conn(&self) -> Result<db::PooledConnection, InvoiceTrackerError> {
+        self.db
+            .get_conn()
+            .map_err(|e| InvoiceTrackerError::new(format!("error getting db connection: {:?}", e)))
+    }
+}
+
+/// loop_out_state_after_cancel returns the state a loop out in state moves to once its invoice of kind is cancelled,
+/// along with the reason, or None if it stays put. A cancelled swap invoice expires any loop out that wasn't paid for,
+/// funded or not. A cancelled prepay only matters before funding, which waits for it to be paid.
+pub fn loop_out_state_after_cancel(
+    kind: &str,
+    state: models::LoopOutState,
+) -> Option<(models::LoopOutState, &'static str)> {
+    use models::LoopOutState::*;
+
+    match (kind, state) {
+        (models::INVOICE_KIND_SWAP, AwaitingPayment | Initiated | Confirmed) => {
+            Some((Expired, "swap invoice was cancelled unpaid"))
+        }
+        (models::INVOICE_KIND_PREPAY, AwaitingPayment) => {
+            Some((Expired, "prepay invoice was cancelled unpaid"))
+        }
+        _ => None,
+    }
+}
+
+/// apply_invoice_update moves invoice to the state LND reports in update. It returns false if there is nothing new to
+/// store.
+pub fn apply_invoice_update(invoice: &mut models::Invoice, update: &lnrpc::Invoice) -> bool {
+    let new_state = match InvoiceState::from_i32(update.state) {
+        // only hold invoices are ever ACCEPTED
+        Some(InvoiceState::Accepted) => models::InvoiceState::Accepted,
+        Some(InvoiceState::Settled) => models::InvoiceState::Settled,
+        Some(InvoiceState::Canceled) => models::InvoiceState::Cancelled,
+        _ => return false,
+    };
+
+    // settled invoices are replayed on every resubscribe. Hold invoices may have been marked SETTLED by the loop out
+    // service before their settle_index arrives here.
+    if invoice.state == new_state
+        && (new_state != models::InvoiceState::Settled || invoice.settle_index.is_some())
+    {
+        return false;
+    }
+
+    log::info!(
+        "invoice {} moved from {} to {}",
+        invoice.payment_hash,
+        invoice.state,
+        new_state
+    );
+    invoice.state = new_state;
+    if new_state == models::InvoiceState::Settled {
+        invoice.settle_index = Some(update.settle_index as i64);
+        if invoice.payment_preimage.is_none() && !update.r_preimage.is_empty() {
+            invoice.payment_preimage = Some(hex::encode(&update.r_preimage));
+        }
+    }
+
+    true
+}
+
+#[derive(Debug)]
+pub struct InvoiceTrackerError {
+    pub message: String,
+}
+
+impl InvoiceTrackerError {
+    pub fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn test_invoice(state: models::InvoiceState, is_hold: bool) -> models::Invoice {
+        let now = chrono::Utc::now().naive_utc();
+        models::Invoice {
+            id: 1,
+            loop_out_id: Some(1),
+            payment_request: "test-payment-request".to_string(),
+            payment_hash: "test-payment-hash".to_string(),
+            payment_preimage: None,
+            amount: 100,
+            state,
+            created_at: now,
+            updated_at: now,
+            settle_index: None,
+            is_hold,
+            kind: models::INVOICE_KIND_SWAP.to_string(),
+        }
+    }
+
+    fn test_update(state: InvoiceState, settle_index: u64) -> lnrpc::Invoice {
+        lnrpc::Invoice {
+            state: state as i32,
+            settle_index,
+            r_preimage: vec![1; 32],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_apply_invoice_update() {
+        let mut invoice = test_invoice(models::InvoiceState::Open, true);
+        assert!(apply_invoice_update(
+            &mut invoice,
+            &test_update(InvoiceState::Accepted, 0)
+        ));
+        assert_eq!(models::InvoiceState::Accepted, invoice.state);
+        assert!(!apply_invoice_update(
+            &mut invoice,
+            &test_update(InvoiceState::Accepted, 0)
+        ));
+
+        assert!(apply_invoice_update(
+            &mut invoice,
+            &test_update(InvoiceState::Settled, 7)
+        ));
+        assert_eq!(models::InvoiceState::Settled, invoice.state);
+        assert_eq!(Some(7), invoice.settle_index);
+        assert_eq!(Some(hex::encode([1; 32])), invoice.payment_preimage);
+        // replayed on resubscribe
+        assert!(!apply_invoice_update(
+            &mut invoice,
+            &test_update(InvoiceState::Settled, 7)
+        ));
+
+        let mut invoice = test_invoice(models::InvoiceState::Open, false);
+        assert!(!apply_invoice_update(
+            &mut invoice,
+            &test_update(InvoiceState::Open, 0)
+        ));
+        assert!(apply_invoice_update(
+            &mut invoice,
+            &test_update(InvoiceState::Canceled, 0)
+        ));
+        assert_eq!(models::InvoiceState::Cancelled, invoice.state);
+        assert_eq!(None, invoice.settle_index);
+    }
+
+    #[test]
+    fn test_apply_invoice_update_settled_by_service() {
+        // the loop out service marks hold invoices SETTLED before the tracker sees their settle_index
+        let mut invoice = test_invoice(models::InvoiceState::Settled, true);
+        assert!(apply_invoice_update(
+            &mut invoice,
+            &test_update(InvoiceState::Settled, 3)
+        ));
+        assert_eq!(Some(3), invoice.settle_index);
+    }
+
+    #[test]
+    fn test_loop_out_state_after_cancel() {
+        use models::LoopOutState::*;
+
+        for state in [AwaitingPayment, Initiated, Confirmed] {
+            assert_eq!(
+                Some(Expired),
+                loop_out_state_after_cancel(models::INVOICE_KIND_SWAP, state).map(|(s, _)| s)
+            );
+        }
+        for state in [Claimed, Timeout, Expired] {
+            assert_eq!(
+                None,
+                loop_out_state_after_cancel(models::INVOICE_KIND_SWAP, state)
+            );
+        }
+
+        assert_eq!(
+            Some(Expired),
+            loop_out_state_after_cancel(models::INVOICE_KIND_PREPAY, AwaitingPayment)
+                .map(|(s, _)| s)
+        );
+        // a funded loop out has had its prepay paid, so it can't be cancelled
+        for state in [Initiated, Confirmed, Claimed, Timeout, Expired] {
+            assert_eq!(
+                None,
+                loop_out_state_after_cancel(models::INVOICE_KIND_PREPAY, state)
+            );
+        }
+    }
+}
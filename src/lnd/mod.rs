@@ -1 +1,2 @@
 pub mod client;
+pub mod invoice_tracker;
@@ -9,16 +9,18 @@ mod db;
 pub mod lnd;
 pub mod mempool;
 pub mod models;
+pub mod musig;
 mod schema;
 mod services;
 pub mod settings;
 mod utils;
 pub mod wallet;
 
-use crate::lnd::client::LNDGateway;
+use crate::lnd::{client::LNDGateway, invoice_tracker::InvoiceTracker};
 // use bdk::bitcoin::secp256k1::PublicKey;
 use db::DB;
 use std::io::{self, BufRead};
+use std::sync::Arc;
 // use std::str::FromStr;
 
 // use rand::Rng;
@@ -36,13 +38,66 @@ async fn run() {
     let migration_conn = &mut db.get_conn().unwrap();
     db::run_migrations(migration_conn).unwrap();
 
-    let wallet = wallet::LooperWallet::new(&cfg).unwrap();
+    // the wallet is shared between loop out and loop in so both derive keys from the same index
+    let wallet = Arc::new(tokio::sync::Mutex::new(
+        wallet::LooperWallet::new(&cfg).unwrap(),
+    ));
 
     let lndg = LNDGateway::new().await.unwrap();
 
-    let loopout_svc = services::loop_out::LoopOutService::new(&cfg, db, wallet, lndg).unwrap();
+    let invoice_tracker = InvoiceTracker::new(db.clone(), LNDGateway::new().await.unwrap());
+    tokio::spawn(async move { invoice_tracker.start().await });
 
-    let server = api::server::LooperServer::new(loopout_svc);
+    let loopin_svc = Arc::new(
+        services::loop_in::LoopInService::new(
+            &cfg,
+            db.clone(),
+            wallet.clone(),
+            LNDGateway::new().await.unwrap(),
+        )
+        .unwrap(),
+    );
+
+    let webhook_svc = services::webhook::WebhookService::new(&cfg, db.clone()).unwrap();
+    tokio::spawn(async move { webhook_svc.run_webhook_dispatcher().await });
+
+    let loopout_svc =
+        Arc::new(services::loop_out::LoopOutService::new(&cfg, db, wallet, lndg).unwrap());
+
+    // swaps that were in flight when we last stopped are reconciled before the workers pick them up
+    if let Err(e) = loopout_svc.recover_loop_outs().await {
+        log::error!("error recovering loop outs: {:?}", e);
+    }
+
+    let confirmation_watcher = loopout_svc.clone();
+    tokio::spawn(async move { confirmation_watcher.run_confirmation_watcher().await });
+    let claim_watcher = loopout_svc.clone();
+    tokio::spawn(async move { claim_watcher.run_claim_watcher().await });
+    let funding_watcher = loopout_svc.clone();
+    tokio::spawn(async move { funding_watcher.run_funding_watcher().await });
+    let timeout_sweeper = loopout_svc.clone();
+    tokio::spawn(async move { timeout_sweeper.run_timeout_sweeper().await });
+    let fee_bumper = loopout_svc.clone();
+    tokio::spawn(async move { fee_bumper.run_fee_bumper().await });
+    let expiry_watcher = loopout_svc.clone();
+    tokio::spawn(async move { expiry_watcher.run_expiry_watcher().await });
+    let balance_syncer = loopout_svc.clone();
+    tokio::spawn(async move { balance_syncer.run_balance_syncer().await });
+
+    let payment_watcher = loopin_svc.clone();
+    tokio::spawn(async move { payment_watcher.run_payment_watcher().await });
+    let claim_sweeper = loopin_svc.clone();
+    tokio::spawn(async move { claim_sweeper.run_claim_sweeper().await });
+
+    let rate_limiter = Arc::new(api::rate_limit::RateLimiter::from_config(&cfg).unwrap());
+
+    // the gRPC API shares the loop out service and rate limits with the REST API, on its own port
+    let grpc_server =
+        api::grpc::LooperGrpcServer::new(&cfg, loopout_svc.clone(), rate_limiter.clone()).unwrap();
+    let grpc_incoming = grpc_server.bind().unwrap();
+    tokio::spawn(async move { grpc_server.serve(grpc_incoming).await });
+
+    let server = api::server::LooperServer::new(loopout_svc, loopin_svc, rate_limiter);
     server.start();
 
     let stdin = io::stdin();
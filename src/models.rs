@@ -1,23 +1,84 @@
-use crate::schema::{invoices, loop_outs, scripts, utxos};
-// use diesel::deserialize::FromSql;
-// use diesel::pg::sql_types::Jsonb;
-// use diesel::pg::Pg;
+use crate::schema::{
+    invoices, liquidity_reservations, loop_ins, loop_out_quotes, loop_out_state_history, loop_outs,
+    scripts, utxos, webhook_deliveries,
+};
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::{Pg, PgValue};
 use diesel::prelude::*;
-// use diesel::serialize::{Output, ToSql};
-// use diesel::sql_types::Array;
-// use diesel::{AsExpression, FromSqlRow};
-
-// pub enum InvoiceState {
-//     Open,
-//     Settled,
-//     Cancelled,
-// }
-
-pub const INVOICE_STATE_OPEN: &str = "OPEN";
-#[allow(dead_code)]
-pub const INVOICE_STATE_SETTLED: &str = "SETTLED";
-#[allow(dead_code)]
-pub const INVOICE_STATE_CANCELLED: &str = "CANCELLED";
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Text;
+use diesel::{AsExpression, FromSqlRow};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+// impl_text_state stores a state enum as its as_str() name in a TEXT column.
+macro_rules! impl_text_state {
+    ($state:ty) => {
+        impl ToSql<Text, Pg> for $state {
+            fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+                out.write_all(self.as_str().as_bytes())?;
+                Ok(IsNull::No)
+            }
+        }
+
+        impl FromSql<Text, Pg> for $state {
+            fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+                let state = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+                Ok(state.parse()?)
+            }
+        }
+
+        impl fmt::Display for $state {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+    };
+}
+
+/// InvoiceState is the state of a swap or prepay invoice, as last reported by LND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+pub enum InvoiceState {
+    Open,
+    /// Accepted is only used by hold invoices, once the payment is held by our node.
+    Accepted,
+    Settled,
+    Cancelled,
+}
+
+impl InvoiceState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InvoiceState::Open => "OPEN",
+            InvoiceState::Accepted => "ACCEPTED",
+            InvoiceState::Settled => "SETTLED",
+            InvoiceState::Cancelled => "CANCELLED",
+        }
+    }
+}
+
+impl FromStr for InvoiceState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "OPEN" => Ok(InvoiceState::Open),
+            "ACCEPTED" => Ok(InvoiceState::Accepted),
+            "SETTLED" => Ok(InvoiceState::Settled),
+            "CANCELLED" => Ok(InvoiceState::Cancelled),
+            _ => Err(format!("unknown invoice state: {}", s)),
+        }
+    }
+}
+
+impl_text_state!(InvoiceState);
+
+/// INVOICE_KIND_SWAP is the invoice paying for the swap itself.
+pub const INVOICE_KIND_SWAP: &str = "SWAP";
+/// INVOICE_KIND_PREPAY is the small invoice that must be paid before the HTLC is funded. It is kept if the swap expires or times out.
+pub const INVOICE_KIND_PREPAY: &str = "PREPAY";
 
 #[derive(Insertable, Clone)]
 #[diesel(belongs_to(LoopOut))]
@@ -28,7 +89,9 @@ pub struct NewInvoice<'a> {
     pub payment_hash: &'a str,
     pub payment_preimage: Option<&'a str>,
     pub amount: i64,
-    pub state: String,
+    pub state: InvoiceState,
+    pub is_hold: bool,
+    pub kind: String,
 }
 
 #[derive(Debug, Queryable, AsChangeset)]
@@ -40,9 +103,12 @@ pub struct Invoice {
     pub payment_hash: String,
     pub payment_preimage: Option<String>,
     pub amount: i64,
-    pub state: String,
+    pub state: InvoiceState,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    pub settle_index: Option<i64>,
+    pub is_hold: bool,
+    pub kind: String,
 }
 
 // Scripts
@@ -51,7 +117,7 @@ pub struct Invoice {
 #[diesel(belongs_to(LoopOut))]
 #[diesel(table_name = scripts)]
 pub struct NewScript<'a> {
-    pub loop_out_id: i64,
+    pub loop_out_id: Option<i64>,
     pub address: &'a str,
     pub external_tapkey: &'a str,
     pub internal_tapkey: &'a str,
@@ -62,6 +128,10 @@ pub struct NewScript<'a> {
     pub remote_pubkey: String,
     pub local_pubkey: String,
     pub local_pubkey_index: i32,
+    // is_musig is set when the internal key is the MuSig2 aggregate of remote_pubkey and local_pubkey
+    pub is_musig: bool,
+    // a script belongs to either a loop out or a loop in
+    pub loop_in_id: Option<i64>,
 }
 
 #[derive(Debug, Queryable, AsChangeset)]
@@ -83,6 +153,8 @@ pub struct Script {
     pub local_pubkey_index: i32,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    pub is_musig: bool,
+    pub loop_in_id: Option<i64>,
 }
 
 // UTXOs
@@ -95,6 +167,8 @@ pub struct NewUTXO<'a> {
     pub vout: i32,
     pub amount: i64,
     pub script_id: i64,
+    // raw_tx is the hex encoded funding tx, only set for HTLCs the server funds itself
+    pub raw_tx: Option<&'a str>,
 }
 
 #[derive(Debug, Queryable, Associations, AsChangeset)]
@@ -108,41 +182,388 @@ pub struct Utxo {
     pub script_id: i64,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    pub raw_tx: Option<String>,
+    // replaced_txids are the funding txs txid replaced through RBF, oldest first
+    pub replaced_txids: Vec<Option<String>>,
 }
 
 // Loop Outs
 
-/// LOOP_OUT_STATE_INITIATED should be set when the server has registered the loop out and returned the swap invoice.
-pub const LOOP_OUT_STATE_INITIATED: &str = "INITIATED";
-/// LOOP_OUT_STATE_CONFIRMED should be set when the funding transaction is confirmed onchain.
-#[allow(dead_code)]
-pub const LOOP_OUT_STATE_CONFIRMED: &str = "CONFIRMED";
-/// LOOP_OUT_STATE_CLAIMED should be set when the server has seen the claim transaction in the mempool or confirmed onchain.
-#[allow(dead_code)]
-pub const LOOP_OUT_STATE_CLAIMED: &str = "CLAIMED";
-/// LOOP_OUT_STATE_TIMEOUT should be set when the server has broadcast the timeout spend back to the server's wallet.
-#[allow(dead_code)]
-pub const LOOP_OUT_STATE_TIMEOUT: &str = "TIMEOUT";
+/// LoopOutState is where a loop out is in its lifecycle. Every change is checked with transition and recorded in
+/// loop_out_state_history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+pub enum LoopOutState {
+    /// AwaitingPayment is set when the server is waiting for the prepay invoice to be paid or the hold invoice payment
+    /// to be accepted before funding the HTLC. Batched loop outs also wait here for the next batch.
+    AwaitingPayment,
+    /// Initiated is set when the server has funded the HTLC and returned the swap invoice.
+    Initiated,
+    /// Confirmed is set when the funding transaction is confirmed onchain.
+    Confirmed,
+    /// Claimed is set when the server has seen the claim transaction in the mempool or confirmed onchain.
+    Claimed,
+    /// Timeout is set when the server has broadcast the timeout spend back to the server's wallet.
+    Timeout,
+    /// Expired is set when the swap invoice expired or was cancelled unpaid. A funded HTLC is still swept back once it
+    /// times out, which only sets timeout_txid.
+    Expired,
+}
+
+impl LoopOutState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoopOutState::AwaitingPayment => "AWAITING_PAYMENT",
+            LoopOutState::Initiated => "INITIATED",
+            LoopOutState::Confirmed => "CONFIRMED",
+            LoopOutState::Claimed => "CLAIMED",
+            LoopOutState::Timeout => "TIMEOUT",
+            LoopOutState::Expired => "EXPIRED",
+        }
+    }
+
+    /// can_transition_to returns whether a loop out may move from this state to next. Claimed is final. Timeout goes
+    /// back to Confirmed if the sweep was dropped, or to Claimed if the buyer's claim beat the unconfirmed sweep.
+    /// Expired only moves to Claimed if the buyer claims a funded HTLC.
+    pub fn can_transition_to(&self, next: LoopOutState) -> bool {
+        use LoopOutState::*;
+
+        matches!(
+            (self, next),
+            (AwaitingPayment, Initiated)
+                | (AwaitingPayment, Expired)
+                | (Initiated, Confirmed)
+                | (Initiated, Claimed)
+                | (Initiated, Timeout)
+                | (Initiated, Expired)
+                | (Confirmed, Claimed)
+                | (Confirmed, Timeout)
+                | (Confirmed, Expired)
+                | (Timeout, Confirmed)
+                | (Timeout, Claimed)
+                | (Expired, Claimed)
+        )
+    }
+
+    /// transition returns next if a loop out may move to it from this state.
+    pub fn transition(self, next: LoopOutState) -> Result<LoopOutState, InvalidStateTransition> {
+        match self.can_transition_to(next) {
+            true => Ok(next),
+            false => Err(InvalidStateTransition {
+                from: self,
+                to: next,
+            }),
+        }
+    }
+}
+
+impl FromStr for LoopOutState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "AWAITING_PAYMENT" => Ok(LoopOutState::AwaitingPayment),
+            "INITIATED" => Ok(LoopOutState::Initiated),
+            "CONFIRMED" => Ok(LoopOutState::Confirmed),
+            "CLAIMED" => Ok(LoopOutState::Claimed),
+            "TIMEOUT" => Ok(LoopOutState::Timeout),
+            "EXPIRED" => Ok(LoopOutState::Expired),
+            _ => Err(format!("unknown loop out state: {}", s)),
+        }
+    }
+}
+
+impl_text_state!(LoopOutState);
+
+#[derive(Debug)]
+pub struct InvalidStateTransition {
+    pub from: LoopOutState,
+    pub to: LoopOutState,
+}
+
+impl fmt::Display for InvalidStateTransition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid loop out transition from {} to {}",
+            self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for InvalidStateTransition {}
 
 #[derive(Insertable, Clone)]
 #[diesel(table_name = loop_outs)]
 pub struct NewLoopOut {
-    pub state: String,
+    pub state: LoopOutState,
+    // amount is the on-chain amount locked in the HTLC, excluding fees
+    pub amount: i64,
+    // fee is the swap fee charged on top of amount
+    pub fee: i64,
+    // miner_fee and fee_rate (sat/vB) are those of the funding tx, None until the HTLC is funded
+    pub miner_fee: Option<i64>,
+    pub fee_rate: Option<f64>,
+    // batched loop outs are funded together with others in a single funding tx, see loopout.batch_window
+    pub batched: bool,
+    // webhook_url is sent every state change, signed with webhook_secret
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
 }
 
 #[derive(Debug, Queryable, AsChangeset)]
 #[diesel(table_name = loop_outs)]
 pub struct LoopOut {
     pub id: i64,
-    pub state: String,
+    pub state: LoopOutState,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    pub claim_txid: Option<String>,
+    pub timeout_txid: Option<String>,
+    pub amount: i64,
+    pub fee: i64,
+    pub miner_fee: Option<i64>,
+    pub fee_rate: Option<f64>,
+    pub batched: bool,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+    // timeout_confirmed_at is set once the timeout sweep has loopout.confs confirmations and the buyer can no longer
+    // claim the HTLC
+    pub timeout_confirmed_at: Option<chrono::NaiveDateTime>,
 }
 
 #[derive(Debug)]
 pub struct FullLoopOutData {
     pub loop_out: LoopOut,
     pub script: Script,
-    pub utxo: Utxo,
+    // utxo is None until the HTLC is funded
+    pub utxo: Option<Utxo>,
     pub invoice: Invoice,
+    pub prepay_invoice: Option<Invoice>,
+}
+
+// Loop Out State History
+
+#[derive(Insertable)]
+#[diesel(table_name = loop_out_state_history)]
+pub struct NewLoopOutStateChange<'a> {
+    pub loop_out_id: i64,
+    // from_state is None for the state a loop out was created in. It equals to_state for changes that don't move the
+    // loop out, e.g. a replaced funding tx.
+    pub from_state: Option<LoopOutState>,
+    pub to_state: LoopOutState,
+    // reason says why the loop out moved, e.g. which tx funded or spent the HTLC
+    pub reason: &'a str,
+}
+
+#[derive(Debug, Queryable)]
+#[diesel(table_name = loop_out_state_history)]
+pub struct LoopOutStateChange {
+    pub id: i64,
+    pub loop_out_id: i64,
+    pub from_state: Option<LoopOutState>,
+    pub to_state: LoopOutState,
+    pub reason: String,
+    pub created_at: chrono::NaiveDateTime,
+    // notified_at is set once webhook deliveries for the change have been queued
+    pub notified_at: Option<chrono::NaiveDateTime>,
+}
+
+// Webhook Deliveries
+
+/// WebhookDeliveryState is where a webhook delivery is in its retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Text)]
+pub enum WebhookDeliveryState {
+    /// Pending deliveries are sent at next_attempt_at.
+    Pending,
+    Delivered,
+    /// Failed is set once a delivery has used up its attempts.
+    Failed,
+}
+
+impl WebhookDeliveryState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookDeliveryState::Pending => "PENDING",
+            WebhookDeliveryState::Delivered => "DELIVERED",
+            WebhookDeliveryState::Failed => "FAILED",
+        }
+    }
+}
+
+impl FromStr for WebhookDeliveryState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PENDING" => Ok(WebhookDeliveryState::Pending),
+            "DELIVERED" => Ok(WebhookDeliveryState::Delivered),
+            "FAILED" => Ok(WebhookDeliveryState::Failed),
+            _ => Err(format!("unknown webhook delivery state: {}", s)),
+        }
+    }
+}
+
+impl_text_state!(WebhookDeliveryState);
+
+#[derive(Insertable)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewWebhookDelivery {
+    pub state_change_id: i64,
+    pub url: String,
+    // payload is the JSON body. signature is the hex encoded HMAC-SHA256 sent with the last attempt, empty until the
+    // first one.
+    pub payload: String,
+    pub signature: String,
+    pub state: WebhookDeliveryState,
+}
+
+#[derive(Debug, Queryable, AsChangeset)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub state_change_id: i64,
+    pub url: String,
+    pub payload: String,
+    pub signature: String,
+    pub state: WebhookDeliveryState,
+    pub attempts: i32,
+    pub next_attempt_at: chrono::NaiveDateTime,
+    // last_status is the HTTP status of the last attempt, None if the request itself failed
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+// Loop Out Quotes
+
+#[derive(Insertable)]
+#[diesel(table_name = loop_out_quotes)]
+pub struct NewLoopOutQuote<'a> {
+    // quote_id is the random id clients redeem the quote with
+    pub quote_id: &'a str,
+    pub amount: i64,
+    // fee is the swap fee honored when the quote is redeemed
+    pub fee: i64,
+    // miner_fee is the estimated cost of the funding tx at fee_rate (sat/vB)
+    pub miner_fee: i64,
+    pub fee_rate: f64,
+    pub cltv_delta: i64,
+    pub expires_at: chrono::NaiveDateTime,
+    // batched quotes can only be redeemed by batched loop outs
+    pub batched: bool,
+}
+
+#[derive(Debug, Queryable)]
+#[diesel(table_name = loop_out_quotes)]
+pub struct LoopOutQuote {
+    pub id: i64,
+    pub quote_id: String,
+    pub amount: i64,
+    pub fee: i64,
+    pub miner_fee: i64,
+    pub fee_rate: f64,
+    pub cltv_delta: i64,
+    pub expires_at: chrono::NaiveDateTime,
+    // redeemed_at is set once a loop out is requested with the quote, so it can only be used once
+    pub redeemed_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+    pub batched: bool,
+}
+
+// Liquidity Reservations
+
+#[derive(Insertable)]
+#[diesel(table_name = liquidity_reservations)]
+pub struct NewLiquidityReservation {
+    pub loop_out_id: i64,
+    // amount is what the loop out will take from the wallet: the HTLC amount plus the expected funding and sweep fees
+    pub amount: i64,
+}
+
+#[derive(Debug, Queryable)]
+#[diesel(table_name = liquidity_reservations)]
+pub struct LiquidityReservation {
+    pub id: i64,
+    pub loop_out_id: i64,
+    pub amount: i64,
+    // released_at is set once the wallet balance no longer includes the reserved funds
+    pub released_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+// Loop Ins
+
+/// LOOP_IN_STATE_INITIATED should be set when the server has registered the loop in and is waiting for the client to
+/// fund the HTLC.
+pub const LOOP_IN_STATE_INITIATED: &str = "INITIATED";
+/// LOOP_IN_STATE_FUNDED should be set when the HTLC funding is confirmed and the server is paying the client's invoice.
+pub const LOOP_IN_STATE_FUNDED: &str = "FUNDED";
+/// LOOP_IN_STATE_PAID should be set when the client's invoice is paid and the server knows the preimage.
+pub const LOOP_IN_STATE_PAID: &str = "PAID";
+/// LOOP_IN_STATE_CLAIMING should be set when the server has broadcast the claim of the HTLC. The claim is watched,
+/// and broadcast again or replaced if needed, until it's buried.
+pub const LOOP_IN_STATE_CLAIMING: &str = "CLAIMING";
+/// LOOP_IN_STATE_CLAIMED should be set when the claim of the HTLC is buried under loopin.confs confirmations.
+pub const LOOP_IN_STATE_CLAIMED: &str = "CLAIMED";
+/// LOOP_IN_STATE_FAILED should be set when the server won't pay the invoice, e.g. because the HTLC was underfunded or
+/// the payment failed. The client refunds itself through the timeout leaf.
+pub const LOOP_IN_STATE_FAILED: &str = "FAILED";
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = loop_ins)]
+pub struct NewLoopIn<'a> {
+    pub state: String,
+    pub payment_request: &'a str,
+    pub payment_hash: &'a str,
+    // amount is the value of the client's invoice. The client funds the HTLC with amount + fee.
+    pub amount: i64,
+    pub fee: i64,
+}
+
+#[derive(Debug, Queryable, AsChangeset)]
+#[diesel(table_name = loop_ins)]
+pub struct LoopIn {
+    pub id: i64,
+    pub state: String,
+    pub payment_request: String,
+    pub payment_hash: String,
+    pub payment_preimage: Option<String>,
+    pub amount: i64,
+    pub fee: i64,
+    pub claim_txid: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub struct FullLoopInData {
+    pub loop_in: LoopIn,
+    pub script: Script,
+    // utxo is None until the client's HTLC funding is confirmed
+    pub utxo: Option<Utxo>,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_out_state_transitions() {
+        use LoopOutState::*;
+
+        assert!(Initiated.can_transition_to(Timeout));
+        // the buyer's claim can beat an unconfirmed timeout sweep
+        assert_eq!(Claimed, Timeout.transition(Claimed).unwrap());
+        assert_eq!(Confirmed, Timeout.transition(Confirmed).unwrap());
+
+        let err = Claimed.transition(Timeout).unwrap_err();
+        assert_eq!(Claimed, err.from);
+        assert_eq!(Timeout, err.to);
+        assert!(!Timeout.can_transition_to(Expired));
+        assert!(!Expired.can_transition_to(AwaitingPayment));
+    }
 }
@@ -0,0 +1,638 @@
+// A minimal BIP327 (MuSig2) implementation covering what looper needs for cooperative key-path spends: key
+// aggregation with a taproot tweak, nonce generation and aggregation, partial signing and signature aggregation.
+//
+// Keys are exchanged x-only, like everywhere else in looper, and are lifted to their even-y point before being
+// aggregated. Signers holding a key with an odd y negate their secret key when signing.
+
+use bdk::bitcoin::{
+    hashes::{sha256, Hash, HashEngine},
+    key::TapTweak,
+    secp256k1::{
+        self, rand::thread_rng, schnorr, KeyPair, Parity, PublicKey, Secp256k1, SecretKey,
+        XOnlyPublicKey,
+    },
+    taproot::TapNodeHash,
+};
+
+const PUBNONCE_LEN: usize = 66;
+// the secp256k1 group order
+const CURVE_ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+
+/// KeyAggContext is the aggregate of a set of public keys, along with the tweaks applied to it.
+#[derive(Clone, Debug)]
+pub struct KeyAggContext {
+    pubkeys: Vec<PublicKey>,
+    q: PublicKey,
+    // gacc is -1 when an odd number of tweaks negated the aggregate key
+    gacc_negated: bool,
+    tacc: ModN,
+}
+
+impl KeyAggContext {
+    /// new aggregates pubkeys. Every signer must pass the keys in the same order; use sort_pubkeys to agree on one.
+    pub fn new(pubkeys: Vec<XOnlyPublicKey>) -> Result<Self, MusigError> {
+        if pubkeys.is_empty() {
+            return Err(MusigError::new("no pubkeys to aggregate".to_string()));
+        }
+
+        Self::from_pubkeys(
+            pubkeys
+                .iter()
+                .map(|pk| pk.public_key(Parity::Even))
+                .collect(),
+        )
+    }
+
+    fn from_pubkeys(pubkeys: Vec<PublicKey>) -> Result<Self, MusigError> {
+        let secp256k1 = Secp256k1::new();
+
+        let mut q: Option<PublicKey> = None;
+        for pk in &pubkeys {
+            let coef = key_agg_coeff(&pubkeys, pk);
+            q = point_add(q, point_mul(&secp256k1, pk, &coef)?);
+        }
+        let q = q.ok_or_else(|| MusigError::new("aggregate key is infinity".to_string()))?;
+
+        Ok(Self {
+            pubkeys,
+            q,
+            gacc_negated: false,
+            tacc: ModN::zero(),
+        })
+    }
+
+    /// with_taproot_tweak applies the BIP341 tweak for merkle_root, so that agg_pubkey is the taproot output key
+    /// committing to the script tree.
+    pub fn with_taproot_tweak(self, merkle_root: Option<TapNodeHash>) -> Result<Self, MusigError> {
+        let secp256k1 = Secp256k1::new();
+        let internal_key = self.agg_pubkey();
+        let (output_key, _) = internal_key.tap_tweak(&secp256k1, merkle_root);
+
+        let mut eng = sha256::Hash::engine();
+        let tag = sha256::Hash::hash(b"TapTweak");
+        eng.input(tag.as_ref());
+        eng.input(tag.as_ref());
+        eng.input(&internal_key.serialize());
+        if let Some(merkle_root) = merkle_root {
+            eng.input(merkle_root.as_ref());
+        }
+        let tweak = ModN::from_bytes(sha256::Hash::from_engine(eng).to_byte_array())?;
+
+        let ctx = self.with_xonly_tweak(&tweak)?;
+        if ctx.agg_pubkey() != output_key.to_inner() {
+            return Err(MusigError::new("taproot tweak mismatch".to_string()));
+        }
+
+        Ok(ctx)
+    }
+
+    fn with_xonly_tweak(self, tweak: &ModN) -> Result<Self, MusigError> {
+        let secp256k1 = Secp256k1::new();
+        let odd = has_odd_y(&self.q);
+        // Q' = g*Q + t*G
+        let q = match odd {
+            true => self.q.negate(&secp256k1),
+            false => self.q,
+        };
+        let q = point_add(Some(q), point_mul_g(&secp256k1, tweak))
+            .ok_or_else(|| MusigError::new("tweaked key is infinity".to_string()))?;
+        // tacc' = t + g*tacc
+        let tacc = match odd {
+            true => tweak.add(&self.tacc.negate()),
+            false => tweak.add(&self.tacc),
+        };
+
+        Ok(Self {
+            pubkeys: self.pubkeys,
+            q,
+            gacc_negated: self.gacc_negated ^ odd,
+            tacc,
+        })
+    }
+
+    pub fn agg_pubkey(&self) -> XOnlyPublicKey {
+        self.q.x_only_public_key().0
+    }
+}
+
+/// sort_pubkeys returns pubkeys in the order defined by BIP327's KeySort.
+pub fn sort_pubkeys(mut pubkeys: Vec<XOnlyPublicKey>) -> Vec<XOnlyPublicKey> {
+    pubkeys.sort_by_key(|pk| pk.serialize());
+    pubkeys
+}
+
+/// SecNonce is a signer's secret nonce. It must only ever be used for a single signature, so it can't be cloned or
+/// serialized.
+pub struct SecNonce {
+    k1: ModN,
+    k2: ModN,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PubNonce {
+    r1: PublicKey,
+    r2: PublicKey,
+}
+
+impl PubNonce {
+    pub fn serialize(&self) -> [u8; PUBNONCE_LEN] {
+        let mut bytes = [0u8; PUBNONCE_LEN];
+        bytes[..33].copy_from_slice(&self.r1.serialize());
+        bytes[33..].copy_from_slice(&self.r2.serialize());
+        bytes
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, MusigError> {
+        if bytes.len() != PUBNONCE_LEN {
+            return Err(MusigError::new(format!(
+                "invalid pubnonce length {}",
+                bytes.len()
+            )));
+        }
+        let r1 = PublicKey::from_slice(&bytes[..33])
+            .map_err(|e| MusigError::new(format!("invalid pubnonce: {:?}", e)))?;
+        let r2 = PublicKey::from_slice(&bytes[33..])
+            .map_err(|e| MusigError::new(format!("invalid pubnonce: {:?}", e)))?;
+
+        Ok(Self { r1, r2 })
+    }
+}
+
+/// new_nonce generates a fresh random nonce pair.
+pub fn new_nonce() -> (SecNonce, PubNonce) {
+    let secp256k1 = Secp256k1::new();
+    let mut rng = thread_rng();
+    let k1 = SecretKey::new(&mut rng);
+    let k2 = SecretKey::new(&mut rng);
+    let pubnonce = PubNonce {
+        r1: PublicKey::from_secret_key(&secp256k1, &k1),
+        r2: PublicKey::from_secret_key(&secp256k1, &k2),
+    };
+
+    (
+        SecNonce {
+            k1: ModN(Some(k1)),
+            k2: ModN(Some(k2)),
+        },
+        pubnonce,
+    )
+}
+
+/// AggNonce is the sum of every signer's PubNonce.
+#[derive(Clone, Copy, Debug)]
+pub struct AggNonce {
+    r1: Option<PublicKey>,
+    r2: Option<PublicKey>,
+}
+
+impl AggNonce {
+    pub fn new(pubnonces: &[PubNonce]) -> Self {
+        let mut r1 = None;
+        let mut r2 = None;
+        for pubnonce in pubnonces {
+            r1 = point_add(r1, Some(pubnonce.r1));
+            r2 = point_add(r2, Some(pubnonce.r2));
+        }
+
+        Self { r1, r2 }
+    }
+
+    fn serialize(&self) -> [u8; PUBNONCE_LEN] {
+        // infinity is encoded as 33 zero bytes
+        let mut bytes = [0u8; PUBNONCE_LEN];
+        if let Some(r1) = self.r1 {
+            bytes[..33].copy_from_slice(&r1.serialize());
+        }
+        if let Some(r2) = self.r2 {
+            bytes[33..].copy_from_slice(&r2.serialize());
+        }
+        bytes
+    }
+}
+
+// SessionValues are the values every signer derives from the aggregate nonce and the message.
+struct SessionValues {
+    b: ModN,
+    r: PublicKey,
+    e: ModN,
+}
+
+fn session_values(
+    ctx: &KeyAggContext,
+    aggnonce: &AggNonce,
+    msg: &[u8; 32],
+) -> Result<SessionValues, MusigError> {
+    let secp256k1 = Secp256k1::new();
+    let agg_pubkey = ctx.agg_pubkey().serialize();
+
+    let b = ModN::from_bytes(tagged_hash(
+        "MuSig/noncecoef",
+        &[&aggnonce.serialize(), &agg_pubkey, msg],
+    ))?;
+    let r2b = match aggnonce.r2 {
+        Some(r2) => point_mul(&secp256k1, &r2, &b)?,
+        None => None,
+    };
+    // an infinite R is replaced by G
+    let r = match point_add(aggnonce.r1, r2b) {
+        Some(r) => r,
+        None => PublicKey::from_secret_key(&secp256k1, &ModN::one().secret_key()?),
+    };
+    let e = ModN::from_bytes(tagged_hash(
+        "BIP0340/challenge",
+        &[&r.x_only_public_key().0.serialize(), &agg_pubkey, msg],
+    ))?;
+
+    Ok(SessionValues { b, r, e })
+}
+
+/// PartialSig is one signer's share of the final signature.
+#[derive(Clone, Copy, Debug)]
+pub struct PartialSig(ModN);
+
+impl PartialSig {
+    pub fn serialize(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, MusigError> {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| MusigError::new(format!("invalid partial sig length {}", bytes.len())))?;
+        if bytes >= CURVE_ORDER {
+            return Err(MusigError::new("partial sig out of range".to_string()));
+        }
+
+        Ok(Self(ModN::from_bytes(bytes)?))
+    }
+}
+
+/// sign produces keypair's partial signature over msg. secnonce is consumed so it can't be reused.
+pub fn sign(
+    secnonce: SecNonce,
+    keypair: &KeyPair,
+    ctx: &KeyAggContext,
+    aggnonce: &AggNonce,
+    msg: &[u8; 32],
+) -> Result<PartialSig, MusigError> {
+    let session = session_values(ctx, aggnonce, msg)?;
+
+    // the signer's key is either in the aggregate as is, or as the even-y lift of its x-only key, in which case d' is
+    // the secret key of the lifted point
+    let mut d = ModN(Some(keypair.secret_key()));
+    let pk = keypair.public_key();
+    let pk = match ctx.pubkeys.contains(&pk) {
+        true => pk,
+        false => {
+            let (xonly, parity) = keypair.x_only_public_key();
+            let lifted = xonly.public_key(Parity::Even);
+            if !ctx.pubkeys.contains(&lifted) {
+                return Err(MusigError::new("signer key not in aggregate".to_string()));
+            }
+            if parity == Parity::Odd {
+                d = d.negate();
+            }
+            lifted
+        }
+    };
+    let a = key_agg_coeff(&ctx.pubkeys, &pk);
+
+    // d = g * gacc * d'
+    if has_odd_y(&ctx.q) ^ ctx.gacc_negated {
+        d = d.negate();
+    }
+
+    let (mut k1, mut k2) = (secnonce.k1, secnonce.k2);
+    if has_odd_y(&session.r) {
+        k1 = k1.negate();
+        k2 = k2.negate();
+    }
+
+    // s = k1 + b*k2 + e*a*d
+    let s = k1.add(&session.b.mul(&k2)).add(&session.e.mul(&a).mul(&d));
+
+    Ok(PartialSig(s))
+}
+
+/// aggregate_partial_sigs combines every signer's partial signature into a BIP340 signature for ctx.agg_pubkey().
+pub fn aggregate_partial_sigs(
+    psigs: &[PartialSig],
+    ctx: &KeyAggContext,
+    aggnonce: &AggNonce,
+    msg: &[u8; 32],
+) -> Result<schnorr::Signature, MusigError> {
+    let session = session_values(ctx, aggnonce, msg)?;
+
+    // s = sum(s_i) + e*g*tacc
+    let mut s = psigs.iter().fold(ModN::zero(), |s, psig| s.add(&psig.0));
+    let etacc = session.e.mul(&ctx.tacc);
+    s = match has_odd_y(&ctx.q) {
+        true => s.add(&etacc.negate()),
+        false => s.add(&etacc),
+    };
+
+    let mut sig = [0u8; 64];
+    sig[..32].copy_from_slice(&session.r.x_only_public_key().0.serialize());
+    sig[32..].copy_from_slice(&s.to_bytes());
+
+    schnorr::Signature::from_slice(&sig)
+        .map_err(|e| MusigError::new(format!("invalid signature: {:?}", e)))
+}
+
+fn key_agg_coeff(pubkeys: &[PublicKey], pk: &PublicKey) -> ModN {
+    // the second distinct key gets a coefficient of 1
+    if pubkeys.iter().find(|p| *p != &pubkeys[0]) == Some(pk) {
+        return ModN::one();
+    }
+
+    let serialized: Vec<u8> = pubkeys.iter().flat_map(|p| p.serialize()).collect();
+    let list_hash = tagged_hash("KeyAgg list", &[&serialized]);
+
+    // a hash that isn't a valid scalar happens with negligible probability
+    ModN::from_bytes(tagged_hash(
+        "KeyAgg coefficient",
+        &[&list_hash, &pk.serialize()],
+    ))
+    .expect("key agg coefficient out of range")
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut eng = sha256::Hash::engine();
+    eng.input(tag.as_ref());
+    eng.input(tag.as_ref());
+    for d in data {
+        eng.input(d);
+    }
+
+    sha256::Hash::from_engine(eng).to_byte_array()
+}
+
+fn has_odd_y(pk: &PublicKey) -> bool {
+    pk.x_only_public_key().1 == Parity::Odd
+}
+
+// point_add adds two points, using None for the point at infinity.
+fn point_add(a: Option<PublicKey>, b: Option<PublicKey>) -> Option<PublicKey> {
+    match (a, b) {
+        (Some(a), Some(b)) => a.combine(&b).ok(),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+fn point_mul<C: secp256k1::Verification>(
+    secp256k1: &Secp256k1<C>,
+    pk: &PublicKey,
+    scalar: &ModN,
+) -> Result<Option<PublicKey>, MusigError> {
+    match scalar.0 {
+        Some(sk) => pk
+            .mul_tweak(secp256k1, &sk.into())
+            .map(Some)
+            .map_err(|e| MusigError::new(format!("failed to multiply point: {:?}", e))),
+        None => Ok(None),
+    }
+}
+
+fn point_mul_g<C: secp256k1::Signing>(
+    secp256k1: &Secp256k1<C>,
+    scalar: &ModN,
+) -> Option<PublicKey> {
+    scalar
+        .0
+        .map(|sk| PublicKey::from_secret_key(secp256k1, &sk))
+}
+
+// ModN is a scalar modulo the curve order. SecretKey does the arithmetic but can't be zero, so zero is None.
+#[derive(Clone, Copy, Debug)]
+struct ModN(Option<SecretKey>);
+
+impl ModN {
+    fn zero() -> Self {
+        Self(None)
+    }
+
+    fn one() -> Self {
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        Self(Some(
+            SecretKey::from_slice(&one).expect("one is a valid scalar"),
+        ))
+    }
+
+    // from_bytes interprets bytes as a big endian integer modulo the curve order.
+    fn from_bytes(mut bytes: [u8; 32]) -> Result<Self, MusigError> {
+        if bytes >= CURVE_ORDER {
+            // bytes < 2^256 < 2n, so one subtraction is enough
+            let mut borrow = 0i16;
+            for i in (0..32).rev() {
+                let diff = bytes[i] as i16 - CURVE_ORDER[i] as i16 - borrow;
+                borrow = (diff < 0) as i16;
+                bytes[i] = diff.rem_euclid(256) as u8;
+            }
+        }
+        if bytes == [0u8; 32] {
+            return Ok(Self::zero());
+        }
+
+        SecretKey::from_slice(&bytes)
+            .map(|sk| Self(Some(sk)))
+            .map_err(|e| MusigError::new(format!("invalid scalar: {:?}", e)))
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        match self.0 {
+            Some(sk) => sk.secret_bytes(),
+            None => [0u8; 32],
+        }
+    }
+
+    fn secret_key(&self) -> Result<SecretKey, MusigError> {
+        self.0
+            .ok_or_else(|| MusigError::new("scalar is zero".to_string()))
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        match (self.0, other.0) {
+            // the sum is only invalid when it is zero
+            (Some(a), Some(b)) => Self(a.add_tweak(&b.into()).ok()),
+            (a, None) => Self(a),
+            (None, b) => Self(b),
+        }
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        match (self.0, other.0) {
+            // the product of two non-zero scalars modulo a prime is non-zero
+            (Some(a), Some(b)) => Self(Some(a.mul_tweak(&b.into()).expect("non-zero product"))),
+            _ => Self::zero(),
+        }
+    }
+
+    fn negate(&self) -> Self {
+        Self(self.0.map(|sk| sk.negate()))
+    }
+}
+
+#[derive(Debug)]
+pub struct MusigError {
+    pub message: String,
+}
+
+impl MusigError {
+    pub fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use bdk::bitcoin::{hashes::hex::FromHex, secp256k1::Message};
+    use std::str::FromStr;
+
+    fn test_keypair(secret: u8) -> KeyPair {
+        let secp256k1 = Secp256k1::new();
+        KeyPair::from_seckey_slice(&secp256k1, &[secret; 32]).unwrap()
+    }
+
+    fn sign_2_of_2(
+        ctx: &KeyAggContext,
+        keypairs: &[KeyPair; 2],
+        msg: &[u8; 32],
+    ) -> schnorr::Signature {
+        let (secnonce_a, pubnonce_a) = new_nonce();
+        let (secnonce_b, pubnonce_b) = new_nonce();
+        // pubnonces survive a round trip through the wire format
+        let pubnonce_b = PubNonce::from_slice(&pubnonce_b.serialize()).unwrap();
+        let aggnonce = AggNonce::new(&[pubnonce_a, pubnonce_b]);
+
+        let psig_a = sign(secnonce_a, &keypairs[0], ctx, &aggnonce, msg).unwrap();
+        let psig_b = sign(secnonce_b, &keypairs[1], ctx, &aggnonce, msg).unwrap();
+        let psig_b = PartialSig::from_slice(&psig_b.serialize()).unwrap();
+
+        aggregate_partial_sigs(&[psig_a, psig_b], ctx, &aggnonce, msg).unwrap()
+    }
+
+    #[test]
+    fn test_aggregate_signature_verifies() {
+        let secp256k1 = Secp256k1::new();
+        let msg = [9u8; 32];
+
+        // cover every combination of key parities
+        for secrets in [[1u8, 2u8], [3u8, 4u8], [5u8, 6u8], [7u8, 8u8]] {
+            let keypairs = [test_keypair(secrets[0]), test_keypair(secrets[1])];
+            let pubkeys =
+                sort_pubkeys(keypairs.iter().map(|kp| kp.x_only_public_key().0).collect());
+            let ctx = KeyAggContext::new(pubkeys).unwrap();
+
+            let sig = sign_2_of_2(&ctx, &keypairs, &msg);
+            let msg = Message::from_slice(&msg).unwrap();
+            assert!(secp256k1
+                .verify_schnorr(&sig, &msg, &ctx.agg_pubkey())
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn test_taproot_tweaked_signature_verifies() {
+        let secp256k1 = Secp256k1::new();
+        let msg = [9u8; 32];
+        let merkle_root = TapNodeHash::from_byte_array([3u8; 32]);
+
+        for secrets in [[1u8, 2u8], [3u8, 4u8], [5u8, 6u8], [7u8, 8u8]] {
+            let keypairs = [test_keypair(secrets[0]), test_keypair(secrets[1])];
+            let pubkeys =
+                sort_pubkeys(keypairs.iter().map(|kp| kp.x_only_public_key().0).collect());
+            let internal_key = KeyAggContext::new(pubkeys.clone()).unwrap().agg_pubkey();
+            let ctx = KeyAggContext::new(pubkeys)
+                .unwrap()
+                .with_taproot_tweak(Some(merkle_root))
+                .unwrap();
+            let (output_key, _) = internal_key.tap_tweak(&secp256k1, Some(merkle_root));
+            assert_eq!(output_key.to_inner(), ctx.agg_pubkey());
+
+            let sig = sign_2_of_2(&ctx, &keypairs, &msg);
+            let msg = Message::from_slice(&msg).unwrap();
+            assert!(secp256k1
+                .verify_schnorr(&sig, &msg, &ctx.agg_pubkey())
+                .is_ok());
+        }
+    }
+
+    fn hex_pubkey(hex: &str) -> PublicKey {
+        PublicKey::from_str(hex).unwrap()
+    }
+
+    fn hex_scalar(hex: &str) -> ModN {
+        ModN::from_bytes(<[u8; 32]>::from_hex(hex).unwrap()).unwrap()
+    }
+
+    // vectors from BIP327's key_agg_vectors.json and sign_verify_vectors.json
+    #[test]
+    fn test_bip327_vectors() {
+        let pubkeys = vec![
+            hex_pubkey("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            hex_pubkey("03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659"),
+            hex_pubkey("023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66"),
+        ];
+        let ctx = KeyAggContext::from_pubkeys(pubkeys).unwrap();
+        assert_eq!(
+            "90539eede565f5d054f32cc0c220126889ed1e5d193baf15aef344fe59d4610c",
+            ctx.agg_pubkey().to_string()
+        );
+
+        let secp256k1 = Secp256k1::new();
+        let keypair = KeyPair::from_seckey_str(
+            &secp256k1,
+            "7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671",
+        )
+        .unwrap();
+        let pubkeys = vec![
+            hex_pubkey("03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9"),
+            hex_pubkey("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            hex_pubkey("02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661"),
+        ];
+        let ctx = KeyAggContext::from_pubkeys(pubkeys).unwrap();
+        let secnonce = SecNonce {
+            k1: hex_scalar("508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61"),
+            k2: hex_scalar("FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7"),
+        };
+        let aggnonce = AggNonce {
+            r1: Some(hex_pubkey(
+                "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61",
+            )),
+            r2: Some(hex_pubkey(
+                "037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9",
+            )),
+        };
+        let msg = <[u8; 32]>::from_hex(
+            "F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF",
+        )
+        .unwrap();
+        let psig = sign(secnonce, &keypair, &ctx, &aggnonce, &msg).unwrap();
+        assert_eq!(
+            <[u8; 32]>::from_hex(
+                "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB"
+            )
+            .unwrap(),
+            psig.serialize()
+        );
+    }
+
+    #[test]
+    fn test_mod_n_reduction() {
+        assert_eq!([0u8; 32], ModN::from_bytes(CURVE_ORDER).unwrap().to_bytes());
+
+        let mut n_plus_one = CURVE_ORDER;
+        n_plus_one[31] += 1;
+        assert_eq!(
+            ModN::one().to_bytes(),
+            ModN::from_bytes(n_plus_one).unwrap().to_bytes()
+        );
+    }
+}
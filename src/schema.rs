@@ -11,6 +11,62 @@ diesel::table! {
         state -> Text,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        settle_index -> Nullable<Int8>,
+        is_hold -> Bool,
+        kind -> Text,
+    }
+}
+
+diesel::table! {
+    liquidity_reservations (id) {
+        id -> Int8,
+        loop_out_id -> Int8,
+        amount -> Int8,
+        released_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    loop_ins (id) {
+        id -> Int8,
+        state -> Text,
+        payment_request -> Text,
+        payment_hash -> Text,
+        payment_preimage -> Nullable<Text>,
+        amount -> Int8,
+        fee -> Int8,
+        claim_txid -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    loop_out_quotes (id) {
+        id -> Int8,
+        quote_id -> Text,
+        amount -> Int8,
+        fee -> Int8,
+        miner_fee -> Int8,
+        fee_rate -> Float8,
+        cltv_delta -> Int8,
+        expires_at -> Timestamp,
+        redeemed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        batched -> Bool,
+    }
+}
+
+diesel::table! {
+    loop_out_state_history (id) {
+        id -> Int8,
+        loop_out_id -> Int8,
+        from_state -> Nullable<Text>,
+        to_state -> Text,
+        reason -> Text,
+        created_at -> Timestamp,
+        notified_at -> Nullable<Timestamp>,
     }
 }
 
@@ -20,6 +76,16 @@ diesel::table! {
         state -> Text,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        claim_txid -> Nullable<Text>,
+        timeout_txid -> Nullable<Text>,
+        amount -> Int8,
+        fee -> Int8,
+        miner_fee -> Nullable<Int8>,
+        fee_rate -> Nullable<Float8>,
+        batched -> Bool,
+        webhook_url -> Nullable<Text>,
+        webhook_secret -> Nullable<Text>,
+        timeout_confirmed_at -> Nullable<Timestamp>,
     }
 }
 
@@ -39,6 +105,8 @@ diesel::table! {
         local_pubkey_index -> Int4,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        is_musig -> Bool,
+        loop_in_id -> Nullable<Int8>,
     }
 }
 
@@ -51,11 +119,45 @@ diesel::table! {
         script_id -> Int8,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        raw_tx -> Nullable<Text>,
+        replaced_txids -> Array<Nullable<Text>>,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int8,
+        state_change_id -> Int8,
+        url -> Text,
+        payload -> Text,
+        signature -> Text,
+        state -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        last_status -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        delivered_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
 diesel::joinable!(invoices -> loop_outs (loop_out_id));
+diesel::joinable!(liquidity_reservations -> loop_outs (loop_out_id));
+diesel::joinable!(loop_out_state_history -> loop_outs (loop_out_id));
+diesel::joinable!(scripts -> loop_ins (loop_in_id));
 diesel::joinable!(scripts -> loop_outs (loop_out_id));
 diesel::joinable!(utxos -> scripts (script_id));
+diesel::joinable!(webhook_deliveries -> loop_out_state_history (state_change_id));
 
-diesel::allow_tables_to_appear_in_same_query!(invoices, loop_outs, scripts, utxos,);
+diesel::allow_tables_to_appear_in_same_query!(
+    invoices,
+    liquidity_reservations,
+    loop_ins,
+    loop_out_quotes,
+    loop_out_state_history,
+    loop_outs,
+    scripts,
+    utxos,
+    webhook_deliveries,
+);
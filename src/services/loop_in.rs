@@ -0,0 +1,1198 @@
+use bdk::bitcoin::{
+    absolute::LockTime,
+    secp256k1::{self, Secp256k1, XOnlyPublicKey},
+    taproot::TaprootSpendInfo,
+    Address, Network, OutPoint, ScriptBuf, Transaction, TxOut, Txid,
+};
+use bdk::FeeRate;
+use fedimint_tonic_lnd::lnrpc::payment::PaymentStatus;
+use std::mem;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::{
+    db::{self, DB},
+    lnd::client::LNDGateway,
+    mempool,
+    models::{self, FullLoopInData, LoopIn, NewLoopIn, NewScript, NewUTXO, Script},
+    services::{self, loop_out},
+    settings, utils,
+    wallet::{HtlcUtxo, LooperWallet, SpendScanner},
+};
+
+// default number of confirmations the client's HTLC needs before we pay the invoice. Override with loopin.confs.
+pub const TARGET_CONFS: u32 = 3;
+
+// default most we pay in routing fees for the client's invoice, in parts per million of its amount. Override with
+// loopin.max_routing_fee_ppm.
+pub const MAX_ROUTING_FEE_PPM: i64 = 5_000;
+
+// how often the workers poll bitcoind.
+const CHAIN_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// blocks we keep between a payment resolving and the HTLC expiring, so we can still claim it before the client can
+// refund itself through the timeout leaf.
+const CLAIM_BUFFER: u32 = 12;
+
+pub struct LoopInConfig {
+    pub min_amount: i64,
+    pub max_amount: i64,
+    // cltv_delta is how many blocks before the UTXO's timelock expires
+    pub cltv_delta: u64,
+    pub fee_pct: i64,
+    // target_confs is how many confirmations the client's HTLC needs before the loop in is FUNDED, and our claim of it
+    // before the loop in is CLAIMED
+    pub target_confs: u32,
+    pub max_routing_fee_ppm: i64,
+}
+
+impl LoopInConfig {
+    /// from_config reads the loopin settings. Settings that are left out use their defaults, but a setting of the
+    /// wrong type is an error.
+    pub fn from_config(cfg: &settings::Config) -> Result<Self, LoopInServiceError> {
+        Ok(Self {
+            min_amount: get_setting(cfg, "loopin.min", 10_000)?,
+            max_amount: get_setting(cfg, "loopin.max", 10_000_000)?,
+            cltv_delta: get_setting(cfg, "loopin.cltv", 144)?,
+            fee_pct: get_setting(cfg, "loopin.fee", 0)?,
+            target_confs: get_setting(cfg, "loopin.confs", TARGET_CONFS)?,
+            max_routing_fee_ppm: get_setting(
+                cfg,
+                "loopin.max_routing_fee_ppm",
+                MAX_ROUTING_FEE_PPM,
+            )?,
+        })
+    }
+}
+
+// get_setting is settings::get_or with the error mapped to a LoopInServiceError.
+fn get_setting<'de, T: serde::Deserialize<'de>>(
+    cfg: &settings::Config,
+    key: &str,
+    default: T,
+) -> Result<T, LoopInServiceError> {
+    settings::get_or(cfg, key, default)
+        .map_err(|e| LoopInServiceError::new(format!("error getting {} from config: {}", key, e)))
+}
+
+pub struct LoopInService {
+    cfg: LoopInConfig,
+    secp256k1: Secp256k1<secp256k1::All>,
+    network: Network,
+    db: DB,
+    // the wallet is shared with the loop out service
+    wallet: Arc<Mutex<LooperWallet>>,
+    // payments block until they resolve, so the loop in service gets its own gateway
+    lnd_gateway: Mutex<LNDGateway>,
+    // scanned_height is the last block check_fundings scanned for HTLC fundings
+    scanned_height: Mutex<u32>,
+    // spends finds the txs spending HTLCs, picking up where its last scan of each HTLC left off
+    spends: Mutex<SpendScanner>,
+}
+
+impl LoopInService {
+    pub fn new(
+        cfg: &settings::Config,
+        db: DB,
+        wallet: Arc<Mutex<LooperWallet>>,
+        lnd_gateway: LNDGateway,
+    ) -> Result<Self, LoopInServiceError> {
+        let loop_in_cfg = LoopInConfig::from_config(cfg)?;
+        let network = LooperWallet::parse_network_from_config(cfg).map_err(|e| {
+            LoopInServiceError::new(format!(
+                "error getting bitcoin.network from config: {:?}",
+                e
+            ))
+        })?;
+
+        Ok(Self {
+            cfg: loop_in_cfg,
+            db,
+            secp256k1: Secp256k1::new(),
+            network,
+            wallet,
+            lnd_gateway: Mutex::new(lnd_gateway),
+            scanned_height: Mutex::new(0),
+            spends: Mutex::new(SpendScanner::default()),
+        })
+    }
+
+    pub fn get_loop_in(&self, payment_hash: String) -> Result<FullLoopInData, LoopInServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        db::get_full_loop_in(conn, payment_hash).map_err(|e| match e {
+            diesel::result::Error::NotFound => {
+                LoopInServiceError::new(services::NOT_FOUND.to_string())
+            }
+            e => LoopInServiceError::new(format!("error getting loop_in from db: {:?}", e)),
+        })
+    }
+
+    // handle_loop_in_request registers a new loop in paying invoice. The client must then fund the returned HTLC
+    // with the invoice amount plus the fee. Once the funding confirms, we pay the invoice and claim the HTLC with
+    // the preimage. See run_payment_watcher and run_claim_sweeper.
+    pub async fn handle_loop_in_request(
+        &self,
+        pubkey: String,
+        invoice: String,
+    ) -> Result<FullLoopInData, LoopInServiceError> {
+        let client_pubkey = XOnlyPublicKey::from_str(&pubkey).map_err(|e| {
+            LoopInServiceError::new(format!("error converting pubkey to XOnlyPublicKey: {}", e))
+        })?;
+
+        let lndg = self.lnd_gateway.lock().await;
+        let pay_req = lndg.decode_invoice(&invoice).await;
+        mem::drop(lndg);
+        let pay_req = pay_req.map_err(Self::invalid_invoice)?;
+
+        let amount = pay_req.num_satoshis;
+        self.validate_amount(amount)
+            .map_err(|e| Self::invalid_invoice(e.message))?;
+        if pay_req.timestamp + pay_req.expiry <= chrono::Utc::now().timestamp() {
+            return Err(Self::invalid_invoice("invoice expired"));
+        }
+        // the payment must be able to reach the client before we stop paying to keep time to claim
+        if pay_req.cltv_expiry as u64 + CLAIM_BUFFER as u64 >= self.cfg.cltv_delta {
+            return Err(Self::invalid_invoice(format!(
+                "invoice cltv {} too high",
+                pay_req.cltv_expiry
+            )));
+        }
+        let fee = self.calculate_loop_in_fee(&amount);
+
+        let conn = &mut self.get_conn()?;
+        let loop_in = db::insert_loop_in(
+            conn,
+            NewLoopIn {
+                state: models::LOOP_IN_STATE_INITIATED.to_string(),
+                payment_request: &invoice,
+                payment_hash: &pay_req.payment_hash,
+                amount,
+                fee,
+            },
+        )
+        .map_err(|e| {
+            LoopInServiceError::new(format!("error inserting loop_in into db: {:?}", e))
+        })?;
+
+        let script = self
+            .add_onchain_htlc(conn, &loop_in.id, &client_pubkey, &pay_req.payment_hash)
+            .await?;
+        log::info!(
+            "waiting for loop in {} htlc {} to be funded",
+            loop_in.id,
+            script.address
+        );
+
+        Ok(db::new_full_loop_in_data(loop_in, script, None))
+    }
+
+    /// run_payment_watcher waits for the HTLC of every INITIATED loop in to be funded and confirmed, then pays the
+    /// client's invoice, moving the loop in to PAID. Underfunded HTLCs and failed payments move it to FAILED. It never
+    /// returns.
+    pub async fn run_payment_watcher(&self) {
+        loop {
+            if let Err(e) = self.check_fundings().await {
+                log::error!("error checking loop in fundings: {:?}", e);
+            }
+            if let Err(e) = self.check_payments().await {
+                log::error!("error paying loop ins: {:?}", e);
+            }
+
+            tokio::time::sleep(CHAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    // check_fundings scans the blocks mined since the last scan for the HTLCs of INITIATED loop ins, moving those whose
+    // funding has target_confs confirmations to FUNDED. The last target_confs blocks are scanned again so a funding is
+    // seen until it's confirmed, even across reorgs. Loop ins whose HTLC isn't funded in time are FAILED.
+    async fn check_fundings(&self) -> Result<(), LoopInServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let loop_ins = db::list_full_loop_ins_in_states(
+            conn,
+            vec![models::LOOP_IN_STATE_INITIATED.to_string()],
+        )
+        .map_err(|e| {
+            LoopInServiceError::new(format!("error listing initiated loop ins: {:?}", e))
+        })?;
+        if loop_ins.is_empty() {
+            return Ok(());
+        }
+
+        let mut scripts = Vec::with_capacity(loop_ins.len());
+        for data in &loop_ins {
+            let address = Address::from_str(&data.script.address)
+                .map_err(|e| {
+                    LoopInServiceError::new(format!(
+                        "error parsing address {}: {:?}",
+                        data.script.address, e
+                    ))
+                })?
+                .require_network(self.network)
+                .map_err(|e| {
+                    LoopInServiceError::new(format!(
+                        "error parsing address {}: {:?}",
+                        data.script.address, e
+                    ))
+                })?;
+            scripts.push(address.script_pubkey());
+        }
+
+        let wallet = self.wallet.lock().await;
+        let curr_height = (*wallet).get_height().map_err(|e| {
+            LoopInServiceError::new(format!("error getting wallet height: {:?}", e))
+        })?;
+        mem::drop(wallet);
+
+        // the HTLCs can't have been funded before we created them
+        let created_height = loop_ins
+            .iter()
+            .map(|data| Self::created_height(data, self.cfg.cltv_delta))
+            .min()
+            .unwrap_or(curr_height);
+        let mut scanned_height = self.scanned_height.lock().await;
+        let from_height = created_height.max(scanned_height.saturating_sub(self.cfg.target_confs));
+
+        let mut fundings: Vec<Option<(OutPoint, TxOut, u32)>> = vec![None; loop_ins.len()];
+        for height in from_height..=curr_height {
+            // the wallet is only locked per block, so a long scan doesn't hold up the other workers
+            let wallet = self.wallet.lock().await;
+            let block = (*wallet).get_block_at(height);
+            mem::drop(wallet);
+            let block = block.map_err(|e| {
+                LoopInServiceError::new(format!("error getting block at {}: {:?}", height, e))
+            })?;
+
+            for (funding, script_pubkey) in fundings.iter_mut().zip(&scripts) {
+                if funding.is_none() {
+                    *funding = LooperWallet::find_output_to_script(&block, script_pubkey)
+                        .map(|(outpoint, txout)| (outpoint, txout, height));
+                }
+            }
+            *scanned_height = height;
+        }
+        mem::drop(scanned_height);
+
+        for (data, funding) in loop_ins.into_iter().zip(fundings) {
+            if let Some((outpoint, txout, height)) = funding {
+                let confs = (curr_height + 1).saturating_sub(height);
+                if confs >= self.cfg.target_confs {
+                    self.record_funding(conn, data, outpoint, txout.value)?;
+                    continue;
+                }
+                log::debug!(
+                    "loop in {} funding {} has {}/{} confirmations",
+                    data.loop_in.id,
+                    outpoint,
+                    confs,
+                    self.cfg.target_confs
+                );
+            }
+
+            if Self::is_funding_expired(&data, curr_height, self.cfg.target_confs) {
+                log::warn!(
+                    "loop in {} htlc {} not funded in time",
+                    data.loop_in.id,
+                    data.script.address
+                );
+                let mut loop_in = data.loop_in;
+                loop_in.state = models::LOOP_IN_STATE_FAILED.to_string();
+                self.update_loop_in(conn, loop_in)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // created_height is the height the loop in's HTLC was created at.
+    fn created_height(data: &FullLoopInData, cltv_delta: u64) -> u32 {
+        (data.script.cltv_expiry as u32).saturating_sub(cltv_delta as u32)
+    }
+
+    // is_funding_expired reports whether it's too late for the HTLC's funding to confirm with enough time left to pay
+    // the invoice and claim it. The client has to refund any later funding through the timeout leaf.
+    fn is_funding_expired(data: &FullLoopInData, curr_height: u32, target_confs: u32) -> bool {
+        curr_height + target_confs + CLAIM_BUFFER >= data.script.cltv_expiry as u32
+    }
+
+    fn record_funding(
+        &self,
+        conn: &mut db::PooledConnection,
+        data: FullLoopInData,
+        outpoint: OutPoint,
+        value: u64,
+    ) -> Result<(), LoopInServiceError> {
+        db::insert_utxo(
+            conn,
+            NewUTXO {
+                txid: &outpoint.txid.to_string(),
+                vout: outpoint.vout as i32,
+                amount: value as i64,
+                script_id: data.script.id,
+                raw_tx: None,
+            },
+        )
+        .map_err(|e| LoopInServiceError::new(format!("error inserting utxo into db: {:?}", e)))?;
+
+        let mut loop_in = data.loop_in;
+        let expected = loop_in.amount + loop_in.fee;
+        loop_in.state = match (value as i64) < expected {
+            true => {
+                log::warn!(
+                    "loop in {} htlc {} underfunded: {} < {}",
+                    loop_in.id,
+                    outpoint,
+                    value,
+                    expected
+                );
+                models::LOOP_IN_STATE_FAILED.to_string()
+            }
+            false => models::LOOP_IN_STATE_FUNDED.to_string(),
+        };
+
+        self.update_loop_in(conn, loop_in)
+    }
+
+    async fn check_payments(&self) -> Result<(), LoopInServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let loop_ins =
+            db::list_full_loop_ins_in_states(conn, vec![models::LOOP_IN_STATE_FUNDED.to_string()])
+                .map_err(|e| {
+                    LoopInServiceError::new(format!("error listing funded loop ins: {:?}", e))
+                })?;
+
+        for data in loop_ins {
+            let mut loop_in = data.loop_in;
+            let preimage = match self
+                .pay_invoice(&loop_in, data.script.cltv_expiry as u32)
+                .await
+            {
+                Ok(preimage) => preimage,
+                Err(e) => {
+                    log::error!("error paying loop in {}: {:?}", loop_in.id, e);
+                    continue;
+                }
+            };
+
+            loop_in.state = match preimage {
+                Some(preimage) => {
+                    loop_in.payment_preimage = Some(hex::encode(preimage));
+                    models::LOOP_IN_STATE_PAID.to_string()
+                }
+                None => models::LOOP_IN_STATE_FAILED.to_string(),
+            };
+            self.update_loop_in(conn, loop_in)?;
+        }
+
+        Ok(())
+    }
+
+    // pay_invoice pays the client's invoice and returns the preimage, or None if the payment failed. A payment made
+    // before a restart is looked up rather than retried.
+    async fn pay_invoice(
+        &self,
+        loop_in: &LoopIn,
+        cltv_expiry: u32,
+    ) -> Result<Option<[u8; 32]>, LoopInServiceError> {
+        let payment_hash = Self::decode_hex32(&loop_in.payment_hash)?;
+
+        let lndg = self.lnd_gateway.lock().await;
+        let payment = lndg.lookup_payment(&payment_hash).await;
+        mem::drop(lndg);
+        let payment = payment
+            .map_err(|e| LoopInServiceError::new(format!("error looking up payment: {:?}", e)))?;
+
+        if let Some(payment) = payment {
+            return match PaymentStatus::from_i32(payment.status) {
+                Some(PaymentStatus::Succeeded) => {
+                    Self::check_preimage(&payment_hash, &hex::decode(&payment.payment_preimage))
+                }
+                Some(PaymentStatus::Failed) => Ok(None),
+                _ => Err(LoopInServiceError::new(format!(
+                    "payment {} still in flight",
+                    loop_in.payment_hash
+                ))),
+            };
+        }
+
+        let wallet = self.wallet.lock().await;
+        let curr_height = (*wallet).get_height().map_err(|e| {
+            LoopInServiceError::new(format!("error getting wallet height: {:?}", e))
+        })?;
+        mem::drop(wallet);
+
+        // the payment can't be held past the point where we'd no longer have time to claim the HTLC
+        let cltv_limit = cltv_expiry.saturating_sub(curr_height + CLAIM_BUFFER);
+        if cltv_limit == 0 {
+            log::warn!(
+                "loop in {} htlc expires at {}, too late to pay",
+                loop_in.id,
+                cltv_expiry
+            );
+            return Ok(None);
+        }
+
+        let fee_limit = Self::routing_fee_limit(loop_in.amount, self.cfg.max_routing_fee_ppm);
+        log::info!(
+            "paying loop in {} invoice with a fee limit of {}...",
+            loop_in.id,
+            fee_limit
+        );
+        let lndg = self.lnd_gateway.lock().await;
+        let resp = lndg
+            .pay_invoice_sync(loop_in.payment_request.clone(), fee_limit, cltv_limit)
+            .await;
+        mem::drop(lndg);
+        let resp =
+            resp.map_err(|e| LoopInServiceError::new(format!("error paying invoice: {:?}", e)))?;
+
+        if !resp.payment_error.is_empty() {
+            log::warn!(
+                "loop in {} payment failed: {}",
+                loop_in.id,
+                resp.payment_error
+            );
+            return Ok(None);
+        }
+
+        Self::check_preimage(&payment_hash, &Ok(resp.payment_preimage))
+    }
+
+    // routing_fee_limit is the most we pay to route amount, max_routing_fee_ppm parts per million of it.
+    fn routing_fee_limit(amount: i64, max_routing_fee_ppm: i64) -> i64 {
+        amount.saturating_mul(max_routing_fee_ppm) / 1_000_000
+    }
+
+    fn check_preimage(
+        payment_hash: &[u8; 32],
+        preimage: &Result<Vec<u8>, hex::FromHexError>,
+    ) -> Result<Option<[u8; 32]>, LoopInServiceError> {
+        let preimage: [u8; 32] = match preimage {
+            Ok(preimage) => preimage.as_slice().try_into().map_err(|e| {
+                LoopInServiceError::new(format!("invalid preimage length: {:?}", e))
+            })?,
+            Err(e) => {
+                return Err(LoopInServiceError::new(format!(
+                    "error decoding preimage: {:?}",
+                    e
+                )))
+            }
+        };
+        if utils::sha256(&preimage) != *payment_hash {
+            return Err(LoopInServiceError::new(
+                "preimage does not match payment hash".to_string(),
+            ));
+        }
+
+        Ok(Some(preimage))
+    }
+
+    /// run_claim_sweeper claims the HTLC of every PAID loop in through the claim leaf, moving it to CLAIMING, and
+    /// follows the claim until it has loopin.confs confirmations, moving the loop in to CLAIMED. We've paid the
+    /// client's invoice by then and the client can refund itself through the timeout leaf once the HTLC expires, so a
+    /// claim that was dropped from the mempool is broadcast again and one paying less than the fee estimate is
+    /// replaced. It never returns.
+    pub async fn run_claim_sweeper(&self) {
+        loop {
+            if let Err(e) = self.check_claims().await {
+                log::error!("error claiming loop ins: {:?}", e);
+            }
+
+            tokio::time::sleep(CHAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn check_claims(&self) -> Result<(), LoopInServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let loop_ins = db::list_full_loop_ins_in_states(
+            conn,
+            vec![
+                models::LOOP_IN_STATE_PAID.to_string(),
+                models::LOOP_IN_STATE_CLAIMING.to_string(),
+            ],
+        )
+        .map_err(|e| LoopInServiceError::new(format!("error listing paid loop ins: {:?}", e)))?;
+        let watched = loop_ins
+            .iter()
+            .filter_map(|data| data.utxo.as_ref())
+            .filter_map(|utxo| {
+                Some(OutPoint {
+                    txid: Txid::from_str(&utxo.txid).ok()?,
+                    vout: utxo.vout as u32,
+                })
+            })
+            .collect();
+        self.spends.lock().await.retain(&watched);
+
+        for data in loop_ins {
+            let loop_in_id = data.loop_in.id;
+            if let Err(e) = self.advance_claim(conn, data).await {
+                log::error!("error claiming loop in {}: {:?}", loop_in_id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // advance_claim takes the next step towards burying the claim of a paid loop in's HTLC, see claim_step.
+    async fn advance_claim(
+        &self,
+        conn: &mut db::PooledConnection,
+        data: FullLoopInData,
+    ) -> Result<(), LoopInServiceError> {
+        let htlc = Self::rebuild_htlc(&data)?;
+        let status = self.find_claim(&data, &htlc).await?;
+
+        let (txid, buried) = match Self::claim_step(&status, self.cfg.target_confs) {
+            ClaimStep::Broadcast => {
+                if let Some(claim_txid) = &data.loop_in.claim_txid {
+                    log::warn!(
+                        "loop in {} claim {} was dropped, claiming htlc {} again",
+                        data.loop_in.id,
+                        claim_txid,
+                        htlc.utxo.outpoint
+                    );
+                }
+                let fee_rate = self.estimate_claim_fee_rate(&data).await?;
+                (self.broadcast_claim(&data, &htlc, &fee_rate).await?, false)
+            }
+            ClaimStep::CheckFee { txid, fee_rate } => {
+                let estimate = self.estimate_claim_fee_rate(&data).await?;
+                match Self::bump_fee_rate(fee_rate, estimate.as_sat_per_vb()) {
+                    Some(bumped) => {
+                        let bumped = FeeRate::from_sat_per_vb(bumped);
+                        let replacement = self.broadcast_claim(&data, &htlc, &bumped).await?;
+                        log::info!(
+                            "replaced loop in {} claim {} paying {} sat/vB with {} at {} sat/vB",
+                            data.loop_in.id,
+                            txid,
+                            fee_rate,
+                            replacement,
+                            bumped.as_sat_per_vb()
+                        );
+                        (replacement, false)
+                    }
+                    None => (txid, false),
+                }
+            }
+            ClaimStep::Wait { txid } => (txid, false),
+            ClaimStep::Bury { txid } => (txid, true),
+            ClaimStep::Refunded { txid } => {
+                log::warn!(
+                    "loop in {} htlc {} was not spent by our claim but by {}",
+                    data.loop_in.id,
+                    htlc.utxo.outpoint,
+                    txid
+                );
+                return Ok(());
+            }
+        };
+
+        let mut loop_in = data.loop_in;
+        if !Self::apply_claim(&mut loop_in, &txid, buried) {
+            return Ok(());
+        }
+        self.update_loop_in(conn, loop_in)
+    }
+
+    // claim_step is what advance_claim does about a claim in status. A claim is broadcast if the HTLC is unspent,
+    // i.e. it was never claimed or the claim was dropped from the mempool. An unconfirmed claim has its fee rate
+    // checked and a confirming one is waited on until it has target_confs confirmations.
+    fn claim_step(status: &ClaimStatus, target_confs: u32) -> ClaimStep {
+        match *status {
+            ClaimStatus::Unclaimed => ClaimStep::Broadcast,
+            // an unconfirmed claim is never buried
+            ClaimStatus::Claimed { txid, confs, .. } if confs >= target_confs.max(1) => {
+                ClaimStep::Bury { txid }
+            }
+            ClaimStatus::Claimed {
+                txid,
+                confs: 0,
+                fee_rate,
+            } => ClaimStep::CheckFee { txid, fee_rate },
+            ClaimStatus::Claimed { txid, .. } => ClaimStep::Wait { txid },
+            ClaimStatus::Refunded { txid } => ClaimStep::Refunded { txid },
+        }
+    }
+
+    // apply_claim points loop_in at its claim txid, moving it to CLAIMED once the claim is buried and to CLAIMING
+    // otherwise. It returns false if there is nothing new to store.
+    fn apply_claim(loop_in: &mut LoopIn, txid: &Txid, buried: bool) -> bool {
+        let state = match buried {
+            true => models::LOOP_IN_STATE_CLAIMED,
+            false => models::LOOP_IN_STATE_CLAIMING,
+        };
+        let claim_txid = Some(txid.to_string());
+        if loop_in.state == state && loop_in.claim_txid == claim_txid {
+            return false;
+        }
+
+        loop_in.state = state.to_string();
+        loop_in.claim_txid = claim_txid;
+        true
+    }
+
+    // bump_fee_rate returns the fee rate, in sat/vB, to replace a claim paying fee_rate with, or None if it pays at
+    // least the estimate.
+    fn bump_fee_rate(fee_rate: f32, estimate: f32) -> Option<f32> {
+        if estimate <= fee_rate {
+            return None;
+        }
+        // a replacement must pay at least the min relay fee rate on top of the tx it replaces
+        Some(estimate.max(fee_rate + 1.0))
+    }
+
+    // claim_fee_priority is how fast a claim of an HTLC expiring at cltv_expiry needs to confirm. Close to the expiry
+    // we'd be racing the client's refund, so the claim pays for the next block.
+    fn claim_fee_priority(curr_height: u32, cltv_expiry: u32) -> mempool::MempoolFeePriority {
+        match curr_height + CLAIM_BUFFER / 2 >= cltv_expiry {
+            true => mempool::MempoolFeePriority::Fastest,
+            false => mempool::MempoolFeePriority::Blocks6,
+        }
+    }
+
+    async fn estimate_claim_fee_rate(
+        &self,
+        data: &FullLoopInData,
+    ) -> Result<FeeRate, LoopInServiceError> {
+        let wallet = self.wallet.lock().await;
+        let curr_height = (*wallet).get_height().map_err(|e| {
+            LoopInServiceError::new(format!("error getting wallet height: {:?}", e))
+        })?;
+        mem::drop(wallet);
+
+        let priority = Self::claim_fee_priority(curr_height, data.script.cltv_expiry as u32);
+        mempool::get_mempool_fee_rate(priority)
+            .await
+            .map_err(|e| LoopInServiceError::new(format!("error estimating fee rate: {:?}", e)))
+    }
+
+    // find_claim reports how the HTLC of a paid loop in has been spent, if at all.
+    async fn find_claim(
+        &self,
+        data: &FullLoopInData,
+        htlc: &LoopInHtlc,
+    ) -> Result<ClaimStatus, LoopInServiceError> {
+        let outpoint = htlc.utxo.outpoint;
+
+        let wallet = self.wallet.lock().await;
+        let spent = (*wallet).is_output_spent(&outpoint).map_err(|e| {
+            LoopInServiceError::new(format!("error checking htlc {}: {:?}", outpoint, e))
+        })?;
+        mem::drop(wallet);
+        if !spent {
+            return Ok(ClaimStatus::Unclaimed);
+        }
+
+        let from_height = Self::created_height(data, self.cfg.cltv_delta);
+        let spending_tx = self
+            .spends
+            .lock()
+            .await
+            .find_spending_tx(&self.wallet, &outpoint, from_height)
+            .await
+            .map_err(|e| {
+                LoopInServiceError::new(format!("error finding htlc {} spend: {:?}", outpoint, e))
+            })?
+            .ok_or_else(|| {
+                LoopInServiceError::new(format!("htlc {} is spent by an unknown tx", outpoint))
+            })?;
+        let txid = spending_tx.txid();
+        let is_claim = spending_tx.input.iter().any(|txin| {
+            txin.previous_output == outpoint
+                && LooperWallet::extract_htlc_preimage(
+                    &txin.witness,
+                    &htlc.htlc_script,
+                    &utils::sha256(&htlc.preimage),
+                )
+                .is_some()
+        });
+        if !is_claim {
+            return Ok(ClaimStatus::Refunded { txid });
+        }
+
+        let wallet = self.wallet.lock().await;
+        let confs = (*wallet).get_tx_confirmations(&txid);
+        mem::drop(wallet);
+        let confs = confs.map_err(|e| {
+            LoopInServiceError::new(format!("error getting confirmations for {}: {:?}", txid, e))
+        })?;
+
+        Ok(ClaimStatus::Claimed {
+            txid,
+            confs,
+            fee_rate: Self::claim_fee_rate(&spending_tx, htlc.utxo.amount),
+        })
+    }
+
+    // claim_fee_rate is the fee rate, in sat/vB, a claim of an HTLC of amount pays.
+    fn claim_fee_rate(tx: &Transaction, amount: u64) -> f32 {
+        let value: u64 = tx.output.iter().map(|txout| txout.value).sum();
+        amount.saturating_sub(value) as f32 / tx.vsize() as f32
+    }
+
+    // broadcast_claim broadcasts a claim of the loop in's HTLC paying fee_rate. It replaces an unconfirmed claim
+    // paying less.
+    async fn broadcast_claim(
+        &self,
+        data: &FullLoopInData,
+        htlc: &LoopInHtlc,
+        fee_rate: &FeeRate,
+    ) -> Result<Txid, LoopInServiceError> {
+        let wallet = self.wallet.lock().await;
+        let tx = (*wallet)
+            .new_claim_sweep(
+                &htlc.utxo,
+                &htlc.htlc_script,
+                &htlc.preimage,
+                data.script.local_pubkey_index as u32,
+                fee_rate,
+            )
+            .map_err(|e| LoopInServiceError::new(format!("error building claim: {:?}", e)))?;
+        let res = (*wallet).broadcast_tx(&tx);
+        mem::drop(wallet);
+
+        res.map_err(|e| LoopInServiceError::new(format!("error broadcasting claim: {:?}", e)))?;
+        log::info!(
+            "claimed htlc {} for loop in {} in {}",
+            htlc.utxo.outpoint,
+            data.loop_in.id,
+            tx.txid()
+        );
+
+        Ok(tx.txid())
+    }
+
+    // rebuild_htlc rebuilds the funded HTLC of a paid loop in along with what we need to claim it.
+    fn rebuild_htlc(data: &FullLoopInData) -> Result<LoopInHtlc, LoopInServiceError> {
+        let utxo = data.utxo.as_ref().ok_or_else(|| {
+            LoopInServiceError::new(format!("loop in {} has no htlc utxo", data.loop_in.id))
+        })?;
+        let outpoint = OutPoint {
+            txid: Txid::from_str(&utxo.txid).map_err(|e| {
+                LoopInServiceError::new(format!("error parsing txid {}: {:?}", utxo.txid, e))
+            })?,
+            vout: utxo.vout as u32,
+        };
+        let preimage = data
+            .loop_in
+            .payment_preimage
+            .as_deref()
+            .ok_or_else(|| {
+                LoopInServiceError::new(format!("loop in {} has no preimage", data.loop_in.id))
+            })
+            .and_then(Self::decode_hex32)?;
+        let (htlc_script, timeout_script) = Self::htlc_scripts(&data.script, &preimage)?;
+
+        let internal_tapkey =
+            XOnlyPublicKey::from_str(&data.script.internal_tapkey).map_err(|e| {
+                LoopInServiceError::new(format!("error parsing internal_tapkey: {:?}", e))
+            })?;
+        let spend_info =
+            LooperWallet::build_taproot(&htlc_script, &timeout_script, internal_tapkey).map_err(
+                |e| LoopInServiceError::new(format!("error rebuilding htlc tree: {:?}", e)),
+            )?;
+
+        Ok(LoopInHtlc {
+            utxo: HtlcUtxo {
+                outpoint,
+                amount: utxo.amount as u64,
+                spend_info,
+            },
+            htlc_script,
+            preimage,
+        })
+    }
+
+    // htlc_scripts rebuilds both leaves of a loop in's HTLC tree. Unlike a loop out, looper claims with the preimage
+    // and the client refunds itself after the timeout.
+    fn htlc_scripts(
+        script: &Script,
+        preimage: &[u8; 32],
+    ) -> Result<(ScriptBuf, ScriptBuf), LoopInServiceError> {
+        let client_pubkey = XOnlyPublicKey::from_str(&script.remote_pubkey).map_err(|e| {
+            LoopInServiceError::new(format!("error parsing remote_pubkey: {:?}", e))
+        })?;
+        let looper_pubkey = XOnlyPublicKey::from_str(&script.local_pubkey)
+            .map_err(|e| LoopInServiceError::new(format!("error parsing local_pubkey: {:?}", e)))?;
+        let locktime = LockTime::from_height(script.cltv_expiry as u32)
+            .map_err(|e| LoopInServiceError::new(format!("error parsing cltv_expiry: {:?}", e)))?;
+
+        Ok((
+            LooperWallet::new_htlc_script(&looper_pubkey, &utils::sha256(preimage)),
+            LooperWallet::new_timeout_script(client_pubkey, locktime),
+        ))
+    }
+
+    async fn add_onchain_htlc(
+        &self,
+        conn: &mut db::PooledConnection,
+        loop_in_id: &i64,
+        client_pubkey: &XOnlyPublicKey,
+        payment_hash: &String,
+    ) -> Result<Script, LoopInServiceError> {
+        let looper_pubkey_idx = db::next_htlc_key_index(conn).map_err(|e| {
+            LoopInServiceError::new(format!("error reserving htlc key index: {:?}", e))
+        })?;
+        let wallet = self.wallet.lock().await;
+        let looper_pubkey = (*wallet).get_pubkey(looper_pubkey_idx).map_err(|e| {
+            LoopInServiceError::new(format!("error generating new pubkey: {:?}", e))
+        })?;
+        let curr_height = (*wallet).get_height().map_err(|e| {
+            LoopInServiceError::new(format!("error getting wallet height: {:?}", e))
+        })?;
+        mem::drop(wallet);
+        let cltv_expiry = curr_height + self.cfg.cltv_delta as u32;
+
+        let payhash_bytes = Self::decode_hex32(payment_hash)?;
+
+        // looper is the claimant here, the client can only take its funds back after the timeout
+        let (tr, tweak) =
+            LooperWallet::new_htlc(looper_pubkey, *client_pubkey, &payhash_bytes, cltv_expiry)
+                .map_err(|e| LoopInServiceError::new(format!("error creating htlc: {:?}", e)))?;
+
+        let address = self.p2tr_address(&tr);
+
+        let new_script = NewScript {
+            loop_out_id: None,
+            address: &address.to_string(),
+            external_tapkey: &tr.output_key().to_string(),
+            internal_tapkey: &tr.internal_key().to_string(),
+            internal_tapkey_tweak: &hex::encode(tweak.secret_bytes()),
+            payment_hash,
+            tree: loop_out::tree_to_vec(&tr),
+            cltv_expiry: cltv_expiry as i32,
+            remote_pubkey: client_pubkey.to_string(),
+            local_pubkey: looper_pubkey.to_string(),
+            local_pubkey_index: looper_pubkey_idx as i32,
+            is_musig: false,
+            loop_in_id: Some(*loop_in_id),
+        };
+
+        db::insert_script(conn, new_script).map_err(|e| {
+            LoopInServiceError::new(format!("error inserting script into db: {:?}", e))
+        })
+    }
+
+    fn update_loop_in(
+        &self,
+        conn: &mut db::PooledConnection,
+        loop_in: LoopIn,
+    ) -> Result<(), LoopInServiceError> {
+        let loop_in_id = loop_in.id;
+        let new_state = loop_in.state.clone();
+        db::update_loop_in(conn, loop_in).map_err(|e| {
+            LoopInServiceError::new(format!(
+                "error updating loop in {} to {}: {:?}",
+                loop_in_id, new_state, e
+            ))
+        })?;
+        log::info!("loop in {} moved to {}", loop_in_id, new_state);
+
+        Ok(())
+    }
+
+    fn decode_hex32(value: &str) -> Result<[u8; 32], LoopInServiceError> {
+        utils::decode_hex32(value)
+            .map_err(|e| LoopInServiceError::new(format!("error decoding {}: {:?}", value, e)))
+    }
+
+    fn get_conn(&self) -> Result<db::PooledConnection, LoopInServiceError> {
+        self.db
+            .get_conn()
+            .map_err(|e| LoopInServiceError::new(format!("error getting db connection: {:?}", e)))
+    }
+
+    fn p2tr_address(&self, tr: &TaprootSpendInfo) -> Address {
+        Address::p2tr(
+            &self.secp256k1,
+            tr.internal_key(),
+            tr.merkle_root(),
+            self.network,
+        )
+    }
+
+    fn calculate_loop_in_fee(&self, amount: &i64) -> i64 {
+        amount * self.cfg.fee_pct / 100
+    }
+
+    // invalid_invoice logs why an invoice was rejected and returns the error the API maps to a bad request.
+    fn invalid_invoice<E: std::fmt::Debug>(e: E) -> LoopInServiceError {
+        log::info!("invalid loop in invoice: {:?}", e);
+        LoopInServiceError::new(services::INVALID_INVOICE.to_string())
+    }
+
+    pub fn validate_amount(&self, amount: i64) -> Result<(), LoopInServiceError> {
+        if amount < self.cfg.min_amount {
+            return Err(LoopInServiceError::new("amount too low".to_string()));
+        }
+
+        if amount > self.cfg.max_amount {
+            return Err(LoopInServiceError::new("amount too high".to_string()));
+        }
+
+        Ok(())
+    }
+
+    pub fn validate_pubkey(&self, pubkey_str: &str) -> Result<(), LoopInServiceError> {
+        match XOnlyPublicKey::from_str(pubkey_str) {
+            Ok(_) => Ok(()),
+
+            Err(e) => Err(LoopInServiceError::new(format!(
+                "invalid pubkey: {:?}",
+                e.to_string()
+            ))),
+        }
+    }
+}
+
+// LoopInHtlc is a funded loop in HTLC, with the leaf and preimage we claim it through.
+struct LoopInHtlc {
+    utxo: HtlcUtxo,
+    htlc_script: ScriptBuf,
+    preimage: [u8; 32],
+}
+
+// ClaimStatus is how the HTLC of a paid loop in has been spent.
+#[derive(Debug, PartialEq)]
+enum ClaimStatus {
+    // the HTLC is unspent, either because it was never claimed or because the claim was dropped from the mempool
+    Unclaimed,
+    // our claim txid has confs confirmations and pays fee_rate sat/vB
+    Claimed {
+        txid: Txid,
+        confs: u32,
+        fee_rate: f32,
+    },
+    // the client refunded itself in txid
+    Refunded {
+        txid: Txid,
+    },
+}
+
+// ClaimStep is what advance_claim does next about a claim, see claim_step.
+#[derive(Debug, PartialEq)]
+enum ClaimStep {
+    Broadcast,
+    CheckFee { txid: Txid, fee_rate: f32 },
+    Wait { txid: Txid },
+    Bury { txid: Txid },
+    Refunded { txid: Txid },
+}
+
+#[derive(Debug)]
+pub struct LoopInServiceError {
+    pub message: String,
+}
+
+impl LoopInServiceError {
+    pub fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_fee_limit() {
+        assert_eq!(500, LoopInService::routing_fee_limit(100_000, 5_000));
+        assert_eq!(0, LoopInService::routing_fee_limit(100, 5_000));
+        assert_eq!(0, LoopInService::routing_fee_limit(100_000, 0));
+    }
+
+    #[test]
+    fn test_loop_in_config_from_config() {
+        let build = |overrides: &[(&str, &str)]| {
+            overrides
+                .iter()
+                .try_fold(settings::Config::builder(), |b, (k, v)| {
+                    b.set_override(*k, *v)
+                })
+                .and_then(|b| b.build())
+                .expect("failed to build config")
+        };
+
+        let cfg = LoopInConfig::from_config(&build(&[])).expect("failed to load defaults");
+        assert_eq!(10_000, cfg.min_amount);
+        assert_eq!(10_000_000, cfg.max_amount);
+        assert_eq!(144, cfg.cltv_delta);
+        assert_eq!(0, cfg.fee_pct);
+        assert_eq!(TARGET_CONFS, cfg.target_confs);
+        assert_eq!(MAX_ROUTING_FEE_PPM, cfg.max_routing_fee_ppm);
+
+        let cfg = LoopInConfig::from_config(&build(&[("loopin.max_routing_fee_ppm", "1000")]))
+            .expect("failed to load loopin.max_routing_fee_ppm");
+        assert_eq!(1_000, cfg.max_routing_fee_ppm);
+
+        // mistyped settings don't fall back to the defaults
+        for key in [
+            "loopin.min",
+            "loopin.max",
+            "loopin.cltv",
+            "loopin.fee",
+            "loopin.confs",
+            "loopin.max_routing_fee_ppm",
+        ] {
+            assert!(LoopInConfig::from_config(&build(&[(key, "a lot")])).is_err());
+        }
+    }
+
+    fn test_loop_in() -> LoopIn {
+        LoopIn {
+            id: 1,
+            state: models::LOOP_IN_STATE_PAID.to_string(),
+            payment_request: String::new(),
+            payment_hash: String::new(),
+            payment_preimage: None,
+            amount: 100_000,
+            fee: 0,
+            claim_txid: None,
+            created_at: chrono::NaiveDateTime::default(),
+            updated_at: chrono::NaiveDateTime::default(),
+        }
+    }
+
+    #[test]
+    fn test_claim_step() {
+        let txid =
+            Txid::from_str("6ad7ee0a6b0c2f7e3b2cb4c7a6b1d8e4d3c1f0b9a8e7d6c5b4a3928170605040")
+                .expect("failed to parse txid");
+        let claimed = |confs| ClaimStatus::Claimed {
+            txid,
+            confs,
+            fee_rate: 2.0,
+        };
+
+        assert_eq!(
+            ClaimStep::Broadcast,
+            LoopInService::claim_step(&ClaimStatus::Unclaimed, 3)
+        );
+        assert_eq!(
+            ClaimStep::CheckFee {
+                txid,
+                fee_rate: 2.0
+            },
+            LoopInService::claim_step(&claimed(0), 3)
+        );
+        assert_eq!(
+            ClaimStep::Wait { txid },
+            LoopInService::claim_step(&claimed(2), 3)
+        );
+        assert_eq!(
+            ClaimStep::Bury { txid },
+            LoopInService::claim_step(&claimed(3), 3)
+        );
+        // an unconfirmed claim is never buried
+        assert_eq!(
+            ClaimStep::CheckFee {
+                txid,
+                fee_rate: 2.0
+            },
+            LoopInService::claim_step(&claimed(0), 0)
+        );
+        assert_eq!(
+            ClaimStep::Bury { txid },
+            LoopInService::claim_step(&claimed(1), 0)
+        );
+        assert_eq!(
+            ClaimStep::Refunded { txid },
+            LoopInService::claim_step(&ClaimStatus::Refunded { txid }, 3)
+        );
+    }
+
+    #[test]
+    fn test_dropped_claim_is_broadcast_again() {
+        let dropped =
+            Txid::from_str("6ad7ee0a6b0c2f7e3b2cb4c7a6b1d8e4d3c1f0b9a8e7d6c5b4a3928170605040")
+                .expect("failed to parse txid");
+        let replacement =
+            Txid::from_str("0405060708192a3b4c5d6e7f8091a2b3c4d5e6f708192a3b4c5d6e7f80910203")
+                .expect("failed to parse txid");
+        let mut loop_in = LoopIn {
+            state: models::LOOP_IN_STATE_CLAIMING.to_string(),
+            claim_txid: Some(dropped.to_string()),
+            ..test_loop_in()
+        };
+
+        // the HTLC is unspent again once the claim is dropped from the mempool
+        assert_eq!(
+            ClaimStep::Broadcast,
+            LoopInService::claim_step(&ClaimStatus::Unclaimed, 3)
+        );
+        assert!(LoopInService::apply_claim(
+            &mut loop_in,
+            &replacement,
+            false
+        ));
+        assert_eq!(models::LOOP_IN_STATE_CLAIMING, loop_in.state);
+        assert_eq!(Some(replacement.to_string()), loop_in.claim_txid);
+    }
+
+    #[test]
+    fn test_apply_claim() {
+        let txid =
+            Txid::from_str("6ad7ee0a6b0c2f7e3b2cb4c7a6b1d8e4d3c1f0b9a8e7d6c5b4a3928170605040")
+                .expect("failed to parse txid");
+        let mut loop_in = LoopIn {
+            state: models::LOOP_IN_STATE_PAID.to_string(),
+            ..test_loop_in()
+        };
+
+        assert!(LoopInService::apply_claim(&mut loop_in, &txid, false));
+        assert_eq!(models::LOOP_IN_STATE_CLAIMING, loop_in.state);
+        assert_eq!(Some(txid.to_string()), loop_in.claim_txid);
+        assert!(!LoopInService::apply_claim(&mut loop_in, &txid, false));
+
+        assert!(LoopInService::apply_claim(&mut loop_in, &txid, true));
+        assert_eq!(models::LOOP_IN_STATE_CLAIMED, loop_in.state);
+        assert!(!LoopInService::apply_claim(&mut loop_in, &txid, true));
+    }
+
+    #[test]
+    fn test_bump_fee_rate() {
+        assert_eq!(None, LoopInService::bump_fee_rate(5.0, 5.0));
+        assert_eq!(None, LoopInService::bump_fee_rate(5.0, 3.0));
+        assert_eq!(Some(10.0), LoopInService::bump_fee_rate(5.0, 10.0));
+        assert_eq!(Some(6.0), LoopInService::bump_fee_rate(5.0, 5.5));
+    }
+
+    #[test]
+    fn test_claim_fee_rate() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: 99_000,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+
+        let fee_rate = LoopInService::claim_fee_rate(&tx, 100_000);
+        assert_eq!(1_000.0 / tx.vsize() as f32, fee_rate);
+        assert_eq!(0.0, LoopInService::claim_fee_rate(&tx, 50_000));
+    }
+
+    #[test]
+    fn test_claim_fee_priority() {
+        assert!(matches!(
+            LoopInService::claim_fee_priority(100, 200),
+            mempool::MempoolFeePriority::Blocks6
+        ));
+        assert!(matches!(
+            LoopInService::claim_fee_priority(200 - CLAIM_BUFFER / 2, 200),
+            mempool::MempoolFeePriority::Fastest
+        ));
+        assert!(matches!(
+            LoopInService::claim_fee_priority(250, 200),
+            mempool::MempoolFeePriority::Fastest
+        ));
+    }
+}
@@ -1,9 +1,18 @@
-use bdk::bitcoin::{
-    secp256k1::{self, Secp256k1, XOnlyPublicKey},
-    taproot::TaprootSpendInfo,
-    Address, Network,
+use bdk::{
+    bitcoin::{
+        absolute::LockTime,
+        consensus::encode,
+        secp256k1::{self, Secp256k1, XOnlyPublicKey},
+        taproot::TaprootSpendInfo,
+        Address, Network, OutPoint, ScriptBuf, Txid,
+    },
+    FeeRate,
 };
+use fedimint_tonic_lnd::lnrpc::invoice::InvoiceState;
+use std::collections::HashMap;
 use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // use diesel_async::{pg::AsyncPgConnection, AsyncConnection};
 
@@ -12,23 +21,144 @@ use tokio::sync::Mutex;
 
 use crate::{
     db::{self, DB},
-    lnd::client::LNDGateway,
+    lnd::{
+        self,
+        client::{AddInvoiceResp, LNDGateway},
+        invoice_tracker,
+    },
     mempool,
-    models::{self, FullLoopOutData, Invoice, NewInvoice, NewScript, NewUTXO, Script, Utxo},
-    settings,
-    wallet::LooperWallet,
+    models::{
+        self, FullLoopOutData, Invoice, LoopOutQuote, LoopOutStateChange, NewInvoice,
+        NewLoopOutQuote, NewScript, NewUTXO, Script, Utxo,
+    },
+    musig::{PartialSig, PubNonce},
+    services::{self, webhook::WebhookTarget},
+    settings, utils,
+    wallet::{HtlcUtxo, LooperWallet, SendTx, SpendScanner},
 };
 
-// TODO: maybe make configurable
-#[allow(dead_code)]
-pub const TARGET_CONFS: usize = 6;
+// default number of confirmations before a funding transaction is considered buried. Override with loopout.confs.
+pub const TARGET_CONFS: u32 = 6;
+
+// how often the chain watchers poll bitcoind.
+const CHAIN_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// blocks added on top of loopout.cltv to a hold invoice's final CLTV, so the incoming payment is still held when the
+// buyer claims the on-chain HTLC just before it expires.
+const HOLD_INVOICE_CLTV_BUFFER: u64 = 18;
+
+// the loop out protocol versions the server supports. Version 1 is the original taproot HTLC, claimed through the
+// preimage leaf. Version 2 adds MuSig2 internal keys, claimed cooperatively through the key path.
+pub const PROTOCOL_VERSIONS: [u32; 2] = [1, 2];
+
+// default number of seconds a quote can be redeemed for. Override with loopout.quote_expiry.
+pub const QUOTE_EXPIRY_SECS: i64 = 600;
+
+// estimated vsize of a funding tx spending a single P2WPKH input to the P2TR HTLC and a P2WPKH change output.
+const FUNDING_TX_VSIZE: usize = 153;
+
+// default number of seconds a funding tx can stay unconfirmed before its fee is bumped. Override with
+// loopout.fee_bump_after, 0 disables fee bumping.
+pub const FEE_BUMP_AFTER_SECS: i64 = 3600;
+
+// vsize of the P2TR HTLC output. A batched loop out shares the rest of the funding tx with the other loop outs in its
+// batch, so its share of the funding tx is roughly just its own output.
+const HTLC_OUTPUT_VSIZE: usize = 43;
+
+// most loop outs funded by a single batch funding tx. Any others wait for the next batch.
+const MAX_BATCH_SIZE: usize = 100;
+
+// how long a funding tx's confirmations are cached for, so event streams watching it don't each ask bitcoind.
+const CONFIRMATIONS_CACHE_TTL: Duration = Duration::from_secs(10);
+
+// estimated vsize of a timeout sweep spending the HTLC through the timeout leaf to a P2TR wallet output.
+const TIMEOUT_SWEEP_VSIZE: usize = 138;
+
+/// FeePolicy prices the swap fee charged on top of a loop out's amount. fee_rate is the current fee estimate, used
+/// for any on-chain costs passed on to the buyer. batched loop outs share their funding tx, see loopout.batch_window.
+pub trait FeePolicy: Send + Sync {
+    fn swap_fee(&self, amount: i64, fee_rate: &FeeRate, batched: bool) -> i64;
+}
+
+/// FeeSchedule is the default FeePolicy, configured under [loopout].
+#[derive(Clone, Copy, Debug)]
+pub struct FeeSchedule {
+    // base_fee is charged on every loop out, in sats
+    pub base_fee: i64,
+    // fee_ppm is charged per million sats of the amount
+    pub fee_ppm: i64,
+    // pass_through_miner_fee adds the cost of funding the HTLC and sweeping it back at the current fee rate
+    pub pass_through_miner_fee: bool,
+}
+
+impl FeeSchedule {
+    pub fn from_config(cfg: &settings::Config) -> Result<Self, LoopOutServiceError> {
+        let map_err = |e| LoopOutServiceError::new(format!("error getting fee config: {}", e));
+        let base_fee = settings::get_or(cfg, "loopout.base_fee", 0).map_err(map_err)?;
+        // loopout.fee is the legacy fee, in whole percent
+        let fee_ppm = match (
+            settings::get_opt(cfg, "loopout.fee_ppm").map_err(map_err)?,
+            settings::get_opt::<i64>(cfg, "loopout.fee").map_err(map_err)?,
+        ) {
+            (Some(v), _) => v,
+            (None, Some(fee_pct)) => fee_pct * 10_000,
+            (None, None) => 0,
+        };
+        let pass_through_miner_fee =
+            settings::get_or(cfg, "loopout.miner_fee", false).map_err(map_err)?;
+        if base_fee < 0 || fee_ppm < 0 {
+            return Err(LoopOutServiceError::new(
+                "loopout.base_fee and loopout.fee_ppm must not be negative".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            base_fee,
+            fee_ppm,
+            pass_through_miner_fee,
+        })
+    }
+}
+
+impl FeePolicy for FeeSchedule {
+    fn swap_fee(&self, amount: i64, fee_rate: &FeeRate, batched: bool) -> i64 {
+        let proportional = (amount as i128 * self.fee_ppm as i128 / 1_000_000) as i64;
+        let miner_fee = match self.pass_through_miner_fee {
+            true => fee_rate.fee_vb(funding_vsize(batched) + TIMEOUT_SWEEP_VSIZE) as i64,
+            false => 0,
+        };
+
+        self.base_fee + proportional + miner_fee
+    }
+}
+
+// funding_vsize is the estimated share of the funding tx a loop out pays for.
+fn funding_vsize(batched: bool) -> usize {
+    match batched {
+        true => HTLC_OUTPUT_VSIZE,
+        false => FUNDING_TX_VSIZE,
+    }
+}
 
 pub struct LoopOutConfig {
     pub min_amount: i64,
     pub max_amount: i64,
     // cltv_delta is how many blocks before the UTXO's timelock expires
     pub cltv_delta: u64,
-    pub fee_pct: i64,
+    // fee is the schedule the default fee policy is built from
+    pub fee: FeeSchedule,
+    // target_confs is how many confirmations the funding tx needs before the loop out is CONFIRMED
+    pub target_confs: u32,
+    // prepay_amount is charged up front, before the HTLC is funded, and deducted from the swap invoice. 0 disables it.
+    pub prepay_amount: i64,
+    // quote_expiry is how many seconds a quote can be redeemed for
+    pub quote_expiry: i64,
+    // invoice_lifetime is how many seconds swap and prepay invoices can be paid for, from lnd.invoice_lifetime
+    pub invoice_lifetime: i64,
+    // batch_window is how many seconds batched loop outs wait to share a funding tx. 0 disables batching.
+    pub batch_window: i64,
+    // fee_bump_after is how many seconds a funding tx can stay unconfirmed before it's replaced. 0 disables it.
+    pub fee_bump_after: i64,
 }
 
 pub struct LoopOutService {
@@ -36,8 +166,19 @@ pub struct LoopOutService {
     secp256k1: Secp256k1<secp256k1::All>,
     network: Network,
     db: DB,
-    wallet: Mutex<LooperWallet>,
+    // the wallet is shared with the loop in service
+    wallet: Arc<Mutex<LooperWallet>>,
     lnd_gateway: Mutex<LNDGateway>,
+    fee_policy: Box<dyn FeePolicy>,
+    // admission is held while a loop out request is admitted, stored and funded, so concurrent requests can't
+    // reserve the same funds
+    admission: Mutex<()>,
+    // balance is the wallet's spendable balance as of the last sync, see run_balance_syncer
+    balance: Mutex<Option<i64>>,
+    // confirmations caches the confirmations of funding txs, with the time they were looked up
+    confirmations: Mutex<HashMap<Txid, (u32, Instant)>>,
+    // spends finds the txs spending HTLCs, picking up where its last scan of each HTLC left off
+    spends: Mutex<SpendScanner>,
 }
 
 impl LoopOutService {
@@ -45,7 +186,7 @@ impl LoopOutService {
         // TODO: use LoopOutSvcConfig
         cfg: &settings::Config,
         db: DB,
-        wallet: LooperWallet,
+        wallet: Arc<Mutex<LooperWallet>>,
         lnd_gateway: LNDGateway,
     ) -> Result<Self, LoopOutServiceError> {
         let min_amount = cfg.get_int("loopout.min").map_err(|e| {
@@ -64,171 +205,2031 @@ impl LoopOutService {
             .map_err(|e| {
                 LoopOutServiceError::new(format!("error converting loopout.cltv to u64: {}", e))
             })?;
-        let fee_pct = cfg.get_int("loopout.fee").map_err(|e| {
-            LoopOutServiceError::new(format!("error getting loopout.fee from config: {}", e))
+        let fee = FeeSchedule::from_config(cfg)?;
+        let target_confs = settings::get_or(cfg, "loopout.confs", TARGET_CONFS).map_err(|e| {
+            LoopOutServiceError::new(format!("error getting loopout.confs from config: {}", e))
+        })?;
+        let prepay_amount = settings::get_or(cfg, "loopout.prepay", 0).map_err(|e| {
+            LoopOutServiceError::new(format!("error getting loopout.prepay from config: {}", e))
+        })?;
+        let quote_expiry = settings::get_or(cfg, "loopout.quote_expiry", QUOTE_EXPIRY_SECS)
+            .map_err(|e| {
+                LoopOutServiceError::new(format!(
+                    "error getting loopout.quote_expiry from config: {}",
+                    e
+                ))
+            })?;
+        let batch_window = settings::get_or(cfg, "loopout.batch_window", 0).map_err(|e| {
+            LoopOutServiceError::new(format!(
+                "error getting loopout.batch_window from config: {}",
+                e
+            ))
+        })?;
+        let fee_bump_after = settings::get_or(cfg, "loopout.fee_bump_after", FEE_BUMP_AFTER_SECS)
+            .map_err(|e| {
+            LoopOutServiceError::new(format!(
+                "error getting loopout.fee_bump_after from config: {}",
+                e
+            ))
+        })?;
+        let invoice_lifetime = lnd::client::get_lnd_config(cfg)
+            .map_err(|e| LoopOutServiceError::new(format!("error getting lnd config: {:?}", e)))?
+            .invoice_lifetime;
+        let network = LooperWallet::parse_network_from_config(cfg).map_err(|e| {
+            LoopOutServiceError::new(format!(
+                "error getting bitcoin.network from config: {:?}",
+                e
+            ))
+        })?;
+
+        Ok(Self {
+            cfg: LoopOutConfig {
+                min_amount,
+                max_amount,
+                cltv_delta,
+                fee,
+                target_confs,
+                prepay_amount,
+                quote_expiry,
+                invoice_lifetime,
+                batch_window,
+                fee_bump_after,
+            },
+            db,
+            secp256k1: Secp256k1::new(),
+            network,
+            wallet,
+            lnd_gateway: Mutex::new(lnd_gateway),
+            fee_policy: Box::new(fee),
+            admission: Mutex::new(()),
+            balance: Mutex::new(None),
+            confirmations: Mutex::new(HashMap::new()),
+            spends: Mutex::new(SpendScanner::default()),
+        })
+    }
+
+    pub fn get_config(&self) -> &LoopOutConfig {
+        &self.cfg
+    }
+
+    pub fn get_network(&self) -> Network {
+        self.network
+    }
+
+    pub fn get_loop_out(
+        &self,
+        payment_hash: String,
+    ) -> Result<FullLoopOutData, LoopOutServiceError> {
+        let conn = &mut self.db.get_conn().map_err(|e| {
+            LoopOutServiceError::new(format!("error getting db connection: {:?}", e))
+        })?;
+
+        db::get_full_loop_out(conn, payment_hash).map_err(|e| {
+            LoopOutServiceError::new(format!("error getting loop_out from db: {:?}", e))
+        })
+    }
+
+    /// list_state_changes returns every state the loop out has been in, oldest first.
+    pub fn list_state_changes(
+        &self,
+        loop_out_id: i64,
+    ) -> Result<Vec<LoopOutStateChange>, LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        db::list_loop_out_state_history(conn, loop_out_id).map_err(|e| {
+            LoopOutServiceError::new(format!(
+                "error listing loop out {} state changes: {:?}",
+                loop_out_id, e
+            ))
+        })
+    }
+
+    /// get_funding_confirmations returns the funding txid and its confirmations, or None if the HTLC isn't funded.
+    /// Confirmations are cached for a few seconds, since every client streaming a batched loop out's events asks for
+    /// the same tx.
+    pub async fn get_funding_confirmations(
+        &self,
+        data: &FullLoopOutData,
+    ) -> Result<Option<(Txid, u32)>, LoopOutServiceError> {
+        if data.utxo.is_none() {
+            return Ok(None);
+        }
+        let txid = Self::htlc_outpoint(data)?.txid;
+
+        let mut confirmations = self.confirmations.lock().await;
+        confirmations
+            .retain(|_, (_, looked_up_at)| looked_up_at.elapsed() < CONFIRMATIONS_CACHE_TTL);
+        if let Some((confs, _)) = confirmations.get(&txid) {
+            return Ok(Some((txid, *confs)));
+        }
+
+        let wallet = self.wallet.lock().await;
+        let confs = (*wallet).get_tx_confirmations(&txid);
+        mem::drop(wallet);
+
+        let confs = confs.map_err(|e| {
+            LoopOutServiceError::new(format!("error getting confirmations for {}: {:?}", txid, e))
+        })?;
+        confirmations.insert(txid, (confs, Instant::now()));
+
+        Ok(Some((txid, confs)))
+    }
+
+    /// is_loop_out_in_flight reports whether the loop out paying payment_hash can still be funded or is funded and
+    /// not yet claimed or swept. Unknown loop outs aren't in flight.
+    pub fn is_loop_out_in_flight(&self, payment_hash: String) -> Result<bool, LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let data = match db::get_full_loop_out(conn, payment_hash) {
+            Ok(data) => data,
+            Err(diesel::result::Error::NotFound) => return Ok(false),
+            Err(e) => {
+                return Err(LoopOutServiceError::new(format!(
+                    "error getting loop_out from db: {:?}",
+                    e
+                )))
+            }
+        };
+
+        Ok(match data.loop_out.state {
+            models::LoopOutState::AwaitingPayment => {
+                data.invoice.state != models::InvoiceState::Cancelled
+            }
+            models::LoopOutState::Initiated | models::LoopOutState::Confirmed => true,
+            _ => false,
+        })
+    }
+
+    /// quote_loop_out prices a loop out of amount. The quoted swap fee is honored by handle_loop_out_request until the
+    /// quote expires. miner_fee estimates what the funding tx, paid by the server, costs at the current fee rate. For
+    /// batched quotes, it's the loop out's share of a batch funding tx.
+    pub async fn quote_loop_out(
+        &self,
+        amount: i64,
+        batched: bool,
+    ) -> Result<LoopOutQuote, LoopOutServiceError> {
+        self.validate_amount(amount)?;
+        self.validate_batched(batched)?;
+
+        let fee_rate = Self::estimate_fee_rate().await?;
+        let quote_id = hex::encode(utils::rand_32_bytes());
+        let expires_at = chrono::Utc::now() + chrono::Duration::seconds(self.cfg.quote_expiry);
+
+        let conn = &mut self.get_conn()?;
+        db::insert_loop_out_quote(
+            conn,
+            NewLoopOutQuote {
+                quote_id: &quote_id,
+                amount,
+                fee: self.fee_policy.swap_fee(amount, &fee_rate, batched),
+                miner_fee: fee_rate.fee_vb(funding_vsize(batched)) as i64,
+                fee_rate: fee_rate.as_sat_per_vb() as f64,
+                cltv_delta: self.cfg.cltv_delta as i64,
+                expires_at: expires_at.naive_utc(),
+                batched,
+            },
+        )
+        .map_err(|e| LoopOutServiceError::new(format!("error inserting quote into db: {:?}", e)))
+    }
+
+    // handle_loop_out_request registers a new loop out. If the client supplies payment_hash, the swap is paid
+    // through a hold invoice and the HTLC is only funded once the payment is accepted. If a prepay is configured,
+    // the HTLC is only funded once the prepay invoice is settled. See run_funding_watcher. If musig is set, the HTLC's
+    // internal key is the MuSig2 aggregate of both pubkeys, so it can be claimed cooperatively. See sign_musig_claim.
+    // If quote_id is set, the quote's swap fee is charged instead of the current one. See quote_loop_out. If batched
+    // is set, the HTLC is funded along with others in the next batch, see run_funding_watcher. If webhook is set,
+    // every state change is POSTed to it, see WebhookService.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn handle_loop_out_request(
+        &self,
+        pubkey: String,
+        amount: i64,
+        payment_hash: Option<String>,
+        musig: bool,
+        quote_id: Option<String>,
+        batched: bool,
+        webhook: Option<WebhookTarget>,
+    ) -> Result<FullLoopOutData, LoopOutServiceError> {
+        self.validate_amount(amount)?;
+        self.validate_pubkey(&pubkey)?;
+        self.validate_batched(batched)?;
+        log::info!("validated request");
+        let buyer_pubkey: XOnlyPublicKey = XOnlyPublicKey::from_str(&pubkey).map_err(|e| {
+            LoopOutServiceError::new(format!("error converting pubkey to XOnlyPublicKey: {}", e))
+        })?;
+        // the same fee rate prices the swap and funds the HTLC
+        let fee_rate = Self::estimate_fee_rate().await?;
+        let required = Self::liquidity_required(amount, &fee_rate, batched);
+        let _admission = self.admission.lock().await;
+        self.check_liquidity(amount, required).await?;
+        // the quote is only redeemed once the loop out is stored, see db::insert_full_loop_out_data
+        let fee = match &quote_id {
+            Some(quote_id) => self.get_quote(quote_id, amount, batched)?.fee,
+            None => self.fee_policy.swap_fee(amount, &fee_rate, batched),
+        };
+        // the prepay is part of what the buyer pays for the swap, not on top of it
+        let invoice_amount = amount + fee - self.cfg.prepay_amount;
+
+        let awaiting_payment = payment_hash.is_some() || self.cfg.prepay_amount > 0 || batched;
+        let state = match awaiting_payment {
+            true => models::LoopOutState::AwaitingPayment,
+            false => models::LoopOutState::Initiated,
+        };
+
+        // invoices, keys and the funding tx are all created before touching the db, so the loop out is stored in a
+        // single transaction, along with the raw funding tx, before anything is broadcast.
+        let (invoice, is_hold) = match &payment_hash {
+            Some(payment_hash) => (
+                self.new_hold_invoice(invoice_amount, payment_hash).await?,
+                true,
+            ),
+            None => (
+                self.new_invoice(invoice_amount, models::INVOICE_KIND_SWAP)
+                    .await?,
+                false,
+            ),
+        };
+        let prepay_invoice = match self.cfg.prepay_amount {
+            0 => None,
+            prepay_amount => Some(
+                self.new_invoice(prepay_amount, models::INVOICE_KIND_PREPAY)
+                    .await?,
+            ),
+        };
+        let htlc = self
+            .new_onchain_htlc(&buyer_pubkey, &invoice.payment_hash, musig)
+            .await?;
+        let funding = match awaiting_payment {
+            true => None,
+            false => {
+                let send_tx = self
+                    .build_tx_to_addresses(&[(htlc.address.as_str(), amount as u64)], &fee_rate)
+                    .await?;
+                let txid = send_tx.tx.txid().to_string();
+                let raw_tx = encode::serialize_hex(&send_tx.tx);
+                Some((txid, raw_tx, send_tx))
+            }
+        };
+
+        let mut new_invoice =
+            Self::new_invoice_row(&invoice, invoice_amount, is_hold, models::INVOICE_KIND_SWAP);
+        let mut new_prepay_invoice = prepay_invoice.as_ref().map(|prepay_invoice| {
+            Self::new_invoice_row(
+                prepay_invoice,
+                self.cfg.prepay_amount,
+                false,
+                models::INVOICE_KIND_PREPAY,
+            )
+        });
+        let mut new_script = htlc.new_script(&invoice.payment_hash);
+        // the script id is set once the script is inserted
+        let mut new_utxo = funding.as_ref().map(|(txid, raw_tx, send_tx)| {
+            Self::new_funding_utxo(0, txid, send_tx.vouts[0], raw_tx, amount)
+        });
+
+        let conn = &mut self.get_conn()?;
+        let data = db::insert_full_loop_out_data(
+            conn,
+            models::NewLoopOut {
+                state,
+                amount,
+                fee,
+                miner_fee: funding.as_ref().map(|(_, _, send_tx)| send_tx.fee as i64),
+                fee_rate: funding
+                    .as_ref()
+                    .map(|(_, _, send_tx)| send_tx.fee_rate.as_sat_per_vb() as f64),
+                batched,
+                webhook_url: webhook.as_ref().map(|w| w.url.clone()),
+                webhook_secret: webhook.map(|w| w.secret),
+            },
+            &mut new_invoice,
+            new_prepay_invoice.as_mut(),
+            &mut new_script,
+            new_utxo.as_mut(),
+            required,
+            quote_id.as_deref(),
+        )
+        .map_err(|e| match (e, &quote_id) {
+            // the quote was redeemed by a concurrent request since we looked it up
+            (diesel::result::Error::NotFound, Some(quote_id)) => {
+                Self::invalid_quote(quote_id, amount)
+            }
+            (e, _) => {
+                LoopOutServiceError::new(format!("error inserting loop out into db: {:?}", e))
+            }
+        })?;
+
+        match funding {
+            Some((txid, _, send_tx)) => {
+                self.broadcast_tx(&send_tx.tx).await?;
+                log::info!("funded loop out {} htlc {}", data.loop_out.id, txid);
+            }
+            None => log::info!(
+                "waiting for loop out {} to be paid before funding",
+                data.loop_out.id
+            ),
+        }
+
+        Ok(data)
+    }
+
+    /// sign_musig_claim returns looper's MuSig2 nonce and partial signature for tx, a key path spend of a musig loop
+    /// out's HTLC. The buyer proves the swap is paid by revealing the preimage, which we also need to settle a hold
+    /// invoice. tx is hex encoded and pubnonce is the buyer's 66 byte public nonce. tx is checked and signed before a
+    /// hold invoice is settled, and the signature is only returned once it is.
+    pub async fn sign_musig_claim(
+        &self,
+        payment_hash: String,
+        preimage: String,
+        pubnonce: String,
+        tx: String,
+    ) -> Result<(PubNonce, PartialSig), LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+        let data = db::get_full_loop_out(conn, payment_hash).map_err(|e| {
+            LoopOutServiceError::new(format!("error getting loop_out from db: {:?}", e))
+        })?;
+
+        let preimage_bytes = Self::decode_hex32(&preimage).map_err(Self::invalid_claim)?;
+        if utils::sha256(&preimage_bytes) != Self::decode_hex32(&data.invoice.payment_hash)? {
+            return Err(Self::invalid_claim("preimage does not match payment hash"));
+        }
+        if !data.script.is_musig {
+            return Err(Self::invalid_claim("loop out is not musig"));
+        }
+        if data.loop_out.state != models::LoopOutState::Initiated
+            && data.loop_out.state != models::LoopOutState::Confirmed
+        {
+            return Err(Self::invalid_claim(format!(
+                "loop out is {}",
+                data.loop_out.state
+            )));
+        }
+        let claimant_nonce = hex::decode(&pubnonce)
+            .map_err(Self::invalid_claim)
+            .and_then(|bytes| PubNonce::from_slice(&bytes).map_err(Self::invalid_claim))?;
+        let tx = hex::decode(&tx)
+            .map_err(Self::invalid_claim)
+            .and_then(|bytes| {
+                encode::deserialize::<bitcoin::Transaction>(&bytes).map_err(Self::invalid_claim)
+            })?;
+
+        let wallet = self.wallet.lock().await;
+        let curr_height = (*wallet).get_height().map_err(|e| {
+            LoopOutServiceError::new(format!("error getting wallet height: {:?}", e))
+        })?;
+        mem::drop(wallet);
+        // once the timeout sweep can be mined we'd be racing it
+        if curr_height + 1 >= data.script.cltv_expiry as u32 {
+            return Err(Self::invalid_claim("htlc has expired"));
+        }
+
+        // the buyer keeps the preimage of a hold invoice, so make sure we can get paid before giving up the HTLC
+        let settle = data.invoice.is_hold && data.invoice.state != models::InvoiceState::Settled;
+        if settle && data.invoice.state != models::InvoiceState::Accepted {
+            return Err(Self::invalid_claim("hold invoice is not accepted"));
+        }
+
+        let scripts = Self::htlc_scripts(&data.script)?;
+        let htlc = Self::rebuild_htlc(&data, &scripts)?;
+        let buyer_pubkey = XOnlyPublicKey::from_str(&data.script.remote_pubkey).map_err(|e| {
+            LoopOutServiceError::new(format!("error parsing remote_pubkey: {:?}", e))
+        })?;
+
+        // the tx is checked and signed before the invoice is settled, so a bad tx doesn't get the payment settled
+        let wallet = self.wallet.lock().await;
+        let res = (*wallet).sign_musig_key_spend(
+            &htlc,
+            &tx,
+            buyer_pubkey,
+            data.script.local_pubkey_index as u32,
+            &claimant_nonce,
+        );
+        mem::drop(wallet);
+        let (pubnonce, partial_sig) = res.map_err(Self::invalid_claim)?;
+
+        let mut invoice = data.invoice;
+        invoice.payment_preimage = Some(preimage);
+        let mut invoice = db::update_invoice(conn, invoice)
+            .map_err(|e| LoopOutServiceError::new(format!("error saving preimage: {:?}", e)))?;
+
+        // the signature is only handed out once we've been paid
+        if settle {
+            let lndg = self.lnd_gateway.lock().await;
+            let res = lndg.settle_invoice(&preimage_bytes).await;
+            mem::drop(lndg);
+            res.map_err(|e| {
+                LoopOutServiceError::new(format!("error settling hold invoice: {:?}", e))
+            })?;
+            log::info!("settled hold invoice {}", invoice.payment_hash);
+
+            invoice.state = models::InvoiceState::Settled;
+            db::update_invoice(conn, invoice).map_err(|e| {
+                LoopOutServiceError::new(format!("error updating invoice: {:?}", e))
+            })?;
+        }
+
+        log::info!(
+            "signed musig claim of htlc {} for loop out {}",
+            htlc.outpoint,
+            data.loop_out.id
+        );
+
+        Ok((pubnonce, partial_sig))
+    }
+
+    fn get_quote(
+        &self,
+        quote_id: &str,
+        amount: i64,
+        batched: bool,
+    ) -> Result<LoopOutQuote, LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        db::get_redeemable_loop_out_quote(conn, quote_id, amount, batched).map_err(|e| match e {
+            diesel::result::Error::NotFound => Self::invalid_quote(quote_id, amount),
+            e => LoopOutServiceError::new(format!("error getting quote: {:?}", e)),
+        })
+    }
+
+    // invalid_quote logs why a quote was rejected and returns the error the API maps to a bad request. The quote is
+    // unknown, expired, already redeemed or for another amount or funding mode.
+    fn invalid_quote(quote_id: &str, amount: i64) -> LoopOutServiceError {
+        log::info!("invalid quote {} for amount {}", quote_id, amount);
+        LoopOutServiceError::new(services::INVALID_QUOTE.to_string())
+    }
+
+    // check_liquidity rejects a loop out that needs required sats from the wallet if the balance as of the last sync,
+    // less the liquidity reserved for loop outs it doesn't account for yet, can't cover it. self.admission must be
+    // held.
+    async fn check_liquidity(&self, amount: i64, required: i64) -> Result<(), LoopOutServiceError> {
+        if self.balance.lock().await.is_none() {
+            self.sync_balance().await?;
+        }
+
+        let conn = &mut self.get_conn()?;
+        // the balance is locked so it isn't paired with reservations released by a later sync
+        let balance = self.balance.lock().await;
+        let spendable = (*balance).ok_or_else(|| {
+            LoopOutServiceError::new("wallet balance hasn't been synced".to_string())
         })?;
+        let reserved = db::sum_active_liquidity_reservations(conn).map_err(|e| {
+            LoopOutServiceError::new(format!("error summing liquidity reservations: {:?}", e))
+        })?;
+        mem::drop(balance);
+
+        Self::check_available_liquidity(amount, required, spendable, reserved)
+    }
+
+    // check_available_liquidity rejects a loop out of amount needing required sats if spendable less reserved is short.
+    fn check_available_liquidity(
+        amount: i64,
+        required: i64,
+        spendable: i64,
+        reserved: i64,
+    ) -> Result<(), LoopOutServiceError> {
+        let available = spendable - reserved;
+        if required > available {
+            log::warn!(
+                "rejecting loop out of {}: {} sats required, {} available, {} reserved",
+                amount,
+                required,
+                available,
+                reserved
+            );
+            return Err(LoopOutServiceError::new(
+                services::INSUFFICIENT_LIQUIDITY.to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // liquidity_required is what a loop out of amount is expected to take from the wallet: the HTLC amount, its share
+    // of the funding fee and the fee of sweeping it back if it times out, since the sweep only returns what's left.
+    fn liquidity_required(amount: i64, fee_rate: &FeeRate, batched: bool) -> i64 {
+        amount + fee_rate.fee_vb(funding_vsize(batched) + TIMEOUT_SWEEP_VSIZE) as i64
+    }
+
+    /// run_balance_syncer syncs the wallet and caches its balance for check_liquidity, releasing the liquidity
+    /// reservations the synced balance accounts for. It never returns.
+    pub async fn run_balance_syncer(&self) {
+        loop {
+            if let Err(e) = self.sync_balance().await {
+                log::error!("error syncing wallet balance: {:?}", e);
+            }
+
+            tokio::time::sleep(CHAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    // sync_balance syncs the wallet and caches its spendable balance. A reservation is released along with it once the
+    // synced wallet has seen the loop out's funding tx, or once the loop out will no longer be funded, so funds are
+    // never counted both in the balance and as reserved. Reservations made during the sync are kept.
+    async fn sync_balance(&self) -> Result<(), LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+        let reservations = db::list_active_liquidity_reservations(conn).map_err(|e| {
+            LoopOutServiceError::new(format!("error listing liquidity reservations: {:?}", e))
+        })?;
+
+        let wallet = self.wallet.lock().await;
+        let res = (*wallet).sync().and_then(|_| {
+            let balance = (*wallet).get_balance()?;
+            let mut released = vec![];
+            for (reservation, loop_out, utxo) in &reservations {
+                let is_accounted_for = match utxo {
+                    Some(utxo) => match Txid::from_str(&utxo.txid) {
+                        Ok(txid) => (*wallet).has_synced_tx(&txid)?,
+                        Err(_) => false,
+                    },
+                    None => loop_out.state != models::LoopOutState::AwaitingPayment,
+                };
+                if is_accounted_for {
+                    released.push(reservation.id);
+                }
+            }
+            Ok((balance, released))
+        });
+        mem::drop(wallet);
+        let (balance, released) = res.map_err(|e| {
+            LoopOutServiceError::new(format!("error syncing wallet balance: {:?}", e))
+        })?;
+
+        let mut cached = self.balance.lock().await;
+        if !released.is_empty() {
+            db::release_liquidity_reservations(conn, released).map_err(|e| {
+                LoopOutServiceError::new(format!("error releasing liquidity reservations: {:?}", e))
+            })?;
+        }
+        *cached = Some(balance.get_spendable() as i64);
+
+        Ok(())
+    }
+
+    // invalid_claim logs why a musig claim was rejected and returns the error the API maps to a bad request.
+    fn invalid_claim<E: std::fmt::Debug>(e: E) -> LoopOutServiceError {
+        log::info!("invalid musig claim: {:?}", e);
+        LoopOutServiceError::new(services::INVALID_CLAIM.to_string())
+    }
+
+    /// recover_loop_outs reconciles every loop out that was in flight when the server stopped. It should run once at
+    /// startup, before the background workers. Open invoices are synced with LND, funding txs bitcoind doesn't know
+    /// are rebroadcast and timeout sweeps that were dropped are handed back to the timeout sweeper. Each worker then
+    /// runs once, so every loop out is advanced to its current state before the API serves it. A loop out or worker
+    /// that fails is logged and left to the background workers.
+    pub async fn recover_loop_outs(&self) -> Result<(), LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let loop_outs = db::list_full_loop_outs_in_states(
+            conn,
+            vec![
+                models::LoopOutState::AwaitingPayment,
+                models::LoopOutState::Initiated,
+                models::LoopOutState::Confirmed,
+                // the sweep may have been dropped from the mempool, see reopen_dropped_timeout
+                models::LoopOutState::Timeout,
+                models::LoopOutState::Expired,
+            ],
+        )
+        .map_err(|e| {
+            LoopOutServiceError::new(format!("error listing in flight loop outs: {:?}", e))
+        })?;
+        log::info!("recovering {} loop outs", loop_outs.len());
+
+        for data in loop_outs {
+            let loop_out_id = data.loop_out.id;
+            if let Err(e) = self.recover_loop_out(conn, data).await {
+                log::error!("error recovering loop out {}: {:?}", loop_out_id, e);
+            }
+        }
+
+        if let Err(e) = self.check_payments().await {
+            log::error!("error checking loop out payments during recovery: {:?}", e);
+        }
+        if let Err(e) = self.check_confirmations().await {
+            log::error!(
+                "error checking loop out confirmations during recovery: {:?}",
+                e
+            );
+        }
+        if let Err(e) = self.check_claims().await {
+            log::error!("error checking loop out claims during recovery: {:?}", e);
+        }
+        if let Err(e) = self.check_timeouts().await {
+            log::error!(
+                "error sweeping timed out loop outs during recovery: {:?}",
+                e
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn recover_loop_out(
+        &self,
+        conn: &mut db::PooledConnection,
+        data: FullLoopOutData,
+    ) -> Result<(), LoopOutServiceError> {
+        match Self::recovery_step(&data.loop_out) {
+            RecoveryStep::RebroadcastFunding => self.rebroadcast_funding_tx(&data).await?,
+            RecoveryStep::ReopenTimeout => self.reopen_dropped_timeout(conn, &data).await?,
+            RecoveryStep::None => {}
+        }
+
+        self.sync_invoice(conn, data.invoice).await?;
+        if let Some(prepay_invoice) = data.prepay_invoice {
+            self.sync_invoice(conn, prepay_invoice).await?;
+        }
+
+        Ok(())
+    }
+
+    // recovery_step returns what recover_loop_out has to do on chain for loop_out, besides syncing its invoices.
+    fn recovery_step(loop_out: &models::LoopOut) -> RecoveryStep {
+        match loop_out.state {
+            models::LoopOutState::Initiated => RecoveryStep::RebroadcastFunding,
+            // a buried sweep can't have been dropped
+            models::LoopOutState::Timeout | models::LoopOutState::Expired
+                if loop_out.timeout_txid.is_some() && loop_out.timeout_confirmed_at.is_none() =>
+            {
+                RecoveryStep::ReopenTimeout
+            }
+            _ => RecoveryStep::None,
+        }
+    }
+
+    // sync_invoice moves an OPEN or ACCEPTED invoice to the state LND reports, in case the invoice tracker missed the
+    // update while we were down. The tracker only replays settled invoices.
+    async fn sync_invoice(
+        &self,
+        conn: &mut db::PooledConnection,
+        mut invoice: Invoice,
+    ) -> Result<(), LoopOutServiceError> {
+        if invoice.state != models::InvoiceState::Open
+            && invoice.state != models::InvoiceState::Accepted
+        {
+            return Ok(());
+        }
+
+        let payment_hash = Self::decode_hex32(&invoice.payment_hash)?;
+        let lndg = self.lnd_gateway.lock().await;
+        let res = lndg.lookup_invoice(&payment_hash).await;
+        mem::drop(lndg);
+        let ln_invoice = res
+            .map_err(|e| LoopOutServiceError::new(format!("error looking up invoice: {:?}", e)))?;
+
+        if invoice_tracker::apply_invoice_update(&mut invoice, &ln_invoice) {
+            db::update_invoice(conn, invoice).map_err(|e| {
+                LoopOutServiceError::new(format!("error updating invoice: {:?}", e))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // rebroadcast_funding_tx broadcasts the stored funding tx of an INITIATED loop out again if bitcoind doesn't know
+    // it, e.g. because we stopped between storing and broadcasting it or it was evicted from the mempool.
+    async fn rebroadcast_funding_tx(
+        &self,
+        data: &FullLoopOutData,
+    ) -> Result<(), LoopOutServiceError> {
+        let txid = Self::htlc_outpoint(data)?.txid;
+
+        let wallet = self.wallet.lock().await;
+        // unknown transactions are an error to the wallet
+        let confs = (*wallet).get_tx_confirmations(&txid).unwrap_or(0);
+        let in_mempool = (*wallet).is_tx_in_mempool(&txid);
+        mem::drop(wallet);
+        if confs > 0 || in_mempool {
+            return Ok(());
+        }
+
+        let raw_tx = match &Self::htlc_utxo(data)?.raw_tx {
+            Some(raw_tx) => raw_tx,
+            None => {
+                log::warn!(
+                    "loop out {} funding tx {} is unknown and wasn't stored",
+                    data.loop_out.id,
+                    txid
+                );
+                return Ok(());
+            }
+        };
+        let tx = hex::decode(raw_tx)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| {
+                encode::deserialize::<bitcoin::Transaction>(&bytes).map_err(|e| e.to_string())
+            })
+            .map_err(|e| {
+                LoopOutServiceError::new(format!("error decoding funding tx {}: {}", txid, e))
+            })?;
+
+        self.broadcast_tx(&tx).await?;
+        log::info!(
+            "rebroadcast loop out {} funding tx {}",
+            data.loop_out.id,
+            txid
+        );
+
+        Ok(())
+    }
+
+    // reopen_dropped_timeout moves a TIMEOUT loop out back to CONFIRMED if its HTLC is unspent, i.e. the sweep never
+    // made it into a block and was dropped from the mempool. The timeout sweeper then sweeps it again. EXPIRED loop
+    // outs stay EXPIRED and only forget their timeout_txid.
+    async fn reopen_dropped_timeout(
+        &self,
+        conn: &mut db::PooledConnection,
+        data: &FullLoopOutData,
+    ) -> Result<(), LoopOutServiceError> {
+        let outpoint = Self::htlc_outpoint(data)?;
+
+        let wallet = self.wallet.lock().await;
+        let spent = (*wallet).is_output_spent(&outpoint).map_err(|e| {
+            LoopOutServiceError::new(format!("error checking htlc {}: {:?}", outpoint, e))
+        })?;
+        mem::drop(wallet);
+        if spent {
+            return Ok(());
+        }
+
+        log::warn!(
+            "loop out {} timeout sweep {:?} was dropped, sweeping htlc {} again",
+            data.loop_out.id,
+            data.loop_out.timeout_txid,
+            outpoint
+        );
+        db::reopen_loop_out_timeout(
+            conn,
+            data.loop_out.id,
+            Self::reopened_state(data.loop_out.state),
+            &format!("timeout sweep {:?} was dropped", data.loop_out.timeout_txid),
+        )
+        .map_err(|e| {
+            LoopOutServiceError::new(format!(
+                "error reopening loop out {}: {:?}",
+                data.loop_out.id, e
+            ))
+        })?;
+
+        Ok(())
+    }
+
+    // reopened_state returns the state a loop out in state moves to once its timeout sweep was dropped. A TIMEOUT loop
+    // out is handed back to the timeout sweeper, an EXPIRED one stays EXPIRED.
+    fn reopened_state(state: models::LoopOutState) -> models::LoopOutState {
+        match state {
+            models::LoopOutState::Timeout => models::LoopOutState::Confirmed,
+            state => state,
+        }
+    }
+
+    /// run_expiry_watcher moves every loop out whose swap invoice expired unpaid to EXPIRED, cancelling its
+    /// invoices. Its liquidity reservation is released on the next balance sync. Loop outs whose swap invoice was
+    /// cancelled elsewhere are expired too. A funded HTLC is left to the timeout sweeper. It never returns.
+    pub async fn run_expiry_watcher(&self) {
+        loop {
+            if let Err(e) = self.check_expiries().await {
+                log::error!("error checking loop out expiries: {:?}", e);
+            }
+
+            tokio::time::sleep(CHAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn check_expiries(&self) -> Result<(), LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let loop_outs = db::list_full_loop_outs_in_states(
+            conn,
+            vec![
+                models::LoopOutState::AwaitingPayment,
+                models::LoopOutState::Initiated,
+                models::LoopOutState::Confirmed,
+            ],
+        )
+        .map_err(|e| {
+            LoopOutServiceError::new(format!("error listing unpaid loop outs: {:?}", e))
+        })?;
+
+        let now = chrono::Utc::now().naive_utc();
+        for data in loop_outs {
+            let expires_at =
+                data.invoice.created_at + chrono::Duration::seconds(self.cfg.invoice_lifetime);
+            let expired = match data.invoice.state {
+                models::InvoiceState::Open => expires_at <= now,
+                models::InvoiceState::Cancelled => true,
+                // paid, or being paid
+                _ => false,
+            };
+            if !expired {
+                continue;
+            }
+
+            if let Err(e) = self.expire_loop_out(conn, &data).await {
+                log::error!("error expiring loop out {}: {:?}", data.loop_out.id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn expire_loop_out(
+        &self,
+        conn: &mut db::PooledConnection,
+        data: &FullLoopOutData,
+    ) -> Result<(), LoopOutServiceError> {
+        // the invoice tracker marks the invoices CANCELLED. If the swap invoice was paid in the meantime, this fails
+        // and the loop out is left alone.
+        if data.invoice.state != models::InvoiceState::Cancelled {
+            self.cancel_swap_invoice(&data.invoice).await?;
+        }
+        if let Some(prepay_invoice) = &data.prepay_invoice {
+            if prepay_invoice.state == models::InvoiceState::Open {
+                if let Err(e) = self.cancel_swap_invoice(prepay_invoice).await {
+                    log::error!(
+                        "error cancelling prepay invoice {}: {:?}",
+                        prepay_invoice.payment_hash,
+                        e
+                    );
+                }
+            }
+        }
+
+        let reason = match data.invoice.state {
+            models::InvoiceState::Cancelled => "swap invoice was cancelled unpaid",
+            _ => "swap invoice expired unpaid",
+        };
+        db::update_loop_out_state(
+            conn,
+            data.loop_out.id,
+            models::LoopOutState::Expired,
+            reason,
+        )
+        .map_err(|e| {
+            LoopOutServiceError::new(format!(
+                "error updating loop out {} state: {:?}",
+                data.loop_out.id, e
+            ))
+        })?;
+        match &data.utxo {
+            Some(utxo) => log::info!(
+                "loop out {} expired unpaid, htlc {}:{} will be swept at {}",
+                data.loop_out.id,
+                utxo.txid,
+                utxo.vout,
+                data.script.cltv_expiry
+            ),
+            None => log::info!("loop out {} expired unpaid", data.loop_out.id),
+        }
+
+        Ok(())
+    }
+
+    /// run_confirmation_watcher polls the funding transaction of every INITIATED loop out and moves it to CONFIRMED
+    /// once it has at least loopout.confs confirmations. It never returns.
+    pub async fn run_confirmation_watcher(&self) {
+        loop {
+            if let Err(e) = self.check_confirmations().await {
+                log::error!("error checking loop out confirmations: {:?}", e);
+            }
+
+            tokio::time::sleep(CHAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn check_confirmations(&self) -> Result<(), LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let loop_outs =
+            db::list_full_loop_outs_in_states(conn, vec![models::LoopOutState::Initiated])
+                .map_err(|e| {
+                    LoopOutServiceError::new(format!("error listing initiated loop outs: {:?}", e))
+                })?;
+
+        for data in loop_outs {
+            let txid = match Self::htlc_outpoint(&data) {
+                Ok(outpoint) => outpoint.txid,
+                Err(e) => {
+                    log::error!("error getting loop out {} htlc: {:?}", data.loop_out.id, e);
+                    continue;
+                }
+            };
+
+            let wallet = self.wallet.lock().await;
+            let confs = (*wallet).get_tx_confirmations(&txid);
+            mem::drop(wallet);
+
+            let confs = match confs {
+                Ok(confs) => confs,
+                Err(e) => {
+                    log::error!("error getting confirmations for {}: {:?}", txid, e);
+                    continue;
+                }
+            };
+
+            if confs < self.cfg.target_confs {
+                log::debug!(
+                    "loop out {} funding tx {} has {}/{} confirmations",
+                    data.loop_out.id,
+                    txid,
+                    confs,
+                    self.cfg.target_confs
+                );
+                continue;
+            }
+
+            let reason = format!("funding tx {} has {} confirmations", txid, confs);
+            if let Err(e) = db::update_loop_out_state(
+                conn,
+                data.loop_out.id,
+                models::LoopOutState::Confirmed,
+                &reason,
+            ) {
+                log::error!(
+                    "error updating loop out {} state: {:?}",
+                    data.loop_out.id,
+                    e
+                );
+                continue;
+            }
+            log::info!(
+                "loop out {} funding tx {} confirmed with {} confirmations",
+                data.loop_out.id,
+                txid,
+                confs
+            );
+        }
+
+        Ok(())
+    }
+
+    /// run_fee_bumper replaces the funding tx of INITIATED loop outs once it has been unconfirmed for longer than
+    /// loopout.fee_bump_after, if the current fee estimate is higher than what it pays. Every loop out funded by the tx
+    /// is pointed at the replacement, including EXPIRED ones still waiting for their HTLC to be swept back. Clients
+    /// find the replacement's outpoint, and the txids it replaced, on the loop out and in its state history. It never
+    /// returns.
+    pub async fn run_fee_bumper(&self) {
+        loop {
+            if let Err(e) = self.check_fee_bumps().await {
+                log::error!("error checking loop out funding fees: {:?}", e);
+            }
+
+            tokio::time::sleep(CHAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn check_fee_bumps(&self) -> Result<(), LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let loop_outs = db::list_full_loop_outs_in_states(
+            conn,
+            vec![
+                models::LoopOutState::Initiated,
+                models::LoopOutState::Expired,
+            ],
+        )
+        .map_err(|e| {
+            LoopOutServiceError::new(format!("error listing funded loop outs: {:?}", e))
+        })?;
+
+        let mut funding_txs: HashMap<String, Vec<FullLoopOutData>> = HashMap::new();
+        for data in loop_outs {
+            // expired loop outs may never have been funded, and a swept HTLC's funding tx is confirmed
+            let txid = match (&data.utxo, &data.loop_out.timeout_txid) {
+                (Some(utxo), None) => utxo.txid.clone(),
+                _ => continue,
+            };
+            funding_txs.entry(txid).or_default().push(data);
+        }
+
+        for (txid, loop_outs) in funding_txs {
+            if let Err(e) = self.bump_funding_fee(conn, loop_outs).await {
+                log::error!("error bumping fee of funding tx {}: {:?}", txid, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // bump_funding_fee replaces the unconfirmed funding tx shared by loop_outs if it's stuck and still funds an
+    // INITIATED loop out. If a tx it replaced confirmed after all, the loop outs are pointed back at that one instead.
+    async fn bump_funding_fee(
+        &self,
+        conn: &mut db::PooledConnection,
+        loop_outs: Vec<FullLoopOutData>,
+    ) -> Result<(), LoopOutServiceError> {
+        let utxo = Self::htlc_utxo(&loop_outs[0])?;
+        let funded_at = utxo.updated_at;
+        let replaced_txids: Vec<String> = utxo.replaced_txids.iter().flatten().cloned().collect();
+        let txid = Self::htlc_outpoint(&loop_outs[0])?.txid;
+        let recipients: Vec<(&str, u64)> = loop_outs
+            .iter()
+            .map(|data| (data.script.address.as_str(), data.loop_out.amount as u64))
+            .collect();
+
+        let wallet = self.wallet.lock().await;
+        // unknown transactions are an error to the wallet
+        let confs = (*wallet).get_tx_confirmations(&txid).unwrap_or(0);
+        if confs > 0 {
+            return Ok(());
+        }
+        for replaced_txid in replaced_txids {
+            let replaced_txid = Txid::from_str(&replaced_txid).map_err(|e| {
+                LoopOutServiceError::new(format!("error parsing txid {}: {:?}", replaced_txid, e))
+            })?;
+            if (*wallet).get_tx_confirmations(&replaced_txid).unwrap_or(0) == 0 {
+                continue;
+            }
+
+            let map_err = |e| {
+                LoopOutServiceError::new(format!(
+                    "error restoring replaced tx {}: {:?}",
+                    replaced_txid, e
+                ))
+            };
+            let tx = (*wallet).get_wallet_tx(&replaced_txid).map_err(map_err)?;
+            let vouts = (*wallet)
+                .find_recipient_vouts(&tx, &recipients)
+                .map_err(map_err)?;
+            mem::drop(wallet);
+
+            log::warn!(
+                "funding tx {} was replaced by {} but confirmed, restoring it",
+                replaced_txid,
+                txid
+            );
+            return Self::replace_funding_tx(conn, loop_outs, &tx, &vouts, None);
+        }
+        mem::drop(wallet);
+
+        let bump_at = funded_at + chrono::Duration::seconds(self.cfg.fee_bump_after);
+        if self.cfg.fee_bump_after <= 0 || bump_at > chrono::Utc::now().naive_utc() {
+            return Ok(());
+        }
+        // expired HTLCs are only swept back, so they aren't worth paying more for on their own
+        if loop_outs
+            .iter()
+            .all(|data| data.loop_out.state != models::LoopOutState::Initiated)
+        {
+            return Ok(());
+        }
+
+        let funding_fee_rate = loop_outs[0].loop_out.fee_rate.unwrap_or(0.0) as f32;
+        let fee_rate = Self::estimate_fee_rate().await?.as_sat_per_vb();
+        if fee_rate <= funding_fee_rate {
+            log::debug!(
+                "funding tx {} pays {} sat/vB, estimate is {}",
+                txid,
+                funding_fee_rate,
+                fee_rate
+            );
+            return Ok(());
+        }
+        // a replacement must pay at least the min relay fee rate on top of the tx it replaces
+        let fee_rate = FeeRate::from_sat_per_vb(fee_rate.max(funding_fee_rate + 1.0));
+
+        let wallet = self.wallet.lock().await;
+        let res = (*wallet).bump_fee(&txid, &recipients, &fee_rate);
+        mem::drop(wallet);
+        let send_tx = res.map_err(|e| {
+            LoopOutServiceError::new(format!("error bumping fee of tx {}: {:?}", txid, e))
+        })?;
+
+        let funding_fees = (
+            send_tx.fee as i64 / loop_outs.len() as i64,
+            send_tx.fee_rate.as_sat_per_vb() as f64,
+        );
+        Self::replace_funding_tx(
+            conn,
+            loop_outs,
+            &send_tx.tx,
+            &send_tx.vouts,
+            Some(funding_fees),
+        )?;
+        self.broadcast_tx(&send_tx.tx).await?;
+        log::info!(
+            "replaced funding tx {} with {} at {} sat/vB",
+            txid,
+            send_tx.tx.txid(),
+            send_tx.fee_rate.as_sat_per_vb()
+        );
+
+        Ok(())
+    }
+
+    // replace_funding_tx points the HTLC utxo of each loop out at its output of tx, vouts[i] being loop_outs[i]'s,
+    // and remembers the txid it replaced. It's stored before tx is broadcast, like the original funding.
+    fn replace_funding_tx(
+        conn: &mut db::PooledConnection,
+        loop_outs: Vec<FullLoopOutData>,
+        tx: &bitcoin::Transaction,
+        vouts: &[u32],
+        funding_fees: Option<(i64, f64)>,
+    ) -> Result<(), LoopOutServiceError> {
+        let txid = tx.txid().to_string();
+        let raw_tx = encode::serialize_hex(tx);
+
+        let mut replacements = vec![];
+        for (data, vout) in loop_outs.into_iter().zip(vouts) {
+            let mut utxo = data.utxo.ok_or_else(|| {
+                LoopOutServiceError::new(format!("loop out {} has no htlc utxo", data.loop_out.id))
+            })?;
+            let replaced_txid = mem::replace(&mut utxo.txid, txid.clone());
+            // restoring a replaced tx drops it from the list
+            utxo.replaced_txids.retain(|t| t.as_ref() != Some(&txid));
+            utxo.replaced_txids.push(Some(replaced_txid));
+            utxo.vout = *vout as i32;
+            utxo.raw_tx = Some(raw_tx.clone());
+            replacements.push((data.loop_out.id, utxo));
+        }
+
+        db::replace_loop_out_funding(conn, replacements, funding_fees).map_err(|e| {
+            LoopOutServiceError::new(format!("error replacing funding tx {}: {:?}", txid, e))
+        })?;
+
+        Ok(())
+    }
+
+    /// run_claim_watcher watches the HTLC output of every funded loop out and moves the loop out to CLAIMED once
+    /// the buyer spends it through the preimage leaf, or to TIMEOUT if it was swept back to us. A swept HTLC is
+    /// watched until the sweep is buried, so a claim replacing the sweep still moves the loop out to CLAIMED and
+    /// settles its hold invoice. It never returns.
+    pub async fn run_claim_watcher(&self) {
+        loop {
+            if let Err(e) = self.check_claims().await {
+                log::error!("error checking loop out claims: {:?}", e);
+            }
+
+            tokio::time::sleep(CHAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn check_claims(&self) -> Result<(), LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let loop_outs = self.list_funded_loop_outs(conn)?;
+        let watched = loop_outs
+            .iter()
+            .filter_map(|data| Self::htlc_outpoint(data).ok())
+            .collect();
+        self.spends.lock().await.retain(&watched);
+
+        for data in loop_outs {
+            let spend = match self.find_htlc_spend(&data).await {
+                Ok(Some(spend)) => spend,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!(
+                        "error finding htlc spend for loop out {}: {:?}",
+                        data.loop_out.id,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            let loop_out_id = data.loop_out.id;
+            if let Err(e) = self.record_htlc_spend(conn, data, spend).await {
+                log::error!(
+                    "error recording htlc spend for loop out {}: {:?}",
+                    loop_out_id,
+                    e
+                );
+            }
+        }
+
+        self.settle_claimed_hold_invoices(conn).await
+    }
+
+    // settle_claimed_hold_invoices settles every accepted hold invoice whose preimage we learned from a claim.
+    async fn settle_claimed_hold_invoices(
+        &self,
+        conn: &mut db::PooledConnection,
+    ) -> Result<(), LoopOutServiceError> {
+        let loop_outs = db::list_full_loop_outs_with_invoice_state(
+            conn,
+            models::LoopOutState::Claimed,
+            models::InvoiceState::Accepted,
+        )
+        .map_err(|e| {
+            LoopOutServiceError::new(format!("error listing claimed loop outs: {:?}", e))
+        })?;
+
+        for data in loop_outs {
+            let mut invoice = data.invoice;
+            let preimage = match invoice.payment_preimage.as_deref().map(Self::decode_hex32) {
+                Some(Ok(preimage)) => preimage,
+                Some(Err(e)) => {
+                    log::error!(
+                        "error decoding preimage of {}: {:?}",
+                        invoice.payment_hash,
+                        e
+                    );
+                    continue;
+                }
+                None => continue,
+            };
+
+            let lndg = self.lnd_gateway.lock().await;
+            let res = lndg.settle_invoice(&preimage).await;
+            mem::drop(lndg);
+            if let Err(e) = res {
+                log::error!("error settling invoice {}: {:?}", invoice.payment_hash, e);
+                continue;
+            }
+
+            log::info!("settled hold invoice {}", invoice.payment_hash);
+            let payment_hash = invoice.payment_hash.clone();
+            invoice.state = models::InvoiceState::Settled;
+            if let Err(e) = db::update_invoice(conn, invoice) {
+                log::error!("error updating invoice {}: {:?}", payment_hash, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// run_funding_watcher funds the HTLC of every loop out that has been paid for, moving it from AWAITING_PAYMENT to
+    /// INITIATED. A loop out is paid for once its prepay invoice, if any, is settled and its hold invoice, if any, is
+    /// accepted. Payments that arrive too close to the HTLC's expiry are cancelled instead. Paid batched loop outs are
+    /// funded together in a single tx of up to MAX_BATCH_SIZE HTLCs once the oldest has waited loopout.batch_window.
+    /// It never returns.
+    pub async fn run_funding_watcher(&self) {
+        loop {
+            if let Err(e) = self.check_payments().await {
+                log::error!("error checking loop out payments: {:?}", e);
+            }
+
+            tokio::time::sleep(CHAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn check_payments(&self) -> Result<(), LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let loop_outs =
+            db::list_full_loop_outs_in_states(conn, vec![models::LoopOutState::AwaitingPayment])
+                .map_err(|e| {
+                    LoopOutServiceError::new(format!("error listing unfunded loop outs: {:?}", e))
+                })?;
+
+        let mut paid = vec![];
+        for mut data in loop_outs {
+            // cancelled or expired invoices will never be paid
+            if data.invoice.state == models::InvoiceState::Cancelled {
+                continue;
+            }
+
+            // the prepay is a regular invoice, so the invoice tracker marks it SETTLED
+            if let Some(prepay_invoice) = &data.prepay_invoice {
+                if prepay_invoice.state != models::InvoiceState::Settled {
+                    continue;
+                }
+            }
+
+            if data.invoice.is_hold {
+                match self.is_hold_invoice_accepted(&data.invoice).await {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        log::error!(
+                            "error looking up invoice {}: {:?}",
+                            data.invoice.payment_hash,
+                            e
+                        );
+                        continue;
+                    }
+                }
+
+                let payment_hash = data.invoice.payment_hash.clone();
+                data.invoice.state = models::InvoiceState::Accepted;
+                data.invoice = match db::update_invoice(conn, data.invoice) {
+                    Ok(invoice) => invoice,
+                    Err(e) => {
+                        log::error!("error updating invoice {}: {:?}", payment_hash, e);
+                        continue;
+                    }
+                };
+            }
+
+            match self.cancel_late_payment(conn, &data).await {
+                Ok(false) => {}
+                Ok(true) => continue,
+                Err(e) => {
+                    log::error!(
+                        "error checking loop out {} expiry: {:?}",
+                        data.loop_out.id,
+                        e
+                    );
+                    continue;
+                }
+            }
+
+            paid.push(data);
+        }
+
+        let (unbatched, batch) =
+            Self::plan_fundings(paid, self.cfg.batch_window, chrono::Utc::now().naive_utc());
+        for data in unbatched {
+            if let Err(e) = self.fund_loop_outs(conn, &[data]).await {
+                log::error!("error funding loop out: {:?}", e);
+            }
+        }
+        if !batch.is_empty() {
+            if let Err(e) = self.fund_batch(conn, batch).await {
+                log::error!("error funding loop out batch: {:?}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // plan_fundings splits paid loop outs into the ones funded on their own and the batch funded together. The batch
+    // is empty until the oldest batched loop out has waited batch_window, and then holds up to MAX_BATCH_SIZE of the
+    // oldest batched loop outs.
+    fn plan_fundings(
+        paid: Vec<FullLoopOutData>,
+        batch_window: i64,
+        now: chrono::NaiveDateTime,
+    ) -> (Vec<FullLoopOutData>, Vec<FullLoopOutData>) {
+        let (mut batch, unbatched): (Vec<_>, Vec<_>) =
+            paid.into_iter().partition(|data| data.loop_out.batched);
+
+        batch.sort_by_key(|data| data.loop_out.created_at);
+        let is_due = match batch.first() {
+            Some(oldest) => {
+                oldest.loop_out.created_at + chrono::Duration::seconds(batch_window) <= now
+            }
+            None => false,
+        };
+        if !is_due {
+            return (unbatched, vec![]);
+        }
+        batch.truncate(MAX_BATCH_SIZE);
+
+        (unbatched, batch)
+    }
+
+    // is_hold_invoice_accepted asks LND directly, since the invoice stream doesn't replay ACCEPTED updates we missed
+    // while disconnected.
+    async fn is_hold_invoice_accepted(
+        &self,
+        invoice: &Invoice,
+    ) -> Result<bool, LoopOutServiceError> {
+        let payment_hash = Self::decode_hex32(&invoice.payment_hash)?;
+        let lndg = self.lnd_gateway.lock().await;
+        let res = lndg.lookup_invoice(&payment_hash).await;
+        mem::drop(lndg);
+
+        let ln_invoice = res
+            .map_err(|e| LoopOutServiceError::new(format!("error looking up invoice: {:?}", e)))?;
+
+        Ok(InvoiceState::from_i32(ln_invoice.state) == Some(InvoiceState::Accepted))
+    }
+
+    // cancel_late_payment cancels the swap invoice of a loop out paid too close to its HTLC's expiry, moves the loop
+    // out to EXPIRED and returns true if it did.
+    async fn cancel_late_payment(
+        &self,
+        conn: &mut db::PooledConnection,
+        data: &FullLoopOutData,
+    ) -> Result<bool, LoopOutServiceError> {
+        let wallet = self.wallet.lock().await;
+        let curr_height = (*wallet).get_height().map_err(|e| {
+            LoopOutServiceError::new(format!("error getting wallet height: {:?}", e))
+        })?;
+        mem::drop(wallet);
+
+        // leave the buyer at least half of loopout.cltv to confirm the HTLC and claim it. A swap invoice the buyer
+        // already paid can't be cancelled, so it's funded regardless.
+        let cltv_delta = self.cfg.cltv_delta as u32;
+        if curr_height + cltv_delta / 2 >= data.script.cltv_expiry as u32
+            && data.invoice.state != models::InvoiceState::Settled
+        {
+            log::warn!(
+                "loop out {} paid too close to htlc expiry {}, cancelling",
+                data.loop_out.id,
+                data.script.cltv_expiry
+            );
+            self.cancel_swap_invoice(&data.invoice).await?;
+            db::update_loop_out_state(
+                conn,
+                data.loop_out.id,
+                models::LoopOutState::Expired,
+                "swap invoice paid too close to htlc expiry",
+            )
+            .map_err(|e| {
+                LoopOutServiceError::new(format!(
+                    "error updating loop out {} state: {:?}",
+                    data.loop_out.id, e
+                ))
+            })?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    // fund_batch funds the HTLCs of batch in a single tx. If that tx can't be built or stored, the batch is split in
+    // halves that are funded on their own, so a loop out that can't be funded is left for the next batch instead of
+    // holding up the others.
+    async fn fund_batch(
+        &self,
+        conn: &mut db::PooledConnection,
+        batch: Vec<FullLoopOutData>,
+    ) -> Result<(), LoopOutServiceError> {
+        let fee_rate = Self::estimate_fee_rate().await?;
+
+        let mut pending = vec![batch];
+        while let Some(mut loop_outs) = pending.pop() {
+            let (tx, utxos) = match self.store_funding(conn, &loop_outs, &fee_rate).await {
+                Ok(funding) => funding,
+                Err(e) if loop_outs.len() > 1 => {
+                    log::warn!(
+                        "error funding batch of {} loop outs, splitting it: {:?}",
+                        loop_outs.len(),
+                        e
+                    );
+                    let rest = loop_outs.split_off(loop_outs.len() / 2);
+                    pending.push(rest);
+                    pending.push(loop_outs);
+                    continue;
+                }
+                Err(e) => {
+                    log::error!(
+                        "error funding loop out {}, leaving it for the next batch: {:?}",
+                        loop_outs[0].loop_out.id,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.broadcast_funding(&tx, &loop_outs, &utxos).await {
+                log::error!("error broadcasting batch funding tx {}: {:?}", tx.txid(), e);
+            }
+        }
+
+        Ok(())
+    }
 
-        Ok(Self {
-            cfg: LoopOutConfig {
-                min_amount,
-                max_amount,
-                cltv_delta,
-                fee_pct,
-            },
-            db,
-            secp256k1: Secp256k1::new(),
-            network: wallet.get_network(),
-            wallet: Mutex::new(wallet),
-            lnd_gateway: Mutex::new(lnd_gateway),
-        })
+    // fund_loop_outs funds the HTLCs of loop_outs in a single tx with one output each, and stores the funding before
+    // broadcasting it.
+    async fn fund_loop_outs(
+        &self,
+        conn: &mut db::PooledConnection,
+        loop_outs: &[FullLoopOutData],
+    ) -> Result<(), LoopOutServiceError> {
+        let fee_rate = Self::estimate_fee_rate().await?;
+        let (tx, utxos) = self.store_funding(conn, loop_outs, &fee_rate).await?;
+
+        self.broadcast_funding(&tx, loop_outs, &utxos).await
     }
 
-    pub fn get_loop_out(
+    // store_funding builds a tx paying the HTLC of each of loop_outs and stores it as their funding, moving them to
+    // INITIATED. The tx's miner fee is split evenly between the loop outs. Nothing is stored if it fails.
+    async fn store_funding(
         &self,
-        payment_hash: String,
-    ) -> Result<FullLoopOutData, LoopOutServiceError> {
-        let conn = &mut self.db.get_conn().map_err(|e| {
-            LoopOutServiceError::new(format!("error getting db connection: {:?}", e))
+        conn: &mut db::PooledConnection,
+        loop_outs: &[FullLoopOutData],
+        fee_rate: &FeeRate,
+    ) -> Result<(bitcoin::Transaction, Vec<Utxo>), LoopOutServiceError> {
+        let recipients: Vec<(&str, u64)> = loop_outs
+            .iter()
+            .map(|data| (data.script.address.as_str(), data.loop_out.amount as u64))
+            .collect();
+        let send_tx = self.build_tx_to_addresses(&recipients, fee_rate).await?;
+        let txid = send_tx.tx.txid().to_string();
+        let raw_tx = encode::serialize_hex(&send_tx.tx);
+        let fundings = loop_outs
+            .iter()
+            .zip(send_tx.vouts.iter())
+            .map(|(data, vout)| {
+                (
+                    data.loop_out.id,
+                    Self::new_funding_utxo(
+                        data.script.id,
+                        &txid,
+                        *vout,
+                        &raw_tx,
+                        data.loop_out.amount,
+                    ),
+                )
+            })
+            .collect();
+        let utxos = db::insert_loop_out_fundings(
+            conn,
+            fundings,
+            send_tx.fee as i64 / loop_outs.len() as i64,
+            send_tx.fee_rate.as_sat_per_vb() as f64,
+        )
+        .map_err(|e| {
+            LoopOutServiceError::new(format!("error inserting loop out funding: {:?}", e))
         })?;
 
-        db::get_full_loop_out(conn, payment_hash).map_err(|e| {
-            LoopOutServiceError::new(format!("error getting loop_out from db: {:?}", e))
-        })
+        Ok((send_tx.tx, utxos))
     }
 
-    pub async fn handle_loop_out_request(
+    // broadcast_funding broadcasts the stored funding tx of loop_outs, utxos being their HTLCs. If it fails, the tx
+    // is rebroadcast on restart.
+    async fn broadcast_funding(
         &self,
-        pubkey: String,
-        amount: i64,
-    ) -> Result<FullLoopOutData, LoopOutServiceError> {
-        self.validate_amount(amount)?;
-        self.validate_pubkey(&pubkey)?;
-        log::info!("validated request");
-        let buyer_pubkey: XOnlyPublicKey = XOnlyPublicKey::from_str(&pubkey).map_err(|e| {
-            LoopOutServiceError::new(format!("error converting pubkey to XOnlyPublicKey: {}", e))
-        })?;
-        let fee = self.calculate_loop_out_fee(&amount);
-        let invoice_amount = amount + fee;
+        tx: &bitcoin::Transaction,
+        loop_outs: &[FullLoopOutData],
+        utxos: &[Utxo],
+    ) -> Result<(), LoopOutServiceError> {
+        self.broadcast_tx(tx).await?;
+        for (data, utxo) in loop_outs.iter().zip(utxos.iter()) {
+            log::info!(
+                "funded loop out {} htlc {}:{}",
+                data.loop_out.id,
+                utxo.txid,
+                utxo.vout
+            );
+        }
 
-        let conn = &mut self.db.get_conn().map_err(|e| {
-            LoopOutServiceError::new(format!("error getting db connection: {:?}", e))
+        Ok(())
+    }
+
+    async fn cancel_swap_invoice(&self, invoice: &Invoice) -> Result<(), LoopOutServiceError> {
+        let payment_hash = Self::decode_hex32(&invoice.payment_hash)?;
+        let lndg = self.lnd_gateway.lock().await;
+        let res = lndg.cancel_invoice(&payment_hash).await;
+        mem::drop(lndg);
+
+        res.map_err(|e| LoopOutServiceError::new(format!("error cancelling invoice: {:?}", e)))
+    }
+
+    /// run_timeout_sweeper sweeps the HTLC of every funded loop out back to the wallet through the timeout leaf once
+    /// its cltv_expiry is reached, and moves the loop out to TIMEOUT. Sweeps that are dropped are broadcast again, and
+    /// a held payment is only cancelled once the sweep has loopout.confs confirmations. It never returns.
+    pub async fn run_timeout_sweeper(&self) {
+        loop {
+            if let Err(e) = self.check_timeouts().await {
+                log::error!("error sweeping timed out loop outs: {:?}", e);
+            }
+
+            tokio::time::sleep(CHAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn check_timeouts(&self) -> Result<(), LoopOutServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let wallet = self.wallet.lock().await;
+        let curr_height = (*wallet).get_height().map_err(|e| {
+            LoopOutServiceError::new(format!("error getting wallet height: {:?}", e))
         })?;
+        mem::drop(wallet);
 
-        let loop_out = self.add_loop_out(conn)?;
+        let loop_outs = self.list_funded_loop_outs(conn)?;
+        for data in loop_outs {
+            // a tx with locktime cltv_expiry can be mined in block cltv_expiry, so it's relayed once the tip is one
+            // block short of it.
+            if curr_height + 1 < data.script.cltv_expiry as u32 {
+                continue;
+            }
 
-        let invoice = self.add_invoice(conn, &loop_out.id, invoice_amount).await?;
+            let spend = match self.sweep_htlc(&data).await {
+                Ok(spend) => spend,
+                Err(e) => {
+                    log::error!(
+                        "error sweeping htlc for loop out {}: {:?}",
+                        data.loop_out.id,
+                        e
+                    );
+                    continue;
+                }
+            };
 
-        let script = self
-            .add_onchain_htlc(conn, &loop_out.id, &buyer_pubkey, &invoice.payment_hash)
-            .await?;
+            match spend {
+                HtlcSpend::Timeout { txid } if Self::is_swept_by(&data, &txid) => {
+                    if let Err(e) = self.confirm_timeout_sweep(conn, &data, txid).await {
+                        log::error!(
+                            "error confirming timeout sweep for loop out {}: {:?}",
+                            data.loop_out.id,
+                            e
+                        );
+                    }
+                }
+                spend => {
+                    let loop_out_id = data.loop_out.id;
+                    if let Err(e) = self.record_htlc_spend(conn, data, spend).await {
+                        log::error!(
+                            "error recording htlc spend for loop out {}: {:?}",
+                            loop_out_id,
+                            e
+                        );
+                    }
+                }
+            }
+        }
 
-        // TODO: save to dB BEFORE broadcasting tx
-        let (utxo, tx) = self
-            .add_utxo_to_htlc(conn, script.id, &script.address, amount as u64)
-            .await?;
+        Ok(())
+    }
+
+    // confirm_timeout_sweep stops watching a swept HTLC once the sweep has loopout.confs confirmations. Until then the
+    // buyer of a hold invoice, who knows the preimage, can still claim the HTLC by replacing the sweep, so the held
+    // payment is only failed back now. The prepay, if any, was settled before the HTLC was funded and is kept.
+    async fn confirm_timeout_sweep(
+        &self,
+        conn: &mut db::PooledConnection,
+        data: &FullLoopOutData,
+        txid: Txid,
+    ) -> Result<(), LoopOutServiceError> {
+        let wallet = self.wallet.lock().await;
+        let confs = (*wallet).get_tx_confirmations(&txid);
+        mem::drop(wallet);
 
-        let full_loop_out_data = db::new_full_loop_out_data(loop_out, invoice, script, utxo);
+        let confs = confs.map_err(|e| {
+            LoopOutServiceError::new(format!("error getting confirmations for {}: {:?}", txid, e))
+        })?;
+        if confs < self.cfg.target_confs {
+            log::debug!(
+                "loop out {} timeout sweep {} has {}/{} confirmations",
+                data.loop_out.id,
+                txid,
+                confs,
+                self.cfg.target_confs
+            );
+            return Ok(());
+        }
 
-        match self.broadcast_tx(&tx).await {
-            Ok(_) => {
-                log::info!("broadcasted tx");
-                Ok(full_loop_out_data)
+        if data.invoice.is_hold && data.invoice.state == models::InvoiceState::Accepted {
+            if let Err(e) = self.cancel_swap_invoice(&data.invoice).await {
+                log::error!(
+                    "error cancelling hold invoice {}: {:?}",
+                    data.invoice.payment_hash,
+                    e
+                );
             }
-            Err(e) => Err(e),
         }
+
+        db::confirm_loop_out_timeout(conn, data.loop_out.id).map_err(|e| {
+            LoopOutServiceError::new(format!(
+                "error confirming loop out {} timeout: {:?}",
+                data.loop_out.id, e
+            ))
+        })?;
+        log::info!(
+            "loop out {} timeout sweep {} confirmed with {} confirmations",
+            data.loop_out.id,
+            txid,
+            confs
+        );
+
+        Ok(())
+    }
+
+    // is_swept_by reports whether txid is the timeout sweep already recorded for the loop out.
+    fn is_swept_by(data: &FullLoopOutData, txid: &Txid) -> bool {
+        (data.loop_out.state == models::LoopOutState::Timeout
+            || data.loop_out.state == models::LoopOutState::Expired)
+            && data.loop_out.timeout_txid == Some(txid.to_string())
+    }
+
+    // sweep_htlc broadcasts the timeout spend of an expired HTLC. If the HTLC has already been spent, e.g. by a
+    // claim or by a sweep broadcast before a restart, that spend is returned instead.
+    async fn sweep_htlc(&self, data: &FullLoopOutData) -> Result<HtlcSpend, LoopOutServiceError> {
+        if let Some(spend) = self.find_htlc_spend(data).await? {
+            return Ok(spend);
+        }
+
+        let scripts = Self::htlc_scripts(&data.script)?;
+        let htlc = Self::rebuild_htlc(data, &scripts)?;
+
+        let fee_rate = Self::estimate_fee_rate().await?;
+
+        let wallet = self.wallet.lock().await;
+        let tx = (*wallet)
+            .new_timeout_sweep(
+                &htlc,
+                &scripts.timeout_script,
+                data.script.cltv_expiry as u32,
+                data.script.local_pubkey_index as u32,
+                &fee_rate,
+            )
+            .map_err(|e| {
+                LoopOutServiceError::new(format!("error building timeout sweep: {:?}", e))
+            })?;
+        mem::drop(wallet);
+
+        self.broadcast_tx(&tx).await?;
+        log::info!(
+            "swept htlc {} for loop out {} in {}",
+            htlc.outpoint,
+            data.loop_out.id,
+            tx.txid()
+        );
+
+        Ok(HtlcSpend::Timeout { txid: tx.txid() })
     }
 
-    // unused for now, but a first attempt at db transactions
-    #[allow(dead_code)]
-    async fn do_loop_out_request(
+    fn list_funded_loop_outs(
         &self,
         conn: &mut db::PooledConnection,
-        utxo_amount: i64,
-        _fee: i64,
-        invoice_amount: i64,
-        buyer_pubkey: XOnlyPublicKey,
-    ) -> Result<FullLoopOutData, LoopOutServiceError> {
-        let loop_out = self.add_loop_out(conn)?;
+    ) -> Result<Vec<FullLoopOutData>, LoopOutServiceError> {
+        let loop_outs = db::list_full_loop_outs_in_states(
+            conn,
+            vec![
+                models::LoopOutState::Initiated,
+                models::LoopOutState::Confirmed,
+                models::LoopOutState::Timeout,
+                models::LoopOutState::Expired,
+            ],
+        )
+        .map_err(|e| {
+            LoopOutServiceError::new(format!("error listing funded loop outs: {:?}", e))
+        })?;
 
-        let invoice = self.add_invoice(conn, &loop_out.id, invoice_amount).await?;
+        // swept HTLCs are watched until the sweep is buried, since the buyer can still claim them until then. Expired
+        // loop outs are only watched if their HTLC was funded.
+        Ok(loop_outs
+            .into_iter()
+            .filter(|data| match data.loop_out.state {
+                models::LoopOutState::Timeout => data.loop_out.timeout_confirmed_at.is_none(),
+                models::LoopOutState::Expired => {
+                    data.utxo.is_some() && data.loop_out.timeout_confirmed_at.is_none()
+                }
+                _ => true,
+            })
+            .collect())
+    }
 
-        let script = self
-            .add_onchain_htlc(conn, &loop_out.id, &buyer_pubkey, &invoice.payment_hash)
-            .await?;
+    // find_htlc_spend returns how the HTLC has been spent, or None if it's still unspent.
+    async fn find_htlc_spend(
+        &self,
+        data: &FullLoopOutData,
+    ) -> Result<Option<HtlcSpend>, LoopOutServiceError> {
+        let outpoint = Self::htlc_outpoint(data)?;
+        let scripts = Self::htlc_scripts(&data.script)?;
+        let from_height =
+            (data.script.cltv_expiry as u32).saturating_sub(self.cfg.cltv_delta as u32);
 
-        // TODO: save to dB BEFORE broadcasting tx
-        let (utxo, tx) = self
-            .add_utxo_to_htlc(conn, script.id, &script.address, utxo_amount as u64)
-            .await?;
+        let wallet = self.wallet.lock().await;
+        let spent = (*wallet).is_output_spent(&outpoint).map_err(|e| {
+            LoopOutServiceError::new(format!("error checking htlc {}: {:?}", outpoint, e))
+        })?;
+        mem::drop(wallet);
+        if !spent {
+            return Ok(None);
+        }
+        let spending_tx = self
+            .spends
+            .lock()
+            .await
+            .find_spending_tx(&self.wallet, &outpoint, from_height)
+            .await
+            .map_err(|e| {
+                LoopOutServiceError::new(format!("error finding htlc {} spend: {:?}", outpoint, e))
+            })?;
 
-        let full_loop_out_data = db::new_full_loop_out_data(loop_out, invoice, script, utxo);
+        let spending_tx = match spending_tx {
+            Some(tx) => tx,
+            None => return Ok(None),
+        };
+        let txid = spending_tx.txid();
+        let txin = match spending_tx
+            .input
+            .iter()
+            .find(|txin| txin.previous_output == outpoint)
+        {
+            Some(txin) => txin,
+            None => return Ok(None),
+        };
 
-        match self.broadcast_tx(&tx).await {
-            Ok(_) => {
-                log::info!("broadcasted tx");
-                Ok(full_loop_out_data)
-            }
-            Err(e) => Err(e),
+        if let Some(preimage) = LooperWallet::extract_htlc_preimage(
+            &txin.witness,
+            &scripts.htlc_script,
+            &scripts.payment_hash,
+        ) {
+            return Ok(Some(HtlcSpend::Claim { txid, preimage }));
         }
+        if LooperWallet::is_timeout_spend(&txin.witness, &scripts.timeout_script) {
+            return Ok(Some(HtlcSpend::Timeout { txid }));
+        }
+        // only a musig internal key can be spent through the key path, and we only sign for claims
+        if data.script.is_musig && LooperWallet::is_key_spend(&txin.witness) {
+            return Ok(Some(HtlcSpend::Cooperative { txid }));
+        }
+
+        log::warn!(
+            "htlc {} spent by {} through an unknown path",
+            outpoint,
+            txid
+        );
+        Ok(None)
     }
 
-    fn add_loop_out(
+    async fn record_htlc_spend(
         &self,
         conn: &mut db::PooledConnection,
-    ) -> Result<models::LoopOut, LoopOutServiceError> {
-        let new_loop_out = models::NewLoopOut {
-            state: models::LOOP_OUT_STATE_INITIATED.to_string(),
+        data: FullLoopOutData,
+        spend: HtlcSpend,
+    ) -> Result<(), LoopOutServiceError> {
+        // a sweep we already recorded, see confirm_timeout_sweep
+        if let HtlcSpend::Timeout { txid } = &spend {
+            if Self::is_swept_by(&data, txid) {
+                return Ok(());
+            }
+        }
+
+        let loop_out_id = data.loop_out.id;
+        let mut loop_out = data.loop_out;
+        let reason = match spend {
+            HtlcSpend::Claim { txid, .. } => {
+                loop_out.state = models::LoopOutState::Claimed;
+                loop_out.claim_txid = Some(txid.to_string());
+                format!("htlc claimed by {}", txid)
+            }
+            HtlcSpend::Cooperative { txid } => {
+                loop_out.state = models::LoopOutState::Claimed;
+                loop_out.claim_txid = Some(txid.to_string());
+                format!("htlc claimed cooperatively by {}", txid)
+            }
+            HtlcSpend::Timeout { txid } => {
+                // EXPIRED is terminal, the sweep only recovers the funds
+                if loop_out.state != models::LoopOutState::Expired {
+                    loop_out.state = models::LoopOutState::Timeout;
+                }
+                loop_out.timeout_txid = Some(txid.to_string());
+                format!("htlc swept by timeout tx {}", txid)
+            }
         };
+        let new_state = loop_out.state;
+        db::update_loop_out(conn, loop_out, &reason).map_err(|e| {
+            LoopOutServiceError::new(format!(
+                "error updating loop out {} to {}: {:?}",
+                loop_out_id, new_state, e
+            ))
+        })?;
+
+        match spend {
+            HtlcSpend::Claim { preimage, .. } => {
+                let mut invoice = data.invoice;
+                if invoice.payment_preimage.is_none() {
+                    invoice.payment_preimage = Some(hex::encode(preimage));
+                    db::update_invoice(conn, invoice).map_err(|e| {
+                        LoopOutServiceError::new(format!("error saving preimage: {:?}", e))
+                    })?;
+                }
+            }
+            // a held payment is failed back once the sweep is buried, see confirm_timeout_sweep. The preimage of a
+            // cooperative claim was saved when we signed it.
+            HtlcSpend::Timeout { .. } | HtlcSpend::Cooperative { .. } => {}
+        }
+
+        log::info!("loop out {} moved to {}", loop_out_id, new_state);
+
+        Ok(())
+    }
+
+    // rebuild_htlc rebuilds the funded HTLC, including its taproot tree, from the stored loop out.
+    fn rebuild_htlc(
+        data: &FullLoopOutData,
+        scripts: &HtlcScripts,
+    ) -> Result<HtlcUtxo, LoopOutServiceError> {
+        let internal_tapkey =
+            XOnlyPublicKey::from_str(&data.script.internal_tapkey).map_err(|e| {
+                LoopOutServiceError::new(format!("error parsing internal_tapkey: {:?}", e))
+            })?;
+        let spend_info = LooperWallet::build_taproot(
+            &scripts.htlc_script,
+            &scripts.timeout_script,
+            internal_tapkey,
+        )
+        .map_err(|e| LoopOutServiceError::new(format!("error rebuilding htlc tree: {:?}", e)))?;
+
+        Ok(HtlcUtxo {
+            outpoint: Self::htlc_outpoint(data)?,
+            amount: Self::htlc_utxo(data)?.amount as u64,
+            spend_info,
+        })
+    }
+
+    fn htlc_utxo(data: &FullLoopOutData) -> Result<&Utxo, LoopOutServiceError> {
+        data.utxo.as_ref().ok_or_else(|| {
+            LoopOutServiceError::new(format!("loop out {} has no htlc utxo", data.loop_out.id))
+        })
+    }
+
+    fn htlc_outpoint(data: &FullLoopOutData) -> Result<OutPoint, LoopOutServiceError> {
+        let utxo = Self::htlc_utxo(data)?;
+        let txid = Txid::from_str(&utxo.txid).map_err(|e| {
+            LoopOutServiceError::new(format!("error parsing txid {}: {:?}", utxo.txid, e))
+        })?;
+
+        Ok(OutPoint {
+            txid,
+            vout: utxo.vout as u32,
+        })
+    }
+
+    // htlc_scripts rebuilds both leaves of the HTLC tree from the stored script.
+    fn htlc_scripts(script: &Script) -> Result<HtlcScripts, LoopOutServiceError> {
+        let buyer_pubkey = XOnlyPublicKey::from_str(&script.remote_pubkey).map_err(|e| {
+            LoopOutServiceError::new(format!("error parsing remote_pubkey: {:?}", e))
+        })?;
+        let looper_pubkey = XOnlyPublicKey::from_str(&script.local_pubkey).map_err(|e| {
+            LoopOutServiceError::new(format!("error parsing local_pubkey: {:?}", e))
+        })?;
+        let payment_hash = Self::decode_hex32(&script.payment_hash)?;
+        let locktime = LockTime::from_height(script.cltv_expiry as u32)
+            .map_err(|e| LoopOutServiceError::new(format!("error parsing cltv_expiry: {:?}", e)))?;
 
-        db::insert_loop_out(conn, new_loop_out).map_err(|e| {
-            LoopOutServiceError::new(format!("error inserting loop_out into db: {:?}", e))
+        Ok(HtlcScripts {
+            htlc_script: LooperWallet::new_htlc_script(&buyer_pubkey, &payment_hash),
+            timeout_script: LooperWallet::new_timeout_script(looper_pubkey, locktime),
+            payment_hash,
         })
     }
 
-    async fn add_invoice(
+    fn decode_hex32(value: &str) -> Result<[u8; 32], LoopOutServiceError> {
+        utils::decode_hex32(value)
+            .map_err(|e| LoopOutServiceError::new(format!("error decoding {}: {:?}", value, e)))
+    }
+
+    fn get_conn(&self) -> Result<db::PooledConnection, LoopOutServiceError> {
+        self.db
+            .get_conn()
+            .map_err(|e| LoopOutServiceError::new(format!("error getting db connection: {:?}", e)))
+    }
+
+    async fn new_invoice(
         &self,
-        conn: &mut db::PooledConnection,
-        loop_out_id: &i64,
         amount: i64,
-    ) -> Result<Invoice, LoopOutServiceError> {
-        log::info!("adding invoice...");
+        kind: &str,
+    ) -> Result<AddInvoiceResp, LoopOutServiceError> {
+        log::info!("adding {} invoice...", kind);
+        let memo = match kind {
+            models::INVOICE_KIND_PREPAY => "looper swap out prepay",
+            _ => "looper swap out",
+        };
         let lndg = self.lnd_gateway.lock().await;
         let invoice = lndg
-            .add_invoice(amount)
+            .add_invoice(amount, memo)
             .await
             .map_err(|e| LoopOutServiceError::new(format!("error adding invoice: {:?}", e)))?;
         mem::drop(lndg);
         log::info!("added invoice: {:?}", invoice.payment_hash);
 
-        let new_invoice = NewInvoice {
-            loop_out_id: *loop_out_id,
+        Ok(invoice)
+    }
+
+    async fn new_hold_invoice(
+        &self,
+        amount: i64,
+        payment_hash: &str,
+    ) -> Result<AddInvoiceResp, LoopOutServiceError> {
+        log::info!("adding hold invoice...");
+        let payhash_bytes = Self::decode_hex32(payment_hash)?;
+        // the final hop's HTLC must outlive the on-chain HTLC so we can still settle after the buyer claims.
+        let final_cltv = self.cfg.cltv_delta + HOLD_INVOICE_CLTV_BUFFER;
+        let lndg = self.lnd_gateway.lock().await;
+        let invoice = lndg
+            .add_hold_invoice(amount, &payhash_bytes, final_cltv)
+            .await
+            .map_err(|e| LoopOutServiceError::new(format!("error adding hold invoice: {:?}", e)))?;
+        mem::drop(lndg);
+        log::info!("added hold invoice: {:?}", invoice.payment_hash);
+
+        Ok(invoice)
+    }
+
+    // new_invoice_row builds the db row for an invoice added to lnd. loop_out_id is set once the loop out is inserted.
+    fn new_invoice_row<'a>(
+        invoice: &'a AddInvoiceResp,
+        amount: i64,
+        is_hold: bool,
+        kind: &str,
+    ) -> NewInvoice<'a> {
+        NewInvoice {
+            loop_out_id: 0,
             payment_request: &invoice.invoice,
             payment_hash: &invoice.payment_hash,
-            payment_preimage: Some(&invoice.preimage),
+            payment_preimage: invoice.preimage.as_deref(),
             amount,
-            state: models::INVOICE_STATE_OPEN.to_string(),
-        };
-
-        db::insert_invoice(conn, new_invoice).map_err(|e| {
-            LoopOutServiceError::new(format!("error inserting invoice into db: {:?}", e))
-        })
+            state: models::InvoiceState::Open,
+            is_hold,
+            kind: kind.to_string(),
+        }
     }
 
-    async fn add_onchain_htlc(
+    async fn new_onchain_htlc(
         &self,
-        conn: &mut db::PooledConnection,
-        loop_out_id: &i64,
         buyer_pubkey: &XOnlyPublicKey,
-        payment_hash: &String,
-    ) -> Result<Script, LoopOutServiceError> {
+        payment_hash: &str,
+        musig: bool,
+    ) -> Result<OnchainHtlc, LoopOutServiceError> {
+        let looper_pubkey_idx = db::next_htlc_key_index(&mut self.get_conn()?).map_err(|e| {
+            LoopOutServiceError::new(format!("error reserving htlc key index: {:?}", e))
+        })?;
         // Lock wallet here and get all necessary info
         let wallet = self.wallet.lock().await;
-        // create new pubkey
-        // TODO: use max local_pubkey_index from db
-        let (looper_pubkey, looper_pubkey_idx) = (*wallet).new_pubkey().map_err(|e| {
+        let looper_pubkey = (*wallet).get_pubkey(looper_pubkey_idx).map_err(|e| {
             LoopOutServiceError::new(format!("error generating new pubkey: {:?}", e))
         })?;
-        // TODO: sync here to get proper height?
+        // the height comes from bitcoind, so it doesn't wait on a wallet sync
         let curr_height = (*wallet).get_height().map_err(|e| {
             LoopOutServiceError::new(format!("error getting wallet height: {:?}", e))
         })?;
@@ -236,107 +2237,90 @@ impl LoopOutService {
         let cltv_delta: u32 = self.cfg.cltv_delta.try_into().map_err(|e| {
             LoopOutServiceError::new(format!("error converting cltv_delta to u32: {}", e))
         })?;
-        // TODO: currently unused. bad
-        let cltv_expiry_i32 = curr_height + cltv_delta;
+        let cltv_expiry = curr_height + cltv_delta;
         mem::drop(wallet);
         // Unlock wallet
 
-        let mut payhash_bytes = [0u8; 32];
-        hex::decode_to_slice(payment_hash, &mut payhash_bytes as &mut [u8]).map_err(|e| {
-            LoopOutServiceError::new(format!("error decoding payment_hash: {:?}", e))
-        })?;
+        let payhash_bytes = Self::decode_hex32(payment_hash)?;
 
-        let (tr, tweak) = LooperWallet::new_htlc(
-            *buyer_pubkey,
-            looper_pubkey,
-            &payhash_bytes,
-            cltv_expiry_i32,
-        )
+        // a musig internal key has no tweak to reveal, the buyer can rebuild it from both pubkeys
+        let (tr, tweak) = match musig {
+            true => LooperWallet::new_musig_htlc(
+                *buyer_pubkey,
+                looper_pubkey,
+                &payhash_bytes,
+                cltv_expiry,
+            )
+            .map(|tr| (tr, String::new())),
+            false => {
+                LooperWallet::new_htlc(*buyer_pubkey, looper_pubkey, &payhash_bytes, cltv_expiry)
+                    .map(|(tr, tweak)| (tr, hex::encode(tweak.secret_bytes())))
+            }
+        }
         .map_err(|e| LoopOutServiceError::new(format!("error creating htlc: {:?}", e)))?;
 
         let address = self.p2tr_address(&tr);
 
-        let cltv_expiry_u32 = cltv_expiry_i32.try_into().map_err(|e| {
-            LoopOutServiceError::new(format!("error converting cltv_expiry to u32: {}", e))
+        let cltv_expiry = cltv_expiry.try_into().map_err(|e| {
+            LoopOutServiceError::new(format!("error converting cltv_expiry to i32: {}", e))
         })?;
 
-        // TODO: factor into function
-        let new_script = NewScript {
-            loop_out_id: *loop_out_id,
-            address: &address.to_string(),
-            external_tapkey: &tr.output_key().to_string(),
-            internal_tapkey: &tr.internal_key().to_string(),
-            internal_tapkey_tweak: &hex::encode(tweak.secret_bytes()),
-            payment_hash,
+        Ok(OnchainHtlc {
+            address: address.to_string(),
+            external_tapkey: tr.output_key().to_string(),
+            internal_tapkey: tr.internal_key().to_string(),
+            internal_tapkey_tweak: tweak,
             tree: tree_to_vec(&tr),
-            cltv_expiry: cltv_expiry_u32,
+            cltv_expiry,
             remote_pubkey: buyer_pubkey.to_string(),
             local_pubkey: looper_pubkey.to_string(),
             local_pubkey_index: looper_pubkey_idx as i32,
-        };
-
-        log::info!("adding script...");
-        let res = db::insert_script(conn, new_script).map_err(|e| {
-            LoopOutServiceError::new(format!("error inserting script into db: {:?}", e))
-        });
-
-        log::info!("added script");
-
-        res
+            is_musig: musig,
+        })
     }
 
-    async fn add_utxo_to_htlc(
-        &self,
-        conn: &mut db::PooledConnection,
+    // new_funding_utxo builds the db row for a funding tx paying the HTLC. The raw tx is stored before it is
+    // broadcast, so it can be found and rebroadcast if we crash in between.
+    fn new_funding_utxo<'a>(
         script_id: i64,
-        address: &str,
-        amount: u64,
-    ) -> Result<(Utxo, bitcoin::Transaction), LoopOutServiceError> {
-        log::info!("adding utxo...");
-        let tx = self.build_tx_to_address(address, amount).await?;
-        let txid = tx.txid();
-        let amount = amount.try_into().map_err(|e| {
-            LoopOutServiceError::new(format!("error converting amount to u64: {}", e))
-        })?;
-        let utxo = NewUTXO {
-            txid: &txid.to_string(),
-            // TODO: fix
-            vout: 0,
+        txid: &'a str,
+        vout: u32,
+        raw_tx: &'a str,
+        amount: i64,
+    ) -> NewUTXO<'a> {
+        NewUTXO {
+            txid,
+            vout: vout as i32,
             amount,
             script_id,
-        };
-        let utxo = db::insert_utxo(conn, utxo).map_err(|e| {
-            LoopOutServiceError::new(format!("error inserting utxo into db: {:?}", e))
-        })?;
-
-        log::info!("added utxo {:?}:{:?}", utxo.txid, utxo.vout);
-
-        Ok((utxo, tx))
+            raw_tx: Some(raw_tx),
+        }
     }
 
-    async fn build_tx_to_address(
+    async fn build_tx_to_addresses(
         &self,
-        address: &str,
-        amount: u64,
-    ) -> Result<bitcoin::Transaction, LoopOutServiceError> {
+        recipients: &[(&str, u64)],
+        fee_rate: &FeeRate,
+    ) -> Result<SendTx, LoopOutServiceError> {
         let wallet = self.wallet.lock().await;
-        log::info!("estimating fee rate...");
-        let fee_rate = mempool::get_mempool_fee_rate(mempool::MempoolFeePriority::Blocks6)
-            .await
-            .map_err(|e| LoopOutServiceError::new(format!("error estimating fee rate: {:?}", e)))?;
-
         log::info!("building tx...");
-        let tx = &wallet
-            .send_to_address(address, amount, &fee_rate)
+        let send_tx = wallet
+            .send_to_addresses(recipients, fee_rate)
             .map_err(|e| {
                 LoopOutServiceError::new(format!(
-                    "error building tx to address {}: {:?}",
-                    address, e
+                    "error building tx to {} addresses: {:?}",
+                    recipients.len(),
+                    e
                 ))
             })?;
         mem::drop(wallet);
-        log::info!("built tx");
-        Ok(tx.clone())
+        log::info!(
+            "built tx {} paying {} htlcs with fee {}",
+            send_tx.tx.txid(),
+            recipients.len(),
+            send_tx.fee
+        );
+        Ok(send_tx)
     }
 
     async fn broadcast_tx(&self, tx: &bitcoin::Transaction) -> Result<(), LoopOutServiceError> {
@@ -360,8 +2344,11 @@ impl LoopOutService {
         )
     }
 
-    fn calculate_loop_out_fee(&self, amount: &i64) -> i64 {
-        amount * self.cfg.fee_pct / 100
+    async fn estimate_fee_rate() -> Result<FeeRate, LoopOutServiceError> {
+        log::info!("estimating fee rate...");
+        mempool::get_mempool_fee_rate(mempool::MempoolFeePriority::Blocks6)
+            .await
+            .map_err(|e| LoopOutServiceError::new(format!("error estimating fee rate: {:?}", e)))
     }
 
     pub fn validate_amount(&self, amount: i64) -> Result<(), LoopOutServiceError> {
@@ -372,6 +2359,21 @@ impl LoopOutService {
         if amount > self.cfg.max_amount {
             return Err(LoopOutServiceError::new("amount too high".to_string()));
         }
+
+        // the swap invoice must still be worth something once the prepay is deducted. Any miner fee only adds to it.
+        let fee = self
+            .fee_policy
+            .swap_fee(amount, &FeeRate::from_sat_per_vb(0.0), false);
+        if amount + fee <= self.cfg.prepay_amount {
+            return Err(LoopOutServiceError::new("amount too low".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn validate_batched(&self, batched: bool) -> Result<(), LoopOutServiceError> {
+        if batched && self.cfg.batch_window <= 0 {
+            return Err(LoopOutServiceError::new("batching is disabled".to_string()));
+        }
         Ok(())
     }
 
@@ -387,6 +2389,41 @@ impl LoopOutService {
     }
 }
 
+// OnchainHtlc is a new HTLC's script, kept in memory until it is inserted along with the rest of its loop out.
+struct OnchainHtlc {
+    address: String,
+    external_tapkey: String,
+    internal_tapkey: String,
+    internal_tapkey_tweak: String,
+    tree: Vec<String>,
+    cltv_expiry: i32,
+    remote_pubkey: String,
+    local_pubkey: String,
+    local_pubkey_index: i32,
+    is_musig: bool,
+}
+
+impl OnchainHtlc {
+    // new_script builds the db row for the HTLC. loop_out_id is set once the loop out is inserted.
+    fn new_script<'a>(&'a self, payment_hash: &'a str) -> NewScript<'a> {
+        NewScript {
+            loop_out_id: None,
+            address: &self.address,
+            external_tapkey: &self.external_tapkey,
+            internal_tapkey: &self.internal_tapkey,
+            internal_tapkey_tweak: &self.internal_tapkey_tweak,
+            payment_hash,
+            tree: self.tree.clone(),
+            cltv_expiry: self.cltv_expiry,
+            remote_pubkey: self.remote_pubkey.clone(),
+            local_pubkey: self.local_pubkey.clone(),
+            local_pubkey_index: self.local_pubkey_index,
+            is_musig: self.is_musig,
+            loop_in_id: None,
+        }
+    }
+}
+
 pub fn tree_to_vec(tsi: &TaprootSpendInfo) -> Vec<String> {
     let mut vec: Vec<String> = vec![];
     let iter = tsi.as_script_map().iter();
@@ -398,6 +2435,28 @@ pub fn tree_to_vec(tsi: &TaprootSpendInfo) -> Vec<String> {
     vec
 }
 
+// HtlcSpend is how a loop out HTLC left the chain: claimed by the buyer, claimed through the musig key path or swept
+// back to us after the timeout.
+enum HtlcSpend {
+    Claim { txid: Txid, preimage: [u8; 32] },
+    Cooperative { txid: Txid },
+    Timeout { txid: Txid },
+}
+
+// RecoveryStep is what recover_loop_out has to do on chain for a loop out that was in flight at startup.
+#[derive(Debug, PartialEq)]
+enum RecoveryStep {
+    RebroadcastFunding,
+    ReopenTimeout,
+    None,
+}
+
+struct HtlcScripts {
+    htlc_script: ScriptBuf,
+    timeout_script: ScriptBuf,
+    payment_hash: [u8; 32],
+}
+
 #[derive(Debug)]
 pub struct LoopOutServiceError {
     pub message: String,
@@ -408,3 +2467,240 @@ impl LoopOutServiceError {
         Self { message }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fee_schedule_swap_fee() {
+        let schedule = FeeSchedule {
+            base_fee: 100,
+            fee_ppm: 2_500,
+            pass_through_miner_fee: false,
+        };
+        let fee_rate = FeeRate::from_sat_per_vb(10.0);
+
+        // 0.25% of 1_000_000 plus the base fee
+        assert_eq!(schedule.swap_fee(1_000_000, &fee_rate, false), 2_600);
+        // the proportional fee rounds down
+        assert_eq!(schedule.swap_fee(399, &fee_rate, false), 100);
+
+        let schedule = FeeSchedule {
+            pass_through_miner_fee: true,
+            ..schedule
+        };
+        let miner_fee = (FUNDING_TX_VSIZE + TIMEOUT_SWEEP_VSIZE) as i64 * 10;
+        assert_eq!(
+            schedule.swap_fee(1_000_000, &fee_rate, false),
+            2_600 + miner_fee
+        );
+
+        // batched loop outs only pay for their own HTLC output
+        let miner_fee = (HTLC_OUTPUT_VSIZE + TIMEOUT_SWEEP_VSIZE) as i64 * 10;
+        assert_eq!(
+            schedule.swap_fee(1_000_000, &fee_rate, true),
+            2_600 + miner_fee
+        );
+    }
+
+    fn test_loop_out_data(id: i64, batched: bool, age_secs: i64) -> FullLoopOutData {
+        let now = chrono::Utc::now().naive_utc();
+        let created_at = now - chrono::Duration::seconds(age_secs);
+        FullLoopOutData {
+            loop_out: models::LoopOut {
+                id,
+                state: models::LoopOutState::AwaitingPayment,
+                created_at,
+                updated_at: created_at,
+                claim_txid: None,
+                timeout_txid: None,
+                amount: 100_000,
+                fee: 0,
+                miner_fee: None,
+                fee_rate: None,
+                batched,
+                webhook_url: None,
+                webhook_secret: None,
+                timeout_confirmed_at: None,
+            },
+            script: Script {
+                id,
+                loop_out_id: Some(id),
+                address: format!("test-address-{}", id),
+                external_tapkey: "test-external-tapkey".to_string(),
+                internal_tapkey: "test-internal-tapkey".to_string(),
+                internal_tapkey_tweak: "test-internal-tapkey-tweak".to_string(),
+                payment_hash: format!("test-payment-hash-{}", id),
+                tree: vec![],
+                cltv_expiry: 100,
+                remote_pubkey: "test-remote-pubkey".to_string(),
+                local_pubkey: "test-local-pubkey".to_string(),
+                local_pubkey_index: 0,
+                created_at,
+                updated_at: created_at,
+                is_musig: false,
+                loop_in_id: None,
+            },
+            utxo: None,
+            invoice: Invoice {
+                id,
+                loop_out_id: Some(id),
+                payment_request: format!("test-payment-request-{}", id),
+                payment_hash: format!("test-payment-hash-{}", id),
+                payment_preimage: None,
+                amount: 100_000,
+                state: models::InvoiceState::Settled,
+                created_at,
+                updated_at: created_at,
+                settle_index: None,
+                is_hold: false,
+                kind: models::INVOICE_KIND_SWAP.to_string(),
+            },
+            prepay_invoice: None,
+        }
+    }
+
+    fn loop_out_ids(loop_outs: &[FullLoopOutData]) -> Vec<i64> {
+        loop_outs.iter().map(|data| data.loop_out.id).collect()
+    }
+
+    #[test]
+    fn test_plan_fundings() {
+        let now = chrono::Utc::now().naive_utc();
+
+        // batched loop outs wait until the oldest has waited the batch window
+        let paid = vec![
+            test_loop_out_data(1, true, 30),
+            test_loop_out_data(2, false, 10),
+            test_loop_out_data(3, true, 50),
+        ];
+        let (unbatched, batch) = LoopOutService::plan_fundings(paid, 60, now);
+        assert_eq!(vec![2], loop_out_ids(&unbatched));
+        assert!(batch.is_empty());
+
+        // the whole batch is funded once the window expires, oldest first
+        let paid = vec![
+            test_loop_out_data(1, true, 30),
+            test_loop_out_data(2, false, 10),
+            test_loop_out_data(3, true, 61),
+            test_loop_out_data(4, false, 100),
+        ];
+        let (unbatched, batch) = LoopOutService::plan_fundings(paid, 60, now);
+        assert_eq!(vec![2, 4], loop_out_ids(&unbatched));
+        assert_eq!(vec![3, 1], loop_out_ids(&batch));
+
+        let (unbatched, batch) = LoopOutService::plan_fundings(vec![], 60, now);
+        assert!(unbatched.is_empty());
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn test_plan_fundings_caps_batch_size() {
+        let now = chrono::Utc::now().naive_utc();
+
+        let paid: Vec<FullLoopOutData> = (0..MAX_BATCH_SIZE as i64 + 5)
+            .map(|id| test_loop_out_data(id, true, 100 + id))
+            .collect();
+        let (unbatched, batch) = LoopOutService::plan_fundings(paid, 60, now);
+        assert!(unbatched.is_empty());
+        assert_eq!(MAX_BATCH_SIZE, batch.len());
+        // the newest wait for the next batch
+        assert_eq!(MAX_BATCH_SIZE as i64 + 4, batch[0].loop_out.id);
+        assert_eq!(5, batch[MAX_BATCH_SIZE - 1].loop_out.id);
+    }
+
+    #[test]
+    fn test_fee_schedule_from_config() {
+        let build = |overrides: &[(&str, &str)]| {
+            overrides
+                .iter()
+                .try_fold(settings::Config::builder(), |b, (k, v)| {
+                    b.set_override(*k, *v)
+                })
+                .and_then(|b| b.build())
+                .expect("failed to build config")
+        };
+
+        let schedule = FeeSchedule::from_config(&build(&[])).expect("failed to load defaults");
+        assert_eq!(0, schedule.base_fee);
+        assert_eq!(0, schedule.fee_ppm);
+        assert!(!schedule.pass_through_miner_fee);
+
+        let schedule = FeeSchedule::from_config(&build(&[("loopout.fee", "1")]))
+            .expect("failed to load legacy fee");
+        assert_eq!(10_000, schedule.fee_ppm);
+
+        // mistyped settings don't fall back to the defaults
+        assert!(FeeSchedule::from_config(&build(&[("loopout.fee_ppm", "a lot")])).is_err());
+        assert!(FeeSchedule::from_config(&build(&[("loopout.miner_fee", "maybe")])).is_err());
+    }
+
+    #[test]
+    fn test_liquidity_required() {
+        let fee_rate = FeeRate::from_sat_per_vb(10.0);
+
+        assert_eq!(
+            100_000 + (FUNDING_TX_VSIZE + TIMEOUT_SWEEP_VSIZE) as i64 * 10,
+            LoopOutService::liquidity_required(100_000, &fee_rate, false)
+        );
+        assert_eq!(
+            100_000 + (HTLC_OUTPUT_VSIZE + TIMEOUT_SWEEP_VSIZE) as i64 * 10,
+            LoopOutService::liquidity_required(100_000, &fee_rate, true)
+        );
+    }
+
+    #[test]
+    fn test_check_available_liquidity() {
+        // exactly what's left after the reservations
+        assert!(
+            LoopOutService::check_available_liquidity(100_000, 102_910, 200_000, 97_090).is_ok()
+        );
+
+        let err = LoopOutService::check_available_liquidity(100_000, 102_911, 200_000, 97_090)
+            .unwrap_err();
+        assert_eq!(services::INSUFFICIENT_LIQUIDITY, err.message);
+
+        // reservations can exceed the balance until the next sync releases them
+        assert!(LoopOutService::check_available_liquidity(1, 1, 100, 200).is_err());
+    }
+
+    #[test]
+    fn test_recovery_step() {
+        use models::LoopOutState::*;
+
+        let mut loop_out = test_loop_out_data(1, false, 0).loop_out;
+        for (state, step) in [
+            (AwaitingPayment, RecoveryStep::None),
+            (Initiated, RecoveryStep::RebroadcastFunding),
+            (Confirmed, RecoveryStep::None),
+            // never swept
+            (Timeout, RecoveryStep::None),
+            (Expired, RecoveryStep::None),
+        ] {
+            loop_out.state = state;
+            assert_eq!(step, LoopOutService::recovery_step(&loop_out));
+        }
+
+        loop_out.timeout_txid = Some("test-timeout-txid".to_string());
+        for state in [Timeout, Expired] {
+            loop_out.state = state;
+            assert_eq!(
+                RecoveryStep::ReopenTimeout,
+                LoopOutService::recovery_step(&loop_out)
+            );
+        }
+
+        // a buried sweep can't have been dropped
+        loop_out.timeout_confirmed_at = Some(chrono::Utc::now().naive_utc());
+        assert_eq!(RecoveryStep::None, LoopOutService::recovery_step(&loop_out));
+    }
+
+    #[test]
+    fn test_reopened_state() {
+        use models::LoopOutState::*;
+
+        assert_eq!(Confirmed, LoopOutService::reopened_state(Timeout));
+        assert_eq!(Expired, LoopOutService::reopened_state(Expired));
+    }
+}
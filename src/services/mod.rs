@@ -1,4 +1,9 @@
+pub mod loop_in;
 pub mod loop_out;
-// pub mod loop_in;
+pub mod webhook;
 
 pub const NOT_FOUND: &str = "not found";
+pub const INVALID_CLAIM: &str = "invalid claim";
+pub const INVALID_INVOICE: &str = "invalid invoice";
+pub const INVALID_QUOTE: &str = "invalid quote";
+pub const INSUFFICIENT_LIQUIDITY: &str = "insufficient liquidity";
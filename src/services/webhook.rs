@@ -0,0 +1,667 @@
+use futures::StreamExt;
+use reqwest::{redirect, Client, Url};
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use crate::{
+    db::{self, DB},
+    models::{
+        Invoice, LoopOut, LoopOutStateChange, NewWebhookDelivery, WebhookDelivery,
+        WebhookDeliveryState,
+    },
+    settings, utils,
+};
+
+// default number of times a delivery is attempted before it's marked FAILED. Override with webhook.max_attempts.
+pub const MAX_ATTEMPTS: i32 = 10;
+
+// default delay before the first retry, in seconds. Each retry waits twice as long as the last, up to
+// MAX_RETRY_DELAY_SECS. Override with webhook.retry_delay.
+pub const RETRY_DELAY_SECS: i64 = 10;
+
+const MAX_RETRY_DELAY_SECS: i64 = 3600;
+
+// how often the dispatcher looks for new state changes and due deliveries.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// how long a receiver has to respond.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how many state changes or deliveries are handled per poll.
+const BATCH_SIZE: i64 = 100;
+
+// how many deliveries are sent at once, so a few slow receivers don't hold up the rest.
+const MAX_CONCURRENT_DELIVERIES: usize = 10;
+
+// the header carrying the hex encoded HMAC-SHA256 of "<timestamp>.<body>", keyed with the webhook secret.
+pub const SIGNATURE_HEADER: &str = "X-Looper-Signature";
+
+// the header carrying the unix timestamp the request was signed at, so receivers can reject replayed requests.
+pub const TIMESTAMP_HEADER: &str = "X-Looper-Timestamp";
+
+// secrets are capped so they fit comfortably in a row.
+const MAX_SECRET_LEN: usize = 256;
+
+pub struct WebhookConfig {
+    // url, if set, is sent every state change of every loop out, signed with secret
+    pub url: Option<String>,
+    pub secret: Option<String>,
+    pub max_attempts: i32,
+    pub retry_delay: i64,
+}
+
+impl WebhookConfig {
+    /// from_config reads the webhook settings. Settings that are left out use their defaults and leaving out
+    /// webhook.url disables the operator's webhook, but a setting of the wrong type is an error.
+    pub fn from_config(cfg: &settings::Config) -> Result<Self, WebhookServiceError> {
+        let url: Option<String> = settings::get_opt(cfg, "webhook.url").map_err(|e| {
+            WebhookServiceError::new(format!("error getting webhook.url from config: {}", e))
+        })?;
+        let secret: Option<String> = settings::get_opt(cfg, "webhook.secret").map_err(|e| {
+            WebhookServiceError::new(format!("error getting webhook.secret from config: {}", e))
+        })?;
+        if let Some(url) = &url {
+            let secret = secret.as_deref().ok_or_else(|| {
+                WebhookServiceError::new("webhook.secret is required with webhook.url".to_string())
+            })?;
+            WebhookService::validate_target(url, secret)?;
+        }
+        let max_attempts =
+            settings::get_or(cfg, "webhook.max_attempts", MAX_ATTEMPTS).map_err(|e| {
+                WebhookServiceError::new(format!(
+                    "error getting webhook.max_attempts from config: {}",
+                    e
+                ))
+            })?;
+        let retry_delay =
+            settings::get_or(cfg, "webhook.retry_delay", RETRY_DELAY_SECS).map_err(|e| {
+                WebhookServiceError::new(format!(
+                    "error getting webhook.retry_delay from config: {}",
+                    e
+                ))
+            })?;
+
+        Ok(Self {
+            url,
+            secret,
+            max_attempts,
+            retry_delay,
+        })
+    }
+}
+
+/// WebhookTarget is a callback URL a client registers for its loop out. Events are signed with its secret.
+#[derive(Debug, Clone)]
+pub struct WebhookTarget {
+    pub url: String,
+    pub secret: String,
+}
+
+/// LoopOutEvent is the JSON body POSTed to webhooks when a loop out changes state.
+#[derive(Debug, Serialize)]
+pub struct LoopOutEvent {
+    // id is unique per state change, so receivers can drop retried duplicates
+    pub id: i64,
+    // event is loop_out.<state>, e.g. loop_out.confirmed, or loop_out.updated for a change that stays in its state
+    pub event: String,
+    pub payment_hash: String,
+    // from_state is None for the state the loop out was created in
+    pub from_state: Option<String>,
+    pub state: String,
+    pub reason: String,
+    // created_at is the unix timestamp of the state change
+    pub created_at: i64,
+}
+
+// event_name names the webhook event of a state change after the state it moved to. Changes that stay in the same
+// state, such as a replaced funding tx, are loop_out.updated.
+fn event_name(change: &LoopOutStateChange) -> String {
+    match change.from_state {
+        Some(from) if from == change.to_state => "loop_out.updated".to_string(),
+        _ => format!("loop_out.{}", change.to_state.as_str().to_lowercase()),
+    }
+}
+
+/// WebhookService POSTs signed events to the loop out's webhook and the operator's webhook on every loop out state
+/// change. Deliveries are logged in the db and retried with exponential backoff.
+pub struct WebhookService {
+    cfg: WebhookConfig,
+    db: DB,
+    client: Client,
+}
+
+impl WebhookService {
+    pub fn new(cfg: &settings::Config, db: DB) -> Result<Self, WebhookServiceError> {
+        Ok(Self {
+            cfg: WebhookConfig::from_config(cfg)?,
+            db,
+            client: new_client(&[])?,
+        })
+    }
+
+    /// validate_target checks that url is an absolute http(s) URL and that secret is usable.
+    pub fn validate_target(url: &str, secret: &str) -> Result<(), WebhookServiceError> {
+        let parsed = Url::parse(url)
+            .map_err(|e| WebhookServiceError::new(format!("invalid webhook url: {:?}", e)))?;
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            return Err(WebhookServiceError::new(format!(
+                "invalid webhook url scheme: {}",
+                parsed.scheme()
+            )));
+        }
+        if secret.is_empty() || secret.len() > MAX_SECRET_LEN {
+            return Err(WebhookServiceError::new(format!(
+                "webhook secret must be 1 to {} bytes",
+                MAX_SECRET_LEN
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// run_webhook_dispatcher queues deliveries for new loop out state changes and sends the ones that are due. It
+    /// never returns.
+    pub async fn run_webhook_dispatcher(&self) {
+        loop {
+            if let Err(e) = self.queue_events() {
+                log::error!("error queueing webhook events: {:?}", e);
+            }
+            if let Err(e) = self.send_due_deliveries().await {
+                log::error!("error sending webhooks: {:?}", e);
+            }
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    fn queue_events(&self) -> Result<(), WebhookServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let changes = db::list_unnotified_state_changes(conn, BATCH_SIZE).map_err(|e| {
+            WebhookServiceError::new(format!("error listing state changes: {:?}", e))
+        })?;
+        for (change, loop_out, invoice) in changes {
+            let change_id = change.id;
+            let deliveries = self.new_deliveries(change, loop_out, invoice)?;
+            db::enqueue_webhook_deliveries(conn, change_id, deliveries).map_err(|e| {
+                WebhookServiceError::new(format!(
+                    "error queueing webhooks for state change {}: {:?}",
+                    change_id, e
+                ))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // new_deliveries builds a delivery of the state change for the loop out's webhook and the operator's, if any.
+    // Deliveries are signed when they're sent.
+    fn new_deliveries(
+        &self,
+        change: LoopOutStateChange,
+        loop_out: LoopOut,
+        invoice: Option<Invoice>,
+    ) -> Result<Vec<NewWebhookDelivery>, WebhookServiceError> {
+        let mut urls = vec![];
+        if let (Some(url), Some(_)) = (loop_out.webhook_url, loop_out.webhook_secret) {
+            urls.push(url);
+        }
+        if let (Some(url), Some(_)) = (&self.cfg.url, &self.cfg.secret) {
+            urls.push(url.clone());
+        }
+        if urls.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let event = LoopOutEvent {
+            id: change.id,
+            event: event_name(&change),
+            payment_hash: invoice.map(|i| i.payment_hash).unwrap_or_default(),
+            from_state: change.from_state.map(|s| s.to_string()),
+            state: change.to_state.to_string(),
+            reason: change.reason,
+            created_at: change.created_at.timestamp(),
+        };
+        let payload = serde_json::to_string(&event)
+            .map_err(|e| WebhookServiceError::new(format!("error encoding event: {:?}", e)))?;
+
+        Ok(urls
+            .into_iter()
+            .map(|url| NewWebhookDelivery {
+                state_change_id: change.id,
+                url,
+                payload: payload.clone(),
+                signature: String::new(),
+                state: WebhookDeliveryState::Pending,
+            })
+            .collect())
+    }
+
+    async fn send_due_deliveries(&self) -> Result<(), WebhookServiceError> {
+        let conn = &mut self.get_conn()?;
+
+        let deliveries = db::list_due_webhook_deliveries(conn, BATCH_SIZE).map_err(|e| {
+            WebhookServiceError::new(format!("error listing webhook deliveries: {:?}", e))
+        })?;
+        let attempts: Vec<_> = futures::stream::iter(deliveries)
+            .map(|(delivery, loop_out)| self.send_delivery(delivery, loop_out))
+            .buffer_unordered(MAX_CONCURRENT_DELIVERIES)
+            .collect()
+            .await;
+        for delivery in attempts {
+            db::update_webhook_delivery(conn, delivery).map_err(|e| {
+                WebhookServiceError::new(format!("error updating webhook delivery: {:?}", e))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // send_delivery signs the delivery with the secret of its webhook and the current time, POSTs it and records the
+    // attempt.
+    async fn send_delivery(
+        &self,
+        mut delivery: WebhookDelivery,
+        loop_out: LoopOut,
+    ) -> WebhookDelivery {
+        let res = match (
+            self.secret_for(&delivery.url, loop_out),
+            self.client_for(&delivery.url).await,
+        ) {
+            (Some(secret), Ok(client)) => {
+                let timestamp = chrono::Utc::now().timestamp();
+                delivery.signature = sign(&secret, timestamp, &delivery.payload);
+                post_event(
+                    &client,
+                    &delivery.url,
+                    &delivery.payload,
+                    timestamp,
+                    &delivery.signature,
+                )
+                .await
+            }
+            (None, _) => Err((None, "webhook secret not found".to_string())),
+            (_, Err(e)) => Err((None, e.message)),
+        };
+        self.record_attempt(&mut delivery, res);
+
+        delivery
+    }
+
+    // secret_for returns the secret of the webhook at url, either the operator's or the one the loop out registered.
+    fn secret_for(&self, url: &str, loop_out: LoopOut) -> Option<String> {
+        if self.cfg.url.as_deref() == Some(url) {
+            return self.cfg.secret.clone();
+        }
+        match loop_out.webhook_url.as_deref() == Some(url) {
+            true => loop_out.webhook_secret,
+            false => None,
+        }
+    }
+
+    // client_for returns the client to POST to url with. The operator's webhook.url is trusted, but a client's url is
+    // resolved again and the client only connects to the public addresses it resolved to, so its host can't be
+    // pointed at an internal address after it was checked.
+    async fn client_for(&self, url: &str) -> Result<Client, WebhookServiceError> {
+        if self.cfg.url.as_deref() == Some(url) {
+            return Ok(self.client.clone());
+        }
+
+        let addrs = resolve_public_target(url).await?;
+        new_client(&addrs)
+    }
+
+    // record_attempt updates the delivery with the result of an attempt, scheduling a retry if it failed and has
+    // attempts left.
+    fn record_attempt(
+        &self,
+        delivery: &mut WebhookDelivery,
+        res: Result<u16, (Option<u16>, String)>,
+    ) {
+        let now = chrono::Utc::now().naive_utc();
+        delivery.attempts += 1;
+        match res {
+            Ok(status) => {
+                delivery.state = WebhookDeliveryState::Delivered;
+                delivery.last_status = Some(status as i32);
+                delivery.delivered_at = Some(now);
+                log::info!("delivered webhook {} to {}", delivery.id, delivery.url);
+            }
+            Err((status, error)) => {
+                delivery.last_status = status.map(|s| s as i32);
+                delivery.last_error = Some(error);
+                if delivery.attempts >= self.cfg.max_attempts {
+                    delivery.state = WebhookDeliveryState::Failed;
+                    log::warn!(
+                        "giving up on webhook {} to {} after {} attempts: {:?}",
+                        delivery.id,
+                        delivery.url,
+                        delivery.attempts,
+                        delivery.last_error
+                    );
+                } else {
+                    delivery.next_attempt_at =
+                        now + retry_delay(self.cfg.retry_delay, delivery.attempts);
+                }
+            }
+        }
+    }
+
+    fn get_conn(&self) -> Result<db::PooledConnection, WebhookServiceError> {
+        self.db
+            .get_conn()
+            .map_err(|e| WebhookServiceError::new(format!("error getting db connection: {:?}", e)))
+    }
+}
+
+// new_client builds a client that doesn't follow redirects, since a public url could otherwise redirect to an internal
+// one. If addrs is given, the client connects to them instead of resolving hosts itself.
+fn new_client(addrs: &[(String, Vec<SocketAddr>)]) -> Result<Client, WebhookServiceError> {
+    let mut builder = Client::builder()
+        .redirect(redirect::Policy::none())
+        .timeout(REQUEST_TIMEOUT);
+    for (host, host_addrs) in addrs {
+        builder = builder.resolve_to_addrs(host, host_addrs);
+    }
+
+    builder
+        .build()
+        .map_err(|e| WebhookServiceError::new(format!("error building webhook client: {:?}", e)))
+}
+
+/// resolve_public_target resolves the host of a client's webhook url and returns its addresses, rejecting the url if
+/// any of them isn't public. Clients can't make the server POST to itself, its internal network or a cloud metadata
+/// endpoint this way.
+pub async fn resolve_public_target(
+    url: &str,
+) -> Result<Vec<(String, Vec<SocketAddr>)>, WebhookServiceError> {
+    let parsed = Url::parse(url)
+        .map_err(|e| WebhookServiceError::new(format!("invalid webhook url: {:?}", e)))?;
+    let port = parsed
+        .port_or_known_default()
+        .ok_or_else(|| WebhookServiceError::new(format!("webhook url {} has no port", url)))?;
+
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| WebhookServiceError::new(format!("webhook url {} has no host", url)))?;
+
+    // ipv6 hosts are bracketed in urls
+    let (host, addrs) = match host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => (None, vec![SocketAddr::new(ip, port)]),
+        Err(_) => {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|e| {
+                    WebhookServiceError::new(format!("error resolving {}: {:?}", host, e))
+                })?
+                .collect();
+            (Some(host.to_string()), addrs)
+        }
+    };
+
+    if addrs.is_empty() {
+        return Err(WebhookServiceError::new(format!(
+            "webhook url {} doesn't resolve",
+            url
+        )));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+        return Err(WebhookServiceError::new(format!(
+            "webhook url {} resolves to non-public address {}",
+            url,
+            addr.ip()
+        )));
+    }
+
+    // ip hosts aren't resolved by the client
+    Ok(host.map(|host| vec![(host, addrs)]).unwrap_or_default())
+}
+
+// is_public_ip reports whether ip is routable on the internet, i.e. not loopback, private, link-local, unspecified or
+// otherwise reserved.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // 0.0.0.0/8 and the 100.64.0.0/10 shared address space
+                || octets[0] == 0
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local fc00::/7 and link-local fe80::/10
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+// sign returns the hex encoded HMAC-SHA256 of "<timestamp>.<payload>" keyed with secret. Covering the timestamp lets
+// receivers reject old requests replayed with their original signature.
+pub fn sign(secret: &str, timestamp: i64, payload: &str) -> String {
+    let message = format!("{}.{}", timestamp, payload);
+    hex::encode(utils::hmac_sha256(secret.as_bytes(), message.as_bytes()))
+}
+
+// retry_delay is how long to wait after the given number of failed attempts: base doubled for every attempt after the
+// first, capped at MAX_RETRY_DELAY_SECS.
+fn retry_delay(base: i64, attempts: i32) -> chrono::Duration {
+    let exp = (attempts - 1).clamp(0, 30) as u32;
+    let delay = base.saturating_mul(2_i64.saturating_pow(exp));
+
+    chrono::Duration::seconds(delay.min(MAX_RETRY_DELAY_SECS))
+}
+
+// post_event POSTs payload to url with the timestamp it was signed at and its signature. It returns the status of a 2xx response, and otherwise the
+// status, if any, along with the error.
+pub async fn post_event(
+    client: &Client,
+    url: &str,
+    payload: &str,
+    timestamp: i64,
+    signature: &str,
+) -> Result<u16, (Option<u16>, String)> {
+    let resp = client
+        .post(url)
+        .timeout(REQUEST_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, signature)
+        .body(payload.to_string())
+        .send()
+        .await
+        .map_err(|e| (None, format!("error sending webhook: {}", e)))?;
+
+    let status = resp.status();
+    match status.is_success() {
+        true => Ok(status.as_u16()),
+        false => Err((
+            Some(status.as_u16()),
+            format!("webhook responded with {}", status),
+        )),
+    }
+}
+
+#[derive(Debug)]
+pub struct WebhookServiceError {
+    pub message: String,
+}
+
+impl WebhookServiceError {
+    pub fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::models::LoopOutState;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    // serve_once answers a single request on a local port with status, returning the url to send to and a channel
+    // receiving the raw request.
+    fn serve_once(status: &'static str) -> (String, std::sync::mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("failed to accept");
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(len) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+
+            let mut stream = stream;
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            tx.send(request).unwrap();
+        });
+
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn test_post_event() {
+        let payload = r#"{"id":1,"event":"loop_out.confirmed"}"#;
+        let timestamp = 1_700_000_000;
+        let signature = sign("test-secret", timestamp, payload);
+        assert_ne!(signature, sign("test-secret", timestamp + 1, payload));
+
+        let (url, rx) = serve_once("200 OK");
+        let res = post_event(&Client::new(), &url, payload, timestamp, &signature).await;
+        assert_eq!(Ok(200), res);
+        let request = rx.recv().unwrap();
+        assert!(request.starts_with("POST /hook"));
+        assert!(request
+            .to_lowercase()
+            .contains(&format!("x-looper-timestamp: {}", timestamp)));
+        assert!(request
+            .to_lowercase()
+            .contains(&format!("x-looper-signature: {}", signature)));
+        assert!(request.ends_with(payload));
+
+        let (url, _rx) = serve_once("500 Internal Server Error");
+        let res = post_event(&Client::new(), &url, payload, timestamp, &signature).await;
+        assert_eq!(Some(500), res.unwrap_err().0);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_public_target() {
+        for url in [
+            "http://127.0.0.1/hook",
+            "http://localhost:8080/hook",
+            "http://10.0.0.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://0.0.0.0:10009/hook",
+            "http://[::1]/hook",
+            "http://[::ffff:192.168.1.1]/hook",
+            "http://[fe80::1]/hook",
+        ] {
+            assert!(resolve_public_target(url).await.is_err(), "{}", url);
+        }
+
+        // ip hosts are connected to directly
+        let addrs = resolve_public_target("https://93.184.216.34/hook")
+            .await
+            .expect("public ip rejected");
+        assert!(addrs.is_empty());
+    }
+
+    #[test]
+    fn test_event_name() {
+        let mut change = LoopOutStateChange {
+            id: 1,
+            loop_out_id: 1,
+            from_state: None,
+            to_state: LoopOutState::Initiated,
+            reason: "test-reason".to_string(),
+            created_at: chrono::Utc::now().naive_utc(),
+            notified_at: None,
+        };
+        assert_eq!("loop_out.initiated", event_name(&change));
+
+        change.from_state = Some(LoopOutState::Initiated);
+        assert_eq!("loop_out.updated", event_name(&change));
+
+        change.from_state = Some(LoopOutState::AwaitingPayment);
+        change.to_state = LoopOutState::Expired;
+        assert_eq!("loop_out.expired", event_name(&change));
+    }
+
+    #[test]
+    fn test_webhook_config_from_config() {
+        let cfg = WebhookConfig::from_config(
+            &settings::Config::builder()
+                .build()
+                .expect("failed to build config"),
+        )
+        .expect("failed to load defaults");
+        assert_eq!(None, cfg.url);
+        assert_eq!(MAX_ATTEMPTS, cfg.max_attempts);
+        assert_eq!(RETRY_DELAY_SECS, cfg.retry_delay);
+
+        let build = |key: &str, value: &str| {
+            settings::Config::builder()
+                .set_override("webhook.url", "https://example.com/hook")
+                .and_then(|b| b.set_override("webhook.secret", "test-secret"))
+                .and_then(|b| b.set_override(key, value))
+                .and_then(|b| b.build())
+                .expect("failed to build config")
+        };
+        let cfg = WebhookConfig::from_config(&build("webhook.max_attempts", "3"))
+            .expect("failed to load webhook config");
+        assert_eq!(Some("https://example.com/hook".to_string()), cfg.url);
+        assert_eq!(3, cfg.max_attempts);
+
+        // mistyped settings don't fall back to the defaults
+        assert!(WebhookConfig::from_config(&build("webhook.max_attempts", "ten")).is_err());
+        assert!(WebhookConfig::from_config(&build("webhook.retry_delay", "1m")).is_err());
+        let cfg = settings::Config::builder()
+            .set_override("webhook.url", vec!["https://example.com/hook"])
+            .and_then(|b| b.set_override("webhook.secret", "test-secret"))
+            .and_then(|b| b.build())
+            .expect("failed to build config");
+        assert!(WebhookConfig::from_config(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(chrono::Duration::seconds(10), retry_delay(10, 1));
+        assert_eq!(chrono::Duration::seconds(40), retry_delay(10, 3));
+        assert_eq!(
+            chrono::Duration::seconds(MAX_RETRY_DELAY_SECS),
+            retry_delay(10, 20)
+        );
+    }
+}
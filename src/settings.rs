@@ -2,6 +2,7 @@ use std::env;
 use std::sync::Once;
 
 use config::{Environment, File};
+use serde::Deserialize;
 
 pub use config::{Config, ConfigError};
 
@@ -29,8 +30,55 @@ pub fn build_test_config() -> Result<Config, ConfigError> {
         .build()
 }
 
+/// get_or returns the value of key, or default if it isn't set. A value that isn't a valid T is an error instead of
+/// falling back to default, so a mistyped setting fails startup.
+pub fn get_or<'de, T: Deserialize<'de>>(
+    cfg: &Config,
+    key: &str,
+    default: T,
+) -> Result<T, ConfigError> {
+    get_opt(cfg, key).map(|v| v.unwrap_or(default))
+}
+
+/// get_opt returns the value of key, or None if it isn't set. A value that isn't a valid T is an error.
+pub fn get_opt<'de, T: Deserialize<'de>>(
+    cfg: &Config,
+    key: &str,
+) -> Result<Option<T>, ConfigError> {
+    match cfg.get(key) {
+        Ok(v) => Ok(Some(v)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
 pub fn init_logging() {
     INIT.call_once(|| {
         log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
     });
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_or() {
+        let cfg = Config::builder()
+            .set_override("test.number", 42)
+            .and_then(|b| b.set_override("test.string", "not-a-number"))
+            .and_then(|b| b.build())
+            .expect("failed to build config");
+
+        assert_eq!(42, get_or(&cfg, "test.number", 1).expect("failed to get"));
+        assert_eq!(1, get_or(&cfg, "test.missing", 1).expect("failed to get"));
+        assert_eq!(1, get_or(&cfg, "missing.number", 1).expect("failed to get"));
+        assert!(get_or(&cfg, "test.string", 1).is_err());
+
+        assert_eq!(
+            None,
+            get_opt::<i64>(&cfg, "test.missing").expect("failed to get")
+        );
+        assert!(get_opt::<bool>(&cfg, "test.string").is_err());
+    }
+}
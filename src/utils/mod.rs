@@ -1,10 +1,25 @@
-use bdk::bitcoin::hashes::{sha256, Hash};
+use bdk::bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
 use rand::Rng;
 
 pub fn sha256(input: &[u8]) -> [u8; 32] {
     sha256::Hash::hash(input).to_byte_array()
 }
 
+pub fn hmac_sha256(key: &[u8], input: &[u8]) -> [u8; 32] {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+    engine.input(input);
+
+    hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+}
+
+// decode_hex32 decodes a hex encoded payment hash or preimage.
+pub fn decode_hex32(value: &str) -> Result<[u8; 32], hex::FromHexError> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(value, &mut bytes as &mut [u8])?;
+
+    Ok(bytes)
+}
+
 pub fn rand_32_bytes() -> [u8; 32] {
     let mut rng = rand::thread_rng();
 
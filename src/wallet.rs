@@ -1,24 +1,47 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::path::Path;
 use std::str::FromStr;
+use tokio::sync::Mutex;
 // use std::thread;
 
 use bitcoin::address;
 
 // use crate::services::errors::{LooperError, LooperErrorResponse};
 
-use crate::{mempool, settings};
+use crate::{
+    mempool,
+    musig::{self, KeyAggContext, PartialSig, PubNonce},
+    settings, utils,
+};
 use bdk::{
     bitcoin::{
         bip32::{ChildNumber, ExtendedPrivKey},
-        blockdata::{locktime::absolute::LockTime, opcodes, script, transaction::Transaction},
+        blockdata::{
+            locktime::absolute::LockTime,
+            opcodes, script,
+            transaction::{Transaction, TxIn, TxOut},
+        },
         // hashes::{sha256::Hash as Sha256, sha256d::Hash as Sha256d, Hash},
-        secp256k1::{rand::thread_rng, KeyPair, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey},
+        hashes::Hash,
+        psbt::PartiallySignedTransaction,
+        secp256k1::{
+            rand::thread_rng, KeyPair, Message, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey,
+        },
+        sighash::{Prevouts, SighashCache, TapSighashType},
         // taproot,
-        taproot::{TaprootBuilder, TaprootSpendInfo},
+        taproot::{
+            self as bitcoin_taproot, LeafVersion, TapLeafHash, TaprootBuilder, TaprootSpendInfo,
+        },
+        Block,
         Network,
+        OutPoint,
         ScriptBuf,
+        Sequence,
+        Txid,
+        Witness,
     },
+    bitcoincore_rpc::RpcApi,
     blockchain::{ConfigurableBlockchain, GetHeight, RpcBlockchain, RpcConfig},
     // database::SqliteDatabase,
     // descriptor::Descriptor,
@@ -27,16 +50,28 @@ use bdk::{
     FeeRate,
     SignOptions,
     SyncOptions,
+    TransactionDetails,
     Wallet,
 };
 use bdk::{blockchain::Blockchain, sled};
 use config::Config;
-use std::sync::Mutex;
+
+// how many of the blocks already scanned for an output's spend are scanned again, in case they were reorged out.
+const SPEND_RESCAN_DEPTH: u32 = 6;
+
+// SendTx is a signed tx built by send_to_addresses.
+pub struct SendTx {
+    pub tx: Transaction,
+    // vouts are the indexes of the outputs paying each recipient, in the order they were given
+    pub vouts: Vec<u32>,
+    // fee is the miner fee paid by tx, in sats
+    pub fee: u64,
+    pub fee_rate: FeeRate,
+}
 
 pub struct LooperWallet {
     blockchain: RpcBlockchain,
     xprv: ExtendedPrivKey,
-    index: Mutex<u32>,
     wallet: Wallet<sled::Tree>,
 }
 
@@ -82,7 +117,6 @@ impl LooperWallet {
         let looper_wallet = Self {
             blockchain,
             xprv,
-            index: Mutex::new(0),
             wallet,
         };
 
@@ -136,7 +170,7 @@ impl LooperWallet {
         self.wallet.network()
     }
 
-    fn parse_network_from_config(cfg: &Config) -> Result<Network, WalletError> {
+    pub fn parse_network_from_config(cfg: &Config) -> Result<Network, WalletError> {
         let network = Network::from_str(&cfg.get_string("bitcoin.network").map_err(|e| {
             WalletError::new(format!(
                 "failed to get bitcoin network from config: {:?}",
@@ -176,14 +210,19 @@ impl LooperWallet {
         })
     }
 
-    pub fn send_to_address(
+    // send_to_addresses builds and signs a single tx paying each (address, amount) recipient. Outputs are shuffled,
+    // so the index of the output paying each recipient is returned along with the tx. The tx signals RBF, see bump_fee.
+    pub fn send_to_addresses(
         &self,
-        address: &str,
-        amount: u64,
+        recipients: &[(&str, u64)],
         fee_rate: &FeeRate,
-    ) -> Result<Transaction, WalletError> {
+    ) -> Result<SendTx, WalletError> {
         let mut tx_builder = self.wallet.build_tx();
-        let addr = self.validate_address(address)?;
+        for (address, amount) in recipients {
+            let script_pubkey = self.validate_address(address)?.script_pubkey();
+            // TODO: amounts will usually be round and the change will be to wpkh for now, so privacy is still poor.
+            tx_builder.add_recipient(script_pubkey, *amount);
+        }
 
         let curr_height = self.get_height().map_err(|e| {
             WalletError::new(format!("failed to get current height: {:?}", e.to_string()))
@@ -192,17 +231,50 @@ impl LooperWallet {
             WalletError::new(format!("failed to get locktime: {:?}", e.to_string()))
         })?;
         tx_builder
-            // TODO: bad for privacy, but simplest way to ensure we know the vout. Also, this amount will usually be round
-            // and the change will be to wpkh for now, so overall poor privacy anyway. All fixable.
-            .ordering(bdk::wallet::tx_builder::TxOrdering::Untouched)
-            .add_recipient(addr.script_pubkey(), amount)
+            .ordering(bdk::wallet::tx_builder::TxOrdering::Shuffle)
             .fee_rate(*fee_rate)
-            .nlocktime(locktime);
+            .nlocktime(locktime)
+            .enable_rbf();
 
-        let (mut psbt, _details) = tx_builder
+        let (psbt, details) = tx_builder
             .finish()
             .map_err(|e| WalletError::new(format!("failed to build tx: {:?}", e)))?;
 
+        self.sign_send_tx(psbt, details, recipients, fee_rate)
+    }
+
+    // bump_fee builds and signs a replacement for the wallet's unconfirmed tx txid at the higher fee_rate. The
+    // replacement pays the same recipients and takes the extra fee from the change, but its outputs are shuffled
+    // again, so the index of the output paying each recipient is returned along with it.
+    pub fn bump_fee(
+        &self,
+        txid: &Txid,
+        recipients: &[(&str, u64)],
+        fee_rate: &FeeRate,
+    ) -> Result<SendTx, WalletError> {
+        // bdk only knows about txs it has synced
+        self.sync()?;
+
+        let mut tx_builder = self
+            .wallet
+            .build_fee_bump(*txid)
+            .map_err(|e| WalletError::new(format!("failed to bump fee of tx {}: {:?}", txid, e)))?;
+        tx_builder.fee_rate(*fee_rate).enable_rbf();
+
+        let (psbt, details) = tx_builder
+            .finish()
+            .map_err(|e| WalletError::new(format!("failed to build tx: {:?}", e)))?;
+
+        self.sign_send_tx(psbt, details, recipients, fee_rate)
+    }
+
+    fn sign_send_tx(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+        details: TransactionDetails,
+        recipients: &[(&str, u64)],
+        fee_rate: &FeeRate,
+    ) -> Result<SendTx, WalletError> {
         let finalized = self
             .wallet
             .sign(&mut psbt, SignOptions::default())
@@ -213,8 +285,58 @@ impl LooperWallet {
         }
 
         let tx = psbt.extract_tx();
+        let vouts = self.find_recipient_vouts(&tx, recipients)?;
+        let fee = details
+            .fee
+            .ok_or_else(|| WalletError::new(format!("tx {} is missing its fee", tx.txid())))?;
 
-        Ok(tx)
+        Ok(SendTx {
+            tx,
+            vouts,
+            fee,
+            fee_rate: *fee_rate,
+        })
+    }
+
+    // find_recipient_vouts returns the index of the output of tx paying each (address, amount) recipient.
+    pub fn find_recipient_vouts(
+        &self,
+        tx: &Transaction,
+        recipients: &[(&str, u64)],
+    ) -> Result<Vec<u32>, WalletError> {
+        let mut outputs = vec![];
+        for (address, amount) in recipients {
+            outputs.push((self.validate_address(address)?.script_pubkey(), *amount));
+        }
+
+        Self::find_output_vouts(tx, &outputs).ok_or_else(|| {
+            WalletError::new(format!(
+                "tx {} doesn't pay all {} recipients",
+                tx.txid(),
+                recipients.len()
+            ))
+        })
+    }
+
+    // find_output_vouts returns the index of the output of tx paying each (script_pubkey, amount), in order, or None
+    // if one isn't paid.
+    pub fn find_output_vouts(tx: &Transaction, outputs: &[(ScriptBuf, u64)]) -> Option<Vec<u32>> {
+        outputs
+            .iter()
+            .map(|(script_pubkey, amount)| Self::find_output_index(tx, script_pubkey, *amount))
+            .collect()
+    }
+
+    // find_output_index returns the index of the first output of tx paying amount to script_pubkey.
+    pub fn find_output_index(
+        tx: &Transaction,
+        script_pubkey: &ScriptBuf,
+        amount: u64,
+    ) -> Option<u32> {
+        tx.output
+            .iter()
+            .position(|txout| txout.script_pubkey == *script_pubkey && txout.value == amount)
+            .map(|vout| vout as u32)
     }
 
     pub fn broadcast_tx(&self, tx: &Transaction) -> Result<(), WalletError> {
@@ -225,25 +347,287 @@ impl LooperWallet {
         Ok(())
     }
 
-    // TODO: make priv
-    // Maybe force pubkey even and return that instead of xonly
-    pub fn new_pubkey(&self) -> Result<(XOnlyPublicKey, u32), WalletError> {
-        let secp256k1 = Secp256k1::new();
-        // TODO: use tokio and async lock
-        let mut pk_index = self.index.lock().map_err(|e| {
-            WalletError::new(format!("failed to get lock on index: {:?}", e.to_string()))
+    // get_tx_confirmations returns how deep one of the wallet's own transactions (e.g. a funding tx) is buried.
+    // Unconfirmed transactions have 0 confirmations.
+    pub fn get_tx_confirmations(&self, txid: &Txid) -> Result<u32, WalletError> {
+        let tx = self
+            .blockchain
+            .get_transaction(txid, Some(true))
+            .map_err(|e| {
+                WalletError::new(format!("failed to get tx {}: {:?}", txid, e.to_string()))
+            })?;
+
+        // bitcoind reports conflicted transactions with negative confirmations
+        Ok(tx.info.confirmations.max(0) as u32)
+    }
+
+    // get_wallet_tx returns one of the wallet's own transactions, e.g. a funding tx that has since been replaced.
+    pub fn get_wallet_tx(&self, txid: &Txid) -> Result<Transaction, WalletError> {
+        self.blockchain
+            .get_transaction(txid, Some(true))
+            .map_err(|e| {
+                WalletError::new(format!("failed to get tx {}: {:?}", txid, e.to_string()))
+            })?
+            .transaction()
+            .map_err(|e| WalletError::new(format!("failed to decode tx {}: {:?}", txid, e)))
+    }
+
+    // has_synced_tx reports whether the wallet saw txid as of its last sync, i.e. whether its balance accounts for it.
+    pub fn has_synced_tx(&self, txid: &Txid) -> Result<bool, WalletError> {
+        self.wallet
+            .get_tx(txid, false)
+            .map(|tx| tx.is_some())
+            .map_err(|e| {
+                WalletError::new(format!("failed to get tx {}: {:?}", txid, e.to_string()))
+            })
+    }
+
+    // is_tx_in_mempool reports whether txid is in bitcoind's mempool. RPC errors are reported as not in the mempool.
+    pub fn is_tx_in_mempool(&self, txid: &Txid) -> bool {
+        self.blockchain.get_mempool_entry(txid).is_ok()
+    }
+
+    // is_output_spent reports whether outpoint has been spent by a transaction in the mempool or the chain. Outputs
+    // of transactions bitcoind has never seen are reported as spent, so only call this for broadcast outputs.
+    pub fn is_output_spent(&self, outpoint: &OutPoint) -> Result<bool, WalletError> {
+        let txout = self
+            .blockchain
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))
+            .map_err(|e| {
+                WalletError::new(format!(
+                    "failed to get txout {}: {:?}",
+                    outpoint,
+                    e.to_string()
+                ))
+            })?;
+
+        Ok(txout.is_none())
+    }
+
+    // get_mempool_txids lists the txids in bitcoind's mempool.
+    pub fn get_mempool_txids(&self) -> Result<Vec<Txid>, WalletError> {
+        self.blockchain
+            .get_raw_mempool()
+            .map_err(|e| WalletError::new(format!("failed to get mempool: {:?}", e.to_string())))
+    }
+
+    // get_raw_tx returns a tx bitcoind knows about, whether it's the wallet's or not.
+    pub fn get_raw_tx(&self, txid: &Txid) -> Result<Transaction, WalletError> {
+        self.blockchain
+            .get_raw_transaction(txid, None)
+            .map_err(|e| {
+                WalletError::new(format!("failed to get tx {}: {:?}", txid, e.to_string()))
+            })
+    }
+
+    // get_block_at returns the block at height in the best chain.
+    pub fn get_block_at(&self, height: u32) -> Result<Block, WalletError> {
+        let block_hash = self.blockchain.get_block_hash(height as u64).map_err(|e| {
+            WalletError::new(format!(
+                "failed to get block hash at {}: {:?}",
+                height,
+                e.to_string()
+            ))
         })?;
-        let index = *pk_index;
-        let child_num = vec![ChildNumber::Normal { index }];
-        let xsk: ExtendedPrivKey = self.xprv.derive_priv(&secp256k1, &child_num).map_err(|e| {
-            WalletError::new(format!("failed to derive priv key: {:?}", e.to_string()))
+
+        self.blockchain.get_block(&block_hash).map_err(|e| {
+            WalletError::new(format!(
+                "failed to get block {}: {:?}",
+                block_hash,
+                e.to_string()
+            ))
+        })
+    }
+
+    // find_spend_in_block returns the tx in block spending outpoint.
+    pub fn find_spend_in_block(block: Block, outpoint: &OutPoint) -> Option<Transaction> {
+        block
+            .txdata
+            .into_iter()
+            .find(|tx| spends_outpoint(tx, outpoint))
+    }
+
+    // find_output_to_script returns the first output in block paying script_pubkey.
+    pub fn find_output_to_script(
+        block: &Block,
+        script_pubkey: &ScriptBuf,
+    ) -> Option<(OutPoint, TxOut)> {
+        block.txdata.iter().find_map(|tx| {
+            let vout = tx
+                .output
+                .iter()
+                .position(|txout| txout.script_pubkey == *script_pubkey)?;
+            let outpoint = OutPoint {
+                txid: tx.txid(),
+                vout: vout as u32,
+            };
+
+            Some((outpoint, tx.output[vout].clone()))
+        })
+    }
+
+    // extract_htlc_preimage returns the preimage revealed by a spend through the claim leaf built by
+    // new_htlc_script. The witness of such a spend is [preimage, signature, htlc_script, control_block].
+    pub fn extract_htlc_preimage(
+        witness: &Witness,
+        htlc_script: &ScriptBuf,
+        payment_hash: &[u8; 32],
+    ) -> Option<[u8; 32]> {
+        if witness.len() != 4 || witness.nth(2)? != htlc_script.as_bytes() {
+            return None;
+        }
+
+        let preimage: [u8; 32] = witness.nth(0)?.try_into().ok()?;
+        if utils::sha256(&preimage) != *payment_hash {
+            return None;
+        }
+
+        Some(preimage)
+    }
+
+    // is_timeout_spend reports whether witness spends through the timeout leaf built by new_timeout_script. The
+    // witness of such a spend is [signature, timeout_script, control_block].
+    pub fn is_timeout_spend(witness: &Witness, timeout_script: &ScriptBuf) -> bool {
+        witness.len() == 3 && witness.nth(1) == Some(timeout_script.as_bytes())
+    }
+
+    // is_key_spend reports whether witness spends through the key path. The witness of such a spend is a single 64 or
+    // 65 byte signature.
+    pub fn is_key_spend(witness: &Witness) -> bool {
+        witness.len() == 1 && matches!(witness.nth(0).map(|sig| sig.len()), Some(64 | 65))
+    }
+
+    // new_timeout_sweep builds and signs a transaction spending htlc through the timeout leaf back to a wallet
+    // address. The transaction is only valid once the chain has reached cltv_expiry.
+    pub fn new_timeout_sweep(
+        &self,
+        htlc: &HtlcUtxo,
+        timeout_script: &ScriptBuf,
+        cltv_expiry: u32,
+        key_index: u32,
+        fee_rate: &FeeRate,
+    ) -> Result<Transaction, WalletError> {
+        let lock_time = LockTime::from_height(cltv_expiry).map_err(|e| {
+            WalletError::new(format!("failed to get locktime: {:?}", e.to_string()))
         })?;
-        *pk_index += 1;
 
-        let keypair = xsk.to_keypair(&secp256k1);
-        let (pk, _) = keypair.x_only_public_key();
+        // a non-final sequence is required for OP_CLTV to check the locktime
+        self.new_script_path_sweep(
+            htlc,
+            timeout_script,
+            vec![],
+            lock_time,
+            Sequence::ENABLE_LOCKTIME_NO_RBF,
+            key_index,
+            fee_rate,
+        )
+    }
+
+    // new_claim_sweep builds and signs a transaction spending htlc through the claim leaf built by new_htlc_script
+    // back to a wallet address, revealing preimage.
+    pub fn new_claim_sweep(
+        &self,
+        htlc: &HtlcUtxo,
+        htlc_script: &ScriptBuf,
+        preimage: &[u8; 32],
+        key_index: u32,
+        fee_rate: &FeeRate,
+    ) -> Result<Transaction, WalletError> {
+        self.new_script_path_sweep(
+            htlc,
+            htlc_script,
+            vec![preimage.to_vec()],
+            LockTime::ZERO,
+            Sequence::ENABLE_RBF_NO_LOCKTIME,
+            key_index,
+            fee_rate,
+        )
+    }
+
+    // new_script_path_sweep builds and signs a transaction spending htlc through leaf_script back to a wallet address.
+    // The witness is [stack.., signature, leaf_script, control_block].
+    #[allow(clippy::too_many_arguments)]
+    fn new_script_path_sweep(
+        &self,
+        htlc: &HtlcUtxo,
+        leaf_script: &ScriptBuf,
+        stack: Vec<Vec<u8>>,
+        lock_time: LockTime,
+        sequence: Sequence,
+        key_index: u32,
+        fee_rate: &FeeRate,
+    ) -> Result<Transaction, WalletError> {
+        let secp256k1 = Secp256k1::new();
+        let control_block = htlc
+            .spend_info
+            .control_block(&(leaf_script.clone(), LeafVersion::TapScript))
+            .ok_or_else(|| WalletError::new("leaf script not in htlc tree".to_string()))?;
+        let sweep_addr = self.new_address()?;
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time,
+            input: vec![TxIn {
+                previous_output: htlc.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: htlc.amount,
+                script_pubkey: sweep_addr.address.script_pubkey(),
+            }],
+        };
+
+        let witness = |sig: Vec<u8>| {
+            let mut witness = stack.clone();
+            witness.extend([sig, leaf_script.to_bytes(), control_block.serialize()]);
+            Witness::from_vec(witness)
+        };
+
+        // estimate the fee against a witness of the final size
+        tx.input[0].witness = witness(vec![0u8; 64]);
+        let fee = fee_rate.fee_vb(tx.vsize());
+        if fee + sweep_addr.address.script_pubkey().dust_value().to_sat() > htlc.amount {
+            return Err(WalletError::new(format!(
+                "htlc amount {} too small to sweep with fee {}",
+                htlc.amount, fee
+            )));
+        }
+        tx.output[0].value = htlc.amount - fee;
+
+        let prevouts = vec![TxOut {
+            value: htlc.amount,
+            script_pubkey: ScriptBuf::new_v1_p2tr_tweaked(htlc.spend_info.output_key()),
+        }];
+        let leaf_hash = TapLeafHash::from_script(leaf_script, LeafVersion::TapScript);
+        let sighash = SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&prevouts),
+                leaf_hash,
+                TapSighashType::Default,
+            )
+            .map_err(|e| WalletError::new(format!("failed to compute sighash: {:?}", e)))?;
+
+        let keypair = self.get_keypair(key_index)?;
+        let sig = bitcoin_taproot::Signature {
+            sig: secp256k1.sign_schnorr(&Message::from(sighash), &keypair),
+            hash_ty: TapSighashType::Default,
+        };
+
+        tx.input[0].witness = witness(sig.to_vec());
+
+        Ok(tx)
+    }
+
+    // TODO: Maybe force pubkey even and return that instead of xonly
+    // get_pubkey returns the HTLC pubkey at index. Indexes must be reserved with db::next_htlc_key_index, so that no
+    // two swaps share a key.
+    pub fn get_pubkey(&self, index: u32) -> Result<XOnlyPublicKey, WalletError> {
+        let (pk, _) = self.get_keypair(index)?.x_only_public_key();
 
-        Ok((pk, index))
+        Ok(pk)
     }
 
     pub fn get_keypair(&self, index: u32) -> Result<KeyPair, WalletError> {
@@ -345,6 +729,118 @@ impl LooperWallet {
         Ok((tr, internal_tapseckey))
     }
 
+    /// new_musig_htlc builds the same tree as new_htlc, but with the MuSig2 aggregate of both keys as the internal key,
+    /// so that the buyer and looper can cooperatively spend the HTLC through the key path.
+    pub fn new_musig_htlc(
+        claimant_pk: XOnlyPublicKey,
+        claimee_pk: XOnlyPublicKey,
+        payment_hash: &[u8; 32],
+        cltv: u32,
+    ) -> Result<TaprootSpendInfo, WalletError> {
+        let htlc_script = LooperWallet::new_htlc_script(&claimant_pk, payment_hash);
+
+        let locktime = LockTime::from_height(cltv).expect("invalid height");
+
+        let timeout_script = LooperWallet::new_timeout_script(claimee_pk, locktime);
+
+        let internal_tapkey =
+            LooperWallet::new_musig_key_agg_ctx(claimant_pk, claimee_pk)?.agg_pubkey();
+
+        LooperWallet::build_taproot(&htlc_script, &timeout_script, internal_tapkey)
+    }
+
+    pub fn new_musig_key_agg_ctx(
+        claimant_pk: XOnlyPublicKey,
+        claimee_pk: XOnlyPublicKey,
+    ) -> Result<KeyAggContext, WalletError> {
+        KeyAggContext::new(musig::sort_pubkeys(vec![claimant_pk, claimee_pk]))
+            .map_err(|e| WalletError::new(format!("failed to aggregate keys: {:?}", e)))
+    }
+
+    /// sign_musig_key_spend returns looper's nonce and partial signature for tx, which must spend htlc and nothing
+    /// else through the key path. Looper always signs last with a fresh nonce, so it doesn't need to keep any state
+    /// between requests.
+    pub fn sign_musig_key_spend(
+        &self,
+        htlc: &HtlcUtxo,
+        tx: &Transaction,
+        claimant_pk: XOnlyPublicKey,
+        key_index: u32,
+        claimant_nonce: &PubNonce,
+    ) -> Result<(PubNonce, PartialSig), WalletError> {
+        LooperWallet::check_musig_claim_tx(tx, &htlc.outpoint, htlc.amount)?;
+
+        let keypair = self.get_keypair(key_index)?;
+        let key_agg_ctx =
+            LooperWallet::new_musig_key_agg_ctx(claimant_pk, keypair.x_only_public_key().0)?
+                .with_taproot_tweak(htlc.spend_info.merkle_root())
+                .map_err(|e| WalletError::new(format!("failed to tweak key: {:?}", e)))?;
+        if key_agg_ctx.agg_pubkey() != htlc.spend_info.output_key().to_inner() {
+            return Err(WalletError::new(
+                "htlc output key is not a musig aggregate".to_string(),
+            ));
+        }
+
+        let prevouts = vec![TxOut {
+            value: htlc.amount,
+            script_pubkey: ScriptBuf::new_v1_p2tr_tweaked(htlc.spend_info.output_key()),
+        }];
+        let sighash = SighashCache::new(tx)
+            .taproot_key_spend_signature_hash(0, &Prevouts::All(&prevouts), TapSighashType::Default)
+            .map_err(|e| WalletError::new(format!("failed to compute sighash: {:?}", e)))?;
+
+        let (secnonce, pubnonce) = musig::new_nonce();
+        let aggnonce = musig::AggNonce::new(&[*claimant_nonce, pubnonce]);
+        let partial_sig = musig::sign(
+            secnonce,
+            &keypair,
+            &key_agg_ctx,
+            &aggnonce,
+            &sighash.to_byte_array(),
+        )
+        .map_err(|e| WalletError::new(format!("failed to sign: {:?}", e)))?;
+
+        Ok((pubnonce, partial_sig))
+    }
+
+    /// check_musig_claim_tx checks that tx only spends the HTLC at outpoint, worth amount, and pays it to outputs
+    /// that can be relayed, leaving a fee.
+    pub fn check_musig_claim_tx(
+        tx: &Transaction,
+        outpoint: &OutPoint,
+        amount: u64,
+    ) -> Result<(), WalletError> {
+        if tx.input.len() != 1 || tx.input[0].previous_output != *outpoint {
+            return Err(WalletError::new(
+                "tx must only spend the htlc output".to_string(),
+            ));
+        }
+        if tx.output.is_empty() {
+            return Err(WalletError::new("tx has no outputs".to_string()));
+        }
+        if let Some(txout) = tx
+            .output
+            .iter()
+            .find(|txout| txout.value < txout.script_pubkey.dust_value().to_sat())
+        {
+            return Err(WalletError::new(format!(
+                "tx output of {} sats is dust",
+                txout.value
+            )));
+        }
+        let value = tx
+            .output
+            .iter()
+            .try_fold(0u64, |sum, txout| sum.checked_add(txout.value));
+        match value {
+            Some(value) if value < amount => Ok(()),
+            _ => Err(WalletError::new(format!(
+                "tx outputs don't leave a fee from the htlc's {} sats",
+                amount
+            ))),
+        }
+    }
+
     pub fn build_taproot(
         htlc_script: &ScriptBuf,
         timeout_script: &ScriptBuf,
@@ -384,6 +880,126 @@ impl LooperWallet {
     }
 }
 
+fn spends_outpoint(tx: &Transaction, outpoint: &OutPoint) -> bool {
+    tx.input
+        .iter()
+        .any(|txin| txin.previous_output == *outpoint)
+}
+
+/// SpendScanner finds the txs spending watched outputs, e.g. HTLCs. It remembers how far the blocks have been scanned
+/// for each output and which mempool txs it has already looked at, so every call only fetches what's new. The wallet
+/// is locked for one RPC call at a time, so a scan doesn't hold up the wallet's other users.
+#[derive(Default)]
+pub struct SpendScanner {
+    // scanned holds the height of the next block to scan for each watched output
+    scanned: HashMap<OutPoint, u32>,
+    // mempool_txids holds the mempool txs already looked at, and mempool_spends those spending a watched output
+    mempool_txids: HashSet<Txid>,
+    mempool_spends: HashMap<OutPoint, Txid>,
+}
+
+impl SpendScanner {
+    /// find_spending_tx looks for the tx spending outpoint, first in the mempool and then in the blocks from
+    /// from_height up to the tip. Blocks scanned by an earlier call aren't fetched again, except the last
+    /// SPEND_RESCAN_DEPTH in case they were reorged out.
+    pub async fn find_spending_tx(
+        &mut self,
+        wallet: &Mutex<LooperWallet>,
+        outpoint: &OutPoint,
+        from_height: u32,
+    ) -> Result<Option<Transaction>, WalletError> {
+        if !self.scanned.contains_key(outpoint) {
+            // mempool txs looked at before outpoint was watched weren't checked for it
+            self.mempool_txids.clear();
+            self.scanned.insert(*outpoint, from_height);
+        }
+
+        if let Some(tx) = self.find_mempool_spend(wallet, outpoint).await? {
+            return Ok(Some(tx));
+        }
+
+        let tip = wallet.lock().await.get_height().map_err(|e| {
+            WalletError::new(format!("failed to get current height: {:?}", e.to_string()))
+        })?;
+        let start = scan_start(self.scanned[outpoint], from_height);
+        for height in start..=tip {
+            let block = wallet.lock().await.get_block_at(height)?;
+            if let Some(tx) = LooperWallet::find_spend_in_block(block, outpoint) {
+                // the spend's block is scanned again until it's buried
+                self.scanned.insert(*outpoint, height);
+                return Ok(Some(tx));
+            }
+            self.scanned.insert(*outpoint, height + 1);
+        }
+
+        Ok(None)
+    }
+
+    /// retain stops tracking the outputs that aren't in watched anymore.
+    pub fn retain(&mut self, watched: &HashSet<OutPoint>) {
+        self.scanned
+            .retain(|outpoint, _| watched.contains(outpoint));
+        self.mempool_spends
+            .retain(|outpoint, _| watched.contains(outpoint));
+    }
+
+    // find_mempool_spend looks at the mempool txs that are new since the last call, and returns the one spending
+    // outpoint if there is one.
+    async fn find_mempool_spend(
+        &mut self,
+        wallet: &Mutex<LooperWallet>,
+        outpoint: &OutPoint,
+    ) -> Result<Option<Transaction>, WalletError> {
+        let mempool: HashSet<Txid> = wallet
+            .lock()
+            .await
+            .get_mempool_txids()?
+            .into_iter()
+            .collect();
+        self.mempool_txids.retain(|txid| mempool.contains(txid));
+        self.mempool_spends.retain(|_, txid| mempool.contains(txid));
+
+        for txid in mempool {
+            if !self.mempool_txids.insert(txid) {
+                continue;
+            }
+            // txs can leave the mempool between listing and fetching them
+            let tx = match wallet.lock().await.get_raw_tx(&txid) {
+                Ok(tx) => tx,
+                Err(_) => continue,
+            };
+            for txin in tx.input {
+                if self.scanned.contains_key(&txin.previous_output) {
+                    self.mempool_spends.insert(txin.previous_output, txid);
+                }
+            }
+        }
+
+        let txid = match self.mempool_spends.get(outpoint) {
+            Some(txid) => *txid,
+            None => return Ok(None),
+        };
+        match wallet.lock().await.get_raw_tx(&txid) {
+            Ok(tx) => Ok(Some(tx)),
+            // mined or dropped since, so it's looked for in the blocks instead
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+// scan_start returns the height to scan for an output's spend from, given the next unscanned height. The last
+// SPEND_RESCAN_DEPTH blocks are scanned again in case they were reorged out.
+fn scan_start(scanned: u32, from_height: u32) -> u32 {
+    scanned.saturating_sub(SPEND_RESCAN_DEPTH).max(from_height)
+}
+
+/// HtlcUtxo is a funded HTLC output along with the taproot tree it commits to.
+pub struct HtlcUtxo {
+    pub outpoint: OutPoint,
+    pub amount: u64,
+    pub spend_info: TaprootSpendInfo,
+}
+
 #[derive(Debug)]
 pub struct WalletError {
     pub message: String,
@@ -394,3 +1010,349 @@ impl WalletError {
         Self { message }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn test_pubkey(secret: u8) -> XOnlyPublicKey {
+        let secp256k1 = Secp256k1::new();
+        let sk = SecretKey::from_slice(&[secret; 32]).unwrap();
+        let (pk, _) = KeyPair::from_secret_key(&secp256k1, &sk).x_only_public_key();
+        pk
+    }
+
+    #[test]
+    fn test_extract_htlc_preimage() {
+        let preimage = [7u8; 32];
+        let payment_hash = utils::sha256(&preimage);
+        let htlc_script = LooperWallet::new_htlc_script(&test_pubkey(1), &payment_hash);
+
+        let witness = Witness::from_vec(vec![
+            preimage.to_vec(),
+            vec![1u8; 64],
+            htlc_script.to_bytes(),
+            vec![0xc0; 65],
+        ]);
+
+        assert_eq!(
+            Some(preimage),
+            LooperWallet::extract_htlc_preimage(&witness, &htlc_script, &payment_hash)
+        );
+    }
+
+    #[test]
+    fn test_extract_htlc_preimage_rejects_other_spends() {
+        let preimage = [7u8; 32];
+        let payment_hash = utils::sha256(&preimage);
+        let htlc_script = LooperWallet::new_htlc_script(&test_pubkey(1), &payment_hash);
+        let locktime = LockTime::from_height(100).unwrap();
+        let timeout_script = LooperWallet::new_timeout_script(test_pubkey(2), locktime);
+
+        // timeout leaf spend
+        let witness = Witness::from_vec(vec![
+            vec![1u8; 64],
+            timeout_script.to_bytes(),
+            vec![0xc0; 65],
+        ]);
+        assert_eq!(
+            None,
+            LooperWallet::extract_htlc_preimage(&witness, &htlc_script, &payment_hash)
+        );
+
+        // wrong preimage
+        let witness = Witness::from_vec(vec![
+            [8u8; 32].to_vec(),
+            vec![1u8; 64],
+            htlc_script.to_bytes(),
+            vec![0xc0; 65],
+        ]);
+        assert_eq!(
+            None,
+            LooperWallet::extract_htlc_preimage(&witness, &htlc_script, &payment_hash)
+        );
+    }
+
+    #[test]
+    fn test_is_timeout_spend() {
+        let payment_hash = utils::sha256(&[7u8; 32]);
+        let htlc_script = LooperWallet::new_htlc_script(&test_pubkey(1), &payment_hash);
+        let locktime = LockTime::from_height(100).unwrap();
+        let timeout_script = LooperWallet::new_timeout_script(test_pubkey(2), locktime);
+
+        let witness = Witness::from_vec(vec![
+            vec![1u8; 64],
+            timeout_script.to_bytes(),
+            vec![0xc0; 65],
+        ]);
+        assert!(LooperWallet::is_timeout_spend(&witness, &timeout_script));
+
+        // claim leaf spend
+        let witness = Witness::from_vec(vec![
+            [7u8; 32].to_vec(),
+            vec![1u8; 64],
+            htlc_script.to_bytes(),
+            vec![0xc0; 65],
+        ]);
+        assert!(!LooperWallet::is_timeout_spend(&witness, &timeout_script));
+    }
+
+    #[test]
+    fn test_check_musig_claim_tx() {
+        let secp256k1 = Secp256k1::new();
+        let outpoint = OutPoint::new(Txid::all_zeros(), 1);
+        let claim_tx = |previous_outputs: &[OutPoint], values: &[u64]| Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: previous_outputs
+                .iter()
+                .map(|previous_output| TxIn {
+                    previous_output: *previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: values
+                .iter()
+                .map(|value| TxOut {
+                    value: *value,
+                    script_pubkey: ScriptBuf::new_v1_p2tr(&secp256k1, test_pubkey(1), None),
+                })
+                .collect(),
+        };
+
+        assert!(LooperWallet::check_musig_claim_tx(
+            &claim_tx(&[outpoint], &[99_000]),
+            &outpoint,
+            100_000
+        )
+        .is_ok());
+        assert!(LooperWallet::check_musig_claim_tx(
+            &claim_tx(&[outpoint], &[50_000, 49_000]),
+            &outpoint,
+            100_000
+        )
+        .is_ok());
+
+        // other inputs
+        let other = OutPoint::new(Txid::all_zeros(), 2);
+        assert!(LooperWallet::check_musig_claim_tx(
+            &claim_tx(&[other], &[99_000]),
+            &outpoint,
+            100_000
+        )
+        .is_err());
+        assert!(LooperWallet::check_musig_claim_tx(
+            &claim_tx(&[outpoint, other], &[99_000]),
+            &outpoint,
+            100_000
+        )
+        .is_err());
+        // no outputs, dust, no fee and overflowing outputs
+        for values in [vec![], vec![99_000, 1], vec![100_000], vec![u64::MAX, 1]] {
+            assert!(LooperWallet::check_musig_claim_tx(
+                &claim_tx(&[outpoint], &values),
+                &outpoint,
+                100_000
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_find_output_index() {
+        let secp256k1 = Secp256k1::new();
+        let htlc_script = ScriptBuf::new_v1_p2tr(&secp256k1, test_pubkey(1), None);
+        let change_script = ScriptBuf::new_v1_p2tr(&secp256k1, test_pubkey(2), None);
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 5_000,
+                    script_pubkey: change_script.clone(),
+                },
+                TxOut {
+                    value: 100_000,
+                    script_pubkey: htlc_script.clone(),
+                },
+            ],
+        };
+
+        assert_eq!(
+            Some(1),
+            LooperWallet::find_output_index(&tx, &htlc_script, 100_000)
+        );
+        assert_eq!(
+            Some(0),
+            LooperWallet::find_output_index(&tx, &change_script, 5_000)
+        );
+        assert_eq!(
+            None,
+            LooperWallet::find_output_index(&tx, &htlc_script, 5_000)
+        );
+    }
+
+    #[test]
+    fn test_find_output_vouts() {
+        let secp256k1 = Secp256k1::new();
+        let htlc_scripts: Vec<ScriptBuf> = (1..=3)
+            .map(|i| ScriptBuf::new_v1_p2tr(&secp256k1, test_pubkey(i), None))
+            .collect();
+        let change_script = ScriptBuf::new_v1_p2tr(&secp256k1, test_pubkey(4), None);
+        let txout = |script_pubkey: &ScriptBuf, value| TxOut {
+            value,
+            script_pubkey: script_pubkey.clone(),
+        };
+        // a batch funding tx with its change in the middle and the htlcs out of order
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![
+                txout(&htlc_scripts[2], 300_000),
+                txout(&change_script, 5_000),
+                txout(&htlc_scripts[0], 100_000),
+                txout(&htlc_scripts[1], 100_000),
+            ],
+        };
+
+        let outputs: Vec<(ScriptBuf, u64)> = vec![
+            (htlc_scripts[0].clone(), 100_000),
+            (htlc_scripts[1].clone(), 100_000),
+            (htlc_scripts[2].clone(), 300_000),
+        ];
+        assert_eq!(
+            Some(vec![2, 3, 0]),
+            LooperWallet::find_output_vouts(&tx, &outputs)
+        );
+
+        // the amount must match too
+        let outputs = vec![
+            (htlc_scripts[0].clone(), 100_000),
+            (htlc_scripts[2].clone(), 100_000),
+        ];
+        assert_eq!(None, LooperWallet::find_output_vouts(&tx, &outputs));
+    }
+
+    #[test]
+    fn test_find_output_to_script() {
+        use bdk::bitcoin::{
+            block::{Header, Version},
+            BlockHash, CompactTarget, TxMerkleNode,
+        };
+
+        let secp256k1 = Secp256k1::new();
+        let htlc_script = ScriptBuf::new_v1_p2tr(&secp256k1, test_pubkey(1), None);
+        let other_script = ScriptBuf::new_v1_p2tr(&secp256k1, test_pubkey(2), None);
+        let tx = |script_pubkey: &ScriptBuf| Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let block = Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0,
+            },
+            txdata: vec![tx(&other_script), tx(&htlc_script)],
+        };
+
+        let (outpoint, txout) = LooperWallet::find_output_to_script(&block, &htlc_script)
+            .expect("htlc output not found");
+        assert_eq!(block.txdata[1].txid(), outpoint.txid);
+        assert_eq!(0, outpoint.vout);
+        assert_eq!(100_000, txout.value);
+
+        let unrelated_script = ScriptBuf::new_v1_p2tr(&secp256k1, test_pubkey(3), None);
+        assert_eq!(
+            None,
+            LooperWallet::find_output_to_script(&block, &unrelated_script)
+        );
+    }
+
+    #[test]
+    fn test_find_spend_in_block() {
+        use bdk::bitcoin::{
+            block::{Header, Version},
+            BlockHash, CompactTarget, TxMerkleNode,
+        };
+
+        let outpoint = |vout| OutPoint {
+            txid: Txid::all_zeros(),
+            vout,
+        };
+        let tx = |spent: OutPoint| Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: spent,
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+        let block = Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0,
+            },
+            txdata: vec![tx(outpoint(0)), tx(outpoint(1))],
+        };
+
+        let spend = LooperWallet::find_spend_in_block(block.clone(), &outpoint(1))
+            .expect("spend not found");
+        assert_eq!(block.txdata[1].txid(), spend.txid());
+        assert_eq!(None, LooperWallet::find_spend_in_block(block, &outpoint(2)));
+    }
+
+    #[test]
+    fn test_scan_start() {
+        // the first scan starts at from_height
+        assert_eq!(100, scan_start(100, 100));
+        // later scans pick up where the last one left off, less the blocks that could have been reorged out
+        assert_eq!(144, scan_start(150, 100));
+        assert_eq!(100, scan_start(103, 100));
+    }
+
+    #[test]
+    fn test_is_key_spend() {
+        assert!(LooperWallet::is_key_spend(&Witness::from_vec(vec![
+            vec![1u8; 64]
+        ])));
+
+        let witness = Witness::from_vec(vec![vec![1u8; 64], vec![2u8; 32], vec![0xc0; 33]]);
+        assert!(!LooperWallet::is_key_spend(&witness));
+    }
+
+    #[test]
+    fn test_new_musig_htlc() {
+        let payment_hash = utils::sha256(&[7u8; 32]);
+        let tr = LooperWallet::new_musig_htlc(test_pubkey(1), test_pubkey(2), &payment_hash, 100)
+            .unwrap();
+
+        // the internal key doesn't depend on which side aggregates it
+        let key_agg_ctx =
+            LooperWallet::new_musig_key_agg_ctx(test_pubkey(2), test_pubkey(1)).unwrap();
+        assert_eq!(key_agg_ctx.agg_pubkey(), tr.internal_key());
+
+        let key_agg_ctx = key_agg_ctx.with_taproot_tweak(tr.merkle_root()).unwrap();
+        assert_eq!(key_agg_ctx.agg_pubkey(), tr.output_key().to_inner());
+
+        let htlc_script = LooperWallet::new_htlc_script(&test_pubkey(1), &payment_hash);
+        assert!(tr
+            .control_block(&(htlc_script, LeafVersion::TapScript))
+            .is_some());
+    }
+}